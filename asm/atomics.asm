@@ -0,0 +1,47 @@
+; Goes through the A extension on a single core. A0 points at scratch memory, with each
+; amo*.w instruction's result stored after the word it works on, and an lr.w/sc.w counter on the
+; next cache line.
+
+atomics:
+        li      t0,5
+        sw      t0,0(a0)
+        li      t1,3
+        amoadd.w t2,t1,(a0)
+        sw      t2,4(a0)
+        li      t1,-2
+        amomax.w t2,t1,(a0)
+        sw      t2,8(a0)
+        amominu.w t2,t1,(a0)
+        sw      t2,12(a0)
+        amomaxu.w t2,t1,(a0)
+        sw      t2,16(a0)
+        li      t1,0xff
+        amoand.w t2,t1,(a0)
+        sw      t2,20(a0)
+        li      t1,0x100
+        amoor.w t2,t1,(a0)
+        sw      t2,24(a0)
+        li      t1,0x10
+        amoxor.w t2,t1,(a0)
+        sw      t2,28(a0)
+        li      t1,42
+        amoswap.w.aqrl t2,t1,(a0)
+        sw      t2,32(a0)
+
+        addi    a1,a0,64
+        li      t0,10
+.Lincrement:
+        lr.w.aq t1,(a1)
+        addi    t1,t1,1
+        sc.w.rl t2,t1,(a1)
+        bnez    t2,.Lincrement
+        addi    t0,t0,-1
+        bnez    t0,.Lincrement
+
+        ; A store to the reserved line breaks the reservation, and so does the sc.w itself.
+        lr.w    t1,(a1)
+        sw      t1,4(a1)
+        sc.w    t2,t1,(a1)
+        sw      t2,36(a0)
+        sc.w    t2,t1,(a1)
+        sw      t2,40(a0)
@@ -0,0 +1,45 @@
+; Runs each Zba/Zbb instruction on A1 and A2, storing the results from A0 onwards.
+
+bitmanip:
+        sh1add  t0,a1,a2
+        sw      t0,0(a0)
+        sh2add  t0,a1,a2
+        sw      t0,4(a0)
+        sh3add  t0,a1,a2
+        sw      t0,8(a0)
+        andn    t0,a1,a2
+        sw      t0,12(a0)
+        orn     t0,a1,a2
+        sw      t0,16(a0)
+        xnor    t0,a1,a2
+        sw      t0,20(a0)
+        min     t0,a1,a2
+        sw      t0,24(a0)
+        minu    t0,a1,a2
+        sw      t0,28(a0)
+        max     t0,a1,a2
+        sw      t0,32(a0)
+        maxu    t0,a1,a2
+        sw      t0,36(a0)
+        rol     t0,a1,a2
+        sw      t0,40(a0)
+        ror     t0,a1,a2
+        sw      t0,44(a0)
+        clz     t0,a1
+        sw      t0,48(a0)
+        ctz     t0,a1
+        sw      t0,52(a0)
+        cpop    t0,a1
+        sw      t0,56(a0)
+        sext.b  t0,a1
+        sw      t0,60(a0)
+        sext.h  t0,a1
+        sw      t0,64(a0)
+        zext.h  t0,a1
+        sw      t0,68(a0)
+        rev8    t0,a1
+        sw      t0,72(a0)
+        clz     t0,zero
+        sw      t0,76(a0)
+        ctz     t0,zero
+        sw      t0,80(a0)
@@ -0,0 +1,16 @@
+; Blends two 8-bit images of A3 pixels from A0 and A1 half and half into A2, as many pixels at a
+; time as fit in a vector register.
+
+blend:
+        vsetvli t0,a3,e8,m1,ta,ma
+        vle8.v  v1,(a0)
+        vle8.v  v2,(a1)
+        vsrl.vi v1,v1,1
+        vsrl.vi v2,v2,1
+        vadd.vv v3,v1,v2
+        vse8.v  v3,(a2)
+        add     a0,a0,t0
+        add     a1,a1,t0
+        add     a2,a2,t0
+        sub     a3,a3,t0
+        bnez    a3,blend
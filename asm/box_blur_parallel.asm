@@ -0,0 +1,66 @@
+; Parallel version of box_blur, for running on several cores. Each core blurs every `cores`th
+; row, writing to the same output as box_blur:
+;
+; void box_blur(int *in_data, int dims, int core, int cores) {
+;     int *out_data = (int *)500000;
+;
+;     for (int y = core; y < dims; y += cores) {
+;         for (int x = 0; x < dims; x++) {
+;             int count = 0, total = 0;
+;
+;             for (int xn = x - 1; xn <= x + 1; xn++) {
+;                 for (int yn = y - 1; yn <= y + 1; yn++) {
+;                     if ((0 <= xn && xn < dims) && (0 <= yn && yn < dims)) {
+;                         total += in_data[y * dims + x];
+;                         count++;
+;                     }
+;                 }
+;             }
+;
+;             out_data[y * dims + x] = total / count;
+;         }
+;     }
+; }
+
+box_blur:
+        lui     t0,122
+        addi    t0,t0,288
+        mv      t1,a2
+.Ly:
+        bge     t1,a1,.Ldone
+        li      t2,0
+.Lx:
+        bge     t2,a1,.Lnext_y
+        mul     t3,t1,a1
+        add     t3,t3,t2
+        slli    t3,t3,2
+        add     t4,a0,t3
+        lw      t4,0(t4)
+        li      t5,0
+        li      t6,0
+        addi    a4,t2,-1
+.Lxn:
+        addi    a5,t1,-1
+.Lyn:
+        blt     a4,zero,.Lskip
+        bge     a4,a1,.Lskip
+        blt     a5,zero,.Lskip
+        bge     a5,a1,.Lskip
+        add     t6,t6,t4
+        addi    t5,t5,1
+.Lskip:
+        addi    a5,a5,1
+        addi    a6,t1,1
+        ble     a5,a6,.Lyn
+        addi    a4,a4,1
+        addi    a6,t2,1
+        ble     a4,a6,.Lxn
+        div     t6,t6,t5
+        add     a7,t0,t3
+        sw      t6,0(a7)
+        addi    t2,t2,1
+        j       .Lx
+.Lnext_y:
+        add     t1,t1,a3
+        j       .Ly
+.Ldone:
@@ -0,0 +1,29 @@
+; Sums 1..A0 recursively, then does a little arithmetic on the result, written entirely in RVC.
+; A2 points to a word of scratch memory.
+
+compressed:
+        c.li    a1,0
+        c.jal   .Lsum
+        c.sw    a1,0(a2)
+        c.lw    a3,0(a2)
+        c.slli  a3,2
+        c.srli  a3,1
+        c.andi  a3,31
+        c.mv    a0,a1
+        c.sub   a0,a3
+        c.lui   a4,1
+        c.addi4spn a5,sp,8
+        c.j     .Ldone
+.Lsum:
+        c.beqz  a0,.Lret
+        c.addi16sp sp,-16
+        c.swsp  ra,12(sp)
+        c.add   a1,a0
+        c.addi  a0,-1
+        c.jal   .Lsum
+        c.lwsp  ra,12(sp)
+        c.addi16sp sp,16
+.Lret:
+        c.jr    ra
+.Ldone:
+        c.nop
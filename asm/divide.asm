@@ -0,0 +1,13 @@
+; Eight independent divides, so only the divider limits how fast they go.
+
+        li      a1,7
+        divu    a2,a0,a1
+        divu    a3,a0,a1
+        divu    a4,a0,a1
+        divu    a5,a0,a1
+        rem     a6,a0,a1
+        rem     a7,a0,a1
+        div     t0,a0,a1
+        div     t1,a0,a1
+        mul     t2,a0,a1
+        mulhu   t3,a0,a1
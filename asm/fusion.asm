@@ -0,0 +1,46 @@
+; Exercises every macro-op fusion pattern while summing the n words at a0 (n in a1) in
+; different ways. Results are left in the saved registers.
+
+fusion:
+        li      t0,0
+        li      s0,0
+        li      s1,0
+        li      s2,0
+        li      s3,0
+        li      s4,0
+        li      s5,0
+        li      s6,0
+        li      s7,0
+        lui     a2,74565
+        addi    a2,a2,1656
+.L2:
+        slli    t1,t0,2
+        add     t2,a0,t1
+        lw      t2,0(t2)
+        add     s0,s0,t2
+        add     t3,a0,t1
+        lb      t3,1(t3)
+        add     s1,s1,t3
+        add     t4,a0,t1
+        lbu     t4,2(t4)
+        add     s2,s2,t4
+        add     t5,a0,t1
+        lh      t5,2(t5)
+        add     s3,s3,t5
+        slli    t6,t2,16
+        srli    t6,t6,16
+        add     s4,s4,t6
+        sltu    a3,t2,a2
+        beqz    a3,.L3
+        addi    s5,s5,1
+.L3:
+        slti    a4,t2,0
+        bnez    a4,.L4
+        addi    s6,s6,1
+.L4:
+        slli    a5,t0,2
+        add     a5,a5,a0
+        add     s7,s7,a5
+        addi    t0,t0,1
+        sltu    a6,t0,a1
+        bnez    a6,.L2
@@ -0,0 +1,24 @@
+; Sums the elements of a picked out by idx, with slli+add for the indexing:
+;
+; int gather(int *a, int *idx, int n) {
+;     int sum = 0;
+;     for (int i = 0; i < n; i++) {
+;         sum += a[idx[i]];
+;     }
+;     return sum;
+; }
+
+gather:
+        li      t0,0
+        li      t1,0
+.Lloop:
+        slli    t2,t0,2
+        add     t2,t2,a1
+        lw      t3,0(t2)
+        slli    t3,t3,2
+        add     t3,t3,a0
+        lw      t3,0(t3)
+        add     t1,t1,t3
+        addi    t0,t0,1
+        blt     t0,a2,.Lloop
+        mv      a0,t1
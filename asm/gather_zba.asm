@@ -0,0 +1,14 @@
+; gather, indexing with sh2add instead of slli+add.
+
+gather:
+        li      t0,0
+        li      t1,0
+.Lloop:
+        sh2add  t2,t0,a1
+        lw      t3,0(t2)
+        sh2add  t3,t3,a0
+        lw      t3,0(t3)
+        add     t1,t1,t3
+        addi    t0,t0,1
+        blt     t0,a2,.Lloop
+        mv      a0,t1
@@ -0,0 +1,54 @@
+; matmul on floats, with the inputs and output laid out the same way:
+;
+; void matmul_float(float *start, int dim) {
+;     float *a = start;
+;     float *b = start + dim * dim;
+;     float *out = start + (dim * dim * 2);
+;
+;     for (int j = 0; j < dim; j++) {
+;         for (int i = 0; i < dim; i++) {
+;             float acc = 0;
+;             for (int k = 0; k < dim; k++) {
+;                 acc = fmaf(a[j * dim + k], b[k * dim + i], acc);
+;             }
+;             out[j * dim + i] = acc;
+;         }
+;     }
+; }
+
+matmul_float:
+        mul     t0,a1,a1
+        slli    t0,t0,2
+        add     t1,a0,t0
+        add     t2,t1,t0
+        slli    t3,a1,2
+        li      t4,0
+.Lrow:
+        bge     t4,a1,.Ldone
+        mul     t5,t4,t3
+        add     t6,a0,t5
+        add     s0,t2,t5
+        li      s1,0
+.Lcol:
+        bge     s1,a1,.Lnext_row
+        slli    a4,s1,2
+        add     a5,t1,a4
+        mv      a6,t6
+        fmv.w.x fa0,zero
+        li      s2,0
+.Lk:
+        flw     fa1,0(a6)
+        flw     fa2,0(a5)
+        fmadd.s fa0,fa1,fa2,fa0
+        addi    a6,a6,4
+        add     a5,a5,t3
+        addi    s2,s2,1
+        blt     s2,a1,.Lk
+        fsw     fa0,0(s0)
+        addi    s0,s0,4
+        addi    s1,s1,1
+        j       .Lcol
+.Lnext_row:
+        addi    t4,t4,1
+        j       .Lrow
+.Ldone:
@@ -0,0 +1,56 @@
+; Parallel version of matmul, for running on several cores. The inputs are already in memory,
+; laid out as in matmul, and each core computes every `cores`th row of the output:
+;
+; void matmul(int *start, int dim, int core, int cores) {
+;     int *a = start;
+;     int *b = start + dim * dim;
+;     int *out = start + (dim * dim * 2);
+;
+;     for (int j = core; j < dim; j += cores) {
+;         for (int i = 0; i < dim; i++) {
+;             int acc = out[j * dim + i];
+;             for (int k = 0; k < dim; k++) {
+;                 acc += a[j * dim + k] * b[k * dim + i];
+;             }
+;             out[j * dim + i] = acc;
+;         }
+;     }
+; }
+
+matmul:
+        mul     t0,a1,a1
+        slli    t0,t0,2
+        add     t1,a0,t0
+        add     t2,t1,t0
+        slli    t3,a1,2
+        mv      t4,a2
+.Lrow:
+        bge     t4,a1,.Ldone
+        mul     t5,t4,t3
+        add     t6,a0,t5
+        add     s0,t2,t5
+        li      s1,0
+.Lcol:
+        bge     s1,a1,.Lnext_row
+        slli    a4,s1,2
+        add     a5,t1,a4
+        mv      a6,t6
+        lw      a7,0(s0)
+        li      s2,0
+.Lk:
+        lw      a4,0(a6)
+        lw      s3,0(a5)
+        mul     a4,a4,s3
+        add     a7,a7,a4
+        addi    a6,a6,4
+        add     a5,a5,t3
+        addi    s2,s2,1
+        blt     s2,a1,.Lk
+        sw      a7,0(s0)
+        addi    s0,s0,4
+        addi    s1,s1,1
+        j       .Lcol
+.Lnext_row:
+        add     t4,t4,a3
+        j       .Lrow
+.Ldone:
@@ -0,0 +1,27 @@
+; Misaligned loads and stores, some of which cross a cache line. A0 points at scratch memory that
+; starts on a line boundary, and the loaded values are stored from A0+128. The amoadd.w at the end
+; is misaligned too, which faults whatever the policy.
+
+misaligned:
+        lui     t0,0x11223
+        addi    t0,t0,0x344
+        sw      t0,1(a0)
+        lw      t1,1(a0)
+        sw      t1,128(a0)
+        lh      t1,2(a0)
+        sw      t1,132(a0)
+
+        ; Both of these straddle the line at A0+64, and the loads read them back across it.
+        li      t1,-2
+        sh      t1,61(a0)
+        sw      t0,63(a0)
+        lw      t1,60(a0)
+        sw      t1,136(a0)
+        lw      t1,62(a0)
+        sw      t1,140(a0)
+        lh      t1,61(a0)
+        sw      t1,144(a0)
+
+        addi    a1,a0,2
+        amoadd.w t1,t0,(a1)
+        sw      t1,148(a0)
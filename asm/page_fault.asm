@@ -0,0 +1,74 @@
+; Demand paging under Sv32. A0 and A1 point at empty, page-aligned memory for the root and level-0
+; page tables. Only the code and the level-0 table are mapped up front, and the handler maps the
+; rest of the first 4 MiB in one page at a time as it's touched. A2 points at A3 pages of data,
+; which are written to, then A3 pages after that are read from. The number of faults is left in
+; s0 and the sum of their causes in s1.
+
+page_fault:
+        ; The root table's first entry points at the level-0 table.
+        srli    t0,a1,12
+        slli    t0,t0,10
+        ori     t0,t0,1
+        sw      t0,0(a0)
+
+        ; Code lives in the first page, and the handler writes to the level-0 table.
+        li      t0,0xcf
+        sw      t0,0(a1)
+        srli    t1,a1,12
+        slli    t0,t1,10
+        ori     t0,t0,0xcf
+        slli    t1,t1,2
+        add     t1,t1,a1
+        sw      t0,0(t1)
+
+        ; Leaves the handler's address in t0.
+        jal     t0,.Lsetup
+
+        ; Identity map the faulting page and go back to retry the access.
+.Lhandler:
+        csrr    t5,scause
+        add     s1,s1,t5
+        addi    s0,s0,1
+        csrr    t5,stval
+        srli    t5,t5,12
+        slli    t6,t5,10
+        ori     t6,t6,0xcf
+        slli    t5,t5,2
+        add     t5,t5,a1
+        sw      t6,0(t5)
+        sfence.vma
+        sret
+
+.Lsetup:
+        csrw    stvec,t0
+        li      t0,1
+        slli    t0,t0,31
+        srli    t1,a0,12
+        or      t0,t0,t1
+        csrw    satp,t0
+
+        li      s0,0
+        li      s1,0
+        mv      t0,a2
+        mv      t1,a3
+.Lwrite:
+        sw      t1,0(t0)
+        sw      t1,4(t0)
+        lui     t2,1
+        add     t0,t0,t2
+        addi    t1,t1,-1
+        bnez    t1,.Lwrite
+
+        li      t3,0
+        mv      t1,a3
+.Lread:
+        lw      t2,0(t0)
+        add     t3,t3,t2
+        lui     t2,1
+        add     t0,t0,t2
+        addi    t1,t1,-1
+        bnez    t1,.Lread
+
+        sw      s0,8(a2)
+        sw      s1,12(a2)
+        sw      t3,16(a2)
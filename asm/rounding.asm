@@ -0,0 +1,37 @@
+; Goes through each rounding mode in fcsr, storing 1/3, 2.5 and -2.5 rounded to integers, and the
+; mode read back, from A0 onwards. Then a static rounding mode overrides fcsr, and fflags is
+; written and read back along with the rest of fcsr.
+
+rounding:
+        li      t0,1
+        fcvt.s.w ft0,t0
+        li      t0,3
+        fcvt.s.w ft1,t0
+        li      t0,5
+        fcvt.s.w ft2,t0
+        li      t0,2
+        fcvt.s.w ft3,t0
+        fdiv.s  ft2,ft2,ft3
+        fneg.s  ft3,ft2
+        li      t1,0
+        li      t2,5
+.Lmode:
+        fsrm    t1
+        fdiv.s  ft4,ft0,ft1
+        fsw     ft4,0(a0)
+        fcvt.w.s t3,ft2
+        sw      t3,4(a0)
+        fcvt.w.s t3,ft3
+        sw      t3,8(a0)
+        frrm    t3
+        sw      t3,12(a0)
+        addi    a0,a0,16
+        addi    t1,t1,1
+        blt     t1,t2,.Lmode
+
+        fcvt.w.s t3,ft3,rtz
+        sw      t3,0(a0)
+        li      t0,0x1f
+        fsflags t0
+        frcsr   t3
+        sw      t3,4(a0)
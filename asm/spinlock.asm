@@ -0,0 +1,29 @@
+; Adds to a shared counter A1 times under a spinlock, for running on several cores:
+;
+; void count(int *lock, int times) {
+;     for (int i = 0; i < times; i++) {
+;         while (lr(lock) != 0 || !sc(lock, 1)) {}
+;         lock[1]++;
+;         amoswap_release(lock, 0);
+;         amoadd(lock + 16, 1);
+;     }
+; }
+;
+; A0 points at the lock, with the counter in the next word. The second counter is on a line of
+; its own, and only ever updated with amoadd.w.
+
+count:
+        li      t0,1
+        addi    t3,a0,64
+.Lacquire:
+        lr.w.aq t1,(a0)
+        bnez    t1,.Lacquire
+        sc.w    t1,t0,(a0)
+        bnez    t1,.Lacquire
+        lw      t2,4(a0)
+        addi    t2,t2,1
+        sw      t2,4(a0)
+        amoswap.w.rl zero,zero,(a0)
+        amoadd.w zero,t0,(t3)
+        addi    a1,a1,-1
+        bnez    a1,.Lacquire
@@ -0,0 +1,101 @@
+; Runs each vector instruction on the 16 words from A0 with A2 elements, giving every result its
+; own 32 bytes from A1 onwards. Words 0-7 are the first vector operand, 8-15 the second.
+
+vector_ops:
+        li      t2,-3
+        li      t3,33
+        li      t5,8
+        addi    t6,a0,32
+        vsetvli t0,a2,e32,m1,ta,ma
+        sw      t0,0(a1)
+        vle32.v v1,(a0)
+        vle32.v v2,(t6)
+
+        addi    a1,a1,32
+        vadd.vv v3,v1,v2
+        vse32.v v3,(a1)
+        addi    a1,a1,32
+        vsub.vv v3,v1,v2
+        vse32.v v3,(a1)
+        addi    a1,a1,32
+        vmul.vx v3,v1,t2
+        vse32.v v3,(a1)
+        addi    a1,a1,32
+        vand.vi v3,v1,12
+        vse32.v v3,(a1)
+        addi    a1,a1,32
+        vor.vv  v3,v1,v2
+        vse32.v v3,(a1)
+        addi    a1,a1,32
+        vxor.vx v3,v1,t2
+        vse32.v v3,(a1)
+        addi    a1,a1,32
+        vsll.vi v3,v2,3
+        vse32.v v3,(a1)
+        addi    a1,a1,32
+        vsrl.vx v3,v1,t3
+        vse32.v v3,(a1)
+        addi    a1,a1,32
+        vsra.vi v3,v1,2
+        vse32.v v3,(a1)
+
+        addi    a1,a1,32
+        vredsum.vs v4,v1,v2
+        vmv.x.s t4,v4
+        sw      t4,0(a1)
+        vredand.vs v4,v1,v2
+        vmv.x.s t4,v4
+        sw      t4,4(a1)
+        vredor.vs v4,v1,v2
+        vmv.x.s t4,v4
+        sw      t4,8(a1)
+        vredxor.vs v4,v1,v2
+        vmv.x.s t4,v4
+        sw      t4,12(a1)
+        vredmax.vs v4,v1,v2
+        vmv.x.s t4,v4
+        sw      t4,16(a1)
+        vredmaxu.vs v4,v1,v2
+        vmv.x.s t4,v4
+        sw      t4,20(a1)
+        vredmin.vs v4,v1,v2
+        vmv.x.s t4,v4
+        sw      t4,24(a1)
+        vredminu.vs v4,v1,v2
+        vmv.x.s t4,v4
+        sw      t4,28(a1)
+
+        addi    a1,a1,32
+        vmv.v.x v3,t2
+        vse32.v v3,(a1)
+        addi    a1,a1,32
+        vlse32.v v3,(a0),t5
+        vse32.v v3,(a1)
+        addi    a1,a1,32
+        vsse32.v v1,(a1),t5
+
+        ; Elements past vl are left as they were.
+        addi    a1,a1,64
+        vsetvli t0,zero,e32
+        vmv.v.x v6,t2
+        vsetvli t0,a2,e32
+        vadd.vv v6,v1,v2
+        vsetvli t0,zero,e32
+        vse32.v v6,(a1)
+
+        addi    a1,a1,32
+        vsetvli t0,zero,e8,m1,tu,mu
+        sw      t0,0(a1)
+        addi    a1,a1,32
+        vle8.v  v5,(a0)
+        vadd.vi v5,v5,-1
+        vse8.v  v5,(a1)
+        addi    a1,a1,32
+        vmv.x.s t4,v5
+        sw      t4,0(a1)
+
+        addi    a1,a1,32
+        vsetvli t0,a2,e16
+        vle16.v v7,(a0)
+        vmul.vv v7,v7,v7
+        vse16.v v7,(a1)
@@ -0,0 +1,127 @@
+use std::{fs::File, io::BufReader, io::BufWriter, time::Instant};
+
+use aca::{
+    branch::{BranchPredictor, BranchPredictorConfig},
+    cpu::Cpu,
+    emulated::Emulated,
+    trace::BranchTrace,
+};
+
+const USAGE: &str = "usage:
+    trace record <trace> <program> [param1] [param2]
+    trace replay <trace> [ras_depth=N] [itp_index_bits=N] [itp_history_bits=N] [loop_entries=N]
+    trace sweep <trace>";
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let (cmd, path) = match (args.first(), args.get(1)) {
+        (Some(cmd), Some(path)) => (cmd.as_str(), path.as_str()),
+        _ => panic!("{USAGE}"),
+    };
+
+    match cmd {
+        "record" => record(path, &args[2..]),
+        "replay" => {
+            let trace = load(path);
+            let config = parse_config(&args[2..]);
+            replay(&trace, config);
+        }
+        "sweep" => sweep(&load(path)),
+        _ => panic!("{USAGE}"),
+    }
+}
+
+fn record(path: &str, args: &[String]) {
+    let name = args.first().unwrap_or_else(|| panic!("{USAGE}"));
+    let prog = aca::load_program(name);
+    let (regs, mem) = aca::initial_state(args.get(1).cloned(), args.get(2).cloned());
+
+    let (_, trace) = Emulated::new(prog, regs, mem).exec_traced();
+
+    let f = File::create(path).expect("could not create trace file");
+    trace
+        .write_to(BufWriter::new(f))
+        .expect("could not write trace");
+
+    println!(
+        "Recorded {} branches over {} instructions to {}",
+        trace.records.len(),
+        trace.insts,
+        path
+    );
+}
+
+fn load(path: &str) -> BranchTrace {
+    let f = File::open(path).expect("could not open trace file");
+    BranchTrace::read_from(BufReader::new(f)).expect("could not read trace")
+}
+
+fn parse_config(args: &[String]) -> BranchPredictorConfig {
+    let mut config = BranchPredictorConfig::default();
+
+    for arg in args {
+        let (key, val) = arg
+            .split_once('=')
+            .unwrap_or_else(|| panic!("expected key=value, got '{arg}'"));
+        let val = val
+            .parse::<usize>()
+            .unwrap_or_else(|_| panic!("invalid value for {key}: '{val}'"));
+
+        match key {
+            "ras_depth" => config.ras_depth = val,
+            "itp_index_bits" => config.itp_index_bits = val.try_into().unwrap(),
+            "itp_history_bits" => config.itp_history_bits = val.try_into().unwrap(),
+            "loop_entries" => config.loop_entries = val,
+            _ => panic!("unknown predictor parameter '{key}'"),
+        }
+    }
+
+    config
+}
+
+fn replay(trace: &BranchTrace, config: BranchPredictorConfig) {
+    let start = Instant::now();
+    let stats = trace.replay(&mut BranchPredictor::new(config));
+
+    println!("{:?}", config);
+    println!("    Instructions: {}", stats.insts);
+    println!(
+        "     Conditional: {}/{} mispredicted",
+        stats.direct_mispredicts, stats.direct_predicts
+    );
+    println!(
+        "        Indirect: {}/{} mispredicted",
+        stats.indirect_mispredicts, stats.indirect_predicts
+    );
+    println!("            MPKI: {:.3}", stats.mpki());
+    println!("    Time elapsed: {:.2}s", start.elapsed().as_secs_f32());
+}
+
+fn sweep(trace: &BranchTrace) {
+    println!("ras_depth itp_index_bits itp_history_bits loop_entries     MPKI");
+
+    for ras_depth in [4, 8, 16, 32] {
+        for itp_index_bits in [6, 8, 10, 12] {
+            for itp_history_bits in [8, 16, 24] {
+                for loop_entries in [0, 16, 64, 256] {
+                    let config = BranchPredictorConfig {
+                        ras_depth,
+                        itp_index_bits,
+                        itp_history_bits,
+                        loop_entries,
+                    };
+                    let stats = trace.replay(&mut BranchPredictor::new(config));
+
+                    println!(
+                        "{:>9} {:>14} {:>16} {:>12} {:>8.3}",
+                        ras_depth,
+                        itp_index_bits,
+                        itp_history_bits,
+                        loop_entries,
+                        stats.mpki()
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,31 +1,399 @@
 #![allow(dead_code, unused)]
 
 use hashbrown::HashMap;
+use std::collections::VecDeque;
 
-use crate::inst::{AbsPc, ArchReg, Imm, Inst, INST_SIZE};
+use crate::inst::{AbsPc, ArchReg, Imm, Inst};
 
-#[derive(Debug, Clone, Default)]
+// Fixed-depth circular return address stack. Pushing onto a full stack overwrites the oldest
+// entry, so deep call chains only lose the outermost return addresses.
+#[derive(Debug, Clone)]
+pub struct ReturnAddressStack {
+    entries: Vec<AbsPc>,
+    top: usize, // Index of the next free slot.
+    len: usize,
+}
+
+// Enough state to undo any wrong-path pushes and pops, as long as the wrong path didn't
+// overwrite anything below the top of the stack.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RasCheckpoint {
+    top: usize,
+    len: usize,
+    top_entry: AbsPc,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct IndirectEntry {
+    tag: u32,
+    target: AbsPc,
+    confidence: u8,
+}
+
+// Predicts targets of indirect jumps from a table indexed by the jump's PC hashed with the
+// targets of recently taken branches, so that the same jump can predict different targets
+// depending on how we got there (switch tables, function pointers).
+#[derive(Debug, Clone)]
+pub struct IndirectTargetPredictor {
+    table: Vec<Option<IndirectEntry>>,
+    index_bits: u32,
+    history_bits: u32,
+    path_history: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IndirectPrediction {
+    pub target: Option<AbsPc>,
+    pub from_table: bool,
+    pub is_return: bool,
+    // Path history used to make the prediction, needed to update the same table entry.
+    pub history: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct LoopEntry {
+    pc: AbsPc,
+    dir: bool, // Outcome of every iteration except the last.
+    trip_count: Option<u32>,
+    confidence: u8,
+    spec_iter: u32, // Iterations fetched so far in the current run.
+}
+
+// Learns how many times a loop branch goes the same way before exiting, so that the exit can be
+// predicted once the count is stable. Iterations are counted at fetch; each update is journaled
+// so that wrong-path iterations can be rolled back.
+#[derive(Debug, Clone)]
+pub struct LoopPredictor {
+    table: Vec<Option<LoopEntry>>,
+    journal: VecDeque<(u64, AbsPc, u32)>,
+    seq: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DirectPrediction {
+    pub taken: bool,
+    pub base_taken: bool,
+    pub from_loop: bool,
+    pub loop_iter: Option<u32>,
+    // Path history from before the prediction, which a misprediction goes back to.
+    pub history: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PredictorCheckpoint {
+    ras: RasCheckpoint,
+    path_history: u32,
+    loop_seq: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct BranchPredictorConfig {
+    pub ras_depth: usize,
+    pub itp_index_bits: u32,
+    pub itp_history_bits: u32,
+    pub loop_entries: usize, // Zero disables the loop predictor.
+}
+
+#[derive(Debug, Clone)]
 pub struct BranchPredictor {
     btb: HashMap<AbsPc, AbsPc>,
-    ras: Vec<AbsPc>,
+    ras: ReturnAddressStack,
+    itp: IndirectTargetPredictor,
+    loops: LoopPredictor,
     last_taken_map: HashMap<AbsPc, i32>,
 }
 
+impl Default for BranchPredictorConfig {
+    fn default() -> Self {
+        Self {
+            ras_depth: 16,
+            itp_index_bits: 10,
+            itp_history_bits: 16,
+            loop_entries: 64,
+        }
+    }
+}
+
+impl ReturnAddressStack {
+    pub fn new(depth: usize) -> Self {
+        assert!(depth > 0, "RAS must have at least one entry");
+
+        Self {
+            entries: vec![AbsPc::default(); depth],
+            top: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, pc: AbsPc) {
+        self.entries[self.top] = pc;
+        self.top = (self.top + 1) % self.entries.len();
+        self.len = (self.len + 1).min(self.entries.len());
+    }
+
+    pub fn pop(&mut self) -> Option<AbsPc> {
+        if self.len == 0 {
+            return None;
+        }
+
+        self.top = self.top_idx();
+        self.len -= 1;
+        Some(self.entries[self.top])
+    }
+
+    pub fn checkpoint(&self) -> RasCheckpoint {
+        RasCheckpoint {
+            top: self.top,
+            len: self.len,
+            top_entry: self.entries[self.top_idx()],
+        }
+    }
+
+    pub fn restore(&mut self, cp: RasCheckpoint) {
+        self.top = cp.top;
+        self.len = cp.len;
+
+        let idx = self.top_idx();
+        self.entries[idx] = cp.top_entry;
+    }
+
+    fn top_idx(&self) -> usize {
+        (self.top + self.entries.len() - 1) % self.entries.len()
+    }
+}
+
+impl IndirectTargetPredictor {
+    const MAX_CONFIDENCE: u8 = 3;
+
+    pub fn new(index_bits: u32, history_bits: u32) -> Self {
+        assert!(history_bits <= 32);
+
+        Self {
+            table: vec![None; 1 << index_bits],
+            index_bits,
+            history_bits,
+            path_history: 0,
+        }
+    }
+
+    pub fn path_history(&self) -> u32 {
+        self.path_history
+    }
+
+    pub fn set_path_history(&mut self, history: u32) {
+        self.path_history = history;
+    }
+
+    // Shift a few bits of each taken branch target into the history.
+    pub fn push_path(&mut self, target: AbsPc) {
+        let mask = u32::MAX.checked_shr(32 - self.history_bits).unwrap_or(0);
+        self.path_history = ((self.path_history << 2) ^ (target.table_index())) & mask;
+    }
+
+    pub fn predict(&self, pc: AbsPc, history: u32) -> Option<AbsPc> {
+        let (idx, tag) = self.index(pc, history);
+        self.table[idx]
+            .filter(|ent| ent.tag == tag)
+            .map(|ent| ent.target)
+    }
+
+    pub fn update(&mut self, pc: AbsPc, history: u32, target: AbsPc) {
+        let (idx, tag) = self.index(pc, history);
+
+        match &mut self.table[idx] {
+            Some(ent) if ent.tag == tag && ent.target == target => {
+                ent.confidence = (ent.confidence + 1).min(Self::MAX_CONFIDENCE);
+            }
+            Some(ent) if ent.confidence > 0 => ent.confidence -= 1,
+            slot => {
+                *slot = Some(IndirectEntry {
+                    tag,
+                    target,
+                    confidence: 0,
+                })
+            }
+        }
+    }
+
+    fn index(&self, pc: AbsPc, history: u32) -> (usize, u32) {
+        let pc = pc.table_index();
+        let folded = history ^ (history >> self.index_bits);
+        let idx = (pc ^ folded) & ((1 << self.index_bits) - 1);
+        let tag = pc ^ history.rotate_left(7);
+        (idx as usize, tag)
+    }
+}
+
+impl LoopPredictor {
+    const CONFIDENT: u8 = 2;
+    const MAX_CONFIDENCE: u8 = 3;
+    // Bounds the journal; the ROB can't hold more unresolved branches than this.
+    const JOURNAL_CAPACITY: usize = 1024;
+
+    pub fn new(entries: usize) -> Self {
+        Self {
+            table: vec![None; entries],
+            journal: VecDeque::new(),
+            seq: 0,
+        }
+    }
+
+    // Returns the current iteration, and the predicted direction if we're confident.
+    pub fn predict(&self, pc: AbsPc) -> (Option<u32>, Option<bool>) {
+        match self.entry(pc) {
+            Some(ent) => {
+                let prediction = ent
+                    .trip_count
+                    .filter(|_| ent.confidence >= Self::CONFIDENT)
+                    .map(|trip_count| (ent.spec_iter < trip_count) == ent.dir);
+                (Some(ent.spec_iter), prediction)
+            }
+            None => (None, None),
+        }
+    }
+
+    // Count a fetched instance of the branch.
+    pub fn advance(&mut self, pc: AbsPc, taken: bool) {
+        self.seq += 1;
+        let seq = self.seq;
+
+        if let Some(ent) = self.entry_mut(pc) {
+            let old = ent.spec_iter;
+            ent.spec_iter = if taken == ent.dir { old + 1 } else { 0 };
+
+            self.journal.push_back((seq, pc, old));
+            if self.journal.len() > Self::JOURNAL_CAPACITY {
+                self.journal.pop_front();
+            }
+        }
+    }
+
+    pub fn update(&mut self, pc: AbsPc, taken: bool, prediction: DirectPrediction) {
+        match (self.entry_mut(pc), prediction.loop_iter) {
+            (Some(ent), Some(iter)) => {
+                if taken == ent.dir {
+                    if ent.trip_count.map(|t| iter >= t).unwrap_or(false) {
+                        // Ran past the learned exit.
+                        ent.trip_count = None;
+                        ent.confidence = 0;
+                    }
+                } else if ent.trip_count == Some(iter) {
+                    ent.confidence = (ent.confidence + 1).min(Self::MAX_CONFIDENCE);
+                } else {
+                    ent.trip_count = Some(iter);
+                    ent.confidence = 0;
+                }
+
+                // Fetch followed the wrong direction, which has been rolled back by now.
+                if taken != prediction.taken {
+                    ent.spec_iter = if taken == ent.dir { iter + 1 } else { 0 };
+                }
+            }
+            (Some(_), None) => (),
+            (None, _) => {
+                // Allocate when the base predictor sees what might be a loop exit.
+                if taken != prediction.base_taken && !self.table.is_empty() {
+                    let idx = self.index(pc);
+                    match &mut self.table[idx] {
+                        Some(ent) if ent.confidence > 0 => ent.confidence -= 1,
+                        slot => {
+                            *slot = Some(LoopEntry {
+                                pc,
+                                dir: prediction.base_taken,
+                                trip_count: None,
+                                confidence: 0,
+                                spec_iter: 0,
+                            })
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn checkpoint(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn restore(&mut self, seq: u64) {
+        while let Some(&(s, pc, old)) = self.journal.back() {
+            if s <= seq {
+                break;
+            }
+
+            if let Some(ent) = self.entry_mut(pc) {
+                ent.spec_iter = old;
+            }
+            self.journal.pop_back();
+        }
+    }
+
+    fn index(&self, pc: AbsPc) -> usize {
+        pc.table_index() as usize % self.table.len()
+    }
+
+    fn entry(&self, pc: AbsPc) -> Option<&LoopEntry> {
+        if self.table.is_empty() {
+            return None;
+        }
+
+        self.table[self.index(pc)]
+            .as_ref()
+            .filter(|ent| ent.pc == pc)
+    }
+
+    fn entry_mut(&mut self, pc: AbsPc) -> Option<&mut LoopEntry> {
+        if self.table.is_empty() {
+            return None;
+        }
+
+        let idx = self.index(pc);
+        self.table[idx].as_mut().filter(|ent| ent.pc == pc)
+    }
+}
+
 impl BranchPredictor {
-    pub fn new() -> Self {
-        Self::default()
+    pub fn new(config: BranchPredictorConfig) -> Self {
+        Self {
+            btb: HashMap::new(),
+            ras: ReturnAddressStack::new(config.ras_depth),
+            itp: IndirectTargetPredictor::new(config.itp_index_bits, config.itp_history_bits),
+            loops: LoopPredictor::new(config.loop_entries),
+            last_taken_map: HashMap::new(),
+        }
     }
 
-    pub fn predict_direct(&self, pc: AbsPc, target: AbsPc) -> bool {
+    pub fn predict_direct(&mut self, pc: AbsPc, target: AbsPc) -> DirectPrediction {
+        let history = self.itp.path_history();
+
         // Simple one-bit history table with static BT, FNT fallback
-        self.last_taken_map
+        let base_taken = self
+            .last_taken_map
             .get(&pc)
             .copied()
             .map(|state| state >= 0)
-            .unwrap_or(target < pc)
+            .unwrap_or(target < pc);
+
+        let (loop_iter, loop_taken) = self.loops.predict(pc);
+        let taken = loop_taken.unwrap_or(base_taken);
+
+        self.loops.advance(pc, taken);
+        if taken {
+            self.itp.push_path(target);
+        }
+
+        DirectPrediction {
+            taken,
+            base_taken,
+            from_loop: loop_taken.is_some(),
+            loop_iter,
+            history,
+        }
     }
 
-    pub fn update_predict_direct(&mut self, pc: AbsPc, taken: bool) {
+    pub fn update_predict_direct(&mut self, pc: AbsPc, taken: bool, prediction: DirectPrediction) {
+        self.loops.update(pc, taken, prediction);
+
         let state = match self.last_taken_map.get(&pc) {
             Some(state) => {
                 if taken {
@@ -44,23 +412,203 @@ impl BranchPredictor {
         self.last_taken_map.insert(pc, state);
     }
 
-    pub fn update_predict_indirect(&mut self, pc: AbsPc, target: AbsPc) {
+    pub fn update_predict_indirect(
+        &mut self,
+        pc: AbsPc,
+        target: AbsPc,
+        prediction: IndirectPrediction,
+    ) {
         self.btb.insert(pc, target);
+
+        if !prediction.is_return {
+            self.itp.update(pc, prediction.history, target);
+        }
+    }
+
+    // Direct jumps (JAL) go through here too, so that they update the RAS and path history. The
+    // size of the jump gives the return address of a call.
+    pub fn predict_indirect(&mut self, inst: &Inst, pc: AbsPc, size: u32) -> IndirectPrediction {
+        let history = self.itp.path_history();
+        let mut from_table = false;
+
+        let target = match inst {
+            Inst::JumpAndLink(_, tgt) => Some(*tgt),
+            _ if inst.is_return() => {
+                // Fall back to the BTB if the RAS has been emptied by a deep call chain.
+                self.ras.pop().or_else(|| self.btb.get(&pc).copied())
+            }
+            _ => match self.itp.predict(pc, history) {
+                Some(target) => {
+                    from_table = true;
+                    Some(target)
+                }
+                None => self.btb.get(&pc).copied(),
+            },
+        };
+
+        if inst.is_call() {
+            self.ras.push(pc + size);
+        }
+
+        if let Some(target) = target {
+            self.itp.push_path(target);
+        }
+
+        IndirectPrediction {
+            target,
+            from_table,
+            is_return: inst.is_return(),
+            history,
+        }
+    }
+
+    pub fn checkpoint(&self) -> PredictorCheckpoint {
+        PredictorCheckpoint {
+            ras: self.ras.checkpoint(),
+            path_history: self.itp.path_history(),
+            loop_seq: self.loops.checkpoint(),
+        }
+    }
+
+    // For a branch, taken once it has been predicted but with the path history from before it
+    // pushed its predicted target, so a misprediction can push the actual one instead.
+    pub fn branch_checkpoint(&self, history: u32) -> PredictorCheckpoint {
+        PredictorCheckpoint {
+            path_history: history,
+            ..self.checkpoint()
+        }
+    }
+
+    pub fn push_path(&mut self, target: AbsPc) {
+        self.itp.push_path(target);
+    }
+
+    pub fn restore(&mut self, cp: PredictorCheckpoint) {
+        self.ras.restore(cp.ras);
+        self.itp.set_path_history(cp.path_history);
+        self.loops.restore(cp.loop_seq);
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inst::INST_SIZE;
+
+    #[test]
+    fn test_ras_overflow() {
+        let mut ras = ReturnAddressStack::new(2);
+        ras.push(AbsPc(4));
+        ras.push(AbsPc(8));
+        ras.push(AbsPc(12));
+
+        assert_eq!(ras.pop(), Some(AbsPc(12)));
+        assert_eq!(ras.pop(), Some(AbsPc(8)));
+        assert_eq!(ras.pop(), None);
+    }
+
+    #[test]
+    fn test_ras_repair() {
+        let mut ras = ReturnAddressStack::new(4);
+        ras.push(AbsPc(4));
+        ras.push(AbsPc(8));
+        let cp = ras.checkpoint();
+
+        // Wrong path: return, then call somewhere else (clobbering the old top).
+        assert_eq!(ras.pop(), Some(AbsPc(8)));
+        ras.push(AbsPc(100));
+        ras.push(AbsPc(200));
+
+        ras.restore(cp);
+        assert_eq!(ras.pop(), Some(AbsPc(8)));
+        assert_eq!(ras.pop(), Some(AbsPc(4)));
+        assert_eq!(ras.pop(), None);
+    }
+
+    #[test]
+    fn test_path_history_targets() {
+        // An indirect jump at 100 whose target depends on which way the branch at 0 went.
+        let jump = Inst::JumpAndLinkRegister(ArchReg::Zero, ArchReg::T0, Imm(0));
+        let mut bp = BranchPredictor::new(BranchPredictorConfig::default());
+        let cp = bp.checkpoint();
+
+        for i in 0..8 {
+            let (from, to) = if i % 2 == 0 {
+                (AbsPc(40), AbsPc(200))
+            } else {
+                (AbsPc(80), AbsPc(300))
+            };
 
-    pub fn predict_indirect(&mut self, inst: &Inst, pc: AbsPc) -> Option<AbsPc> {
-        if matches!(inst, Inst::JumpAndLink(ArchReg::RA, _)) {
-            // Call
-            self.ras.push(pc + INST_SIZE);
-            // println!("Call with RA = {:?}", pc + INST_SIZE);
-            self.btb.get(&pc).copied()
-        } else if inst == &Inst::JumpAndLinkRegister(ArchReg::Zero, ArchReg::RA, Imm(0)) {
-            // Ret
-            self.ras.pop()
-            // println!("Return from {:?} to {:?}", pc, val);
-            // self.btb.get(&pc).copied()
-        } else {
-            self.btb.get(&pc).copied()
+            bp.restore(cp);
+            bp.predict_indirect(&Inst::JumpAndLink(ArchReg::Zero, from), AbsPc(0), INST_SIZE);
+            let prediction = bp.predict_indirect(&jump, AbsPc(100), INST_SIZE);
+
+            if i >= 2 {
+                assert!(prediction.from_table);
+                assert_eq!(prediction.target, Some(to));
+            }
+
+            bp.update_predict_indirect(AbsPc(100), to, prediction);
+        }
+    }
+
+    #[test]
+    fn test_path_history_after_mispredict() {
+        // The branch at 40 is taken two times in three, which the base predictor only sometimes
+        // gets right, and the indirect jump
+        // at 100 goes wherever the branch went. Fetch is redirected as the core would: the
+        // branch's checkpoint is restored and its actual target pushed.
+        let jump = Inst::JumpAndLinkRegister(ArchReg::Zero, ArchReg::T0, Imm(0));
+        let mut bp = BranchPredictor::new(BranchPredictorConfig {
+            loop_entries: 0,
+            ..Default::default()
+        });
+        let start = bp.checkpoint();
+        let mut mispredicts = 0;
+
+        for i in 0..24 {
+            let taken = i % 3 != 2;
+            let to = if taken { AbsPc(200) } else { AbsPc(300) };
+
+            bp.restore(start);
+            let prediction = bp.predict_direct(AbsPc(40), AbsPc(80));
+            let cp = bp.branch_checkpoint(prediction.history);
+            if prediction.taken != taken {
+                mispredicts += 1;
+                bp.restore(cp);
+                if taken {
+                    bp.push_path(AbsPc(80));
+                }
+            }
+            bp.update_predict_direct(AbsPc(40), taken, prediction);
+
+            let target = bp.predict_indirect(&jump, AbsPc(100), INST_SIZE);
+            if i >= 6 {
+                assert_eq!(target.target, Some(to), "iteration {i}");
+            }
+            bp.update_predict_indirect(AbsPc(100), to, target);
+        }
+        assert!(mispredicts >= 8);
+    }
+
+    #[test]
+    fn test_loop_exit() {
+        // A backward branch at 40 which is taken 5 times, then falls through.
+        let mut bp = BranchPredictor::new(BranchPredictorConfig::default());
+        let (pc, target) = (AbsPc(40), AbsPc(8));
+
+        for run in 0..6 {
+            for i in 0..=5 {
+                let prediction = bp.predict_direct(pc, target);
+                let taken = i < 5;
+
+                if run >= 4 {
+                    assert!(prediction.from_loop);
+                    assert_eq!(prediction.taken, taken);
+                }
+
+                bp.update_predict_direct(pc, taken, prediction);
+            }
         }
     }
 }
@@ -0,0 +1,96 @@
+use std::fmt::Debug;
+
+use crate::inst::{ArchReg, Imm, Inst, MemRef};
+
+// Whether an instruction has an RVC encoding, which depends on its registers and immediates
+// fitting the smaller fields. `jump_offset` is from the instruction to its target, for jumps and
+// branches.
+pub fn is_compressible<JumpType: Debug + Clone>(
+    inst: &Inst<ArchReg, ArchReg, JumpType>,
+    jump_offset: Option<i64>,
+) -> bool {
+    let signed = |imm: u32| i64::from(i32::from_le_bytes(imm.to_le_bytes()));
+    let fits = |imm: u32, bits: u32| (-(1 << (bits - 1))..1 << (bits - 1)).contains(&signed(imm));
+    let scaled = |imm: u32, scale: i64, max: i64| {
+        let imm = signed(imm);
+        imm % scale == 0 && (0..max).contains(&imm)
+    };
+    let shamt = |imm: u32| (1..32).contains(&imm);
+    let offset = |range: i64| jump_offset.is_some_and(|off| (-range..range).contains(&off));
+
+    let word_access = |reg: ArchReg, mem_ref: &MemRef| {
+        let offset = mem_ref.offset.0;
+        if mem_ref.base == ArchReg::SP {
+            scaled(offset, 4, 256)
+        } else {
+            is_short(reg) && is_short(mem_ref.base) && scaled(offset, 4, 128)
+        }
+    };
+
+    match *inst {
+        // c.lwsp can't load into zero.
+        Inst::LoadWord(ArchReg::Zero, _) => false,
+        Inst::LoadWord(rd, ref mem_ref) => word_access(rd, mem_ref),
+        Inst::StoreWord(rs, ref mem_ref) => word_access(rs, mem_ref),
+        Inst::AddImm(ArchReg::Zero, ArchReg::Zero, Imm(0)) => true, // c.nop
+        Inst::AddImm(rd, rs, imm) if is_int(rd) && is_int(rs) && rd != ArchReg::Zero => {
+            match (rs, imm.0) {
+                (ArchReg::Zero, imm) => fits(imm, 6), // c.li
+                (_, 0) => true,                       // c.mv
+                (ArchReg::SP, imm) if rd == ArchReg::SP => signed(imm) % 16 == 0 && fits(imm, 10),
+                (ArchReg::SP, imm) => is_short(rd) && scaled(imm, 4, 1024),
+                (rs, imm) => rs == rd && fits(imm, 6),
+            }
+        }
+        Inst::LoadUpperImm(rd, imm) => {
+            rd != ArchReg::Zero && rd != ArchReg::SP && imm.0 != 0 && fits(imm.0 << 12, 18)
+        }
+        Inst::Add(rd, ArchReg::Zero, rs) | Inst::Add(rd, rs, ArchReg::Zero) => {
+            rd != ArchReg::Zero && rs != ArchReg::Zero
+        }
+        Inst::Add(rd, rs1, rs2) => rd == rs1 && rd != ArchReg::Zero && rs2 != ArchReg::Zero,
+        Inst::Sub(rd, rs1, rs2)
+        | Inst::Xor(rd, rs1, rs2)
+        | Inst::Or(rd, rs1, rs2)
+        | Inst::And(rd, rs1, rs2) => rd == rs1 && is_short(rd) && is_short(rs2),
+        Inst::AndImm(rd, rs, imm) => rd == rs && is_short(rd) && fits(imm.0, 6),
+        Inst::ShiftLeftLogicalImm(rd, rs, imm) => rd == rs && rd != ArchReg::Zero && shamt(imm.0),
+        Inst::ShiftRightLogicalImm(rd, rs, imm) | Inst::ShiftRightArithImm(rd, rs, imm) => {
+            rd == rs && is_short(rd) && shamt(imm.0)
+        }
+        Inst::JumpAndLink(ArchReg::Zero | ArchReg::RA, _) => offset(2048),
+        Inst::JumpAndLinkRegister(ArchReg::Zero | ArchReg::RA, rs, imm) => {
+            rs != ArchReg::Zero && imm.0 == 0
+        }
+        Inst::BranchIfEqual(rs, ArchReg::Zero, _)
+        | Inst::BranchIfNotEqual(rs, ArchReg::Zero, _) => is_short(rs) && offset(256),
+        _ => false,
+    }
+}
+
+fn is_int(reg: ArchReg) -> bool {
+    (reg as usize) < ArchReg::FT0 as usize
+}
+
+// The eight registers that the three-bit fields can name: x8-x15, or f8-f15.
+fn is_short(reg: ArchReg) -> bool {
+    matches!(
+        reg,
+        ArchReg::S0
+            | ArchReg::S1
+            | ArchReg::A0
+            | ArchReg::A1
+            | ArchReg::A2
+            | ArchReg::A3
+            | ArchReg::A4
+            | ArchReg::A5
+            | ArchReg::FS0
+            | ArchReg::FS1
+            | ArchReg::FA0
+            | ArchReg::FA1
+            | ArchReg::FA2
+            | ArchReg::FA3
+            | ArchReg::FA4
+            | ArchReg::FA5
+    )
+}
@@ -1,10 +1,15 @@
+use hashbrown::HashMap;
 use std::{fmt, time::Instant};
+use strum::IntoEnumIterator;
 
 use crate::{
     execution_unit::{EuType, ExecutionUnit},
-    mem::MainMemory,
+    fusion::FusionRule,
+    inst::AbsPc,
+    mem::{MainMemory, MemFault},
     program::Program,
     regs::RegSet,
+    reservation_station::ReservationStation,
 };
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -26,35 +31,91 @@ impl Default for Start {
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct BranchProfile {
+    pub executions: u64,
+    pub taken: u64,
+    pub mispredicts: u64,
+    pub flush_cycles: u64, // From redirecting fetch until the correct path reaches rename.
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Stats {
     pub start: Start,
     pub cycles_taken: u64,
     pub insts_retired: u64,
+    pub thread_insts_retired: Vec<u64>, // Per hardware thread, only when there's more than one
     pub direct_mispredicts: u64,
     pub direct_predicts: u64,
     pub indirect_mispredicts: u64,
     pub indirect_predicts: u64,
+    pub return_mispredicts: u64,
+    pub return_predicts: u64,
+    pub path_table_mispredicts: u64,
+    pub path_table_predicts: u64,
+    pub loop_mispredicts: u64,
+    pub loop_predicts: u64,
+    pub loop_overrides: u64,
     pub rob_stalls: u64,
     pub reservation_station_stalls: u64,
+    pub rs_stalls: Vec<(String, u64)>, // Per scheduler queue
+    pub load_replays: u64,
+    pub mem_order_violations: u64, // Loads that executed before an older store to the same address
+    pub mem_order_flushes: u64,
+    pub value_lookups: u64, // Loads that went through the value predictor
+    pub value_predicts: u64,
+    pub value_mispredicts: u64,
     pub lsq_stalls: u64,
     pub phys_reg_stalls: u64,
     pub fetch_stalls: u64,
+    pub fetch_blocks: u64,
+    pub fetch_block_bytes: u64, // Summed over every block, as is fetch_block_insts
+    pub fetch_block_insts: u64,
     pub macro_ops_fused: u64,
+    pub fusions: HashMap<FusionRule, u64>,
+    pub moves_eliminated: u64,
+    pub zero_idioms_eliminated: u64,
+    pub vector_chains: u64, // Vector results passed on to dependents before they had finished
     pub l1_miss: u64,
     pub l2_miss: u64,
     pub l3_miss: u64,
     pub l1_hits: u64,
     pub l2_hits: u64,
     pub l3_hits: u64,
+    pub l1i_hits: u64,
+    pub l1i_misses: u64,
+    pub coherence_misses: u64, // Private cache hits on a line another core has since written
+    pub cache_to_cache_transfers: u64,
+    pub invalidations: u64, // Lines lost from the private caches to another core's write
+    pub misaligned_accesses: u64, // Only when they're handled in hardware
+    pub split_accesses: u64, // Misaligned accesses that cross a cache line
+    pub mshr_stalls: u64,   // Cycles accesses spent waiting for a free MSHR
+    pub mshr_occupancy: [Vec<u64>; 3], // For L1 to L3, the cycles spent with each number in use
+    pub dtlb_hits: u64,
+    pub dtlb_misses: u64,
+    pub itlb_hits: u64,
+    pub itlb_misses: u64,
+    pub page_walks: u64,
+    pub walk_cycles: u64, // Summed over every walk
+    pub page_faults: u64,
     pub eu_util: Vec<(EuType, f32)>,
+    pub branch_profile: HashMap<(usize, AbsPc), BranchProfile>, // By hardware thread and PC
+}
+
+// Per-branch breakdown of Stats::branch_profile, most costly first. Branches are located in the
+// program of the thread that ran them.
+pub struct BranchReport<'a> {
+    pub stats: &'a Stats,
+    pub progs: &'a [Program], // One per hardware thread
 }
 
 #[derive(Clone)]
 pub struct ExecResult {
     pub mem: MainMemory,
     pub regs: RegSet,
+    pub thread_regs: Vec<RegSet>, // Every hardware thread's registers, `regs` being the first
     pub stats: Stats,
+    pub fault: Option<MemFault>, // The access that stopped the program, if one did
 }
 
 pub trait Cpu {
@@ -64,14 +125,31 @@ pub trait Cpu {
 }
 
 impl Stats {
+    // Averaged over every unit of a class, in the order each class first appears.
     pub fn calculate_util(mut self, eus: &[ExecutionUnit]) -> Self {
+        let mut classes: Vec<(EuType, u64, u64)> = Vec::new();
         for eu in eus {
+            match classes.iter_mut().find(|(t, _, _)| *t == eu.eu_type) {
+                Some((_, util, count)) => {
+                    *util += eu.utilisation;
+                    *count += 1;
+                }
+                None => classes.push((eu.eu_type, eu.utilisation, 1)),
+            }
+        }
+
+        for (eu_type, util, count) in classes {
             self.eu_util
-                .push((eu.eu_type, eu.utilisation as f32 / self.cycles_taken as f32));
+                .push((eu_type, util as f32 / (count * self.cycles_taken) as f32));
         }
 
         self
     }
+
+    pub fn collect_rs_stalls(mut self, rss: &[ReservationStation]) -> Self {
+        self.rs_stalls = rss.iter().map(|rs| (rs.name.clone(), rs.stalls)).collect();
+        self
+    }
 }
 
 impl fmt::Debug for ExecResult {
@@ -79,6 +157,7 @@ impl fmt::Debug for ExecResult {
         f.debug_struct("ExecResult")
             .field("regs", &self.regs)
             .field("stats", &self.stats)
+            .field("fault", &self.fault)
             .finish()
     }
 }
@@ -87,6 +166,9 @@ impl fmt::Display for ExecResult {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "    EXECUTION COMPLETED")?;
         writeln!(f, "  =======================")?;
+        if let Some(fault) = self.fault {
+            writeln!(f, "       Stopped by fault: {fault}")?;
+        }
         if self.stats.phys_reg_stalls != 0 {
             writeln!(
                 f,
@@ -103,10 +185,52 @@ impl fmt::Display for ExecResult {
                 "              R/S stalls: {}",
                 self.stats.reservation_station_stalls
             )?;
+            if self.stats.rs_stalls.len() > 1 {
+                for (name, stalls) in &self.stats.rs_stalls {
+                    writeln!(f, "{:>23} = {}", name, stalls)?;
+                }
+            }
+        }
+        if self.stats.mem_order_violations != 0 {
+            writeln!(
+                f,
+                "    Mem order violations: {} ({} flushes)",
+                self.stats.mem_order_violations, self.stats.mem_order_flushes
+            )?;
+        }
+        if self.stats.value_lookups != 0 {
+            writeln!(
+                f,
+                "  Value predict coverage: {:.2}% ({}/{})",
+                100.0 * self.stats.value_predicts as f32 / self.stats.value_lookups as f32,
+                self.stats.value_predicts,
+                self.stats.value_lookups,
+            )?;
+        }
+        if self.stats.value_predicts != 0 {
+            writeln!(
+                f,
+                "  Value predict accuracy: {:.2}% ({} mispredicts)",
+                100.0 * (self.stats.value_predicts - self.stats.value_mispredicts) as f32
+                    / self.stats.value_predicts as f32,
+                self.stats.value_mispredicts,
+            )?;
+        }
+        if self.stats.load_replays != 0 {
+            writeln!(f, "            Load replays: {}", self.stats.load_replays)?;
         }
         if self.stats.fetch_stalls != 0 {
             writeln!(f, "            Fetch stalls: {}", self.stats.fetch_stalls)?;
         }
+        if self.stats.fetch_blocks != 0 {
+            let blocks = self.stats.fetch_blocks as f32;
+            writeln!(
+                f,
+                "        Fetch block size: {:.2} bytes ({:.2} instructions)",
+                self.stats.fetch_block_bytes as f32 / blocks,
+                self.stats.fetch_block_insts as f32 / blocks,
+            )?;
+        }
         if self.stats.rob_stalls != 0 {
             writeln!(f, "   Reorder buffer stalls: {}", self.stats.rob_stalls)?;
         }
@@ -129,6 +253,41 @@ impl fmt::Display for ExecResult {
                 self.stats.indirect_predicts,
             )?;
         }
+        if self.stats.return_predicts != 0 {
+            writeln!(
+                f,
+                "      Return mispredicts: {:.2}% ({}/{})",
+                100.0 * self.stats.return_mispredicts as f32 / self.stats.return_predicts as f32,
+                self.stats.return_mispredicts,
+                self.stats.return_predicts,
+            )?;
+        }
+        if self.stats.path_table_predicts != 0 {
+            writeln!(
+                f,
+                "  Path table mispredicts: {:.2}% ({}/{})",
+                100.0 * self.stats.path_table_mispredicts as f32
+                    / self.stats.path_table_predicts as f32,
+                self.stats.path_table_mispredicts,
+                self.stats.path_table_predicts,
+            )?;
+        }
+        if self.stats.loop_predicts != 0 {
+            writeln!(
+                f,
+                "        Loop mispredicts: {:.2}% ({}/{})",
+                100.0 * self.stats.loop_mispredicts as f32 / self.stats.loop_predicts as f32,
+                self.stats.loop_mispredicts,
+                self.stats.loop_predicts,
+            )?;
+            writeln!(f, "          Loop overrides: {}", self.stats.loop_overrides)?;
+        }
+        if self.stats.l1i_hits != 0 {
+            writeln!(f, "          L1I cache hits: {}", self.stats.l1i_hits)?;
+        }
+        if self.stats.l1i_misses != 0 {
+            writeln!(f, "        L1I cache misses: {}", self.stats.l1i_misses)?;
+        }
         if self.stats.l1_hits != 0 {
             writeln!(f, "           L1 cache hits: {}", self.stats.l1_hits)?;
         }
@@ -147,6 +306,97 @@ impl fmt::Display for ExecResult {
         if self.stats.l3_miss != 0 {
             writeln!(f, "         L3 cache misses: {}", self.stats.l3_miss)?;
         }
+        if self.stats.coherence_misses != 0 {
+            writeln!(
+                f,
+                "        Coherence misses: {}",
+                self.stats.coherence_misses
+            )?;
+        }
+        if self.stats.cache_to_cache_transfers != 0 {
+            writeln!(
+                f,
+                "    Cache-to-cache fills: {}",
+                self.stats.cache_to_cache_transfers
+            )?;
+        }
+        if self.stats.invalidations != 0 {
+            writeln!(f, "           Invalidations: {}", self.stats.invalidations)?;
+        }
+        if self.stats.misaligned_accesses != 0 {
+            writeln!(
+                f,
+                "     Misaligned accesses: {} ({} across lines)",
+                self.stats.misaligned_accesses, self.stats.split_accesses
+            )?;
+        }
+        if self.stats.mshr_stalls != 0 {
+            writeln!(f, "             MSHR stalls: {}", self.stats.mshr_stalls)?;
+        }
+        // Ranges of occupancy are grouped so there are at most eight rows for each level.
+        for (level, histogram) in self.stats.mshr_occupancy.iter().enumerate() {
+            if histogram.len() <= 1 {
+                continue;
+            }
+
+            let cycles = histogram.iter().sum::<u64>() as f32;
+            let used = histogram
+                .iter()
+                .enumerate()
+                .map(|(n, &c)| n as u64 * c)
+                .sum::<u64>();
+            writeln!(
+                f,
+                "{:>24}: {:.2} average, {} at most",
+                format!("L{} MSHRs in use", level + 1),
+                used as f32 / cycles,
+                histogram.len() - 1,
+            )?;
+
+            let width = (histogram.len() - 1).div_ceil(7);
+            let rows = std::iter::once(0..1)
+                .chain((1..histogram.len()).step_by(width).map(|n| n..n + width));
+            for range in rows {
+                let count = histogram
+                    .iter()
+                    .skip(range.start)
+                    .take(range.len())
+                    .sum::<u64>();
+                let last = (range.end - 1).min(histogram.len() - 1);
+                let label = if range.start == last {
+                    format!("{last}")
+                } else {
+                    format!("{}-{}", range.start, last)
+                };
+                writeln!(f, "{:>23} = {:>5.2}%", label, 100.0 * count as f32 / cycles)?;
+            }
+        }
+        for (name, hits, misses) in [
+            ("DTLB", self.stats.dtlb_hits, self.stats.dtlb_misses),
+            ("ITLB", self.stats.itlb_hits, self.stats.itlb_misses),
+        ] {
+            if hits + misses != 0 {
+                writeln!(
+                    f,
+                    "{:>24}: {:.2}% ({}/{})",
+                    format!("{name} miss rate"),
+                    100.0 * misses as f32 / (hits + misses) as f32,
+                    misses,
+                    hits + misses,
+                )?;
+            }
+        }
+        if self.stats.page_walks != 0 {
+            writeln!(
+                f,
+                "       Page walk latency: {:.2} ({} walks)",
+                self.stats.walk_cycles as f32 / self.stats.page_walks as f32,
+                self.stats.page_walks,
+            )?;
+        }
+        if self.stats.page_faults != 0 {
+            writeln!(f, "             Page faults: {}", self.stats.page_faults)?;
+        }
 
         if self.stats.macro_ops_fused != 0 {
             writeln!(
@@ -154,6 +404,29 @@ impl fmt::Display for ExecResult {
                 "         Macro-ops fused: {}",
                 self.stats.macro_ops_fused
             )?;
+            for rule in FusionRule::iter() {
+                if let Some(count) = self.stats.fusions.get(&rule) {
+                    writeln!(f, "{:>23} = {}", format!("{:?}", rule), count)?;
+                }
+            }
+        }
+
+        if self.stats.moves_eliminated != 0 {
+            writeln!(
+                f,
+                "        Moves eliminated: {}",
+                self.stats.moves_eliminated
+            )?;
+        }
+        if self.stats.zero_idioms_eliminated != 0 {
+            writeln!(
+                f,
+                "  Zero idioms eliminated: {}",
+                self.stats.zero_idioms_eliminated
+            )?;
+        }
+        if self.stats.vector_chains != 0 {
+            writeln!(f, "           Vector chains: {}", self.stats.vector_chains)?;
         }
 
         writeln!(f, "    Instructions retired: {}", self.stats.insts_retired)?;
@@ -163,6 +436,15 @@ impl fmt::Display for ExecResult {
             "  Instructions per clock: {:.2}",
             self.stats.insts_retired as f32 / self.stats.cycles_taken as f32
         )?;
+        for (thread, retired) in self.stats.thread_insts_retired.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>23} = {:.2} ({} retired)",
+                format!("Thread {thread} IPC"),
+                *retired as f32 / self.stats.cycles_taken as f32,
+                retired
+            )?;
+        }
         let elapsed = self.stats.start.time.elapsed().as_secs_f32();
         writeln!(
             f,
@@ -188,3 +470,44 @@ impl fmt::Display for ExecResult {
         Ok(())
     }
 }
+
+impl fmt::Display for BranchReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut branches = self.stats.branch_profile.iter().collect::<Vec<_>>();
+        branches
+            .sort_by_key(|(key, p)| (std::cmp::Reverse((p.flush_cycles, p.mispredicts)), **key));
+
+        writeln!(f, "    BRANCH PROFILE")?;
+        writeln!(f, "  ==================")?;
+        writeln!(
+            f,
+            "{:>8}  {:<24} {:>10} {:>7} {:>10} {:>8} {:>12}",
+            "PC", "Location", "Executed", "Taken", "Mispred.", "Rate", "Flush cycles"
+        )?;
+
+        for (&(thread, pc), p) in branches {
+            let mut location = match self.progs[thread].nearest_label(pc) {
+                Some((label, 0)) => label.0.clone(),
+                Some((label, offset)) => format!("{}+{:#x}", label.0, offset),
+                None => "?".to_owned(),
+            };
+            if self.progs.len() > 1 {
+                location = format!("{thread}:{location}");
+            }
+
+            writeln!(
+                f,
+                "{:>#8x}  {:<24} {:>10} {:>6.1}% {:>10} {:>7.2}% {:>12}",
+                pc.0,
+                location,
+                p.executions,
+                100.0 * p.taken as f32 / p.executions as f32,
+                p.mispredicts,
+                100.0 * p.mispredicts as f32 / p.executions as f32,
+                p.flush_cycles,
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,9 +1,14 @@
 use crate::{
     cpu::{Cpu, CpuState, ExecResult, Stats},
-    inst::{AbsPc, Inst, INST_SIZE},
-    mem::MainMemory,
+    fpu,
+    inst::{AbsPc, FpCsr, Inst, RoundingMode, VectorType},
+    mem::{MainMemory, MemFault, Reservations, SharedCache},
+    mmu::{self, PageFault, PageMap, SupervisorCsrs},
+    multicore::Core,
     program::Program,
     regs::RegSet,
+    trace::BranchTrace,
+    vector::{self, VectorRegs},
 };
 
 #[derive(Debug, Clone)]
@@ -13,40 +18,113 @@ pub struct Emulated {
     prog: Program,
     pc: AbsPc,
     stats: Stats,
+    trace: Option<BranchTrace>,
+    reservations: Reservations,
+    core: usize,
+    fcsr: u32,
+    vtype: VectorType,
+    vregs: VectorRegs, // Handles are the architectural register numbers
+    csrs: SupervisorCsrs,
+    fault: Option<MemFault>,
 }
 
 impl Cpu for Emulated {
     fn new(prog: Program, regs: RegSet, mem: MainMemory) -> Self {
+        Self::with_vlen(prog, regs, mem, vector::DEFAULT_VLEN)
+    }
+
+    fn exec_all(mut self) -> ExecResult {
+        self.run();
+        self.into_result()
+    }
+}
+
+impl Core for Emulated {
+    fn step(&mut self) -> CpuState {
+        self.exec_one()
+    }
+
+    // There are no caches to keep coherent, only reservations.
+    fn swap_shared(&mut self, main: &mut MainMemory, shared: &mut SharedCache) {
+        std::mem::swap(&mut self.mem, main);
+        std::mem::swap(&mut self.reservations, &mut shared.reservations);
+        self.core = shared.core;
+    }
+
+    fn into_result(self) -> ExecResult {
+        ExecResult {
+            mem: self.mem,
+            thread_regs: vec![self.regs.clone()],
+            regs: self.regs,
+            stats: self.stats,
+            fault: self.fault,
+        }
+    }
+}
+
+impl Emulated {
+    // VLEN has to match the model being checked against, as it decides how many elements
+    // vsetvli gives.
+    pub fn with_vlen(prog: Program, regs: RegSet, mem: MainMemory, vlen: u32) -> Self {
         Self {
             pc: AbsPc(0),
             stats: Stats::default(),
+            trace: None,
+            reservations: Reservations::default(),
+            core: 0,
+            fcsr: 0,
+            vtype: VectorType::default(),
+            vregs: VectorRegs::new(vlen),
+            csrs: SupervisorCsrs::default(),
+            fault: None,
             regs,
             mem,
             prog,
         }
     }
 
-    fn exec_all(mut self) -> ExecResult {
+    // Start with translation on, as with `mmu::identity_map`.
+    pub fn with_satp(mut self, satp: u32) -> Self {
+        self.csrs.satp = satp;
+        self
+    }
+
+    // Like exec_all, but also records every branch that was executed.
+    pub fn exec_traced(mut self) -> (ExecResult, BranchTrace) {
+        self.trace = Some(BranchTrace::new());
+        self.run();
+
+        let res = ExecResult {
+            mem: self.mem,
+            thread_regs: vec![self.regs.clone()],
+            regs: self.regs,
+            stats: self.stats,
+            fault: self.fault,
+        };
+        (res, self.trace.unwrap())
+    }
+
+    fn run(&mut self) {
         while CpuState::Running == self.exec_one() {
             #[cfg(debug_assertions)]
             if std::env::var("VERBOSE").is_ok() {
                 dbg!(&self.regs);
             }
         }
-
-        ExecResult {
-            mem: self.mem,
-            regs: self.regs,
-            stats: self.stats,
-        }
     }
-}
 
-impl Emulated {
     fn exec_one(&mut self) -> CpuState {
+        self.try_exec_one().unwrap_or_else(|fault| {
+            self.fault = Some(fault);
+            CpuState::Stopped
+        })
+    }
+
+    // Stops without doing anything at an access outside of memory.
+    fn try_exec_one(&mut self) -> Result<CpuState, MemFault> {
         let next_inst = match self.prog.fetch(self.pc) {
             Some(i) => i,
-            None => return CpuState::Stopped,
+            None => return Ok(CpuState::Stopped),
         };
 
         if std::env::var("SINGLE_STEP").is_ok() {
@@ -57,35 +135,86 @@ impl Emulated {
         }
 
         let mut advance_pc = true;
+        let pc = self.pc;
+        let size = self.prog.inst_size(pc);
+
+        // A faulting instruction traps before it has done anything.
+        let pages = match self.translate(next_inst, pc, size) {
+            Ok(pages) => pages,
+            Err(fault) => {
+                self.pc = self.csrs.trap(pc, fault);
+                self.stats.page_faults += 1;
+                return Ok(CpuState::Running);
+            }
+        };
 
         match *next_inst {
             Inst::LoadByte(dst, src) => {
-                let val = self.mem.readb(self.regs.ref_to_addr(src));
+                let val = self
+                    .mem
+                    .readb(pages.apply_access(self.regs.ref_to_addr(src), 1)?)?;
                 self.regs.set(dst, val);
             }
             Inst::LoadByteU(dst, src) => {
-                let val = self.mem.readbu(self.regs.ref_to_addr(src));
+                let val = self
+                    .mem
+                    .readbu(pages.apply_access(self.regs.ref_to_addr(src), 1)?)?;
                 self.regs.set(dst, val);
             }
             Inst::LoadHalfWord(dst, src) => {
-                let val = self.mem.readh(self.regs.ref_to_addr(src));
+                let val = self
+                    .mem
+                    .readh(pages.apply_access(self.regs.ref_to_addr(src), 2)?)?;
                 self.regs.set(dst, val);
             }
             Inst::LoadWord(dst, src) => {
-                let val = self.mem.readw(self.regs.ref_to_addr(src));
+                let val = self
+                    .mem
+                    .readw(pages.apply_access(self.regs.ref_to_addr(src), 4)?)?;
                 self.regs.set(dst, val);
             }
             Inst::StoreByte(src, dst) => {
-                let dst = self.regs.ref_to_addr(dst);
-                self.mem.writeb(dst, self.regs.get(src));
+                let dst = pages.apply_access(self.regs.ref_to_addr(dst), 1)?;
+                self.reservations.write_access(dst, 1);
+                self.mem.writeb(dst, self.regs.get(src))?;
             }
             Inst::StoreHalfWord(src, dst) => {
-                let dst = self.regs.ref_to_addr(dst);
-                self.mem.writeh(dst, self.regs.get(src));
+                let dst = pages.apply_access(self.regs.ref_to_addr(dst), 2)?;
+                self.reservations.write_access(dst, 2);
+                self.mem.writeh(dst, self.regs.get(src))?;
             }
             Inst::StoreWord(src, dst) => {
-                let dst = self.regs.ref_to_addr(dst);
-                self.mem.writew(dst, self.regs.get(src));
+                let dst = pages.apply_access(self.regs.ref_to_addr(dst), 4)?;
+                self.reservations.write_access(dst, 4);
+                self.mem.writew(dst, self.regs.get(src))?;
+            }
+            Inst::LoadReserved(dst, src, _) => {
+                let addr = pages.apply(self.regs.ref_to_addr(src));
+                self.mem.check_aligned(addr, 4)?;
+                let val = self.mem.readw(addr)?;
+                let hart = Reservations::hart(self.core, 0);
+                self.reservations.reserve(hart, addr.to_cache_line());
+                self.regs.set(dst, val);
+            }
+            Inst::StoreConditional(dst, src, addr, _) => {
+                let addr = pages.apply(self.regs.ref_to_addr(addr));
+                // Faults whether or not it would have succeeded, as it does out of order.
+                self.mem.check_aligned(addr, 4)?;
+                let hart = Reservations::hart(self.core, 0);
+                let success = self.reservations.take(hart, addr.to_cache_line());
+                if success {
+                    self.reservations.write(addr.to_cache_line());
+                    self.mem.writew(addr, self.regs.get(src))?;
+                }
+                self.regs.set(dst, u32::from(!success));
+            }
+            Inst::Amo(op, dst, src, addr, _) => {
+                let addr = pages.apply(self.regs.ref_to_addr(addr));
+                self.mem.check_aligned(addr, 4)?;
+                let old = self.mem.readw(addr)?;
+                self.reservations.write(addr.to_cache_line());
+                self.mem.writew(addr, op.apply(old, self.regs.get(src)))?;
+                self.regs.set(dst, old);
             }
             Inst::Add(dst, src0, src1) => {
                 let a = self.regs.get(src0);
@@ -153,6 +282,11 @@ impl Emulated {
                 let b = self.regs.get(src1);
                 self.regs.set(dst, a.wrapping_mul(b));
             }
+            Inst::MulHU(dst, src0, src1) => {
+                let a = self.regs.get(src0);
+                let b = self.regs.get(src1);
+                self.regs.set(dst, ((a as u64 * b as u64) >> 32) as u32);
+            }
             Inst::Div(dst, src0, src1) => {
                 let a = i32::from_le_bytes(self.regs.get(src0).to_le_bytes());
                 let b = i32::from_le_bytes(self.regs.get(src1).to_le_bytes());
@@ -162,7 +296,7 @@ impl Emulated {
             Inst::DivU(dst, src0, src1) => {
                 let a = self.regs.get(src0);
                 let b = self.regs.get(src1);
-                let val = if b == 0 { u32::MAX } else { a / b };
+                let val = a.checked_div(b).unwrap_or(u32::MAX);
                 self.regs.set(dst, val);
             }
             Inst::Rem(dst, src0, src1) => {
@@ -172,14 +306,14 @@ impl Emulated {
                 self.regs.set(dst, u32::from_le_bytes(val.to_le_bytes()));
             }
             Inst::JumpAndLink(dst, tgt) => {
-                self.regs.set(dst, (self.pc + INST_SIZE).0);
+                self.regs.set(dst, (self.pc + size).0);
                 self.pc = tgt;
                 advance_pc = false;
             }
             Inst::JumpAndLinkRegister(dst, src, off) => {
                 // TODO: we prob should sign extend the off value in the assembler
                 assert_eq!(off.0, 0);
-                self.regs.set(dst, (self.pc + INST_SIZE).0);
+                self.regs.set(dst, (self.pc + size).0);
                 self.pc = AbsPc(self.regs.get(src).wrapping_add(off.0) & !1);
                 advance_pc = false;
             }
@@ -251,17 +385,123 @@ impl Emulated {
             Inst::LoadUpperImm(dst, imm) => {
                 self.regs.set(dst, imm.0 << 12);
             }
+            Inst::ShiftAdd(dst, src0, src1, imm) => {
+                let a = self.regs.get(src0);
+                let b = self.regs.get(src1);
+                self.regs.set(dst, b.wrapping_add(a.wrapping_shl(imm.0)));
+            }
+            Inst::Bit(op, dst, src0, src1) => {
+                let a = self.regs.get(src0);
+                let b = self.regs.get(src1);
+                self.regs.set(dst, op.apply(a, b));
+            }
+            Inst::BitUnary(op, dst, src) => {
+                let a = self.regs.get(src);
+                self.regs.set(dst, op.apply(a));
+            }
+            Inst::Fp(_, dst, _, _, _)
+            | Inst::FpSqrt(dst, _, _)
+            | Inst::FpFma(_, dst, _, _, _, _)
+            | Inst::FpCompare(_, dst, _, _)
+            | Inst::FpConvert(_, dst, _, _)
+            | Inst::FpClass(dst, _) => {
+                let frm = RoundingMode::from_frm(FpCsr::Frm.read(self.fcsr));
+                let inst = next_inst
+                    .clone()
+                    .with_rounding(frm)
+                    .map_src_regs(|src| self.regs.get(src));
+                self.regs.set(dst, fpu::compute(&inst));
+            }
+            Inst::ReadFcsr(dst, csr) => {
+                self.regs.set(dst, csr.read(self.fcsr));
+            }
+            Inst::WriteFcsr(dst, src, csr) => {
+                let old = csr.read(self.fcsr);
+                self.fcsr = csr.write(self.fcsr, self.regs.get(src));
+                self.regs.set(dst, old);
+            }
+            Inst::ReadCsr(dst, csr) => {
+                self.regs.set(dst, self.csrs.read(csr));
+            }
+            Inst::WriteCsr(dst, src, csr) => {
+                let old = self.csrs.read(csr);
+                self.csrs.write(csr, self.regs.get(src));
+                self.regs.set(dst, old);
+            }
+            Inst::SupervisorReturn => {
+                self.pc = AbsPc(self.csrs.sepc);
+                advance_pc = false;
+            }
+            // Every access walks the page table, so there's nothing to flush.
+            Inst::FenceVma => (),
+            Inst::VectorSetLength(dst, src, sew) => {
+                let vl = self.regs.get(src).min(self.vregs.vlmax(sew));
+                self.vtype = VectorType { sew, vl };
+                self.regs.set(dst, vl);
+            }
+            Inst::VectorSetMaxLength(dst, sew) => {
+                let vl = self.vregs.vlmax(sew);
+                self.vtype = VectorType { sew, vl };
+                self.regs.set(dst, vl);
+            }
+            ref inst if inst.is_vector() => {
+                let inst = inst
+                    .clone()
+                    .with_vector_type(self.vtype)
+                    .map_src_regs(|src| src.vector_index().unwrap_or_else(|| self.regs.get(src)));
+                let dst = inst.vector_dst().and_then(|dst| dst.vector_index());
+
+                if inst.is_vector_store() {
+                    for line in vector::lines(&inst, &pages) {
+                        self.reservations.write(line);
+                    }
+                }
+
+                let val = vector::execute(
+                    &inst,
+                    dst.unwrap_or(0),
+                    &mut self.vregs,
+                    &mut self.mem,
+                    &pages,
+                )?;
+                if let Inst::VectorToScalar(dst, _, _) = inst {
+                    self.regs.set(dst, val);
+                }
+            }
             Inst::Halt => unreachable!(),
             _ => unimplemented!("{:?}", *next_inst),
         }
 
         if advance_pc {
-            self.pc += INST_SIZE;
+            self.pc += size;
+        }
+
+        if let Some(trace) = &mut self.trace {
+            trace.record(next_inst, pc, size, self.pc);
         }
 
         self.stats.insts_retired += 1;
         self.stats.cycles_taken += next_inst.latency();
 
-        CpuState::Running
+        Ok(CpuState::Running)
+    }
+
+    // The instruction has to be fetched from pages that allow it, and its data accesses have to
+    // be allowed too.
+    fn translate(&self, inst: &Inst, pc: AbsPc, size: u32) -> Result<PageMap, PageFault> {
+        if !self.csrs.is_translating() {
+            return Ok(PageMap::default());
+        }
+
+        mmu::translate(&self.csrs, &mmu::fetch_pages(pc, size), &self.mem)?;
+        if !inst.accesses_memory() {
+            return Ok(PageMap::default());
+        }
+
+        let inst = inst
+            .clone()
+            .with_vector_type(self.vtype)
+            .map_src_regs(|src| src.vector_index().unwrap_or_else(|| self.regs.get(src)));
+        mmu::translate(&self.csrs, &mmu::access_pages(&inst), &self.mem)
     }
 }
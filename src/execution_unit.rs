@@ -1,19 +1,36 @@
+use hashbrown::HashMap;
+use strum::EnumIter;
+
 use crate::{
     cpu::Stats,
-    inst::{ExecutedInst, Inst, ReadyInst, Tag, Tagged},
-    mem::MemoryHierarchy,
+    fpu,
+    inst::{Compare, ExecutedInst, Inst, PhysReg, ReadyInst, Tag, Tagged},
+    mem::{MemFault, MemoryHierarchy},
+    mmu::{PageFault, PageMap},
+    util::Addr,
+    vector::VectorTiming,
 };
 
 #[derive(Debug, Clone, Default)]
 pub struct EuResult {
     pub val: u32,
+    pub fault: Option<MemFault>,
+    pub page_fault: Option<PageFault>, // Taken as an exception once it reaches the ROB head
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+// Where a memory access goes and how long the page table walk for it takes, if there is one.
+pub type Translation = Result<(PageMap, u64), PageFault>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, EnumIter)]
 pub enum EuType {
     Alu,
+    Mul,
+    Div,
+    Fpu,
     LoadStore,
     Branch,
+    Vector,
+    VectorLoadStore,
     Special, // Halt and such.
 }
 
@@ -23,24 +40,51 @@ type CyclesTaken = u64;
 pub struct ExecutionUnit {
     pub eu_type: EuType,
     pub utilisation: u64,
+    pipelined: bool,
+    initiation_interval: CyclesTaken, // Cycles between starting two instructions when pipelined.
     completed_inst: Option<(Tagged<ExecutedInst>, EuResult)>,
     executing_insts: Vec<(Tagged<ReadyInst>, CyclesTaken)>,
+    // Vector instructions have their result worked out when they begin, along with how long
+    // they take.
+    vector_results: HashMap<Tag, (VectorTiming, Result<u32, MemFault>)>,
+    chained: Vec<(PhysReg, u32)>,
+    translations: HashMap<Tag, Translation>,
 }
 
 // TODO: get rid of begin_inst
 
 impl ExecutionUnit {
     pub fn new(eu_type: EuType) -> Self {
+        Self::with_timing(eu_type, true, 1)
+    }
+
+    pub fn with_timing(eu_type: EuType, pipelined: bool, initiation_interval: CyclesTaken) -> Self {
         Self {
             eu_type,
             utilisation: 0,
+            pipelined,
+            initiation_interval,
             completed_inst: Default::default(),
             executing_insts: Default::default(),
+            vector_results: HashMap::new(),
+            chained: Vec::new(),
+            translations: HashMap::new(),
         }
     }
 
     pub fn can_execute(&self, inst: &ReadyInst) -> bool {
-        self.eu_type == inst.eu_type() && !self.was_utilised()
+        self.eu_type == inst.eu_type() && self.can_accept()
+    }
+
+    fn can_accept(&self) -> bool {
+        if self.pipelined {
+            self.executing_insts
+                .last()
+                .map(|e| e.1 >= self.initiation_interval)
+                .unwrap_or(true)
+        } else {
+            self.executing_insts.is_empty()
+        }
     }
 
     pub fn begin_execute(&mut self, inst: ReadyInst, tag: Tag) {
@@ -48,6 +92,29 @@ impl ExecutionUnit {
         self.executing_insts.push((Tagged { tag, inst }, 0));
     }
 
+    pub fn begin_vector(
+        &mut self,
+        inst: ReadyInst,
+        tag: Tag,
+        timing: VectorTiming,
+        val: Result<u32, MemFault>,
+    ) {
+        self.vector_results.insert(tag, (timing, val));
+        self.begin_execute(inst, tag);
+    }
+
+    // Memory accesses are translated as they issue. One that faults finishes straight away.
+    pub fn begin_access(&mut self, inst: ReadyInst, tag: Tag, translation: Translation) {
+        self.translations.insert(tag, translation);
+        self.begin_execute(inst, tag);
+    }
+
+    // Vector destinations that can be read by dependent instructions before their producer has
+    // finished.
+    pub fn take_chained(&mut self) -> Vec<(PhysReg, u32)> {
+        std::mem::take(&mut self.chained)
+    }
+
     pub fn was_utilised(&self) -> bool {
         self.executing_insts
             .last()
@@ -58,23 +125,72 @@ impl ExecutionUnit {
     pub fn advance(&mut self, mem: &mut MemoryHierarchy, stats: &mut Stats) {
         let mut deleted_idx = None;
 
-        if self.was_utilised() {
+        // A non-pipelined unit is busy for as long as anything is executing in it.
+        if self.was_utilised() || (!self.pipelined && !self.executing_insts.is_empty()) {
             self.utilisation += 1;
         }
 
         for (i, (Tagged { tag, inst }, cycles)) in self.executing_insts.iter_mut().enumerate() {
-            let is_done = if inst.is_mem_access() {
-                mem.access_complete(*tag, inst.access_addr(), stats)
+            let access = match self.translations.get(tag) {
+                Some(Ok((pages, _))) if inst.is_mem_access() => {
+                    let range = inst.access_range();
+                    let size = range.end.wrapping_sub(range.start);
+                    Some((pages.apply(Addr(range.start)), size))
+                }
+                _ => None,
+            };
+
+            // The walk has to finish before the access can start.
+            let is_done = if let Some(translation) = self.translations.get_mut(tag) {
+                match translation {
+                    Err(_) => true,
+                    Ok((_, walk)) if *walk > 0 => {
+                        *walk -= 1;
+                        false
+                    }
+                    Ok(_) => {
+                        let (addr, size) = access.expect("only memory accesses are translated");
+                        mem.access_complete(*tag, addr, size, stats)
+                    }
+                }
+            } else if let Some((timing, val)) = self.vector_results.get(tag) {
+                if *cycles + 1 == timing.chain && timing.chain < timing.latency {
+                    if let (Some(dst), Ok(val)) = (inst.vector_dst(), val) {
+                        self.chained.push((dst.phys, *val));
+                        stats.vector_chains += 1;
+                    }
+                }
+                *cycles + 1 >= timing.latency
             } else {
                 *cycles + 1 >= inst.latency()
             };
 
             if is_done && self.completed_inst.is_none() {
-                if inst.is_mem_access() {
-                    mem.finish_access(*tag, inst.access_addr());
+                if access.is_some() {
+                    mem.finish_access(*tag);
                 }
 
-                let res = ExecutionUnit::compute_result(inst, mem);
+                let res = match (
+                    self.translations.remove(tag),
+                    self.vector_results.remove(tag),
+                ) {
+                    (Some(Err(fault)), _) => EuResult {
+                        page_fault: Some(fault),
+                        ..Default::default()
+                    },
+                    (_, Some((_, Ok(val)))) => EuResult {
+                        val,
+                        ..Default::default()
+                    },
+                    (_, Some((_, Err(fault)))) => EuResult {
+                        fault: Some(fault),
+                        ..Default::default()
+                    },
+                    (translation, None) => {
+                        let pages = translation.map(|t| t.unwrap().0).unwrap_or_default();
+                        ExecutionUnit::compute_result(*tag, inst, &pages, mem)
+                    }
+                };
                 deleted_idx = Some(i);
                 self.completed_inst = Some((
                     Tagged {
@@ -95,6 +211,10 @@ impl ExecutionUnit {
         self.completed_inst.take()
     }
 
+    pub fn is_executing(&self, tag: Tag) -> bool {
+        self.executing_insts.iter().any(|(ei, _)| ei.tag == tag)
+    }
+
     pub fn kill_specific(&mut self, tag: Tag) -> Tagged<ReadyInst> {
         if let Some((tagged, _)) = &self.completed_inst {
             if tagged.tag == tag {
@@ -108,21 +228,45 @@ impl ExecutionUnit {
             .iter()
             .position(|(ei, _)| ei.tag == tag)
             .expect("kill_specific failed");
+        self.vector_results.remove(&tag);
+        self.translations.remove(&tag);
         self.executing_insts.remove(pos).0
     }
 
     pub fn kill_tags_after(&mut self, tag: Tag) {
         self.executing_insts
-            .retain(|(Tagged { tag: t, .. }, _)| *t <= tag);
+            .retain(|(Tagged { tag: t, .. }, _)| !t.is_after(tag));
+        self.vector_results.retain(|t, _| !t.is_after(tag));
+        self.translations.retain(|t, _| !t.is_after(tag));
 
         if let Some((tagged, _)) = &self.completed_inst {
-            if tagged.tag > tag {
+            if tagged.tag.is_after(tag) {
                 self.completed_inst = None;
             }
         }
     }
 
-    fn compute_result(inst: &ReadyInst, mem: &mut MemoryHierarchy) -> EuResult {
+    fn compute_result(
+        tag: Tag,
+        inst: &ReadyInst,
+        pages: &PageMap,
+        mem: &mut MemoryHierarchy,
+    ) -> EuResult {
+        // Value prediction can send a load anywhere on a wrong path. The fault is only raised
+        // if the access commits.
+        if inst.is_mem_access() {
+            return match ExecutionUnit::access(tag, inst, pages, mem) {
+                Ok(val) => EuResult {
+                    val,
+                    ..Default::default()
+                },
+                Err(fault) => EuResult {
+                    fault: Some(fault),
+                    ..Default::default()
+                },
+            };
+        }
+
         let val = match inst {
             Inst::Add(_, src0, src1) => src0.wrapping_add(*src1),
             Inst::AddImm(_, src, imm) => src.wrapping_add(imm.0),
@@ -134,6 +278,7 @@ impl ExecutionUnit {
             Inst::XorImm(_, src, imm) => src ^ imm.0,
             Inst::Sub(_, src0, src1) => src0.wrapping_sub(*src1),
             Inst::Mul(_, src0, src1) => src0.wrapping_mul(*src1),
+            Inst::MulHU(_, src0, src1) => ((*src0 as u64 * *src1 as u64) >> 32) as u32,
             Inst::Rem(_, src0, src1) => {
                 if *src1 == 0 {
                     *src0
@@ -144,19 +289,14 @@ impl ExecutionUnit {
             Inst::Div(_, src0, src1) => {
                 let a = i32::from_le_bytes(src0.to_le_bytes());
                 let b = i32::from_le_bytes(src1.to_le_bytes());
-                let res = if b == 0 { -1 } else { a / b };
+                let res = if b == 0 { -1 } else { a.wrapping_div(b) };
                 u32::from_le_bytes(res.to_le_bytes())
             }
-            Inst::DivU(_, src0, src1) => {
-                if *src1 == 0 {
-                    u32::MAX
-                } else {
-                    *src0 / *src1
-                }
-            }
-            Inst::EffectiveAddress(_, src1, src2, imm) => {
+            Inst::DivU(_, src0, src1) => src0.checked_div(*src1).unwrap_or(u32::MAX),
+            Inst::EffectiveAddress(_, src1, src2, imm) | Inst::ShiftAdd(_, src1, src2, imm) => {
                 src2.wrapping_add(src1.wrapping_shl(imm.0))
             }
+            Inst::ZeroExtend(_, src, imm) => src & u32::MAX.wrapping_shr(imm.0),
             Inst::JumpAndLinkRegister(_, src, imm) => src.wrapping_add(imm.0),
             Inst::ShiftRightArithImm(_, src, imm) => {
                 let a = i32::from_le_bytes(src.to_le_bytes());
@@ -173,6 +313,8 @@ impl ExecutionUnit {
             Inst::LoadFullImm(_, imm) => imm.0,
             Inst::LoadUpperImm(_, imm) => imm.0 << 12,
             Inst::SetLessThanImmU(_, src, imm) => (src < &imm.0).into(),
+            Inst::Bit(op, _, src0, src1) => op.apply(*src0, *src1),
+            Inst::BitUnary(op, _, src) => op.apply(*src),
             Inst::JumpAndLink(_, imm) => imm.0,
             Inst::BranchIfEqual(src0, src1, _) => (src0 == src1).into(),
             Inst::BranchIfNotEqual(src0, src1, _) => (src0 != src1).into(),
@@ -188,16 +330,80 @@ impl ExecutionUnit {
                 (a < b).into()
             }
             Inst::BranchIfLessU(src0, src1, _) => (src0 < src1).into(),
+            // The compare result goes to the destination, the branch decides on it afterwards.
+            Inst::CompareAndBranch(_, src0, src1, _, cmp) => set_less_than(*src0, *src1, *cmp),
+            Inst::CompareImmAndBranch(_, src, imm, _, cmp) => set_less_than(*src, imm.0, *cmp),
             Inst::Halt => 0,
-            Inst::IndexedLoadByteU(_, _, _, _) | Inst::LoadByteU(_, _) => {
-                mem.main.readbu(inst.access_addr())
-            }
-            Inst::LoadByte(_, _) => mem.main.readb(inst.access_addr()),
-            Inst::LoadWord(_, _) => mem.main.readw(inst.access_addr()),
-            x if x.is_store() => 0, // Stores are handled by LSQ upon retire.
+            Inst::Fp(_, _, _, _, _)
+            | Inst::FpSqrt(_, _, _)
+            | Inst::FpFma(_, _, _, _, _, _)
+            | Inst::FpCompare(_, _, _, _)
+            | Inst::FpConvert(_, _, _, _)
+            | Inst::FpClass(_, _) => fpu::compute(inst),
             _ => unimplemented!("{:?}", inst),
         };
 
-        EuResult { val }
+        EuResult {
+            val,
+            ..Default::default()
+        }
+    }
+
+    fn access(
+        tag: Tag,
+        inst: &ReadyInst,
+        pages: &PageMap,
+        mem: &mut MemoryHierarchy,
+    ) -> Result<u32, MemFault> {
+        let range = inst.access_range();
+        let size = range.end.wrapping_sub(range.start);
+        let addr = pages.apply_access(Addr(range.start), size)?;
+        if inst.is_atomic() {
+            mem.main.check_aligned(addr, size)?;
+        }
+
+        Ok(match inst {
+            Inst::IndexedLoadByteU(_, _, _, _) | Inst::LoadByteU(_, _) => mem.main.readbu(addr)?,
+            Inst::IndexedLoadByte(_, _, _, _) | Inst::LoadByte(_, _) => mem.main.readb(addr)?,
+            Inst::IndexedLoadHalfWord(_, _, _, _) | Inst::LoadHalfWord(_, _) => {
+                mem.main.readh(addr)?
+            }
+            Inst::IndexedLoadWord(_, _, _, _) | Inst::LoadWord(_, _) => mem.main.readw(addr)?,
+            // Stores are handled by LSQ upon retire, but they fault here.
+            x if x.is_store() => {
+                mem.main.check(addr, size)?;
+                0
+            }
+            // Atomics only execute once they are at the head of the ROB, so they can't be
+            // killed and go straight to memory.
+            Inst::LoadReserved(_, _, _) => {
+                let val = mem.main.readw(addr)?;
+                mem.load_reserved(tag.thread(), addr);
+                val
+            }
+            Inst::StoreConditional(_, val, _, _) => {
+                if mem.store_conditional(tag.thread(), addr) {
+                    mem.main.writew(addr, *val)?;
+                    0
+                } else {
+                    1
+                }
+            }
+            Inst::Amo(op, _, val, _, _) => {
+                let old = mem.main.readw(addr)?;
+                mem.commit_write(addr, 4);
+                mem.main.writew(addr, op.apply(old, *val))?;
+                old
+            }
+            _ => unimplemented!("{:?}", inst),
+        })
+    }
+}
+
+fn set_less_than(a: u32, b: u32, cmp: Compare) -> u32 {
+    if cmp.unsigned {
+        (a < b).into()
+    } else {
+        (i32::from_le_bytes(a.to_le_bytes()) < i32::from_le_bytes(b.to_le_bytes())).into()
     }
 }
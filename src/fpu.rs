@@ -0,0 +1,374 @@
+use std::{cmp::Ordering, fmt::Debug};
+
+use crate::inst::{FmaOp, FpCompare, FpConversion, FpCsr, FpOp, Inst, RoundingMode};
+
+// Every NaN produced by an operation is this one.
+const CANONICAL_NAN: u32 = 0x7fc0_0000;
+const SIGN: u32 = 1 << 31;
+
+// The result of a floating-point instruction, whose operands are f32 bit patterns. The rounding
+// mode has to have been resolved already.
+pub fn compute<DstReg: Debug + Clone, JumpType: Debug + Clone>(
+    inst: &Inst<u32, DstReg, JumpType>,
+) -> u32 {
+    let f = f32::from_bits;
+
+    match *inst {
+        Inst::Fp(op, _, a, b, rm) => match op {
+            FpOp::Add => round_sum(f(a).into(), f(b).into(), rm).to_bits(),
+            FpOp::Sub => round_sum(f(a).into(), (-f(b)).into(), rm).to_bits(),
+            // The product of two f32s always fits in an f64.
+            FpOp::Mul => round(f64::from(f(a)) * f64::from(f(b)), Ordering::Equal, rm).to_bits(),
+            FpOp::Div => divide(f(a), f(b), rm).to_bits(),
+            FpOp::Min => min_max(f(a), f(b), Ordering::Less),
+            FpOp::Max => min_max(f(a), f(b), Ordering::Greater),
+            FpOp::SignInject => (a & !SIGN) | (b & SIGN),
+            FpOp::SignInjectNegated => (a & !SIGN) | (!b & SIGN),
+            FpOp::SignInjectXor => a ^ (b & SIGN),
+        },
+        Inst::FpSqrt(_, a, rm) => {
+            let a = f64::from(f(a));
+            let root = a.sqrt();
+            // The remainder of a correctly rounded square root is exact.
+            let residual = (-root).mul_add(root, a);
+            round(
+                root,
+                residual.partial_cmp(&0.0).unwrap_or(Ordering::Equal),
+                rm,
+            )
+            .to_bits()
+        }
+        Inst::FpFma(op, _, a, b, c, rm) => {
+            let product = f64::from(f(a)) * f64::from(f(b));
+            let c = f64::from(f(c));
+            match op {
+                FmaOp::MulAdd => round_sum(product, c, rm),
+                FmaOp::MulSub => round_sum(product, -c, rm),
+                FmaOp::NegMulSub => round_sum(-product, c, rm),
+                FmaOp::NegMulAdd => round_sum(-product, -c, rm),
+            }
+            .to_bits()
+        }
+        Inst::FpCompare(cmp, _, a, b) => {
+            let (a, b) = (f(a), f(b));
+            let res = match cmp {
+                FpCompare::Eq => a == b,
+                FpCompare::Lt => a < b,
+                FpCompare::Le => a <= b,
+            };
+            res.into()
+        }
+        Inst::FpConvert(conv, _, a, rm) => match conv {
+            FpConversion::ToInt => {
+                let a = round_to_integer(f(a), rm);
+                let res = if a.is_nan() { i32::MAX } else { a as i32 };
+                u32::from_le_bytes(res.to_le_bytes())
+            }
+            FpConversion::ToUnsigned => {
+                let a = round_to_integer(f(a), rm);
+                if a.is_nan() {
+                    u32::MAX
+                } else {
+                    a as u32
+                }
+            }
+            FpConversion::FromInt => {
+                let a = i32::from_le_bytes(a.to_le_bytes());
+                round(a.into(), Ordering::Equal, rm).to_bits()
+            }
+            FpConversion::FromUnsigned => round(a.into(), Ordering::Equal, rm).to_bits(),
+        },
+        Inst::FpClass(_, a) => classify(f(a)),
+        _ => unreachable!("{:?} is not a floating-point instruction", inst),
+    }
+}
+
+impl RoundingMode {
+    // From the frm field of fcsr.
+    pub fn from_frm(frm: u32) -> Self {
+        match frm {
+            0 => RoundingMode::Rne,
+            1 => RoundingMode::Rtz,
+            2 => RoundingMode::Rdn,
+            3 => RoundingMode::Rup,
+            4 => RoundingMode::Rmm,
+            _ => panic!("reserved rounding mode {frm}"),
+        }
+    }
+}
+
+// Exception flags aren't modelled, so fflags only ever holds what was written to it.
+impl FpCsr {
+    pub fn read(self, fcsr: u32) -> u32 {
+        match self {
+            FpCsr::Fcsr => fcsr,
+            FpCsr::Frm => fcsr >> 5,
+            FpCsr::Fflags => fcsr & 0x1f,
+        }
+    }
+
+    // The new value of fcsr. Reserved rounding modes are rejected here, rather than when an
+    // instruction tries to use one.
+    pub fn write(self, fcsr: u32, val: u32) -> u32 {
+        let fcsr = match self {
+            FpCsr::Fcsr => val & 0xff,
+            FpCsr::Frm => (fcsr & 0x1f) | ((val & 0x7) << 5),
+            FpCsr::Fflags => (fcsr & !0x1f) | (val & 0x1f),
+        };
+        assert!(fcsr >> 5 <= 4, "reserved rounding mode {}", fcsr >> 5);
+        fcsr
+    }
+}
+
+// Round to f32 in the given mode. `residual` says how the exact result compares to `x`, for
+// results that an f64 can't hold exactly.
+fn round(x: f64, residual: Ordering, rm: RoundingMode) -> f32 {
+    if x.is_nan() {
+        return f32::from_bits(CANONICAL_NAN);
+    }
+
+    // To nearest, ties to even.
+    let nearest = x as f32;
+    let exact = match x.partial_cmp(&nearest.into()).unwrap() {
+        Ordering::Equal => residual,
+        ord => ord,
+    };
+    if exact == Ordering::Equal {
+        return nearest;
+    }
+
+    let up = || nearest.next_up();
+    let down = || nearest.next_down();
+    match rm {
+        RoundingMode::Rne | RoundingMode::Rmm => {
+            // Only a tie can go the wrong way, either because rounding to f64 made it one or
+            // because it should be broken away from zero.
+            let other = if exact == Ordering::Greater {
+                up()
+            } else {
+                down()
+            };
+            let is_tie = x == (f64::from(nearest) + f64::from(other)) / 2.0;
+            match residual {
+                _ if !is_tie => nearest,
+                Ordering::Equal if rm == RoundingMode::Rmm && other.abs() > nearest.abs() => other,
+                Ordering::Equal => nearest,
+                residual if residual == exact => other,
+                _ => nearest,
+            }
+        }
+        RoundingMode::Rtz => match (nearest.is_sign_negative(), exact) {
+            (false, Ordering::Less) => down(),
+            (true, Ordering::Greater) => up(),
+            _ => nearest,
+        },
+        RoundingMode::Rdn if exact == Ordering::Less => down(),
+        RoundingMode::Rup if exact == Ordering::Greater => up(),
+        RoundingMode::Rdn | RoundingMode::Rup => nearest,
+        RoundingMode::Dyn => unreachable!("rounding mode not resolved"),
+    }
+}
+
+// Round a + b, using the error-free two-sum to find what rounding to f64 lost.
+fn round_sum(a: f64, b: f64, rm: RoundingMode) -> f32 {
+    let sum = a + b;
+    if !sum.is_finite() {
+        return round(sum, Ordering::Equal, rm);
+    }
+
+    // An exact zero from operands of opposite signs is only negative when rounding down.
+    if sum == 0.0 && a.is_sign_negative() != b.is_sign_negative() {
+        return if rm == RoundingMode::Rdn { -0.0 } else { 0.0 };
+    }
+
+    let b_virtual = sum - a;
+    let error = (a - (sum - b_virtual)) + (b - b_virtual);
+    round(sum, error.partial_cmp(&0.0).unwrap(), rm)
+}
+
+fn divide(a: f32, b: f32, rm: RoundingMode) -> f32 {
+    let (a, b) = (f64::from(a), f64::from(b));
+    let quotient = a / b;
+    if !quotient.is_finite() || quotient == 0.0 {
+        return round(quotient, Ordering::Equal, rm);
+    }
+
+    // The remainder of a correctly rounded division is exact, and has the sign of the
+    // quotient's error times the divisor's.
+    let remainder = (-quotient).mul_add(b, a);
+    let residual = remainder.partial_cmp(&0.0).unwrap();
+    let residual = if b < 0.0 {
+        residual.reverse()
+    } else {
+        residual
+    };
+    round(quotient, residual, rm)
+}
+
+// A NaN operand gives the other one, and -0 is less than +0.
+fn min_max(a: f32, b: f32, want: Ordering) -> u32 {
+    let res = match (a.is_nan(), b.is_nan()) {
+        (true, true) => return CANONICAL_NAN,
+        (true, false) => b,
+        (false, true) => a,
+        (false, false) => {
+            let ord = a
+                .partial_cmp(&b)
+                .unwrap()
+                .then(b.is_sign_negative().cmp(&a.is_sign_negative()));
+            if ord == want {
+                a
+            } else {
+                b
+            }
+        }
+    };
+    res.to_bits()
+}
+
+fn round_to_integer(a: f32, rm: RoundingMode) -> f32 {
+    match rm {
+        RoundingMode::Rne => a.round_ties_even(),
+        RoundingMode::Rtz => a.trunc(),
+        RoundingMode::Rdn => a.floor(),
+        RoundingMode::Rup => a.ceil(),
+        RoundingMode::Rmm => a.round(),
+        RoundingMode::Dyn => unreachable!("rounding mode not resolved"),
+    }
+}
+
+// The fclass.s mask, with one of ten bits set.
+fn classify(a: f32) -> u32 {
+    let negative = a.is_sign_negative();
+    let bit = match a.classify() {
+        std::num::FpCategory::Infinite if negative => 0,
+        std::num::FpCategory::Normal if negative => 1,
+        std::num::FpCategory::Subnormal if negative => 2,
+        std::num::FpCategory::Zero if negative => 3,
+        std::num::FpCategory::Zero => 4,
+        std::num::FpCategory::Subnormal => 5,
+        std::num::FpCategory::Normal => 6,
+        std::num::FpCategory::Infinite => 7,
+        // Quiet NaNs have the top bit of the mantissa set.
+        std::num::FpCategory::Nan if a.to_bits() & (1 << 22) == 0 => 8,
+        std::num::FpCategory::Nan => 9,
+    };
+    1 << bit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type FpInst = Inst<u32, ()>;
+
+    const MODES: [RoundingMode; 5] = [
+        RoundingMode::Rne,
+        RoundingMode::Rtz,
+        RoundingMode::Rdn,
+        RoundingMode::Rup,
+        RoundingMode::Rmm,
+    ];
+
+    fn fp(op: FpOp, a: f32, b: f32, rm: RoundingMode) -> f32 {
+        f32::from_bits(compute(&FpInst::Fp(op, (), a.to_bits(), b.to_bits(), rm)))
+    }
+
+    #[test]
+    fn test_divide() {
+        let third = MODES.map(|rm| fp(FpOp::Div, 1.0, 3.0, rm).to_bits());
+        assert_eq!(
+            third,
+            [0x3eaaaaab, 0x3eaaaaaa, 0x3eaaaaaa, 0x3eaaaaab, 0x3eaaaaab]
+        );
+
+        let neg_third = MODES.map(|rm| fp(FpOp::Div, -1.0, 3.0, rm).to_bits());
+        assert_eq!(
+            neg_third,
+            [0xbeaaaaab, 0xbeaaaaaa, 0xbeaaaaab, 0xbeaaaaaa, 0xbeaaaaab]
+        );
+    }
+
+    #[test]
+    fn test_add() {
+        // Too small to change the f64 sum, but it still decides which way to round.
+        let tiny = 2.0f32.powi(-60);
+        assert_eq!(fp(FpOp::Add, 1.0, tiny, RoundingMode::Rne), 1.0);
+        assert_eq!(
+            fp(FpOp::Add, 1.0, tiny, RoundingMode::Rup),
+            1.0f32.next_up()
+        );
+        assert_eq!(
+            fp(FpOp::Sub, 1.0, tiny, RoundingMode::Rtz),
+            1.0f32.next_down()
+        );
+
+        assert!(fp(FpOp::Add, 1.0, -1.0, RoundingMode::Rne).is_sign_positive());
+        assert!(fp(FpOp::Add, 1.0, -1.0, RoundingMode::Rdn).is_sign_negative());
+    }
+
+    #[test]
+    fn test_fma() {
+        // The product is 1 + 2^-22 + 2^-46, which a separate multiply would round to 1 + 2^-22.
+        let a = 0x3f800001;
+        let fma = FpInst::FpFma(
+            FmaOp::MulAdd,
+            (),
+            a,
+            a,
+            (-1.0f32).to_bits(),
+            RoundingMode::Rne,
+        );
+        let expected = 2.0f32.powi(-22) + 2.0f32.powi(-46);
+        assert_eq!(f32::from_bits(compute(&fma)), expected);
+    }
+
+    #[test]
+    fn test_sqrt() {
+        let sqrt = |rm| f32::from_bits(compute(&FpInst::FpSqrt((), 2.0f32.to_bits(), rm)));
+        let down = sqrt(RoundingMode::Rdn);
+        assert_eq!(sqrt(RoundingMode::Rup), down.next_up());
+        assert_eq!(sqrt(RoundingMode::Rne), std::f32::consts::SQRT_2);
+    }
+
+    #[test]
+    fn test_convert() {
+        let convert = |conv, a: u32, rm| compute(&FpInst::FpConvert(conv, (), a, rm));
+        let to_int = |a: f32| MODES.map(|rm| convert(FpConversion::ToInt, a.to_bits(), rm) as i32);
+        assert_eq!(to_int(2.5), [2, 2, 2, 3, 3]);
+        assert_eq!(to_int(-2.5), [-2, -2, -3, -2, -3]);
+        assert_eq!(to_int(f32::NAN), [i32::MAX; 5]);
+        assert_eq!(to_int(-1e10), [i32::MIN; 5]);
+
+        let rm = RoundingMode::Rne;
+        assert_eq!(
+            convert(FpConversion::ToUnsigned, (-1.0f32).to_bits(), rm),
+            0
+        );
+
+        let from_int = |rm| f32::from_bits(convert(FpConversion::FromInt, 16777217, rm));
+        assert_eq!(from_int(RoundingMode::Rne), 16777216.0);
+        assert_eq!(from_int(RoundingMode::Rmm), 16777218.0);
+    }
+
+    #[test]
+    fn test_min_max() {
+        let rm = RoundingMode::Rne;
+        assert!(fp(FpOp::Min, 0.0, -0.0, rm).is_sign_negative());
+        assert!(fp(FpOp::Max, -0.0, 0.0, rm).is_sign_positive());
+        assert_eq!(fp(FpOp::Min, f32::NAN, 1.0, rm), 1.0);
+        assert_eq!(
+            fp(FpOp::Max, f32::NAN, f32::NAN, rm).to_bits(),
+            CANONICAL_NAN
+        );
+    }
+
+    #[test]
+    fn test_classify() {
+        let class = |a: f32| compute(&FpInst::FpClass((), a.to_bits()));
+        assert_eq!(class(f32::NEG_INFINITY), 1);
+        assert_eq!(class(0.0), 1 << 4);
+        assert_eq!(class(f32::MIN_POSITIVE / 2.0), 1 << 5);
+        assert_eq!(class(f32::NAN), 1 << 9);
+    }
+}
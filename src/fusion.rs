@@ -0,0 +1,258 @@
+use strum::{EnumIter, IntoEnumIterator};
+
+use crate::inst::{ArchReg, Compare, Imm, Inst};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, EnumIter)]
+pub enum FusionRule {
+    IndexedLoadByte,     // add rd, rs1, rs2; lb rd, imm(rd)
+    IndexedLoadByteU,    // add rd, rs1, rs2; lbu rd, imm(rd)
+    IndexedLoadHalfWord, // add rd, rs1, rs2; lh rd, imm(rd)
+    IndexedLoadWord,     // add rd, rs1, rs2; lw rd, imm(rd)
+    ShiftAdd,            // slli rd, rs1, imm; add rd, rd, rs2
+    LoadFullImm,         // lui rd, imm1; addi rd, rd, imm2
+    ZeroExtend,          // slli rd, rs, imm; srli rd, rd, imm
+    CompareBranch,       // slt[i][u] rd, rs, rs/imm; beqz/bnez rd, label
+}
+
+// Which rules the decoder is allowed to apply.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FusionConfig {
+    enabled: u32,
+}
+
+type FuseFn = fn(&Inst, &Inst) -> Option<Inst>;
+
+// Tried in order, the first match wins.
+const RULES: &[(FusionRule, FuseFn)] = &[
+    (FusionRule::IndexedLoadByte, fuse_indexed_load_byte),
+    (FusionRule::IndexedLoadByteU, fuse_indexed_load_byte_u),
+    (FusionRule::IndexedLoadHalfWord, fuse_indexed_load_half_word),
+    (FusionRule::IndexedLoadWord, fuse_indexed_load_word),
+    (FusionRule::ShiftAdd, fuse_shift_add),
+    (FusionRule::LoadFullImm, fuse_load_full_imm),
+    (FusionRule::ZeroExtend, fuse_zero_extend),
+    (FusionRule::CompareBranch, fuse_compare_branch),
+];
+
+impl FusionConfig {
+    pub fn all() -> Self {
+        FusionRule::iter().fold(Self::none(), |config, rule| config.with(rule, true))
+    }
+
+    pub fn none() -> Self {
+        Self { enabled: 0 }
+    }
+
+    pub fn with(self, rule: FusionRule, enabled: bool) -> Self {
+        let bit = 1 << rule as u32;
+        Self {
+            enabled: if enabled {
+                self.enabled | bit
+            } else {
+                self.enabled & !bit
+            },
+        }
+    }
+
+    pub fn is_enabled(&self, rule: FusionRule) -> bool {
+        self.enabled & (1 << rule as u32) != 0
+    }
+}
+
+impl Default for FusionConfig {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+// Try to fuse a pair of adjacent instructions into a single macro-op.
+pub fn fuse(config: FusionConfig, first: &Inst, second: &Inst) -> Option<(FusionRule, Inst)> {
+    RULES
+        .iter()
+        .filter(|(rule, _)| config.is_enabled(*rule))
+        .find_map(|(rule, fuse_fn)| fuse_fn(first, second).map(|fused| (*rule, fused)))
+}
+
+fn fuse_indexed_load_byte(first: &Inst, second: &Inst) -> Option<Inst> {
+    match (first, second) {
+        (Inst::Add(rd1, rs1, rs2), Inst::LoadByte(rd2, mem_ref))
+            if *rd1 != ArchReg::Zero && rd1 == rd2 && *rd2 == mem_ref.base =>
+        {
+            Some(Inst::IndexedLoadByte(*rd2, *rs1, *rs2, mem_ref.offset))
+        }
+        _ => None,
+    }
+}
+
+fn fuse_indexed_load_byte_u(first: &Inst, second: &Inst) -> Option<Inst> {
+    match (first, second) {
+        (Inst::Add(rd1, rs1, rs2), Inst::LoadByteU(rd2, mem_ref))
+            if *rd1 != ArchReg::Zero && rd1 == rd2 && *rd2 == mem_ref.base =>
+        {
+            Some(Inst::IndexedLoadByteU(*rd2, *rs1, *rs2, mem_ref.offset))
+        }
+        _ => None,
+    }
+}
+
+fn fuse_indexed_load_half_word(first: &Inst, second: &Inst) -> Option<Inst> {
+    match (first, second) {
+        (Inst::Add(rd1, rs1, rs2), Inst::LoadHalfWord(rd2, mem_ref))
+            if *rd1 != ArchReg::Zero && rd1 == rd2 && *rd2 == mem_ref.base =>
+        {
+            Some(Inst::IndexedLoadHalfWord(*rd2, *rs1, *rs2, mem_ref.offset))
+        }
+        _ => None,
+    }
+}
+
+fn fuse_indexed_load_word(first: &Inst, second: &Inst) -> Option<Inst> {
+    match (first, second) {
+        (Inst::Add(rd1, rs1, rs2), Inst::LoadWord(rd2, mem_ref))
+            if *rd1 != ArchReg::Zero && rd1 == rd2 && *rd2 == mem_ref.base =>
+        {
+            Some(Inst::IndexedLoadWord(*rd2, *rs1, *rs2, mem_ref.offset))
+        }
+        _ => None,
+    }
+}
+
+fn fuse_shift_add(first: &Inst, second: &Inst) -> Option<Inst> {
+    match (first, second) {
+        (Inst::ShiftLeftLogicalImm(rd1, rs1, imm), Inst::Add(rd2, rs2, rs3))
+            if *rd1 != ArchReg::Zero && rd1 == rd2 =>
+        {
+            // The add must read the shifted value exactly once, from either side.
+            let other = match (rs2 == rd1, rs3 == rd1) {
+                (true, false) => rs3,
+                (false, true) => rs2,
+                _ => return None,
+            };
+            Some(Inst::EffectiveAddress(*rd2, *rs1, *other, *imm))
+        }
+        _ => None,
+    }
+}
+
+fn fuse_load_full_imm(first: &Inst, second: &Inst) -> Option<Inst> {
+    match (first, second) {
+        (Inst::LoadUpperImm(rd1, imm1), Inst::AddImm(rd2, rs1, imm2))
+            if *rd1 != ArchReg::Zero && rd1 == rd2 && rd2 == rs1 =>
+        {
+            let x = (imm1.0 << 12).wrapping_add(imm2.0);
+            Some(Inst::LoadFullImm(*rd2, Imm(x)))
+        }
+        _ => None,
+    }
+}
+
+fn fuse_zero_extend(first: &Inst, second: &Inst) -> Option<Inst> {
+    match (first, second) {
+        (Inst::ShiftLeftLogicalImm(rd1, rs1, imm1), Inst::ShiftRightLogicalImm(rd2, rs2, imm2))
+            if *rd1 != ArchReg::Zero && rd1 == rd2 && rd2 == rs2 && imm1 == imm2 =>
+        {
+            Some(Inst::ZeroExtend(*rd2, *rs1, *imm1))
+        }
+        _ => None,
+    }
+}
+
+fn fuse_compare_branch(first: &Inst, second: &Inst) -> Option<Inst> {
+    let (rd, tgt, branch_if_set) = match second {
+        Inst::BranchIfNotEqual(rd, ArchReg::Zero, tgt) => (rd, tgt, true),
+        Inst::BranchIfEqual(rd, ArchReg::Zero, tgt) => (rd, tgt, false),
+        _ => return None,
+    };
+
+    if *rd == ArchReg::Zero {
+        return None;
+    }
+
+    let cmp = |unsigned| Compare {
+        unsigned,
+        branch_if_set,
+    };
+
+    match first {
+        Inst::SetLessThanU(rd1, rs1, rs2) if rd1 == rd => {
+            Some(Inst::CompareAndBranch(*rd, *rs1, *rs2, *tgt, cmp(true)))
+        }
+        Inst::SetLessThanImm(rd1, rs1, imm) if rd1 == rd => {
+            Some(Inst::CompareImmAndBranch(*rd, *rs1, *imm, *tgt, cmp(false)))
+        }
+        Inst::SetLessThanImmU(rd1, rs1, imm) if rd1 == rd => {
+            Some(Inst::CompareImmAndBranch(*rd, *rs1, *imm, *tgt, cmp(true)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inst::{AbsPc, MemRef};
+
+    #[test]
+    fn test_rules_can_be_disabled() {
+        let add = Inst::Add(ArchReg::A5, ArchReg::A0, ArchReg::A1);
+        let lw = Inst::LoadWord(
+            ArchReg::A5,
+            MemRef {
+                base: ArchReg::A5,
+                offset: Imm(8),
+            },
+        );
+
+        let fused = Inst::IndexedLoadWord(ArchReg::A5, ArchReg::A0, ArchReg::A1, Imm(8));
+        assert_eq!(
+            fuse(FusionConfig::all(), &add, &lw),
+            Some((FusionRule::IndexedLoadWord, fused))
+        );
+
+        let config = FusionConfig::all().with(FusionRule::IndexedLoadWord, false);
+        assert_eq!(fuse(config, &add, &lw), None);
+        assert_eq!(fuse(FusionConfig::none(), &add, &lw), None);
+    }
+
+    #[test]
+    fn test_shift_add_operands() {
+        let slli = Inst::ShiftLeftLogicalImm(ArchReg::A5, ArchReg::A0, Imm(2));
+        let fused = Some(Inst::EffectiveAddress(
+            ArchReg::A5,
+            ArchReg::A0,
+            ArchReg::A4,
+            Imm(2),
+        ));
+
+        let add = Inst::Add(ArchReg::A5, ArchReg::A4, ArchReg::A5);
+        assert_eq!(fuse_shift_add(&slli, &add), fused);
+
+        // Doubling the shifted value can't be expressed as one effective address.
+        let add = Inst::Add(ArchReg::A5, ArchReg::A5, ArchReg::A5);
+        assert_eq!(fuse_shift_add(&slli, &add), None);
+    }
+
+    #[test]
+    fn test_compare_branch() {
+        let seqz = Inst::SetLessThanImmU(ArchReg::A5, ArchReg::A0, Imm(1));
+        let bnez = Inst::BranchIfNotEqual(ArchReg::A5, ArchReg::Zero, AbsPc(64));
+
+        assert_eq!(
+            fuse_compare_branch(&seqz, &bnez),
+            Some(Inst::CompareImmAndBranch(
+                ArchReg::A5,
+                ArchReg::A0,
+                Imm(1),
+                AbsPc(64),
+                Compare {
+                    unsigned: true,
+                    branch_if_set: true
+                }
+            ))
+        );
+
+        // The branch must test the compare's result.
+        let bnez = Inst::BranchIfNotEqual(ArchReg::A4, ArchReg::Zero, AbsPc(64));
+        assert_eq!(fuse_compare_branch(&seqz, &bnez), None);
+    }
+}
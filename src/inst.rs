@@ -1,7 +1,9 @@
 use crate::{
     execution_unit::EuType,
+    fusion::FusionRule,
     regs::{PrfEntry, RegFile},
     util::Addr,
+    vector,
 };
 
 use std::{
@@ -27,11 +29,184 @@ pub struct MemRef<RegType: Debug + Clone = ArchReg> {
 }
 
 pub const INST_SIZE: u32 = 4;
+pub const COMPRESSED_INST_SIZE: u32 = 2; // RVC
+
+// How a fused compare and branch-on-zero decides whether to branch.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Compare {
+    pub unsigned: bool,
+    pub branch_if_set: bool, // bnez rather than beqz
+}
+
+// The aq and rl bits of an atomic.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct AmoOrdering {
+    pub acquire: bool, // No later memory access can be seen before it
+    pub release: bool, // It can't be seen before any earlier memory access
+}
+
+// The read-modify-write done by an amo*.w instruction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AmoOp {
+    Swap,
+    Add,
+    And,
+    Or,
+    Xor,
+    Max,
+    Min,
+    MaxU,
+    MinU,
+}
+
+// Zbb operations on two registers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BitOp {
+    AndNot, // andn
+    OrNot,  // orn
+    XorNot, // xnor
+    Max,
+    MaxU,
+    Min,
+    MinU,
+    RotateLeft,
+    RotateRight,
+}
+
+// Zbb operations on one register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BitUnaryOp {
+    CountLeadingZeros,
+    CountTrailingZeros,
+    CountOnes,
+    SignExtendByte,
+    SignExtendHalfWord,
+    ZeroExtendHalfWord,
+    ReverseBytes, // rev8
+}
+
+// How a floating-point result is rounded. Dyn takes the mode from fcsr.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum RoundingMode {
+    Rne, // To nearest, ties to even
+    Rtz, // Towards zero
+    Rdn, // Down
+    Rup, // Up
+    Rmm, // To nearest, ties away from zero
+    #[default]
+    Dyn,
+}
+
+// Floating-point operations on two registers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FpOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Min,
+    Max,
+    SignInject,        // fsgnj.s
+    SignInjectNegated, // fsgnjn.s
+    SignInjectXor,     // fsgnjx.s
+}
+
+// The fused multiply-adds, rs1 * rs2 + rs3 with the product and/or the addend negated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FmaOp {
+    MulAdd,
+    MulSub,
+    NegMulSub, // -(rs1 * rs2) + rs3
+    NegMulAdd, // -(rs1 * rs2) - rs3
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FpCompare {
+    Eq,
+    Lt,
+    Le,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FpConversion {
+    ToInt,
+    ToUnsigned,
+    FromInt,
+    FromUnsigned,
+}
+
+// The parts of fcsr that can be accessed on their own.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum FpCsr {
+    Fcsr,
+    Frm,
+    Fflags,
+}
+
+// The supervisor CSRs, enough to run with translation on and handle page faults.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum Csr {
+    Satp,
+    Stvec,
+    Sepc,
+    Scause,
+    Stval,
+}
+
+// The element width of vector instructions, set by vsetvli. Only LMUL=1 is supported, so it
+// also fixes how many elements fit in a register.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum Sew {
+    E8,
+    E16,
+    #[default]
+    E32,
+}
+
+// The vl and vtype that a vector instruction executes with. Filled in from the last vsetvli
+// before it, like a dynamic rounding mode.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct VectorType {
+    pub sew: Sew,
+    pub vl: u32,
+}
+
+// Element-wise vector integer operations.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VectorOp {
+    Add,
+    Sub,
+    Mul,
+    And,
+    Or,
+    Xor,
+    ShiftLeft,
+    ShiftRightLogical,
+    ShiftRightArith,
+}
+
+// Vector integer reductions, folding every element into the first of the destination.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReduceOp {
+    Sum,
+    And,
+    Or,
+    Xor,
+    Max,
+    MaxU,
+    Min,
+    MinU,
+}
 
 // https://en.wikichip.org/wiki/risc-v/registers
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, EnumString, EnumIter)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, EnumString, EnumIter)]
 #[strum(serialize_all = "lowercase")]
 pub enum ArchReg {
+    #[default]
     Zero,
     RA,
     SP,
@@ -62,6 +237,106 @@ pub enum ArchReg {
     S9,
     S10,
     S11,
+    // The F extension's registers, renamed from the same physical registers. They can be named
+    // either way, fa0 or f10.
+    #[strum(serialize = "ft0", serialize = "f0")]
+    FT0,
+    #[strum(serialize = "ft1", serialize = "f1")]
+    FT1,
+    #[strum(serialize = "ft2", serialize = "f2")]
+    FT2,
+    #[strum(serialize = "ft3", serialize = "f3")]
+    FT3,
+    #[strum(serialize = "ft4", serialize = "f4")]
+    FT4,
+    #[strum(serialize = "ft5", serialize = "f5")]
+    FT5,
+    #[strum(serialize = "ft6", serialize = "f6")]
+    FT6,
+    #[strum(serialize = "ft7", serialize = "f7")]
+    FT7,
+    #[strum(serialize = "fs0", serialize = "f8")]
+    FS0,
+    #[strum(serialize = "fs1", serialize = "f9")]
+    FS1,
+    #[strum(serialize = "fa0", serialize = "f10")]
+    FA0,
+    #[strum(serialize = "fa1", serialize = "f11")]
+    FA1,
+    #[strum(serialize = "fa2", serialize = "f12")]
+    FA2,
+    #[strum(serialize = "fa3", serialize = "f13")]
+    FA3,
+    #[strum(serialize = "fa4", serialize = "f14")]
+    FA4,
+    #[strum(serialize = "fa5", serialize = "f15")]
+    FA5,
+    #[strum(serialize = "fa6", serialize = "f16")]
+    FA6,
+    #[strum(serialize = "fa7", serialize = "f17")]
+    FA7,
+    #[strum(serialize = "fs2", serialize = "f18")]
+    FS2,
+    #[strum(serialize = "fs3", serialize = "f19")]
+    FS3,
+    #[strum(serialize = "fs4", serialize = "f20")]
+    FS4,
+    #[strum(serialize = "fs5", serialize = "f21")]
+    FS5,
+    #[strum(serialize = "fs6", serialize = "f22")]
+    FS6,
+    #[strum(serialize = "fs7", serialize = "f23")]
+    FS7,
+    #[strum(serialize = "fs8", serialize = "f24")]
+    FS8,
+    #[strum(serialize = "fs9", serialize = "f25")]
+    FS9,
+    #[strum(serialize = "fs10", serialize = "f26")]
+    FS10,
+    #[strum(serialize = "fs11", serialize = "f27")]
+    FS11,
+    #[strum(serialize = "ft8", serialize = "f28")]
+    FT8,
+    #[strum(serialize = "ft9", serialize = "f29")]
+    FT9,
+    #[strum(serialize = "ft10", serialize = "f30")]
+    FT10,
+    #[strum(serialize = "ft11", serialize = "f31")]
+    FT11,
+    // The V extension's registers, whose elements live in the vector unit. Renaming only
+    // tracks which copy of the register an instruction uses.
+    V0,
+    V1,
+    V2,
+    V3,
+    V4,
+    V5,
+    V6,
+    V7,
+    V8,
+    V9,
+    V10,
+    V11,
+    V12,
+    V13,
+    V14,
+    V15,
+    V16,
+    V17,
+    V18,
+    V19,
+    V20,
+    V21,
+    V22,
+    V23,
+    V24,
+    V25,
+    V26,
+    V27,
+    V28,
+    V29,
+    V30,
+    V31,
 }
 
 // https://mark.theis.site/riscv/
@@ -76,9 +351,16 @@ pub enum Inst<
     LoadByteU(DstReg, MemRef<SrcReg>),
     LoadHalfWord(DstReg, MemRef<SrcReg>),
     LoadWord(DstReg, MemRef<SrcReg>),
+    IndexedLoadByte(DstReg, SrcReg, SrcReg, Imm),
     IndexedLoadByteU(DstReg, SrcReg, SrcReg, Imm),
+    IndexedLoadHalfWord(DstReg, SrcReg, SrcReg, Imm),
+    IndexedLoadWord(DstReg, SrcReg, SrcReg, Imm),
     EffectiveAddress(DstReg, SrcReg, SrcReg, Imm),
+    ShiftAdd(DstReg, SrcReg, SrcReg, Imm), // sh[123]add, the same operation as EffectiveAddress
     LoadFullImm(DstReg, Imm),
+    ZeroExtend(DstReg, SrcReg, Imm),
+    CompareAndBranch(DstReg, SrcReg, SrcReg, JumpType, Compare),
+    CompareImmAndBranch(DstReg, SrcReg, Imm, JumpType, Compare),
     StoreByte(SrcReg, MemRef<SrcReg>),
     StoreHalfWord(SrcReg, MemRef<SrcReg>),
     StoreWord(SrcReg, MemRef<SrcReg>),
@@ -111,7 +393,38 @@ pub enum Inst<
     SetLessThanImm(DstReg, SrcReg, Imm),
     SetLessThanU(DstReg, SrcReg, SrcReg),
     SetLessThanImmU(DstReg, SrcReg, Imm),
-    Halt, // Used internally when execution finishes.
+    Bit(BitOp, DstReg, SrcReg, SrcReg),
+    BitUnary(BitUnaryOp, DstReg, SrcReg),
+    LoadReserved(DstReg, MemRef<SrcReg>, AmoOrdering),
+    StoreConditional(DstReg, SrcReg, MemRef<SrcReg>, AmoOrdering),
+    Amo(AmoOp, DstReg, SrcReg, MemRef<SrcReg>, AmoOrdering),
+    Fp(FpOp, DstReg, SrcReg, SrcReg, RoundingMode),
+    FpSqrt(DstReg, SrcReg, RoundingMode),
+    FpFma(FmaOp, DstReg, SrcReg, SrcReg, SrcReg, RoundingMode),
+    FpCompare(FpCompare, DstReg, SrcReg, SrcReg),
+    FpConvert(FpConversion, DstReg, SrcReg, RoundingMode),
+    FpClass(DstReg, SrcReg),
+    ReadFcsr(DstReg, FpCsr),
+    WriteFcsr(DstReg, SrcReg, FpCsr), // Swaps in the new value
+    ReadCsr(DstReg, Csr),
+    WriteCsr(DstReg, SrcReg, Csr), // Swaps in the new value
+    SupervisorReturn,              // sret, back to sepc
+    FenceVma,                      // sfence.vma, which flushes the whole TLB
+    // Vector instructions. Those that write a vector register also read its old value, which
+    // is kept past vl.
+    VectorSetLength(DstReg, SrcReg, Sew), // vsetvli with the length asked for in a register
+    VectorSetMaxLength(DstReg, Sew),      // vsetvli with x0, for as many as fit
+    VectorLoad(DstReg, SrcReg, MemRef<SrcReg>, Sew, VectorType),
+    VectorLoadStrided(DstReg, SrcReg, MemRef<SrcReg>, SrcReg, Sew, VectorType),
+    VectorStore(SrcReg, MemRef<SrcReg>, Sew, VectorType),
+    VectorStoreStrided(SrcReg, MemRef<SrcReg>, SrcReg, Sew, VectorType),
+    VectorArith(VectorOp, DstReg, SrcReg, SrcReg, SrcReg, VectorType), // .vv
+    VectorArithScalar(VectorOp, DstReg, SrcReg, SrcReg, SrcReg, VectorType), // .vx
+    VectorArithImm(VectorOp, DstReg, SrcReg, SrcReg, Imm, VectorType), // .vi
+    VectorReduce(ReduceOp, DstReg, SrcReg, SrcReg, SrcReg, VectorType),
+    VectorSplat(DstReg, SrcReg, SrcReg, VectorType), // vmv.v.x
+    VectorToScalar(DstReg, SrcReg, VectorType),      // vmv.x.s
+    Halt,                                            // Used internally when execution finishes.
 }
 
 #[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -123,7 +436,7 @@ pub struct Tagged<I> {
     pub inst: I,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, Hash, PartialEq, Eq)]
 pub struct PhysReg(i32);
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -155,6 +468,8 @@ impl FromStr for LabeledInst {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let (op, args) = s.split_once(' ').unwrap_or((s, ""));
+        let op = op.to_lowercase();
+        let (op, ordering) = AmoOrdering::strip_suffix(&op);
         let args = args.split(',').collect::<Vec<_>>();
 
         let nth_arg = |n: usize| -> Result<&str, String> {
@@ -169,9 +484,159 @@ impl FromStr for LabeledInst {
         let reg_arg = |n: usize| -> Result<ArchReg, String> {
             ArchReg::from_str(nth_arg(n)?).map_err(|e| e.to_string())
         };
+        // Optional, taken from fcsr if left out.
+        let rm_arg = |n: usize| -> Result<RoundingMode, String> {
+            match args.get(n).map(|s| s.trim()).filter(|s| !s.is_empty()) {
+                Some(rm) => RoundingMode::from_str(rm).map_err(|e| e.to_string()),
+                None => Ok(RoundingMode::Dyn),
+            }
+        };
+        let csr_arg = |n: usize| -> Result<FpCsr, String> {
+            FpCsr::from_str(nth_arg(n)?).map_err(|e| e.to_string())
+        };
+        // The old value's destination can be left out.
+        let csr_write = |csr: FpCsr| -> Result<LabeledInst, String> {
+            Ok(match args.len() {
+                1 => LabeledInst::WriteFcsr(ArchReg::Zero, reg_arg(0)?, csr),
+                _ => LabeledInst::WriteFcsr(reg_arg(0)?, reg_arg(1)?, csr),
+            })
+        };
+
+        // Supervisor CSRs share a namespace with the parts of fcsr.
+        let csr_read = |dst: usize, csr: usize| -> Result<LabeledInst, String> {
+            Ok(match Csr::from_str(nth_arg(csr)?) {
+                Ok(csr) => LabeledInst::ReadCsr(reg_arg(dst)?, csr),
+                Err(_) => LabeledInst::ReadFcsr(reg_arg(dst)?, csr_arg(csr)?),
+            })
+        };
+        let csr_swap = |dst: ArchReg, src: usize, csr: usize| -> Result<LabeledInst, String> {
+            Ok(match Csr::from_str(nth_arg(csr)?) {
+                Ok(csr) => LabeledInst::WriteCsr(dst, reg_arg(src)?, csr),
+                Err(_) => LabeledInst::WriteFcsr(dst, reg_arg(src)?, csr_arg(csr)?),
+            })
+        };
+
+        let vreg_arg = |n: usize| -> Result<ArchReg, String> {
+            match reg_arg(n)? {
+                reg if reg.vector_index().is_some() => Ok(reg),
+                reg => Err(format!("expected a vector register, not {reg:?}")),
+            }
+        };
+        let vmem_arg = |n: usize| -> Result<MemRef, String> {
+            match mem_arg(n)? {
+                mem_ref if mem_ref.offset.0 == 0 => Ok(mem_ref),
+                _ => Err(format!("vector accesses take no offset: '{}'", nth_arg(n)?)),
+            }
+        };
+        // Only LMUL=1 is supported. The tail and mask policies are accepted, but elements past vl
+        // are always left undisturbed, which agnostic allows. x0 for the length asks for as many
+        // elements as fit, whatever the destination.
+        let vsetvli = || -> Result<LabeledInst, String> {
+            let width = nth_arg(2)?;
+            let sew = Sew::from_str(width)
+                .map_err(|_| format!("unsupported element width: '{width}'"))?;
+            for n in 3..args.len() {
+                match nth_arg(n)? {
+                    "m1" | "ta" | "tu" | "ma" | "mu" => (),
+                    setting => return Err(format!("unsupported vtype setting: '{setting}'")),
+                }
+            }
+            Ok(match reg_arg(1)? {
+                ArchReg::Zero => LabeledInst::VectorSetMaxLength(reg_arg(0)?, sew),
+                avl => LabeledInst::VectorSetLength(reg_arg(0)?, avl, sew),
+            })
+        };
+        let vtype = VectorType::default();
+        let vle = |eew: Sew| -> Result<LabeledInst, String> {
+            Ok(LabeledInst::VectorLoad(
+                vreg_arg(0)?,
+                vreg_arg(0)?,
+                vmem_arg(1)?,
+                eew,
+                vtype,
+            ))
+        };
+        let vlse = |eew: Sew| -> Result<LabeledInst, String> {
+            Ok(LabeledInst::VectorLoadStrided(
+                vreg_arg(0)?,
+                vreg_arg(0)?,
+                vmem_arg(1)?,
+                reg_arg(2)?,
+                eew,
+                vtype,
+            ))
+        };
+        let vse = |eew: Sew| -> Result<LabeledInst, String> {
+            Ok(LabeledInst::VectorStore(
+                vreg_arg(0)?,
+                vmem_arg(1)?,
+                eew,
+                vtype,
+            ))
+        };
+        let vsse = |eew: Sew| -> Result<LabeledInst, String> {
+            Ok(LabeledInst::VectorStoreStrided(
+                vreg_arg(0)?,
+                vmem_arg(1)?,
+                reg_arg(2)?,
+                eew,
+                vtype,
+            ))
+        };
+        let vv = |op: VectorOp| -> Result<LabeledInst, String> {
+            Ok(LabeledInst::VectorArith(
+                op,
+                vreg_arg(0)?,
+                vreg_arg(0)?,
+                vreg_arg(1)?,
+                vreg_arg(2)?,
+                vtype,
+            ))
+        };
+        let vx = |op: VectorOp| -> Result<LabeledInst, String> {
+            Ok(LabeledInst::VectorArithScalar(
+                op,
+                vreg_arg(0)?,
+                vreg_arg(0)?,
+                vreg_arg(1)?,
+                reg_arg(2)?,
+                vtype,
+            ))
+        };
+        // Shift amounts are unsigned five bits, other immediates are signed.
+        let vi = |op: VectorOp| -> Result<LabeledInst, String> {
+            let imm = imm_arg(2)?;
+            let range = match op {
+                VectorOp::ShiftLeft | VectorOp::ShiftRightLogical | VectorOp::ShiftRightArith => {
+                    0..32
+                }
+                _ => -16..16,
+            };
+            if !range.contains(&i32::from_le_bytes(imm.0.to_le_bytes())) {
+                return Err(format!("immediate out of range: '{}'", nth_arg(2)?));
+            }
+            Ok(LabeledInst::VectorArithImm(
+                op,
+                vreg_arg(0)?,
+                vreg_arg(0)?,
+                vreg_arg(1)?,
+                imm,
+                vtype,
+            ))
+        };
+        let vred = |op: ReduceOp| -> Result<LabeledInst, String> {
+            Ok(LabeledInst::VectorReduce(
+                op,
+                vreg_arg(0)?,
+                vreg_arg(0)?,
+                vreg_arg(1)?,
+                vreg_arg(2)?,
+                vtype,
+            ))
+        };
 
         #[rustfmt::skip]
-        let inst = match op.to_lowercase().as_str() {
+        let inst = match op {
             "lb" => LabeledInst::LoadByte(reg_arg(0)?, mem_arg(1)?),
             "lbu" => LabeledInst::LoadByteU(reg_arg(0)?, mem_arg(1)?),
             "lh" => LabeledInst::LoadHalfWord(reg_arg(0)?, mem_arg(1)?),
@@ -219,12 +684,157 @@ impl FromStr for LabeledInst {
             "bleu" => LabeledInst::BranchIfGreaterEqualU(reg_arg(1)?, reg_arg(0)?, label_arg(2)?),
             "bgeu" => LabeledInst::BranchIfGreaterEqualU(reg_arg(0)?, reg_arg(1)?, label_arg(2)?),
             "bgtu" => LabeledInst::BranchIfLessU(reg_arg(1)?, reg_arg(0)?, label_arg(2)?),
+            "sltu" => LabeledInst::SetLessThanU(reg_arg(0)?, reg_arg(1)?, reg_arg(2)?),
             "sltiu" => LabeledInst::SetLessThanImmU(reg_arg(0)?, reg_arg(1)?, imm_arg(2)?),
             "snez" => LabeledInst::SetLessThanU(reg_arg(0)?, ArchReg::Zero, reg_arg(1)?),
             "slti" => LabeledInst::SetLessThanImm(reg_arg(0)?, reg_arg(1)?, imm_arg(2)?),
             "seqz" => LabeledInst::SetLessThanImmU(reg_arg(0)?, reg_arg(1)?, Imm(1)),
+            "sh1add" => LabeledInst::ShiftAdd(reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, Imm(1)),
+            "sh2add" => LabeledInst::ShiftAdd(reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, Imm(2)),
+            "sh3add" => LabeledInst::ShiftAdd(reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, Imm(3)),
+            "andn" => LabeledInst::Bit(BitOp::AndNot, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?),
+            "orn" => LabeledInst::Bit(BitOp::OrNot, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?),
+            "xnor" => LabeledInst::Bit(BitOp::XorNot, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?),
+            "max" => LabeledInst::Bit(BitOp::Max, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?),
+            "maxu" => LabeledInst::Bit(BitOp::MaxU, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?),
+            "min" => LabeledInst::Bit(BitOp::Min, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?),
+            "minu" => LabeledInst::Bit(BitOp::MinU, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?),
+            "rol" => LabeledInst::Bit(BitOp::RotateLeft, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?),
+            "ror" => LabeledInst::Bit(BitOp::RotateRight, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?),
+            "clz" => LabeledInst::BitUnary(BitUnaryOp::CountLeadingZeros, reg_arg(0)?, reg_arg(1)?),
+            "ctz" => LabeledInst::BitUnary(BitUnaryOp::CountTrailingZeros, reg_arg(0)?, reg_arg(1)?),
+            "cpop" => LabeledInst::BitUnary(BitUnaryOp::CountOnes, reg_arg(0)?, reg_arg(1)?),
+            "sext.b" => LabeledInst::BitUnary(BitUnaryOp::SignExtendByte, reg_arg(0)?, reg_arg(1)?),
+            "sext.h" => LabeledInst::BitUnary(BitUnaryOp::SignExtendHalfWord, reg_arg(0)?, reg_arg(1)?),
+            "zext.h" => LabeledInst::BitUnary(BitUnaryOp::ZeroExtendHalfWord, reg_arg(0)?, reg_arg(1)?),
+            "rev8" => LabeledInst::BitUnary(BitUnaryOp::ReverseBytes, reg_arg(0)?, reg_arg(1)?),
+            "lr.w" => LabeledInst::LoadReserved(reg_arg(0)?, mem_arg(1)?, ordering),
+            "sc.w" => LabeledInst::StoreConditional(reg_arg(0)?, reg_arg(1)?, mem_arg(2)?, ordering),
+            "amoswap.w" => LabeledInst::Amo(AmoOp::Swap, reg_arg(0)?, reg_arg(1)?, mem_arg(2)?, ordering),
+            "amoadd.w" => LabeledInst::Amo(AmoOp::Add, reg_arg(0)?, reg_arg(1)?, mem_arg(2)?, ordering),
+            "amoand.w" => LabeledInst::Amo(AmoOp::And, reg_arg(0)?, reg_arg(1)?, mem_arg(2)?, ordering),
+            "amoor.w" => LabeledInst::Amo(AmoOp::Or, reg_arg(0)?, reg_arg(1)?, mem_arg(2)?, ordering),
+            "amoxor.w" => LabeledInst::Amo(AmoOp::Xor, reg_arg(0)?, reg_arg(1)?, mem_arg(2)?, ordering),
+            "amomax.w" => LabeledInst::Amo(AmoOp::Max, reg_arg(0)?, reg_arg(1)?, mem_arg(2)?, ordering),
+            "amomin.w" => LabeledInst::Amo(AmoOp::Min, reg_arg(0)?, reg_arg(1)?, mem_arg(2)?, ordering),
+            "amomaxu.w" => LabeledInst::Amo(AmoOp::MaxU, reg_arg(0)?, reg_arg(1)?, mem_arg(2)?, ordering),
+            "amominu.w" => LabeledInst::Amo(AmoOp::MinU, reg_arg(0)?, reg_arg(1)?, mem_arg(2)?, ordering),
+            "flw" => LabeledInst::LoadWord(reg_arg(0)?, mem_arg(1)?),
+            "fsw" => LabeledInst::StoreWord(reg_arg(0)?, mem_arg(1)?),
+            "fadd.s" => LabeledInst::Fp(FpOp::Add, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, rm_arg(3)?),
+            "fsub.s" => LabeledInst::Fp(FpOp::Sub, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, rm_arg(3)?),
+            "fmul.s" => LabeledInst::Fp(FpOp::Mul, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, rm_arg(3)?),
+            "fdiv.s" => LabeledInst::Fp(FpOp::Div, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, rm_arg(3)?),
+            "fmin.s" => LabeledInst::Fp(FpOp::Min, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, RoundingMode::Dyn),
+            "fmax.s" => LabeledInst::Fp(FpOp::Max, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, RoundingMode::Dyn),
+            "fsgnj.s" => LabeledInst::Fp(FpOp::SignInject, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, RoundingMode::Dyn),
+            "fsgnjn.s" => LabeledInst::Fp(FpOp::SignInjectNegated, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, RoundingMode::Dyn),
+            "fsgnjx.s" => LabeledInst::Fp(FpOp::SignInjectXor, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, RoundingMode::Dyn),
+            "fmv.s" => LabeledInst::Fp(FpOp::SignInject, reg_arg(0)?, reg_arg(1)?, reg_arg(1)?, RoundingMode::Dyn),
+            "fneg.s" => LabeledInst::Fp(FpOp::SignInjectNegated, reg_arg(0)?, reg_arg(1)?, reg_arg(1)?, RoundingMode::Dyn),
+            "fabs.s" => LabeledInst::Fp(FpOp::SignInjectXor, reg_arg(0)?, reg_arg(1)?, reg_arg(1)?, RoundingMode::Dyn),
+            "fsqrt.s" => LabeledInst::FpSqrt(reg_arg(0)?, reg_arg(1)?, rm_arg(2)?),
+            "fmadd.s" => LabeledInst::FpFma(FmaOp::MulAdd, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, reg_arg(3)?, rm_arg(4)?),
+            "fmsub.s" => LabeledInst::FpFma(FmaOp::MulSub, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, reg_arg(3)?, rm_arg(4)?),
+            "fnmsub.s" => LabeledInst::FpFma(FmaOp::NegMulSub, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, reg_arg(3)?, rm_arg(4)?),
+            "fnmadd.s" => LabeledInst::FpFma(FmaOp::NegMulAdd, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?, reg_arg(3)?, rm_arg(4)?),
+            "feq.s" => LabeledInst::FpCompare(FpCompare::Eq, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?),
+            "flt.s" => LabeledInst::FpCompare(FpCompare::Lt, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?),
+            "fle.s" => LabeledInst::FpCompare(FpCompare::Le, reg_arg(0)?, reg_arg(1)?, reg_arg(2)?),
+            "fgt.s" => LabeledInst::FpCompare(FpCompare::Lt, reg_arg(0)?, reg_arg(2)?, reg_arg(1)?),
+            "fge.s" => LabeledInst::FpCompare(FpCompare::Le, reg_arg(0)?, reg_arg(2)?, reg_arg(1)?),
+            "fcvt.w.s" => LabeledInst::FpConvert(FpConversion::ToInt, reg_arg(0)?, reg_arg(1)?, rm_arg(2)?),
+            "fcvt.wu.s" => LabeledInst::FpConvert(FpConversion::ToUnsigned, reg_arg(0)?, reg_arg(1)?, rm_arg(2)?),
+            "fcvt.s.w" => LabeledInst::FpConvert(FpConversion::FromInt, reg_arg(0)?, reg_arg(1)?, rm_arg(2)?),
+            "fcvt.s.wu" => LabeledInst::FpConvert(FpConversion::FromUnsigned, reg_arg(0)?, reg_arg(1)?, rm_arg(2)?),
+            "fclass.s" => LabeledInst::FpClass(reg_arg(0)?, reg_arg(1)?),
+            "fmv.x.w" | "fmv.w.x" => LabeledInst::AddImm(reg_arg(0)?, reg_arg(1)?, Imm(0)),
+            "frcsr" => LabeledInst::ReadFcsr(reg_arg(0)?, FpCsr::Fcsr),
+            "frrm" => LabeledInst::ReadFcsr(reg_arg(0)?, FpCsr::Frm),
+            "frflags" => LabeledInst::ReadFcsr(reg_arg(0)?, FpCsr::Fflags),
+            "fscsr" => csr_write(FpCsr::Fcsr)?,
+            "fsrm" => csr_write(FpCsr::Frm)?,
+            "fsflags" => csr_write(FpCsr::Fflags)?,
+            "csrr" => csr_read(0, 1)?,
+            "csrw" => csr_swap(ArchReg::Zero, 1, 0)?,
+            "csrrw" => csr_swap(reg_arg(0)?, 2, 1)?,
+            "sret" => LabeledInst::SupervisorReturn,
+            "sfence.vma" => LabeledInst::FenceVma,
+            "vsetvli" => vsetvli()?,
+            "vle8.v" => vle(Sew::E8)?,
+            "vle16.v" => vle(Sew::E16)?,
+            "vle32.v" => vle(Sew::E32)?,
+            "vlse8.v" => vlse(Sew::E8)?,
+            "vlse16.v" => vlse(Sew::E16)?,
+            "vlse32.v" => vlse(Sew::E32)?,
+            "vse8.v" => vse(Sew::E8)?,
+            "vse16.v" => vse(Sew::E16)?,
+            "vse32.v" => vse(Sew::E32)?,
+            "vsse8.v" => vsse(Sew::E8)?,
+            "vsse16.v" => vsse(Sew::E16)?,
+            "vsse32.v" => vsse(Sew::E32)?,
+            "vadd.vv" => vv(VectorOp::Add)?,
+            "vadd.vx" => vx(VectorOp::Add)?,
+            "vadd.vi" => vi(VectorOp::Add)?,
+            "vsub.vv" => vv(VectorOp::Sub)?,
+            "vsub.vx" => vx(VectorOp::Sub)?,
+            "vmul.vv" => vv(VectorOp::Mul)?,
+            "vmul.vx" => vx(VectorOp::Mul)?,
+            "vand.vv" => vv(VectorOp::And)?,
+            "vand.vx" => vx(VectorOp::And)?,
+            "vand.vi" => vi(VectorOp::And)?,
+            "vor.vv" => vv(VectorOp::Or)?,
+            "vor.vx" => vx(VectorOp::Or)?,
+            "vor.vi" => vi(VectorOp::Or)?,
+            "vxor.vv" => vv(VectorOp::Xor)?,
+            "vxor.vx" => vx(VectorOp::Xor)?,
+            "vxor.vi" => vi(VectorOp::Xor)?,
+            "vsll.vv" => vv(VectorOp::ShiftLeft)?,
+            "vsll.vx" => vx(VectorOp::ShiftLeft)?,
+            "vsll.vi" => vi(VectorOp::ShiftLeft)?,
+            "vsrl.vv" => vv(VectorOp::ShiftRightLogical)?,
+            "vsrl.vx" => vx(VectorOp::ShiftRightLogical)?,
+            "vsrl.vi" => vi(VectorOp::ShiftRightLogical)?,
+            "vsra.vv" => vv(VectorOp::ShiftRightArith)?,
+            "vsra.vx" => vx(VectorOp::ShiftRightArith)?,
+            "vsra.vi" => vi(VectorOp::ShiftRightArith)?,
+            "vredsum.vs" => vred(ReduceOp::Sum)?,
+            "vredand.vs" => vred(ReduceOp::And)?,
+            "vredor.vs" => vred(ReduceOp::Or)?,
+            "vredxor.vs" => vred(ReduceOp::Xor)?,
+            "vredmax.vs" => vred(ReduceOp::Max)?,
+            "vredmaxu.vs" => vred(ReduceOp::MaxU)?,
+            "vredmin.vs" => vred(ReduceOp::Min)?,
+            "vredminu.vs" => vred(ReduceOp::MinU)?,
+            "vmv.v.x" => LabeledInst::VectorSplat(vreg_arg(0)?, vreg_arg(0)?, reg_arg(1)?, vtype),
+            "vmv.x.s" => LabeledInst::VectorToScalar(reg_arg(0)?, vreg_arg(1)?, vtype),
             "hlt" => LabeledInst::Halt,
             "nop" => LabeledInst::nop(),
+            // RVC, which the program lays out in two bytes. Where the destination is also the
+            // first source, it is only given once.
+            "c.lw" | "c.lwsp" | "c.flw" | "c.flwsp" => LabeledInst::LoadWord(reg_arg(0)?, mem_arg(1)?),
+            "c.sw" | "c.swsp" | "c.fsw" | "c.fswsp" => LabeledInst::StoreWord(reg_arg(0)?, mem_arg(1)?),
+            "c.li" => LabeledInst::AddImm(reg_arg(0)?, ArchReg::Zero, imm_arg(1)?),
+            "c.lui" => LabeledInst::LoadUpperImm(reg_arg(0)?, imm_arg(1)?),
+            "c.addi" | "c.addi16sp" => LabeledInst::AddImm(reg_arg(0)?, reg_arg(0)?, imm_arg(1)?),
+            "c.addi4spn" => LabeledInst::AddImm(reg_arg(0)?, reg_arg(1)?, imm_arg(2)?),
+            "c.nop" => LabeledInst::nop(),
+            "c.mv" => LabeledInst::Add(reg_arg(0)?, ArchReg::Zero, reg_arg(1)?),
+            "c.add" => LabeledInst::Add(reg_arg(0)?, reg_arg(0)?, reg_arg(1)?),
+            "c.sub" => LabeledInst::Sub(reg_arg(0)?, reg_arg(0)?, reg_arg(1)?),
+            "c.xor" => LabeledInst::Xor(reg_arg(0)?, reg_arg(0)?, reg_arg(1)?),
+            "c.or" => LabeledInst::Or(reg_arg(0)?, reg_arg(0)?, reg_arg(1)?),
+            "c.and" => LabeledInst::And(reg_arg(0)?, reg_arg(0)?, reg_arg(1)?),
+            "c.andi" => LabeledInst::AndImm(reg_arg(0)?, reg_arg(0)?, imm_arg(1)?),
+            "c.slli" => LabeledInst::ShiftLeftLogicalImm(reg_arg(0)?, reg_arg(0)?, imm_arg(1)?),
+            "c.srli" => LabeledInst::ShiftRightLogicalImm(reg_arg(0)?, reg_arg(0)?, imm_arg(1)?),
+            "c.srai" => LabeledInst::ShiftRightArithImm(reg_arg(0)?, reg_arg(0)?, imm_arg(1)?),
+            "c.j" => LabeledInst::JumpAndLink(ArchReg::Zero, label_arg(0)?),
+            "c.jal" => LabeledInst::JumpAndLink(ArchReg::RA, label_arg(0)?),
+            "c.jr" => LabeledInst::JumpAndLinkRegister(ArchReg::Zero, reg_arg(0)?, Imm(0)),
+            "c.jalr" => LabeledInst::JumpAndLinkRegister(ArchReg::RA, reg_arg(0)?, Imm(0)),
+            "c.beqz" => LabeledInst::BranchIfEqual(reg_arg(0)?, ArchReg::Zero, label_arg(1)?),
+            "c.bnez" => LabeledInst::BranchIfNotEqual(reg_arg(0)?, ArchReg::Zero, label_arg(1)?),
             _ => return Err(format!("unknown instruction: '{}'", op)),
         };
 
@@ -236,6 +846,20 @@ impl<J: Debug + Clone> Inst<ArchReg, ArchReg, J> {
     pub fn nop() -> Self {
         Inst::AddImm(ArchReg::Zero, ArchReg::Zero, Imm(0))
     }
+
+    pub fn is_call(&self) -> bool {
+        matches!(
+            self,
+            Inst::JumpAndLink(ArchReg::RA, _) | Inst::JumpAndLinkRegister(ArchReg::RA, _, _)
+        )
+    }
+
+    pub fn is_return(&self) -> bool {
+        matches!(
+            self,
+            Inst::JumpAndLinkRegister(ArchReg::Zero, ArchReg::RA, _)
+        )
+    }
 }
 
 impl<SrcReg: Debug + Clone, DstReg: Debug + Clone, JumpType: Debug + Clone>
@@ -252,6 +876,8 @@ impl<SrcReg: Debug + Clone, DstReg: Debug + Clone, JumpType: Debug + Clone>
                 | Inst::BranchIfLessU(_, _, _)
                 | Inst::BranchIfGreaterEqual(_, _, _)
                 | Inst::BranchIfGreaterEqualU(_, _, _)
+                | Inst::CompareAndBranch(_, _, _, _, _)
+                | Inst::CompareImmAndBranch(_, _, _, _, _)
         )
     }
 
@@ -260,12 +886,23 @@ impl<SrcReg: Debug + Clone, DstReg: Debug + Clone, JumpType: Debug + Clone>
     }
 
     pub fn is_fused(&self) -> bool {
-        matches!(
-            self,
-            Inst::EffectiveAddress(_, _, _, _)
-                | Inst::IndexedLoadByteU(_, _, _, _)
-                | Inst::LoadFullImm(_, _)
-        )
+        self.fusion_rule().is_some()
+    }
+
+    pub fn fusion_rule(&self) -> Option<FusionRule> {
+        Some(match self {
+            Inst::IndexedLoadByte(_, _, _, _) => FusionRule::IndexedLoadByte,
+            Inst::IndexedLoadByteU(_, _, _, _) => FusionRule::IndexedLoadByteU,
+            Inst::IndexedLoadHalfWord(_, _, _, _) => FusionRule::IndexedLoadHalfWord,
+            Inst::IndexedLoadWord(_, _, _, _) => FusionRule::IndexedLoadWord,
+            Inst::EffectiveAddress(_, _, _, _) => FusionRule::ShiftAdd,
+            Inst::LoadFullImm(_, _) => FusionRule::LoadFullImm,
+            Inst::ZeroExtend(_, _, _) => FusionRule::ZeroExtend,
+            Inst::CompareAndBranch(_, _, _, _, _) | Inst::CompareImmAndBranch(_, _, _, _, _) => {
+                FusionRule::CompareBranch
+            }
+            _ => return None,
+        })
     }
 
     pub fn is_load(&self) -> bool {
@@ -275,10 +912,27 @@ impl<SrcReg: Debug + Clone, DstReg: Debug + Clone, JumpType: Debug + Clone>
                 | Inst::LoadByteU(_, _)
                 | Inst::LoadHalfWord(_, _)
                 | Inst::LoadWord(_, _)
+                | Inst::IndexedLoadByte(_, _, _, _)
                 | Inst::IndexedLoadByteU(_, _, _, _)
+                | Inst::IndexedLoadHalfWord(_, _, _, _)
+                | Inst::IndexedLoadWord(_, _, _, _)
         )
     }
 
+    pub fn load_dst(&self) -> Option<&DstReg> {
+        match self {
+            Inst::LoadByte(dst, _)
+            | Inst::LoadByteU(dst, _)
+            | Inst::LoadHalfWord(dst, _)
+            | Inst::LoadWord(dst, _)
+            | Inst::IndexedLoadByte(dst, _, _, _)
+            | Inst::IndexedLoadByteU(dst, _, _, _)
+            | Inst::IndexedLoadHalfWord(dst, _, _, _)
+            | Inst::IndexedLoadWord(dst, _, _, _) => Some(dst),
+            _ => None,
+        }
+    }
+
     pub fn is_store(&self) -> bool {
         matches!(
             self,
@@ -286,6 +940,147 @@ impl<SrcReg: Debug + Clone, DstReg: Debug + Clone, JumpType: Debug + Clone>
         )
     }
 
+    // Atomics go through the store queue, but read memory as well and write a destination.
+    pub fn is_atomic(&self) -> bool {
+        matches!(
+            self,
+            Inst::LoadReserved(_, _, _)
+                | Inst::StoreConditional(_, _, _, _)
+                | Inst::Amo(_, _, _, _, _)
+        )
+    }
+
+    pub fn ordering(&self) -> AmoOrdering {
+        match self {
+            Inst::LoadReserved(_, _, ordering)
+            | Inst::StoreConditional(_, _, _, ordering)
+            | Inst::Amo(_, _, _, _, ordering) => *ordering,
+            _ => AmoOrdering::default(),
+        }
+    }
+
+    // Accesses to fcsr are serialising, done once everything older from the thread has finished.
+    pub fn is_fcsr_access(&self) -> bool {
+        matches!(self, Inst::ReadFcsr(_, _) | Inst::WriteFcsr(_, _, _))
+    }
+
+    // Reading the supervisor CSRs is done at rename like fcsr. Anything that changes them, or
+    // where the thread fetches from, also stops fetch until it has renamed.
+    pub fn is_csr_access(&self) -> bool {
+        matches!(self, Inst::ReadCsr(_, _)) || self.is_csr_serialising()
+    }
+
+    pub fn is_csr_serialising(&self) -> bool {
+        matches!(
+            self,
+            Inst::WriteCsr(_, _, _) | Inst::SupervisorReturn | Inst::FenceVma
+        )
+    }
+
+    // Fill in the rounding mode of an instruction that takes it from fcsr.
+    pub fn with_rounding(self, frm: RoundingMode) -> Self {
+        let resolve = |rm| match rm {
+            RoundingMode::Dyn => frm,
+            rm => rm,
+        };
+
+        match self {
+            Inst::Fp(op, dst, src0, src1, rm) => Inst::Fp(op, dst, src0, src1, resolve(rm)),
+            Inst::FpSqrt(dst, src, rm) => Inst::FpSqrt(dst, src, resolve(rm)),
+            Inst::FpFma(op, dst, src0, src1, src2, rm) => {
+                Inst::FpFma(op, dst, src0, src1, src2, resolve(rm))
+            }
+            Inst::FpConvert(conv, dst, src, rm) => Inst::FpConvert(conv, dst, src, resolve(rm)),
+            inst => inst,
+        }
+    }
+
+    pub fn is_vector(&self) -> bool {
+        matches!(self.eu_type(), EuType::Vector | EuType::VectorLoadStore)
+    }
+
+    // Setting vl is serialising like an fcsr write, so that vl is known when later vector
+    // instructions are renamed.
+    pub fn is_vector_config(&self) -> bool {
+        matches!(
+            self,
+            Inst::VectorSetLength(_, _, _) | Inst::VectorSetMaxLength(_, _)
+        )
+    }
+
+    // Everything that goes to memory, and so can take a page fault.
+    pub fn accesses_memory(&self) -> bool {
+        self.is_mem_access() || self.eu_type() == EuType::VectorLoadStore
+    }
+
+    pub fn is_vector_store(&self) -> bool {
+        matches!(
+            self,
+            Inst::VectorStore(_, _, _, _) | Inst::VectorStoreStrided(_, _, _, _, _)
+        )
+    }
+
+    // The vector register written, if any.
+    pub fn vector_dst(&self) -> Option<&DstReg> {
+        match self {
+            Inst::VectorLoad(dst, _, _, _, _)
+            | Inst::VectorLoadStrided(dst, _, _, _, _, _)
+            | Inst::VectorArith(_, dst, _, _, _, _)
+            | Inst::VectorArithScalar(_, dst, _, _, _, _)
+            | Inst::VectorArithImm(_, dst, _, _, _, _)
+            | Inst::VectorReduce(_, dst, _, _, _, _)
+            | Inst::VectorSplat(dst, _, _, _) => Some(dst),
+            _ => None,
+        }
+    }
+
+    pub fn vector_type(&self) -> Option<VectorType> {
+        match self {
+            Inst::VectorLoad(_, _, _, _, vtype)
+            | Inst::VectorLoadStrided(_, _, _, _, _, vtype)
+            | Inst::VectorStore(_, _, _, vtype)
+            | Inst::VectorStoreStrided(_, _, _, _, vtype)
+            | Inst::VectorArith(_, _, _, _, _, vtype)
+            | Inst::VectorArithScalar(_, _, _, _, _, vtype)
+            | Inst::VectorArithImm(_, _, _, _, _, vtype)
+            | Inst::VectorReduce(_, _, _, _, _, vtype)
+            | Inst::VectorSplat(_, _, _, vtype)
+            | Inst::VectorToScalar(_, _, vtype) => Some(*vtype),
+            _ => None,
+        }
+    }
+
+    // Fill in the vl and vtype of a vector instruction.
+    pub fn with_vector_type(self, vtype: VectorType) -> Self {
+        match self {
+            Inst::VectorLoad(dst, old, addr, eew, _) => {
+                Inst::VectorLoad(dst, old, addr, eew, vtype)
+            }
+            Inst::VectorLoadStrided(dst, old, addr, stride, eew, _) => {
+                Inst::VectorLoadStrided(dst, old, addr, stride, eew, vtype)
+            }
+            Inst::VectorStore(src, addr, eew, _) => Inst::VectorStore(src, addr, eew, vtype),
+            Inst::VectorStoreStrided(src, addr, stride, eew, _) => {
+                Inst::VectorStoreStrided(src, addr, stride, eew, vtype)
+            }
+            Inst::VectorArith(op, dst, old, src0, src1, _) => {
+                Inst::VectorArith(op, dst, old, src0, src1, vtype)
+            }
+            Inst::VectorArithScalar(op, dst, old, src0, src1, _) => {
+                Inst::VectorArithScalar(op, dst, old, src0, src1, vtype)
+            }
+            Inst::VectorArithImm(op, dst, old, src, imm, _) => {
+                Inst::VectorArithImm(op, dst, old, src, imm, vtype)
+            }
+            Inst::VectorReduce(op, dst, old, src0, src1, _) => {
+                Inst::VectorReduce(op, dst, old, src0, src1, vtype)
+            }
+            Inst::VectorSplat(dst, old, src, _) => Inst::VectorSplat(dst, old, src, vtype),
+            Inst::VectorToScalar(dst, src, _) => Inst::VectorToScalar(dst, src, vtype),
+            inst => inst,
+        }
+    }
+
     pub fn eu_type(&self) -> EuType {
         match self {
             Inst::JumpAndLink(_, _)
@@ -295,7 +1090,9 @@ impl<SrcReg: Debug + Clone, DstReg: Debug + Clone, JumpType: Debug + Clone>
             | Inst::BranchIfLess(_, _, _)
             | Inst::BranchIfLessU(_, _, _)
             | Inst::BranchIfGreaterEqual(_, _, _)
-            | Inst::BranchIfGreaterEqualU(_, _, _) => EuType::Branch,
+            | Inst::BranchIfGreaterEqualU(_, _, _)
+            | Inst::CompareAndBranch(_, _, _, _, _)
+            | Inst::CompareImmAndBranch(_, _, _, _, _) => EuType::Branch,
             Inst::Add(_, _, _)
             | Inst::AddImm(_, _, _)
             | Inst::Sub(_, _, _)
@@ -306,6 +1103,9 @@ impl<SrcReg: Debug + Clone, DstReg: Debug + Clone, JumpType: Debug + Clone>
             | Inst::And(_, _, _)
             | Inst::AndImm(_, _, _)
             | Inst::EffectiveAddress(_, _, _, _)
+            | Inst::ShiftAdd(_, _, _, _)
+            | Inst::Bit(_, _, _, _)
+            | Inst::BitUnary(_, _, _)
             | Inst::ShiftLeftLogicalImm(_, _, _)
             | Inst::ShiftRightArithImm(_, _, _)
             | Inst::ShiftRightLogicalImm(_, _, _)
@@ -314,20 +1114,49 @@ impl<SrcReg: Debug + Clone, DstReg: Debug + Clone, JumpType: Debug + Clone>
             | Inst::SetLessThanImm(_, _, _)
             | Inst::LoadUpperImm(_, _)
             | Inst::LoadFullImm(_, _)
-            | Inst::Mul(_, _, _)
-            | Inst::MulHU(_, _, _)
-            | Inst::DivU(_, _, _)
-            | Inst::Div(_, _, _)
-            | Inst::Rem(_, _, _) => EuType::Alu,
+            | Inst::ZeroExtend(_, _, _) => EuType::Alu,
+            Inst::Mul(_, _, _) | Inst::MulHU(_, _, _) => EuType::Mul,
+            Inst::DivU(_, _, _) | Inst::Div(_, _, _) | Inst::Rem(_, _, _) => EuType::Div,
             Inst::LoadByte(_, _)
             | Inst::LoadByteU(_, _)
             | Inst::LoadHalfWord(_, _)
             | Inst::LoadWord(_, _)
+            | Inst::IndexedLoadByte(_, _, _, _)
             | Inst::IndexedLoadByteU(_, _, _, _)
+            | Inst::IndexedLoadHalfWord(_, _, _, _)
+            | Inst::IndexedLoadWord(_, _, _, _)
             | Inst::StoreByte(_, _)
             | Inst::StoreHalfWord(_, _)
-            | Inst::StoreWord(_, _) => EuType::LoadStore,
-            Inst::Halt => EuType::Special,
+            | Inst::StoreWord(_, _)
+            | Inst::LoadReserved(_, _, _)
+            | Inst::StoreConditional(_, _, _, _)
+            | Inst::Amo(_, _, _, _, _) => EuType::LoadStore,
+            // Division and square root share the integer divider.
+            Inst::Fp(FpOp::Div, _, _, _, _) | Inst::FpSqrt(_, _, _) => EuType::Div,
+            Inst::Fp(_, _, _, _, _)
+            | Inst::FpFma(_, _, _, _, _, _)
+            | Inst::FpCompare(_, _, _, _)
+            | Inst::FpConvert(_, _, _, _)
+            | Inst::FpClass(_, _) => EuType::Fpu,
+            Inst::VectorLoad(_, _, _, _, _)
+            | Inst::VectorLoadStrided(_, _, _, _, _, _)
+            | Inst::VectorStore(_, _, _, _)
+            | Inst::VectorStoreStrided(_, _, _, _, _) => EuType::VectorLoadStore,
+            Inst::VectorArith(_, _, _, _, _, _)
+            | Inst::VectorArithScalar(_, _, _, _, _, _)
+            | Inst::VectorArithImm(_, _, _, _, _, _)
+            | Inst::VectorReduce(_, _, _, _, _, _)
+            | Inst::VectorSplat(_, _, _, _)
+            | Inst::VectorToScalar(_, _, _) => EuType::Vector,
+            Inst::ReadFcsr(_, _)
+            | Inst::WriteFcsr(_, _, _)
+            | Inst::ReadCsr(_, _)
+            | Inst::WriteCsr(_, _, _)
+            | Inst::SupervisorReturn
+            | Inst::FenceVma
+            | Inst::VectorSetLength(_, _, _)
+            | Inst::VectorSetMaxLength(_, _)
+            | Inst::Halt => EuType::Special,
         }
     }
 
@@ -350,11 +1179,33 @@ impl<SrcReg: Debug + Clone, DstReg: Debug + Clone, JumpType: Debug + Clone>
             | Inst::LoadFullImm(_, _)
             | Inst::LoadUpperImm(_, _)
             | Inst::EffectiveAddress(_, _, _, _)
+            | Inst::ShiftAdd(_, _, _, _)
+            | Inst::Bit(_, _, _, _)
+            | Inst::BitUnary(_, _, _)
+            | Inst::ZeroExtend(_, _, _)
             | Inst::ShiftRightArithImm(_, _, _)
             | Inst::ShiftRightLogicalImm(_, _, _)
             | Inst::ShiftLeftLogicalImm(_, _, _) => 1,
-            Inst::Mul(_, _, _) => 2,
-            Inst::Rem(_, _, _) | Inst::Div(_, _, _) | Inst::DivU(_, _, _) => 3,
+            Inst::Mul(_, _, _) | Inst::MulHU(_, _, _) => 2,
+            Inst::Rem(_, _, _) | Inst::Div(_, _, _) | Inst::DivU(_, _, _) => 20,
+            Inst::Fp(FpOp::Add | FpOp::Sub | FpOp::Mul, _, _, _, _)
+            | Inst::FpFma(_, _, _, _, _, _) => 4,
+            Inst::Fp(FpOp::Div, _, _, _, _) => 12,
+            Inst::FpSqrt(_, _, _) => 16,
+            Inst::FpConvert(_, _, _, _) => 3,
+            Inst::Fp(_, _, _, _, _) | Inst::FpCompare(_, _, _, _) => 2,
+            Inst::FpClass(_, _) | Inst::ReadFcsr(_, _) | Inst::WriteFcsr(_, _, _) => 1,
+            Inst::ReadCsr(_, _)
+            | Inst::WriteCsr(_, _, _)
+            | Inst::SupervisorReturn
+            | Inst::FenceVma => 1,
+            // The vector unit's own timing depends on vl, these are for a single element group.
+            Inst::VectorSetLength(_, _, _) | Inst::VectorSetMaxLength(_, _) => 1,
+            x if x.is_vector() && x.eu_type() == EuType::VectorLoadStore => 4,
+            Inst::VectorArith(VectorOp::Mul, _, _, _, _, _)
+            | Inst::VectorArithScalar(VectorOp::Mul, _, _, _, _, _) => 5,
+            Inst::VectorReduce(_, _, _, _, _, _) => 4,
+            x if x.is_vector() => 3,
             Inst::Halt => 1,
             _ => unimplemented!("{:?}", self),
         }
@@ -388,6 +1239,9 @@ impl<SrcReg: Debug + Clone, DstReg: Debug + Clone, JumpType: Debug + Clone>
             Inst::LoadFullImm(dst, imm) => Inst::LoadFullImm(dst_fn(dst)?, imm),
             Inst::LoadUpperImm(dst, imm) => Inst::LoadUpperImm(dst_fn(dst)?, imm),
             Inst::EffectiveAddress(dst, src1, src2, imm) => Inst::EffectiveAddress(dst_fn(dst)?, src_fn(src1)?, src_fn(src2)?, imm),
+            Inst::ShiftAdd(dst, src1, src2, imm) => Inst::ShiftAdd(dst_fn(dst)?, src_fn(src1)?, src_fn(src2)?, imm),
+            Inst::Bit(op, dst, src0, src1) => Inst::Bit(op, dst_fn(dst)?, src_fn(src0)?, src_fn(src1)?),
+            Inst::BitUnary(op, dst, src) => Inst::BitUnary(op, dst_fn(dst)?, src_fn(src)?),
             Inst::ShiftLeftLogicalImm(dst, src, imm) => Inst::ShiftLeftLogicalImm(dst_fn(dst)?, src_fn(src)?, imm),
             Inst::ShiftRightArithImm(dst, src, imm) => Inst::ShiftRightArithImm(dst_fn(dst)?, src_fn(src)?, imm),
             Inst::ShiftRightLogicalImm(dst, src, imm) => Inst::ShiftRightLogicalImm(dst_fn(dst)?, src_fn(src)?, imm),
@@ -403,10 +1257,43 @@ impl<SrcReg: Debug + Clone, DstReg: Debug + Clone, JumpType: Debug + Clone>
             Inst::LoadByteU(dst, src) => Inst::LoadByteU(dst_fn(dst)?, MemRef { base: src_fn(src.base)?, offset: src.offset }),
             Inst::LoadHalfWord(dst, src) => Inst::LoadHalfWord(dst_fn(dst)?, MemRef { base: src_fn(src.base)?, offset: src.offset }),
             Inst::LoadWord(dst, src) => Inst::LoadWord(dst_fn(dst)?, MemRef { base: src_fn(src.base)?, offset: src.offset }),
+            Inst::IndexedLoadByte(dst, src0, src1, imm) => Inst::IndexedLoadByte(dst_fn(dst)?, src_fn(src0)?, src_fn(src1)?, imm),
             Inst::IndexedLoadByteU(dst, src0, src1, imm) => Inst::IndexedLoadByteU(dst_fn(dst)?, src_fn(src0)?, src_fn(src1)?, imm),
+            Inst::IndexedLoadHalfWord(dst, src0, src1, imm) => Inst::IndexedLoadHalfWord(dst_fn(dst)?, src_fn(src0)?, src_fn(src1)?, imm),
+            Inst::IndexedLoadWord(dst, src0, src1, imm) => Inst::IndexedLoadWord(dst_fn(dst)?, src_fn(src0)?, src_fn(src1)?, imm),
+            Inst::ZeroExtend(dst, src, imm) => Inst::ZeroExtend(dst_fn(dst)?, src_fn(src)?, imm),
+            Inst::CompareAndBranch(dst, src0, src1, label, cmp) => Inst::CompareAndBranch(dst_fn(dst)?, src_fn(src0)?, src_fn(src1)?, jump_fn(label)?, cmp),
+            Inst::CompareImmAndBranch(dst, src, imm, label, cmp) => Inst::CompareImmAndBranch(dst_fn(dst)?, src_fn(src)?, imm, jump_fn(label)?, cmp),
             Inst::StoreByte(src, dst) => Inst::StoreByte(src_fn(src)?, MemRef { base: src_fn(dst.base)?, offset: dst.offset }),
             Inst::StoreHalfWord(src, dst) => Inst::StoreHalfWord(src_fn(src)?, MemRef { base: src_fn(dst.base)?, offset: dst.offset }),
             Inst::StoreWord(src, dst) => Inst::StoreWord(src_fn(src)?, MemRef { base: src_fn(dst.base)?, offset: dst.offset }),
+            Inst::LoadReserved(dst, src, ordering) => Inst::LoadReserved(dst_fn(dst)?, MemRef { base: src_fn(src.base)?, offset: src.offset }, ordering),
+            Inst::StoreConditional(dst, src, addr, ordering) => Inst::StoreConditional(dst_fn(dst)?, src_fn(src)?, MemRef { base: src_fn(addr.base)?, offset: addr.offset }, ordering),
+            Inst::Amo(op, dst, src, addr, ordering) => Inst::Amo(op, dst_fn(dst)?, src_fn(src)?, MemRef { base: src_fn(addr.base)?, offset: addr.offset }, ordering),
+            Inst::Fp(op, dst, src0, src1, rm) => Inst::Fp(op, dst_fn(dst)?, src_fn(src0)?, src_fn(src1)?, rm),
+            Inst::FpSqrt(dst, src, rm) => Inst::FpSqrt(dst_fn(dst)?, src_fn(src)?, rm),
+            Inst::FpFma(op, dst, src0, src1, src2, rm) => Inst::FpFma(op, dst_fn(dst)?, src_fn(src0)?, src_fn(src1)?, src_fn(src2)?, rm),
+            Inst::FpCompare(cmp, dst, src0, src1) => Inst::FpCompare(cmp, dst_fn(dst)?, src_fn(src0)?, src_fn(src1)?),
+            Inst::FpConvert(conv, dst, src, rm) => Inst::FpConvert(conv, dst_fn(dst)?, src_fn(src)?, rm),
+            Inst::FpClass(dst, src) => Inst::FpClass(dst_fn(dst)?, src_fn(src)?),
+            Inst::ReadFcsr(dst, csr) => Inst::ReadFcsr(dst_fn(dst)?, csr),
+            Inst::WriteFcsr(dst, src, csr) => Inst::WriteFcsr(dst_fn(dst)?, src_fn(src)?, csr),
+            Inst::ReadCsr(dst, csr) => Inst::ReadCsr(dst_fn(dst)?, csr),
+            Inst::WriteCsr(dst, src, csr) => Inst::WriteCsr(dst_fn(dst)?, src_fn(src)?, csr),
+            Inst::SupervisorReturn => Inst::SupervisorReturn,
+            Inst::FenceVma => Inst::FenceVma,
+            Inst::VectorSetLength(dst, src, sew) => Inst::VectorSetLength(dst_fn(dst)?, src_fn(src)?, sew),
+            Inst::VectorSetMaxLength(dst, sew) => Inst::VectorSetMaxLength(dst_fn(dst)?, sew),
+            Inst::VectorLoad(dst, old, src, eew, vtype) => Inst::VectorLoad(dst_fn(dst)?, src_fn(old)?, MemRef { base: src_fn(src.base)?, offset: src.offset }, eew, vtype),
+            Inst::VectorLoadStrided(dst, old, src, stride, eew, vtype) => Inst::VectorLoadStrided(dst_fn(dst)?, src_fn(old)?, MemRef { base: src_fn(src.base)?, offset: src.offset }, src_fn(stride)?, eew, vtype),
+            Inst::VectorStore(src, dst, eew, vtype) => Inst::VectorStore(src_fn(src)?, MemRef { base: src_fn(dst.base)?, offset: dst.offset }, eew, vtype),
+            Inst::VectorStoreStrided(src, dst, stride, eew, vtype) => Inst::VectorStoreStrided(src_fn(src)?, MemRef { base: src_fn(dst.base)?, offset: dst.offset }, src_fn(stride)?, eew, vtype),
+            Inst::VectorArith(op, dst, old, src0, src1, vtype) => Inst::VectorArith(op, dst_fn(dst)?, src_fn(old)?, src_fn(src0)?, src_fn(src1)?, vtype),
+            Inst::VectorArithScalar(op, dst, old, src0, src1, vtype) => Inst::VectorArithScalar(op, dst_fn(dst)?, src_fn(old)?, src_fn(src0)?, src_fn(src1)?, vtype),
+            Inst::VectorArithImm(op, dst, old, src, imm, vtype) => Inst::VectorArithImm(op, dst_fn(dst)?, src_fn(old)?, src_fn(src)?, imm, vtype),
+            Inst::VectorReduce(op, dst, old, src0, src1, vtype) => Inst::VectorReduce(op, dst_fn(dst)?, src_fn(old)?, src_fn(src0)?, src_fn(src1)?, vtype),
+            Inst::VectorSplat(dst, old, src, vtype) => Inst::VectorSplat(dst_fn(dst)?, src_fn(old)?, src_fn(src)?, vtype),
+            Inst::VectorToScalar(dst, src, vtype) => Inst::VectorToScalar(dst_fn(dst)?, src_fn(src)?, vtype),
             Inst::JumpAndLink(dst, label) => Inst::JumpAndLink(dst_fn(dst)?, jump_fn(label)?),
             Inst::JumpAndLinkRegister(dst, src, imm) => Inst::JumpAndLinkRegister(dst_fn(dst)?, src_fn(src)?, imm),
             Inst::BranchIfNotEqual(src0, src1, label) => Inst::BranchIfNotEqual(src_fn(src0)?, src_fn(src1)?, jump_fn(label)?),
@@ -460,6 +1347,21 @@ impl<SrcReg: Debug + Clone, DstReg: Debug + Clone, JumpType: Debug + Clone>
     //     self.map_regs(|src_reg| src_reg, |dst_reg| dst_fn(dst_reg))
     // }
 
+    pub fn jump_target(&self) -> Option<&JumpType> {
+        match self {
+            Inst::JumpAndLink(_, tgt)
+            | Inst::BranchIfEqual(_, _, tgt)
+            | Inst::BranchIfNotEqual(_, _, tgt)
+            | Inst::BranchIfGreaterEqual(_, _, tgt)
+            | Inst::BranchIfGreaterEqualU(_, _, tgt)
+            | Inst::BranchIfLess(_, _, tgt)
+            | Inst::BranchIfLessU(_, _, tgt)
+            | Inst::CompareAndBranch(_, _, _, tgt, _)
+            | Inst::CompareImmAndBranch(_, _, _, tgt, _) => Some(tgt),
+            _ => None,
+        }
+    }
+
     pub fn map_jumps<OtherJumpType, JumpFn>(
         self,
         mut jump_fn: JumpFn,
@@ -497,7 +1399,8 @@ impl RenamedInst {
     }
 }
 
-impl ReadyInst {
+// Once sources are read, whether or not the destination has been renamed.
+impl<D: Debug + Clone> Inst<u32, D> {
     pub fn access_addr(&self) -> Addr {
         match self {
             Inst::LoadWord(_, dst)
@@ -506,25 +1409,42 @@ impl ReadyInst {
             | Inst::LoadByteU(_, dst)
             | Inst::StoreWord(_, dst)
             | Inst::StoreHalfWord(_, dst)
-            | Inst::StoreByte(_, dst) => dst.compute_addr(),
-            Inst::IndexedLoadByteU(_, src1, src2, imm) => Addr(src1 + src2 + imm.0),
+            | Inst::StoreByte(_, dst)
+            | Inst::LoadReserved(_, dst, _)
+            | Inst::StoreConditional(_, _, dst, _)
+            | Inst::Amo(_, _, _, dst, _) => dst.compute_addr(),
+            Inst::IndexedLoadByte(_, src1, src2, imm)
+            | Inst::IndexedLoadByteU(_, src1, src2, imm)
+            | Inst::IndexedLoadHalfWord(_, src1, src2, imm)
+            | Inst::IndexedLoadWord(_, src1, src2, imm) => {
+                Addr(src1.wrapping_add(*src2).wrapping_add(imm.0))
+            }
             _ => unimplemented!("{:?}", self),
         }
     }
 
     pub fn access_range(&self) -> Range<u32> {
+        if self.is_vector_store() {
+            return vector::footprint(self);
+        }
+
         let start = self.access_addr().0;
-        let end = match self {
-            Inst::StoreWord(_, _) | Inst::LoadWord(_, _) => start + 4,
-            Inst::StoreHalfWord(_, _) | Inst::LoadHalfWord(_, _) => start + 2,
+        let size = match self {
+            Inst::StoreWord(_, _) | Inst::LoadWord(_, _) | Inst::IndexedLoadWord(_, _, _, _) => 4,
+            x if x.is_atomic() => 4,
+            Inst::StoreHalfWord(_, _)
+            | Inst::LoadHalfWord(_, _)
+            | Inst::IndexedLoadHalfWord(_, _, _, _) => 2,
             Inst::LoadByte(_, _)
             | Inst::LoadByteU(_, _)
             | Inst::StoreByte(_, _)
-            | Inst::IndexedLoadByteU(_, _, _, _) => start + 1,
+            | Inst::IndexedLoadByte(_, _, _, _)
+            | Inst::IndexedLoadByteU(_, _, _, _) => 1,
             _ => unimplemented!("{:?}", self),
         };
 
-        start..end
+        // Wraps for wild addresses on a mispredicted path, which never pass is_valid_access.
+        start..start.wrapping_add(size)
     }
 }
 
@@ -585,23 +1505,166 @@ impl FromStr for MemRef {
         let base = inner
             .parse::<ArchReg>()
             .map_err(|_| format!("invalid mem ref (reg): '{s}'"))?;
-        let offset = outer
-            .parse::<Imm>()
-            .map_err(|_| format!("invalid mem ref (imm): '{s}'"))?;
+        // Atomics take a bare "(reg)".
+        let offset = match outer.trim() {
+            "" => Imm(0),
+            outer => outer
+                .parse::<Imm>()
+                .map_err(|_| format!("invalid mem ref (imm): '{s}'"))?,
+        };
 
         Ok(MemRef { base, offset })
     }
 }
 
-impl MemRef<u32> {
-    pub fn compute_addr(self) -> Addr {
-        Addr(self.base.wrapping_add(self.offset.0))
+impl AmoOrdering {
+    // Split the .aq, .rl or .aqrl suffix off an atomic's mnemonic.
+    fn strip_suffix(op: &str) -> (&str, AmoOrdering) {
+        if !(op.starts_with("lr.") || op.starts_with("sc.") || op.starts_with("amo")) {
+            return (op, AmoOrdering::default());
+        }
+
+        for (suffix, acquire, release) in [
+            (".aqrl", true, true),
+            (".aq", true, false),
+            (".rl", false, true),
+        ] {
+            if let Some(op) = op.strip_suffix(suffix) {
+                return (op, AmoOrdering { acquire, release });
+            }
+        }
+        (op, AmoOrdering::default())
     }
 }
 
-impl Default for ArchReg {
-    fn default() -> Self {
-        ArchReg::Zero
+impl AmoOp {
+    // The value written back to memory.
+    pub fn apply(self, old: u32, src: u32) -> u32 {
+        let signed = |x: u32| i32::from_le_bytes(x.to_le_bytes());
+        match self {
+            AmoOp::Swap => src,
+            AmoOp::Add => old.wrapping_add(src),
+            AmoOp::And => old & src,
+            AmoOp::Or => old | src,
+            AmoOp::Xor => old ^ src,
+            AmoOp::Max if signed(src) > signed(old) => src,
+            AmoOp::Min if signed(src) < signed(old) => src,
+            AmoOp::MaxU => old.max(src),
+            AmoOp::MinU => old.min(src),
+            AmoOp::Max | AmoOp::Min => old,
+        }
+    }
+}
+
+impl BitOp {
+    pub fn apply(self, a: u32, b: u32) -> u32 {
+        let signed = |x: u32| i32::from_le_bytes(x.to_le_bytes());
+        match self {
+            BitOp::AndNot => a & !b,
+            BitOp::OrNot => a | !b,
+            BitOp::XorNot => !(a ^ b),
+            BitOp::Max if signed(a) < signed(b) => b,
+            BitOp::Min if signed(a) > signed(b) => b,
+            BitOp::Max | BitOp::Min => a,
+            BitOp::MaxU => a.max(b),
+            BitOp::MinU => a.min(b),
+            // Only the bottom five bits of the amount are used.
+            BitOp::RotateLeft => a.rotate_left(b & 0x1f),
+            BitOp::RotateRight => a.rotate_right(b & 0x1f),
+        }
+    }
+}
+
+impl BitUnaryOp {
+    pub fn apply(self, a: u32) -> u32 {
+        match self {
+            BitUnaryOp::CountLeadingZeros => a.leading_zeros(),
+            BitUnaryOp::CountTrailingZeros => a.trailing_zeros(),
+            BitUnaryOp::CountOnes => a.count_ones(),
+            BitUnaryOp::SignExtendByte => a as u8 as i8 as u32,
+            BitUnaryOp::SignExtendHalfWord => a as u16 as i16 as u32,
+            BitUnaryOp::ZeroExtendHalfWord => a & 0xffff,
+            BitUnaryOp::ReverseBytes => a.swap_bytes(),
+        }
+    }
+}
+
+impl Sew {
+    pub fn bytes(self) -> u32 {
+        match self {
+            Sew::E8 => 1,
+            Sew::E16 => 2,
+            Sew::E32 => 4,
+        }
+    }
+
+    fn bits(self) -> u32 {
+        self.bytes() * 8
+    }
+
+    // Cut a value down to an element.
+    pub fn truncate(self, x: u32) -> u32 {
+        x & u32::MAX.wrapping_shr(32 - self.bits())
+    }
+
+    pub fn sign_extend(self, x: u32) -> u32 {
+        let shift = 32 - self.bits();
+        u32::from_le_bytes((i32::from_le_bytes((x << shift).to_le_bytes()) >> shift).to_le_bytes())
+    }
+}
+
+impl VectorOp {
+    // On one element of each operand, which wraps at the element width.
+    pub fn apply(self, a: u32, b: u32, sew: Sew) -> u32 {
+        // Only as many bits of the shift amount as it takes to shift out the element are used.
+        let shamt = b & (sew.bits() - 1);
+        let res = match self {
+            VectorOp::Add => a.wrapping_add(b),
+            VectorOp::Sub => a.wrapping_sub(b),
+            VectorOp::Mul => a.wrapping_mul(b),
+            VectorOp::And => a & b,
+            VectorOp::Or => a | b,
+            VectorOp::Xor => a ^ b,
+            VectorOp::ShiftLeft => a << shamt,
+            VectorOp::ShiftRightLogical => sew.truncate(a) >> shamt,
+            VectorOp::ShiftRightArith => {
+                let a = i32::from_le_bytes(sew.sign_extend(a).to_le_bytes());
+                u32::from_le_bytes((a >> shamt).to_le_bytes())
+            }
+        };
+        sew.truncate(res)
+    }
+}
+
+impl ReduceOp {
+    pub fn apply(self, acc: u32, x: u32, sew: Sew) -> u32 {
+        let signed = |x: u32| i32::from_le_bytes(sew.sign_extend(x).to_le_bytes());
+        let (acc, x) = (sew.truncate(acc), sew.truncate(x));
+        let res = match self {
+            ReduceOp::Sum => acc.wrapping_add(x),
+            ReduceOp::And => acc & x,
+            ReduceOp::Or => acc | x,
+            ReduceOp::Xor => acc ^ x,
+            ReduceOp::Max if signed(x) > signed(acc) => x,
+            ReduceOp::Min if signed(x) < signed(acc) => x,
+            ReduceOp::Max | ReduceOp::Min => acc,
+            ReduceOp::MaxU => acc.max(x),
+            ReduceOp::MinU => acc.min(x),
+        };
+        sew.truncate(res)
+    }
+}
+
+impl ArchReg {
+    // Which of v0-v31 this is, if it is a vector register at all.
+    pub fn vector_index(self) -> Option<u32> {
+        (self as u32).checked_sub(ArchReg::V0 as u32)
+    }
+}
+
+impl MemRef<u32> {
+    pub fn compute_addr(self) -> Addr {
+        Addr(self.base.wrapping_add(self.offset.0))
     }
 }
 
@@ -611,6 +1674,36 @@ impl From<u64> for Tag {
     }
 }
 
+// Hardware threads share the tag space. The thread is kept in the low bits, so tags still order
+// instructions by age, but only tags from the same thread are ordered by program order.
+pub const MAX_THREADS: usize = 4;
+
+impl Tag {
+    pub fn new(seq: u64, thread: usize) -> Self {
+        debug_assert!(thread < MAX_THREADS);
+        Self(seq * MAX_THREADS as u64 + thread as u64)
+    }
+
+    pub fn seq(self) -> u64 {
+        self.0 / MAX_THREADS as u64
+    }
+
+    pub fn thread(self) -> usize {
+        (self.0 % MAX_THREADS as u64) as usize
+    }
+
+    // Younger than `other` in program order, so squashed along with anything after `other`.
+    pub fn is_after(self, other: Tag) -> bool {
+        self.thread() == other.thread() && self > other
+    }
+
+    // The tag just before this one in the same thread, for killing an instruction itself along
+    // with everything after it.
+    pub fn prev(self) -> Tag {
+        Self(self.0 - MAX_THREADS as u64)
+    }
+}
+
 impl fmt::Debug for Imm {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(fmt, "Imm({})", self.0)
@@ -659,6 +1752,15 @@ impl From<PhysReg> for usize {
     }
 }
 
+impl AbsPc {
+    // For indexing predictor tables. Bit 1 is only set for compressed instructions, so it's
+    // folded in rather than shifted down, which would leave half of each table unused.
+    pub fn table_index(self) -> u32 {
+        let half = if self.0 & 2 != 0 { 0x9e37_79b9 } else { 0 };
+        (self.0 / INST_SIZE) ^ half
+    }
+}
+
 impl Add<u32> for AbsPc {
     type Output = AbsPc;
 
@@ -734,12 +1836,53 @@ mod tests {
         assert_eq!(MemRef::from_str("-2(a1)"), Ok(MemRef { offset: Imm(u32::MAX - 1), base: ArchReg::A1 }));
         assert_eq!(MemRef::from_str("-0x123(a1)"), Ok(MemRef { offset: Imm(u32::MAX - 0x123 + 1), base: ArchReg::A1 }));
 
-        assert!(MemRef::from_str("(a1)").is_err());
+        assert_eq!(MemRef::from_str("(a1)"), Ok(MemRef { offset: Imm(0), base: ArchReg::A1 }));
+
         assert!(MemRef::from_str("0").is_err());
         assert!(MemRef::from_str("a1(0)").is_err());
         assert!(MemRef::from_str("()").is_err());
     }
 
+    #[test]
+    fn test_atomic_ordering() {
+        let addr = MemRef {
+            base: ArchReg::A0,
+            offset: Imm(0),
+        };
+        let parse = |s: &str| LabeledInst::from_str(s).unwrap();
+        let ordering = |acquire, release| AmoOrdering { acquire, release };
+
+        assert_eq!(
+            parse("lr.w t0, (a0)"),
+            Inst::LoadReserved(ArchReg::T0, addr, ordering(false, false))
+        );
+        assert_eq!(
+            parse("sc.w.rl t1, t0, (a0)"),
+            Inst::StoreConditional(ArchReg::T1, ArchReg::T0, addr, ordering(false, true))
+        );
+        assert_eq!(
+            parse("amoadd.w.aq t1, t0, 0(a0)"),
+            Inst::Amo(
+                AmoOp::Add,
+                ArchReg::T1,
+                ArchReg::T0,
+                addr,
+                ordering(true, false)
+            )
+        );
+        assert_eq!(
+            parse("amomaxu.w.aqrl t1, t0, (a0)"),
+            Inst::Amo(
+                AmoOp::MaxU,
+                ArchReg::T1,
+                ArchReg::T0,
+                addr,
+                ordering(true, true)
+            )
+        );
+        assert!(LabeledInst::from_str("add.aq t0, t0, t0").is_err());
+    }
+
     #[test]
     #[rustfmt::skip]
     fn test_label() {
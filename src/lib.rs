@@ -1,27 +1,75 @@
+use std::path::PathBuf;
+
 use cpu::{Cpu, ExecResult};
+use inst::ArchReg;
 use mem::MainMemory;
 use program::Program;
 use regs::RegSet;
+use util::Addr;
 
 pub mod branch;
+pub mod compressed;
 pub mod cpu;
 pub mod emulated;
 pub mod execution_unit;
+pub mod fpu;
+pub mod fusion;
 pub mod inst;
 pub mod lsq;
 pub mod mem;
+pub mod mmu;
+pub mod multicore;
 pub mod out_of_order;
 pub mod program;
 pub mod queue;
 pub mod regs;
 pub mod reservation_station;
 pub mod rob;
+pub mod store_set;
+pub mod trace;
 pub mod util;
+pub mod value_predict;
+pub mod vector;
 
 pub fn parse_and_exec<C: Cpu>(name: &'static str, regs: RegSet, mem: MainMemory) -> ExecResult {
-    let contents = std::fs::read_to_string(format!("asm/{}.asm", name)).unwrap();
-    let prog = contents
+    C::new(load_program(name), regs, mem).exec_all()
+}
+
+pub fn load_program(name: &str) -> Program {
+    let contents =
+        std::fs::read_to_string(format!("asm/{}.asm", name)).expect("failed to open file");
+    contents
         .parse::<Program>()
-        .expect("failed to parse assembly");
-    C::new(prog, regs, mem).exec_all()
+        .expect("failed to parse assembly")
+}
+
+// Set up registers and memory from the command line arguments. If a path is given for the first
+// argument, the file is loaded at address 1000 and A0 points to it.
+pub fn initial_state(a0: Option<String>, a1: Option<String>) -> (RegSet, MainMemory) {
+    let mut mem = MainMemory::new();
+    let regs = initial_regs(a0, a1, &mut mem);
+    (regs, mem)
+}
+
+// Like `initial_state`, but into existing memory, for running several programs at once.
+pub fn initial_regs(a0: Option<String>, a1: Option<String>, mem: &mut MainMemory) -> RegSet {
+    let a0 = a0.unwrap_or_default();
+    let a0 = if let Ok(x) = a0.parse::<u32>() {
+        x
+    } else if !a0.is_empty() {
+        let path = PathBuf::from(a0);
+        println!("Loading file: {}", path.display());
+
+        let load_addr = 1000;
+        let data = std::fs::read(&path).expect("could not open file");
+        mem.copy_from_slice(&data, Addr(load_addr))
+            .unwrap_or_else(|fault| panic!("could not load {}: {fault}", path.display()));
+        load_addr
+    } else {
+        0
+    };
+
+    let a1 = a1.and_then(|x| x.parse::<u32>().ok()).unwrap_or(0);
+
+    RegSet::from([(ArchReg::A0, a0), (ArchReg::A1, a1)])
 }
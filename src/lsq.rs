@@ -5,12 +5,25 @@ use crate::{
     mem::MemoryHierarchy,
     queue::Queue,
     regs::RegFile,
+    store_set::StoreSets,
+    util::Addr,
 };
 
+// How loads are ordered against older stores whose address isn't known yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MemDependence {
+    Blind,        // Always speculate, flushing on a violation.
+    Conservative, // Wait for every older store.
+    #[default]
+    StoreSets, // Wait only for stores predicted to conflict.
+}
+
 #[derive(Debug, Clone)]
 pub struct Store {
     tagged: Tagged<RenamedInst>,
-    address: Option<Range<u32>>,
+    pc: AbsPc,
+    address: Option<Range<u32>>, // Virtual, which is what loads are checked against
+    phys_addr: Option<Addr>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -23,62 +36,77 @@ enum LoadStatus {
 #[derive(Debug, Clone)]
 pub struct Load {
     tagged: Tagged<RenamedInst>,
+    pc: AbsPc,
     address: Option<Range<u32>>,
     status: LoadStatus,
+    depends_on: Option<Tag>, // Store predicted to conflict with this load.
 }
 
 #[derive(Debug, Clone)]
 pub struct LoadStoreQueue {
     loads: Queue<Load>,
     stores: Queue<Store>,
+    mem_dependence: MemDependence,
+    store_sets: StoreSets,
 }
 
-pub const MEM_SPECULATION: bool = true;
-
 impl Store {
-    pub fn new(tagged: Tagged<RenamedInst>) -> Self {
+    pub fn new(tagged: Tagged<RenamedInst>, pc: AbsPc) -> Self {
         Self {
             tagged,
+            pc,
             address: None,
+            phys_addr: None,
         }
     }
 }
 
 impl Load {
-    pub fn new(tagged: Tagged<RenamedInst>) -> Self {
+    pub fn new(tagged: Tagged<RenamedInst>, pc: AbsPc, depends_on: Option<Tag>) -> Self {
         Self {
             tagged,
+            pc,
             address: None,
             status: LoadStatus::NotExecuting,
+            depends_on,
         }
     }
 }
 
 impl LoadStoreQueue {
-    pub fn new(load_capacity: usize, store_capacity: usize) -> Self {
+    pub fn new(load_capacity: usize, store_capacity: usize, mem_dependence: MemDependence) -> Self {
         Self {
             loads: Queue::new(load_capacity),
             stores: Queue::new(store_capacity),
+            mem_dependence,
+            store_sets: StoreSets::new(),
         }
     }
 
     pub fn has_space(&self, inst: &Inst) -> bool {
         if inst.is_load() {
             !self.loads.is_full()
-        } else if inst.is_store() {
+        } else if inst.is_store() || inst.is_atomic() || inst.is_vector_store() {
             !self.stores.is_full()
         } else {
             true
         }
     }
 
-    pub fn insert_access(&mut self, inst: RenamedInst, tag: Tag) {
+    pub fn insert_access(&mut self, inst: RenamedInst, tag: Tag, pc: AbsPc) {
         let entry = Tagged { tag, inst };
 
         let res = if entry.inst.is_load() {
-            self.loads.try_push(Load::new(entry)).map(|_| ())
-        } else if entry.inst.is_store() {
-            self.stores.try_push(Store::new(entry)).map(|_| ())
+            let depends_on = match self.mem_dependence {
+                MemDependence::StoreSets => self.store_sets.load_renamed(pc),
+                _ => None,
+            };
+            self.loads
+                .try_push(Load::new(entry, pc, depends_on))
+                .map(|_| ())
+        } else if entry.inst.is_store() || entry.inst.is_atomic() || entry.inst.is_vector_store() {
+            self.store_sets.store_renamed(pc, tag);
+            self.stores.try_push(Store::new(entry, pc)).map(|_| ())
         } else {
             unreachable!()
         };
@@ -103,7 +131,16 @@ impl LoadStoreQueue {
         load_addr: Range<u32>,
         _reg_file: &mut RegFile,
     ) -> bool {
-        if MEM_SPECULATION {
+        // Nothing after an acquire can be seen before it.
+        if self.stores.iter().any(|s| {
+            tag.is_after(s.tagged.tag)
+                && s.tagged.inst.is_atomic()
+                && s.tagged.inst.ordering().acquire
+        }) {
+            return false;
+        }
+
+        if self.mem_dependence != MemDependence::Conservative {
             // Insert the load_addr.
             let load = self
                 .loads
@@ -112,23 +149,39 @@ impl LoadStoreQueue {
                 .expect("no load");
             load.address = Some(load_addr.clone());
 
-            // Optimistically return yes, unless we know the answer is definitely no.
-            !self.stores.iter().filter(|s| s.tagged.tag < tag).any(|s| {
-                if let Some(store_addr) = &s.address {
-                    // dbg!(&self);
-                    Self::ranges_overlap(store_addr, &load_addr)
-                } else {
-                    // Begin a speculation
-                    // println!("BEGIN SPECULATE {:?} at {:?}", tag, load_pc);
-                    // reg_file.begin_predict_mem(tag, load_pc);
-                    false
+            // Wait for the predicted store to find out its address.
+            if let Some(store) = load.depends_on {
+                if self
+                    .stores
+                    .iter()
+                    .any(|s| s.tagged.tag == store && s.address.is_none())
+                {
+                    return false;
                 }
-            })
+            }
+
+            // Optimistically return yes, unless we know the answer is definitely no.
+            !self
+                .stores
+                .iter()
+                .filter(|s| tag.is_after(s.tagged.tag))
+                .any(|s| {
+                    if let Some(store_addr) = &s.address {
+                        // dbg!(&self);
+                        Self::ranges_overlap(store_addr, &load_addr)
+                    } else {
+                        // Begin a speculation
+                        // println!("BEGIN SPECULATE {:?} at {:?}", tag, load_pc);
+                        // reg_file.begin_predict_mem(tag, load_pc);
+                        false
+                    }
+                })
         } else {
             // Stores are entered into the LSQ in order.
-            // Here we enforce that the next store must occur after the designated load
+            // Here we enforce that the thread's next store must occur after the designated load
             self.stores
-                .front()
+                .iter()
+                .find(|s| s.tagged.tag.thread() == tag.thread())
                 .map(|s| s.tagged.tag > tag)
                 .unwrap_or(true)
         }
@@ -144,13 +197,15 @@ impl LoadStoreQueue {
         store_addr: Range<u32>,
         _reg_file: &mut RegFile,
     ) -> (Vec<Tag>, Vec<Tag>) {
-        if MEM_SPECULATION {
+        if self.mem_dependence != MemDependence::Conservative {
             let store = self
                 .stores
                 .iter_mut()
                 .find(|s| s.tagged.tag == store_tag)
                 .expect("no store");
             store.address = Some(store_addr.clone());
+            let store_pc = store.pc;
+            self.store_sets.store_issued(store_pc, store_tag);
 
             // todo dont repeat this
             // Check if any later loads overlap us
@@ -158,13 +213,19 @@ impl LoadStoreQueue {
                 .loads
                 .iter()
                 .filter(|l| {
-                    l.tagged.tag > store_tag
+                    l.tagged.tag.is_after(store_tag)
                         && l.status != LoadStatus::NotExecuting
                         && l.address.is_some()
                 })
                 .filter(|l| Self::ranges_overlap(l.address.as_ref().unwrap(), &store_addr))
                 .collect::<Vec<&Load>>();
 
+            if self.mem_dependence == MemDependence::StoreSets {
+                for load in &loads_to_kill {
+                    self.store_sets.violation(load.pc, store_pc);
+                }
+            }
+
             // if !loads_to_kill.is_empty() {
             // dbg!(store_tag);
             // dbg!(&self);
@@ -188,6 +249,16 @@ impl LoadStoreQueue {
         }
     }
 
+    // Where the store will write when it commits.
+    pub fn store_translated(&mut self, tag: Tag, phys_addr: Addr) {
+        let store = self
+            .stores
+            .iter_mut()
+            .find(|s| s.tagged.tag == tag)
+            .expect("no store");
+        store.phys_addr = Some(phys_addr);
+    }
+
     pub fn kill_inflight(&mut self, load: Tag) {
         let load = self
             .loads
@@ -199,22 +270,48 @@ impl LoadStoreQueue {
     }
 
     pub fn commit_store(&mut self, tag: Tag, rf: &RegFile, mem: &mut MemoryHierarchy) {
-        let store = self.stores.try_pop().unwrap();
+        // Other threads' stores can be ahead of it, but it is the oldest from its own thread.
+        let pos = self
+            .stores
+            .iter()
+            .position(|s| s.tagged.tag.thread() == tag.thread())
+            .unwrap();
+        let store = self.stores.remove(pos).unwrap();
         debug_assert_eq!(store.tagged.tag, tag);
         // println!("COMMITTED STORE {:?}", tag);
 
+        let addr = || {
+            store
+                .phys_addr
+                .expect("store committed before it was translated")
+        };
         match store
             .tagged
             .inst
             .get_ready(rf)
             .expect("store committed when not ready")
         {
-            Inst::StoreByte(val, dst) => {
-                mem.main.writeb(dst.compute_addr(), val);
+            Inst::StoreByte(val, _) => {
+                mem.commit_write(addr(), 1);
+                mem.main
+                    .writeb(addr(), val)
+                    .expect("store faulted when it executed");
             }
-            Inst::StoreWord(val, dst) => {
-                mem.main.writew(dst.compute_addr(), val);
+            Inst::StoreHalfWord(val, _) => {
+                mem.commit_write(addr(), 2);
+                mem.main
+                    .writeh(addr(), val)
+                    .expect("store faulted when it executed");
             }
+            Inst::StoreWord(val, _) => {
+                mem.commit_write(addr(), 4);
+                mem.main
+                    .writew(addr(), val)
+                    .expect("store faulted when it executed");
+            }
+            // Already done when they executed at the head of the ROB
+            x if x.is_atomic() || x.is_vector_store() => (),
+
             _ => unimplemented!("{:?}", store.tagged.inst),
         }
     }
@@ -235,7 +332,7 @@ impl LoadStoreQueue {
     }
 
     pub fn kill_tags_after(&mut self, tag: Tag) {
-        self.loads.retain(|ent| ent.tagged.tag <= tag);
-        self.stores.retain(|ent| ent.tagged.tag <= tag);
+        self.loads.retain(|ent| !ent.tagged.tag.is_after(tag));
+        self.stores.retain(|ent| !ent.tagged.tag.is_after(tag));
     }
 }
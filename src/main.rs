@@ -1,62 +1,114 @@
-mod branch;
-mod cpu;
-mod emulated;
-mod execution_unit;
-mod inst;
-mod lsq;
-mod mem;
-mod out_of_order;
-mod program;
-mod queue;
-mod regs;
-mod reservation_station;
-mod rob;
-mod util;
-
-use std::path::PathBuf;
-
-use crate::{cpu::Cpu, inst::ArchReg, mem::MainMemory, regs::RegSet, util::Addr};
+use aca::{
+    cpu::{BranchReport, Cpu},
+    inst::ArchReg,
+    mem::{MainMemory, Misaligned, MshrConfig, STACK_TOP},
+    mmu,
+    out_of_order::{self, OutOfOrderConfig},
+    program::Program,
+};
 
-fn main() {
-    let file = std::env::args()
-        .nth(1)
-        .expect("required input file as argument argument");
-
-    let contents =
-        std::fs::read_to_string(&format!("asm/{}.asm", file)).expect("failed to open file");
-
-    let prog = contents
-        .parse::<program::Program>()
-        .expect("failed to parse program");
+// Stack space for each hardware thread, below the previous thread's.
+const THREAD_STACK_BYTES: usize = 64_000;
 
-    let mut mem = MainMemory::new();
+// With RVC set, every instruction that can be is compressed.
+fn load_program(file: &str) -> Program {
+    let prog = aca::load_program(file);
+    if std::env::var("RVC").is_ok() {
+        prog.compressed()
+    } else {
+        prog
+    }
+}
 
-    let a0 = std::env::args().nth(2).unwrap_or_else(|| "".to_string());
-    let a0 = if let Ok(x) = a0.parse::<u32>() {
-        x
-    } else if let Ok(path) = a0.parse::<PathBuf>() {
-        println!("Loading file: {}", path.display());
+// With MISALIGNED set, misaligned loads and stores are done in hardware instead of faulting.
+fn misaligned_policy() -> Misaligned {
+    if std::env::var("MISALIGNED").is_ok() {
+        Misaligned::Hardware
+    } else {
+        Misaligned::Fault
+    }
+}
 
-        let load_addr = 1000;
-        let data = std::fs::read(path).expect("could not open file");
-        mem.copy_from_slice(&data, Addr(load_addr));
-        load_addr
+// With SV32 set, the program runs with translation on, through an identity mapping of memory.
+fn enable_translation(mem: &mut MainMemory) -> u32 {
+    if std::env::var("SV32").is_ok() {
+        mmu::identity_map(mem)
     } else {
         0
-    };
+    }
+}
 
-    let a1 = std::env::args()
-        .nth(3)
-        .and_then(|x| x.parse::<u32>().ok())
-        .unwrap_or(0);
-    let initial_regs = RegSet::from([(ArchReg::A0, a0), (ArchReg::A1, a1)]);
+// With MSHRS set to a number, each level of cache has that many MSHRs.
+fn config() -> OutOfOrderConfig {
+    let mut config = OutOfOrderConfig::default();
+    if let Ok(mshrs) = std::env::var("MSHRS") {
+        let mshrs = mshrs.parse().expect("MSHRS should be a number");
+        config.mshrs = MshrConfig {
+            l1: mshrs,
+            l2: mshrs,
+            l3: mshrs,
+        };
+    }
+    config
+}
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
 
-    // let res = emulated::Emulated::new(prog, initial_regs, mem).exec_all();
-    let res = out_of_order::OutOfOrder::new(prog, initial_regs, mem).exec_all();
+    // Programs separated by `+` run together as hardware threads, e.g. `quicksort 0 200 + matmul
+    // 4000 16`. They share memory, so should be given separate regions to work in.
+    let workloads = args.split(|arg| arg == "+").collect::<Vec<_>>();
+    let progs = workloads
+        .iter()
+        .map(|args| load_program(args.first().expect("required input file for each thread")))
+        .collect::<Vec<_>>();
+
+    let res = if workloads.len() == 1 {
+        let (initial_regs, mem) =
+            aca::initial_state(workloads[0].get(1).cloned(), workloads[0].get(2).cloned());
+        let mut mem = mem.with_misaligned(misaligned_policy());
+        let satp = enable_translation(&mut mem);
+
+        // let res = emulated::Emulated::new(progs[0].clone(), initial_regs, mem).exec_all();
+        out_of_order::OutOfOrder::with_config(progs[0].clone(), initial_regs, mem, config())
+            .with_satp(satp)
+            .exec_all()
+    } else {
+        let mut mem = MainMemory::new().with_misaligned(misaligned_policy());
+        let threads = workloads
+            .iter()
+            .zip(&progs)
+            .enumerate()
+            .map(|(thread, (args, prog))| {
+                let mut regs =
+                    aca::initial_regs(args.get(1).cloned(), args.get(2).cloned(), &mut mem);
+                regs.set(
+                    ArchReg::SP,
+                    (STACK_TOP - thread * THREAD_STACK_BYTES)
+                        .try_into()
+                        .unwrap(),
+                );
+                (prog.clone(), regs)
+            })
+            .collect();
+        let satp = enable_translation(&mut mem);
+
+        out_of_order::OutOfOrder::with_threads(threads, mem, config())
+            .with_satp(satp)
+            .exec_all()
+    };
 
     // use std::io::Write;
     // let mut f = std::fs::File::create("/tmp/mem.txt").expect("Unable to create file");
     // writeln!(f, "{:#?}", res.mem).unwrap();
 
     println!("{res}");
+
+    if std::env::var("BRANCH_PROFILE").is_ok() {
+        let report = BranchReport {
+            stats: &res.stats,
+            progs: &progs,
+        };
+        println!("{report}");
+    }
 }
@@ -1,21 +1,40 @@
+use hashbrown::HashMap;
+use std::{fmt, ops::Range, sync::Arc};
+
 use crate::{
     cpu::Stats,
-    inst::Tag,
+    inst::{Tag, MAX_THREADS},
     util::{Addr, CacheCapacity},
 };
 use associative_cache::*;
 
 const L1_CAPACITY_BYTES: usize = 16_000;
+const L1I_CAPACITY_BYTES: usize = 16_000;
 const L2_CAPACITY_BYTES: usize = 32_000;
 const L3_CAPACITY_BYTES: usize = 128_000;
-const DRAM_CAPACITY_BYTES: usize = 1_024_000;
 
-pub const STACK_TOP: usize = DRAM_CAPACITY_BYTES - 16_000;
+// Physical addresses are 32 bits, of which only the mapped regions can be accessed.
+pub const ADDRESS_SPACE_BYTES: u64 = 1 << 32;
+// The region `MainMemory::new` maps, with the stack at the top of it.
+pub const DRAM: Range<u64> = 0..1_024_000;
+pub const STACK_TOP: usize = DRAM.end as usize - 16_000;
+
+// Memory is allocated a block at a time when it's first written. Until then it reads as zero.
+const BLOCK_BYTES: usize = 4096;
 
-const L1_LATENCY: u64 = 5;
+pub const L1_LATENCY: u64 = 5;
 const L2_LATENCY: u64 = 20;
 const L3_LATENCY: u64 = 40;
+// Another core holds the line, so it comes from that core's private caches.
+const CACHE_TO_CACHE_LATENCY: u64 = 50;
 const DRAM_LATENCY: u64 = 400;
+// Shifting and merging the bytes of a misaligned access.
+const MISALIGNED_LATENCY: u64 = 1;
+
+// The program isn't part of MainMemory, so its lines are kept apart from data lines at the same
+// address in L2 and L3, and aren't kept coherent. Line addresses are aligned, so a low bit that's
+// otherwise always clear marks them.
+const INST_LINE_BIT: u32 = 1;
 
 // const L1_LATENCY: u64 = 3;
 // const L2_LATENCY: u64 = 3;
@@ -27,111 +46,609 @@ const DRAM_LATENCY: u64 = 400;
 // const L3_LATENCY: u64 = 1;
 // const DRAM_LATENCY: u64 = 1;
 
-#[derive(Debug, Clone, Default, PartialEq, Eq)]
+type Block = [u8; BLOCK_BYTES];
+
+static ZERO_BLOCK: Block = [0; BLOCK_BYTES];
+
+// A sparse physical address space. Clones share blocks until one of them writes to a block, so
+// they're cheap to take.
+#[derive(Debug, Clone, Default)]
 pub struct MainMemory {
-    mem: Vec<u8>,
+    regions: Arc<Vec<Range<u64>>>, // Sorted and disjoint
+    blocks: Arc<HashMap<u32, Arc<Block>>>,
+    misaligned: Misaligned,
+}
+
+// What happens to a load or store that isn't naturally aligned. Atomics always fault.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Misaligned {
+    #[default]
+    Fault,
+    // Done at extra latency, looking up both lines if it crosses a cache line.
+    Hardware,
+}
+
+// Why an access couldn't be made, which stops the program if it's one that commits.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MemFault {
+    Unmapped(Addr),
+    Misaligned(Addr),
+}
+
+impl fmt::Display for MemFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemFault::Unmapped(addr) => write!(f, "access to unmapped address {:#x}", addr.0),
+            MemFault::Misaligned(addr) => write!(f, "misaligned access to {:#x}", addr.0),
+        }
+    }
+}
+
+// Miss status holding registers for each level of cache. They bound how many lines a level can be
+// waiting on at once, with further misses to a line it's already waiting on merging into its entry.
+#[derive(Debug, Clone, Copy)]
+pub struct MshrConfig {
+    pub l1: usize,
+    pub l2: usize,
+    pub l3: usize, // Shared between cores
+}
+
+impl Default for MshrConfig {
+    fn default() -> Self {
+        Self {
+            l1: 128,
+            l2: 128,
+            l3: 128,
+        }
+    }
+}
+
+// One level's MSHRs, each holding a line along with the cycle it arrives on.
+#[derive(Debug, Clone)]
+struct Mshrs {
+    capacity: usize,
+    misses: Vec<(Addr, u64)>,
+}
+
+impl Mshrs {
+    fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "each level needs at least one MSHR");
+        Self {
+            capacity,
+            misses: Vec::new(),
+        }
+    }
+
+    // Entries are freed once their line arrives.
+    fn retire(&mut self, now: u64) {
+        self.misses.retain(|&(_, arrival)| arrival > now);
+    }
+
+    // Cycles until there are entries for all of the lines, counting those already waited on.
+    fn wait(&self, lines: &[Addr], now: u64) -> u64 {
+        let mut new = lines
+            .iter()
+            .filter(|&&line| !self.misses.iter().any(|&(l, _)| l == line))
+            .collect::<Vec<_>>();
+        new.dedup();
+        let free = self.capacity.saturating_sub(self.misses.len());
+        if new.len() <= free {
+            return 0;
+        }
+
+        let mut arrivals = self.misses.iter().map(|&(_, a)| a).collect::<Vec<_>>();
+        arrivals.sort_unstable();
+        let index = (new.len() - free - 1).min(arrivals.len() - 1);
+        arrivals[index].saturating_sub(now)
+    }
+
+    // With every entry in use, the caller has waited for the earliest to retire and takes it over.
+    fn take(&mut self, line: Addr, arrival: u64) {
+        if let Some((_, a)) = self.misses.iter_mut().find(|(l, _)| *l == line) {
+            *a = (*a).max(arrival);
+        } else if self.misses.len() < self.capacity {
+            self.misses.push((line, arrival));
+        } else {
+            let earliest = self.misses.iter_mut().min_by_key(|&&mut (_, a)| a).unwrap();
+            *earliest = (line, arrival);
+        }
+    }
+}
+
+// Where a line comes from on its way into L1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    InFlight(u64), // An earlier miss is already fetching it, and arrives in this many cycles
+    L1,
+    L2,
+    OtherCore,
+    L3,
+    Dram,
+}
+
+impl Source {
+    // The levels that need an MSHR to fetch it, from L1 down.
+    fn levels_missed(self) -> usize {
+        match self {
+            Source::InFlight(_) | Source::L1 => 0,
+            Source::L2 => 1,
+            Source::OtherCore | Source::L3 => 2,
+            Source::Dram => 3,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Pending {
     tag: Tag,
     addr: Addr,
+    split: Option<Addr>, // The second line of a misaligned access that crosses one
     current: u64,
     end: u64,
 }
 
+// The state of a line in one core's private caches.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Mesi {
+    Modified,
+    Exclusive,
+    Shared,
+    #[default]
+    Invalid,
+}
+
+// Lines reserved by lr.w, for each hart (a hardware thread on a core). Any write to the line
+// breaks every reservation on it.
+#[derive(Debug, Clone, Default)]
+pub struct Reservations {
+    held: HashMap<usize, Addr>,
+}
+
+impl Reservations {
+    pub fn hart(core: usize, thread: usize) -> usize {
+        core * MAX_THREADS + thread
+    }
+
+    pub fn reserve(&mut self, hart: usize, line: Addr) {
+        self.held.insert(hart, line);
+    }
+
+    // Whether a sc.w from the hart succeeds. It gives up the reservation either way.
+    pub fn take(&mut self, hart: usize, line: Addr) -> bool {
+        self.held.remove(&hart) == Some(line)
+    }
+
+    pub fn write(&mut self, line: Addr) {
+        self.held.retain(|_, held| *held != line);
+    }
+
+    pub fn write_access(&mut self, addr: Addr, size: u32) {
+        for line in access_lines(addr, size) {
+            self.write(line);
+        }
+    }
+}
+
+// The lines an access touches, of which there are two if it's misaligned across a boundary.
+fn access_lines(addr: Addr, size: u32) -> impl Iterator<Item = Addr> {
+    let first = addr.to_cache_line();
+    let last = Addr(addr.0.wrapping_add(size - 1)).to_cache_line();
+    std::iter::once(first).chain((last != first).then_some(last))
+}
+
+// Everything below the private caches, shared between cores: the L3, and a directory keeping
+// the private caches coherent with MESI. It is lent to one core at a time.
+#[derive(Debug)]
+pub struct SharedCache {
+    l3: L3Cache,
+    directory: HashMap<Addr, Vec<Mesi>>, // Each core's state for a line
+    pub core: usize,                     // The core it is currently lent to
+    pub invalidations: Vec<u64>,         // Lines each core has lost to another core's write
+    pub reservations: Reservations,
+    mshrs: Mshrs, // The L3's
+}
+
+impl SharedCache {
+    pub fn new(cores: usize, l3_mshrs: usize) -> Self {
+        Self {
+            l3: AssociativeCache::default(),
+            directory: HashMap::new(),
+            core: 0,
+            invalidations: vec![0; cores],
+            reservations: Reservations::default(),
+            mshrs: Mshrs::new(l3_mshrs),
+        }
+    }
+
+    fn cores(&self) -> usize {
+        self.invalidations.len()
+    }
+
+    fn state(&self, line: Addr) -> Mesi {
+        self.directory
+            .get(&line)
+            .map_or(Mesi::Invalid, |states| states[self.core])
+    }
+
+    // Whether another core has the line modified, so a miss would be supplied by it.
+    fn held_modified(&self, line: Addr) -> bool {
+        self.directory.get(&line).is_some_and(|states| {
+            states
+                .iter()
+                .enumerate()
+                .any(|(c, &state)| c != self.core && state == Mesi::Modified)
+        })
+    }
+
+    // A private cache miss. Other cores keep a shared copy, and a core with the line modified
+    // supplies it, in which case this returns true.
+    fn read_miss(&mut self, line: Addr) -> bool {
+        let (core, cores) = (self.core, self.cores());
+        let states = self
+            .directory
+            .entry(line)
+            .or_insert_with(|| vec![Mesi::Invalid; cores]);
+
+        let mut supplied = false;
+        let mut shared = false;
+        for (c, state) in states.iter_mut().enumerate() {
+            if c == core || *state == Mesi::Invalid {
+                continue;
+            }
+
+            supplied |= *state == Mesi::Modified;
+            shared = true;
+            *state = Mesi::Shared;
+        }
+
+        states[core] = if shared {
+            Mesi::Shared
+        } else {
+            Mesi::Exclusive
+        };
+        supplied
+    }
+
+    // Take ownership of a line to write it, invalidating every other copy.
+    fn write(&mut self, line: Addr) {
+        let (core, cores) = (self.core, self.cores());
+        let states = self
+            .directory
+            .entry(line)
+            .or_insert_with(|| vec![Mesi::Invalid; cores]);
+
+        for (c, state) in states.iter_mut().enumerate() {
+            if c != core && *state != Mesi::Invalid {
+                *state = Mesi::Invalid;
+                self.invalidations[c] += 1;
+            }
+        }
+        states[core] = Mesi::Modified;
+    }
+
+    // The line has left the core's private caches.
+    fn evict(&mut self, line: Addr) {
+        let core = self.core;
+        if let Some(states) = self.directory.get_mut(&line) {
+            states[core] = Mesi::Invalid;
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct MemoryHierarchy {
     pub main: MainMemory,
     l1: L1Cache,
     l2: L2Cache,
-    l3: L3Cache,
+    pub shared: SharedCache,
     pending_fetches: Vec<Pending>,
+    mshrs: [Mshrs; 2], // L1's and L2's, with L3's in `shared`
+    cycle: u64,
+    l1i: L1ICache,
+    pending_inst_fills: Vec<(Addr, u64)>, // The cycle each line arrives on
 }
 
 impl MemoryHierarchy {
-    pub fn new(mem: MainMemory) -> Self {
+    pub fn new(mem: MainMemory, mshrs: MshrConfig) -> Self {
         Self {
             main: mem,
             l1: AssociativeCache::default(),
             l2: AssociativeCache::default(),
-            l3: AssociativeCache::default(),
+            shared: SharedCache::new(1, mshrs.l3),
             pending_fetches: Default::default(),
+            mshrs: [Mshrs::new(mshrs.l1), Mshrs::new(mshrs.l2)],
+            cycle: 0,
+            l1i: AssociativeCache::default(),
+            pending_inst_fills: Vec::new(),
+        }
+    }
+
+    fn levels(&mut self) -> [&mut Mshrs; 3] {
+        let [l1, l2] = &mut self.mshrs;
+        [l1, l2, &mut self.shared.mshrs]
+    }
+
+    // Cycles until every level the lines miss in has an MSHR for each of them.
+    fn mshr_wait(&self, lines: &[Addr]) -> u64 {
+        let sources = lines
+            .iter()
+            .map(|&line| (line, self.line_source(line)))
+            .collect::<Vec<_>>();
+        self.mshr_wait_from(&sources)
+    }
+
+    fn mshr_wait_from(&self, sources: &[(Addr, Source)]) -> u64 {
+        [&self.mshrs[0], &self.mshrs[1], &self.shared.mshrs]
+            .into_iter()
+            .enumerate()
+            .map(|(level, mshrs)| {
+                let missed = sources
+                    .iter()
+                    .filter(|(_, source)| source.levels_missed() > level)
+                    .map(|&(line, _)| line)
+                    .collect::<Vec<_>>();
+                mshrs.wait(&missed, self.cycle)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Another core's write leaves stale copies behind, which are dropped when next touched.
+    fn drop_if_invalidated(&mut self, line: Addr, stats: &mut Stats) {
+        let held = self.l1.get(&line).is_some() || self.l2.get(&line).is_some();
+        if held && self.shared.state(line) == Mesi::Invalid {
+            self.l1.remove(&line);
+            self.l2.remove(&line);
+            stats.coherence_misses += 1;
         }
     }
 
-    pub fn access_complete(&mut self, tag: Tag, addr: Addr, stats: &mut Stats) -> bool {
+    // A store of `size` bytes reaching memory at commit, which writes two lines if it's
+    // misaligned across a boundary.
+    pub fn commit_write(&mut self, addr: Addr, size: u32) {
+        for line in access_lines(addr, size) {
+            self.shared.write(line);
+            self.shared.reservations.write(line);
+        }
+    }
+
+    pub fn load_reserved(&mut self, thread: usize, addr: Addr) {
+        let hart = Reservations::hart(self.shared.core, thread);
+        self.shared.reservations.reserve(hart, addr.to_cache_line());
+    }
+
+    // Whether a sc.w succeeds, in which case it is written like any other store.
+    pub fn store_conditional(&mut self, thread: usize, addr: Addr) -> bool {
+        let hart = Reservations::hart(self.shared.core, thread);
+        let success = self.shared.reservations.take(hart, addr.to_cache_line());
+        if success {
+            self.commit_write(addr, 4);
+        }
+        success
+    }
+
+    // Whether the line holding `addr` can be fetched from this cycle. A miss starts filling the
+    // line from L2 down, taking MSHRs like a data miss; hit latency is part of the front end
+    // depth. The caller counts the cycles spent waiting.
+    pub fn inst_line_ready(&mut self, addr: Addr, stats: &mut Stats) -> bool {
+        let addr = Addr(addr.to_cache_line().0 | INST_LINE_BIT);
+
+        if self.l1i.get(&addr).is_some() {
+            stats.l1i_hits += 1;
+            return true;
+        }
+
+        if let Some(pos) = self.pending_inst_fills.iter().position(|(a, _)| *a == addr) {
+            if self.pending_inst_fills[pos].1 > self.cycle {
+                return false;
+            }
+
+            self.pending_inst_fills.swap_remove(pos);
+            self.fill_l1i(addr);
+            return true;
+        }
+
+        let source = if self.l2.get(&addr).is_some() {
+            Source::L2
+        } else if self.shared.l3.get(&addr).is_some() {
+            Source::L3
+        } else {
+            Source::Dram
+        };
+        // Tried again next cycle, until the miss has somewhere to go.
+        if self.mshr_wait_from(&[(addr, source)]) > 0 {
+            stats.mshr_stalls += 1;
+            return false;
+        }
+
+        stats.l1i_misses += 1;
+        let latency = self.source_latency(addr, source, 0, stats);
+        self.pending_inst_fills.push((addr, self.cycle + latency));
+        false
+    }
+
+    pub fn access_complete(&mut self, tag: Tag, addr: Addr, size: u32, stats: &mut Stats) -> bool {
         match self.pending_fetches.iter().find(|p| p.tag == tag) {
             Some(p) => p.current >= p.end,
             None => {
-                let addr = addr.to_cache_line();
-                let latency = if let Some(p) = self
-                    .pending_fetches
-                    .iter()
-                    .filter(|p| p.addr == addr)
-                    .min_by_key(|p| p.end)
-                {
-                    // If we have an outstanding fetch to an addr, only wait until that one
-                    // completes. Otherwise dram fetch + (wait n cycles) + dram fetch to same addr will take
-                    // very long.
-                    // unreachable!();
-                    // dbg!(&self.pending_fetches);
-                    // dbg!(tag);
-                    // dbg!(p);
-                    // TODO: WE should just implement load/store forwarding
-                    L1_LATENCY + (p.end.saturating_sub(p.current))
-                } else if self.l1.get(&addr).is_some() {
+                let line = addr.to_cache_line();
+                let misaligned =
+                    self.main.misaligned == Misaligned::Hardware && !addr.0.is_multiple_of(size);
+                let split = Some(Addr(addr.0.wrapping_add(size - 1)).to_cache_line())
+                    .filter(|&last| misaligned && last != line);
+
+                self.drop_if_invalidated(line, stats);
+                if let Some(last) = split {
+                    self.drop_if_invalidated(last, stats);
+                }
+
+                // Tried again next cycle, until the first line's miss has somewhere to go.
+                if self.mshr_wait(&[line]) > 0 {
+                    stats.mshr_stalls += 1;
+                    return false;
+                }
+
+                let mut latency = self.line_latency(line, 0, stats);
+                if misaligned {
+                    stats.misaligned_accesses += 1;
+
+                    // The second line is looked up the cycle after the first, or once there's an
+                    // MSHR for it, which with only one may be when the first line arrives.
+                    if let Some(last) = split {
+                        stats.split_accesses += 1;
+                        let wait = self.mshr_wait(&[last]);
+                        stats.mshr_stalls += wait;
+                        latency = latency.max(self.line_latency(last, wait.max(1), stats));
+                    }
+                    latency += MISALIGNED_LATENCY;
+                }
+
+                self.pending_fetches.push(Pending {
+                    tag,
+                    addr: line,
+                    split,
+                    current: 0,
+                    end: latency,
+                });
+
+                false
+            }
+        }
+    }
+
+    fn line_source(&self, addr: Addr) -> Source {
+        if let Some(p) = self
+            .pending_fetches
+            .iter()
+            .filter(|p| p.addr == addr || p.split == Some(addr))
+            .min_by_key(|p| p.end)
+        {
+            // If we have an outstanding fetch to an addr, only wait until that one
+            // completes. Otherwise dram fetch + (wait n cycles) + dram fetch to same addr will take
+            // very long.
+            // TODO: WE should just implement load/store forwarding
+            Source::InFlight(p.end.saturating_sub(p.current))
+        } else if self.l1.get(&addr).is_some() {
+            Source::L1
+        } else if self.l2.get(&addr).is_some() {
+            Source::L2
+        } else if self.shared.held_modified(addr) {
+            Source::OtherCore
+        } else if self.shared.l3.get(&addr).is_some() {
+            Source::L3
+        } else {
+            Source::Dram
+        }
+    }
+
+    // Cycles until a line would arrive in L1 if it's requested `delay` cycles from now, counting
+    // it in the stats for the level it's found at. Each level it misses in takes an MSHR until
+    // it arrives, so there has to be one free.
+    fn line_latency(&mut self, addr: Addr, delay: u64, stats: &mut Stats) -> u64 {
+        let source = self.line_source(addr);
+        if !matches!(source, Source::InFlight(_) | Source::L1 | Source::L2) {
+            self.shared.read_miss(addr);
+        }
+        self.source_latency(addr, source, delay, stats)
+    }
+
+    fn source_latency(&mut self, addr: Addr, source: Source, delay: u64, stats: &mut Stats) -> u64 {
+        let latency = delay
+            + match source {
+                Source::InFlight(remaining) => L1_LATENCY + remaining,
+                Source::L1 => {
                     stats.l1_hits += 1;
                     L1_LATENCY
-                } else if self.l2.get(&addr).is_some() {
-                    // stats.l1_miss += 1;
+                }
+                Source::L2 => {
                     stats.l2_hits += 1;
                     L2_LATENCY
-                } else if self.l3.get(&addr).is_some() {
-                    // stats.l1_miss += 1;
+                }
+                Source::OtherCore => {
+                    stats.cache_to_cache_transfers += 1;
+                    CACHE_TO_CACHE_LATENCY
+                }
+                Source::L3 => {
                     stats.l2_miss += 1;
                     stats.l3_hits += 1;
                     L3_LATENCY
-                } else {
-                    // stats.l1_miss += 1;
-                    // stats.l2_miss += 1;
+                }
+                Source::Dram => {
                     stats.l3_miss += 1;
                     DRAM_LATENCY
-                };
-
-                self.pending_fetches.push(Pending {
-                    tag,
-                    addr,
-                    current: 0,
-                    end: latency,
-                });
+                }
+            };
 
-                false
-            }
+        let arrival = self.cycle + latency;
+        for mshrs in self.levels().into_iter().take(source.levels_missed()) {
+            mshrs.take(addr, arrival);
         }
+        latency
     }
 
-    pub fn finish_access(&mut self, tag: Tag, addr: Addr) {
+    // An access whose latency is accounted for by the caller, as with the vector unit, which
+    // requests whole lines itself. The line is in L1 from here on. Rather than stalling for an
+    // MSHR, it waits for one as part of its latency.
+    pub fn access_line(&mut self, line: Addr, stats: &mut Stats) -> u64 {
+        self.drop_if_invalidated(line, stats);
+        let wait = self.mshr_wait(&[line]);
+        stats.mshr_stalls += wait;
+        let latency = self.line_latency(line, wait, stats);
+        self.fill_l1(line);
+        latency
+    }
+
+    pub fn finish_access(&mut self, tag: Tag) {
         let pos = self
             .pending_fetches
             .iter()
             .position(|p| p.tag == tag)
             .unwrap();
-        self.pending_fetches.swap_remove(pos);
+        let pending = self.pending_fetches.swap_remove(pos);
+        self.fill_l1(pending.addr);
+        if let Some(line) = pending.split {
+            self.fill_l1(line);
+        }
+    }
 
-        // Promote address to L1 cache
-        let addr = addr.to_cache_line();
+    // Promote address to L1 cache
+    fn fill_l1(&mut self, addr: Addr) {
         if let Some((evicted, _)) = self.l1.insert(addr, WithLruTimestamp::new(())) {
-            if let Some((evicted, _)) = self.l2.insert(evicted, WithLruTimestamp::new(())) {
-                self.l3.insert(evicted, WithLruTimestamp::new(()));
+            self.fill_l2(evicted);
+        }
+    }
+
+    fn fill_l1i(&mut self, addr: Addr) {
+        if let Some((evicted, _)) = self.l1i.insert(addr, WithLruTimestamp::new(())) {
+            self.fill_l2(evicted);
+        }
+    }
+
+    // Lines evicted from either L1 go to L2, and from there to L3.
+    fn fill_l2(&mut self, addr: Addr) {
+        if let Some((evicted, _)) = self.l2.insert(addr, WithLruTimestamp::new(())) {
+            if evicted.0 & INST_LINE_BIT == 0 && self.l1.get(&evicted).is_none() {
+                self.shared.evict(evicted);
             }
+            self.shared.l3.insert(evicted, WithLruTimestamp::new(()));
         }
     }
 
-    pub fn tick(&mut self) {
+    pub fn tick(&mut self, stats: &mut Stats) {
         for p in &mut self.pending_fetches {
             p.current += 1;
         }
+
+        self.cycle += 1;
+        let now = self.cycle;
+        for (level, mshrs) in self.levels().into_iter().enumerate() {
+            mshrs.retire(now);
+            let histogram = &mut stats.mshr_occupancy[level];
+            if histogram.len() <= mshrs.misses.len() {
+                histogram.resize(mshrs.misses.len() + 1, 0);
+            }
+            histogram[mshrs.misses.len()] += 1;
+        }
     }
 }
 
@@ -141,79 +658,199 @@ impl Clone for MemoryHierarchy {
             main: self.main.clone(),
             l1: AssociativeCache::default(),
             l2: AssociativeCache::default(),
-            l3: AssociativeCache::default(),
+            shared: SharedCache::new(self.shared.cores(), self.shared.mshrs.capacity),
             pending_fetches: Vec::default(),
+            mshrs: self.mshrs.clone().map(|m| Mshrs::new(m.capacity)),
+            cycle: 0,
+            l1i: AssociativeCache::default(),
+            pending_inst_fills: Vec::new(),
         }
     }
 }
 
 impl MainMemory {
     pub fn new() -> Self {
+        Self::with_regions([DRAM])
+    }
+
+    pub fn with_regions(regions: impl IntoIterator<Item = Range<u64>>) -> Self {
+        let mut regions = regions.into_iter().collect::<Vec<_>>();
+        regions.sort_by_key(|r| r.start);
+        for pair in regions.windows(2) {
+            assert!(pair[0].end <= pair[1].start, "regions {pair:?} overlap");
+        }
+        assert!(
+            regions
+                .iter()
+                .all(|r| r.start < r.end && r.end <= ADDRESS_SPACE_BYTES),
+            "regions have to be non-empty and in the 32-bit address space"
+        );
+
         Self {
-            mem: vec![0; DRAM_CAPACITY_BYTES],
+            regions: Arc::new(regions),
+            blocks: Default::default(),
+            misaligned: Misaligned::default(),
+        }
+    }
+
+    pub fn with_misaligned(mut self, misaligned: Misaligned) -> Self {
+        self.misaligned = misaligned;
+        self
+    }
+
+    pub fn regions(&self) -> &[Range<u64>] {
+        &self.regions
+    }
+
+    pub fn misaligned(&self) -> Misaligned {
+        self.misaligned
+    }
+
+    // Whether every byte of the range is in a single region.
+    fn is_mapped(&self, range: &Range<u64>) -> bool {
+        range.start < range.end
+            && self
+                .regions
+                .iter()
+                .any(|r| r.start <= range.start && range.end <= r.end)
+    }
+
+    // A load or store of `size` bytes at `addr` can be made: it's mapped, and aligned unless
+    // misaligned accesses are handled in hardware.
+    pub fn check(&self, addr: Addr, size: u32) -> Result<(), MemFault> {
+        match self.misaligned {
+            Misaligned::Fault => self.check_aligned(addr, size),
+            Misaligned::Hardware => self.check_mapped(addr, size),
         }
     }
 
-    pub fn copy_from_slice(&mut self, data: &[u8], start_addr: Addr) {
-        let start = start_addr.0 as usize;
-        self.mem[start..start + data.len()].copy_from_slice(data);
+    // As atomics need, whatever the policy for other accesses.
+    pub fn check_aligned(&self, addr: Addr, size: u32) -> Result<(), MemFault> {
+        self.check_mapped(addr, size)?;
+        if addr.0.is_multiple_of(size) {
+            Ok(())
+        } else {
+            Err(MemFault::Misaligned(addr))
+        }
+    }
+
+    fn check_mapped(&self, addr: Addr, size: u32) -> Result<(), MemFault> {
+        let start = u64::from(addr.0);
+        if self.is_mapped(&(start..start + u64::from(size))) {
+            Ok(())
+        } else {
+            Err(MemFault::Unmapped(addr))
+        }
+    }
+
+    fn block(&self, index: u32) -> &Block {
+        self.blocks.get(&index).map_or(&ZERO_BLOCK, |block| block)
+    }
+
+    fn block_mut(&mut self, index: u32) -> &mut Block {
+        let block = Arc::make_mut(&mut self.blocks)
+            .entry(index)
+            .or_insert_with(|| Arc::new(ZERO_BLOCK));
+        Arc::make_mut(block)
+    }
+
+    // Only a misaligned access can go into a second block, and it's rare enough to go a byte
+    // at a time.
+    fn read_bytes<const N: usize>(&self, addr: Addr) -> Result<[u8; N], MemFault> {
+        self.check(addr, N as u32)?;
+
+        let offset = addr.0 as usize % BLOCK_BYTES;
+        if offset + N <= BLOCK_BYTES {
+            let block = self.block(addr.0 / BLOCK_BYTES as u32);
+            return Ok(block[offset..offset + N].try_into().unwrap());
+        }
+
+        let mut bytes = [0; N];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let addr = addr.0 + i as u32;
+            *byte = self.block(addr / BLOCK_BYTES as u32)[addr as usize % BLOCK_BYTES];
+        }
+        Ok(bytes)
     }
 
-    pub fn readb(&self, addr: Addr) -> u32 {
-        // println!("READb at {:?}", addr);
-        let sx = i8::from_le_bytes([self.mem[addr.0 as usize]]) as i32;
-        u32::from_le_bytes(sx.to_le_bytes())
+    fn write_bytes(&mut self, addr: Addr, bytes: &[u8]) -> Result<(), MemFault> {
+        self.check(addr, bytes.len() as u32)?;
+        self.copy_bytes(bytes, addr);
+        Ok(())
     }
 
-    pub fn readbu(&self, addr: Addr) -> u32 {
-        // println!("READbu at {:?}", addr);
-        self.mem[addr.0 as usize] as u32
+    fn copy_bytes(&mut self, bytes: &[u8], addr: Addr) {
+        let offset = addr.0 as usize % BLOCK_BYTES;
+        if offset + bytes.len() <= BLOCK_BYTES {
+            let block = self.block_mut(addr.0 / BLOCK_BYTES as u32);
+            block[offset..offset + bytes.len()].copy_from_slice(bytes);
+            return;
+        }
+
+        for (i, &byte) in bytes.iter().enumerate() {
+            let addr = addr.0 + i as u32;
+            self.block_mut(addr / BLOCK_BYTES as u32)[addr as usize % BLOCK_BYTES] = byte;
+        }
     }
 
-    pub fn readh(&self, addr: Addr) -> u32 {
-        // println!("READh at {:?}", addr);
-        let a = addr.0 as usize;
-        assert!(a % 2 == 0);
+    pub fn copy_from_slice(&mut self, data: &[u8], start_addr: Addr) -> Result<(), MemFault> {
+        let start = u64::from(start_addr.0);
+        if !self.is_mapped(&(start..start + data.len() as u64)) {
+            return Err(MemFault::Unmapped(start_addr));
+        }
 
-        let sx = i16::from_le_bytes([self.mem[a], self.mem[a + 1]]) as i32;
-        u32::from_le_bytes(sx.to_le_bytes())
+        self.copy_bytes(data, start_addr);
+        Ok(())
     }
 
-    pub fn readw(&self, addr: Addr) -> u32 {
-        // println!("READw at {:?}", addr);
-        let a = addr.0 as usize;
-        assert!(a % 4 == 0);
+    pub fn readb(&self, addr: Addr) -> Result<u32, MemFault> {
+        let sx = i8::from_le_bytes(self.read_bytes(addr)?) as i32;
+        Ok(u32::from_le_bytes(sx.to_le_bytes()))
+    }
 
-        u32::from_le_bytes([
-            self.mem[a],
-            self.mem[a + 1],
-            self.mem[a + 2],
-            self.mem[a + 3],
-        ])
+    pub fn readbu(&self, addr: Addr) -> Result<u32, MemFault> {
+        Ok(u8::from_le_bytes(self.read_bytes(addr)?) as u32)
     }
 
-    pub fn writeb(&mut self, addr: Addr, val: u32) {
-        // println!("WRITEb {} at {:?}", val, addr);
-        self.mem[addr.0 as usize] = val.to_le_bytes()[0];
+    pub fn readh(&self, addr: Addr) -> Result<u32, MemFault> {
+        let sx = i16::from_le_bytes(self.read_bytes(addr)?) as i32;
+        Ok(u32::from_le_bytes(sx.to_le_bytes()))
     }
 
-    pub fn writeh(&mut self, addr: Addr, val: u32) {
-        // println!("WRITEh {} at {:?}", val, addr);
-        let a = addr.0 as usize;
-        debug_assert!(a % 2 == 0);
+    pub fn readw(&self, addr: Addr) -> Result<u32, MemFault> {
+        Ok(u32::from_le_bytes(self.read_bytes(addr)?))
+    }
 
-        self.mem[a..a + 2].copy_from_slice(&val.to_le_bytes())
+    pub fn writeb(&mut self, addr: Addr, val: u32) -> Result<(), MemFault> {
+        self.write_bytes(addr, &val.to_le_bytes()[..1])
     }
 
-    pub fn writew(&mut self, addr: Addr, val: u32) {
-        // println!("WRITEw {} at {:?}", val, addr);
-        let a = addr.0 as usize;
-        debug_assert!(a % 4 == 0);
+    pub fn writeh(&mut self, addr: Addr, val: u32) -> Result<(), MemFault> {
+        self.write_bytes(addr, &val.to_le_bytes()[..2])
+    }
+
+    pub fn writew(&mut self, addr: Addr, val: u32) -> Result<(), MemFault> {
+        self.write_bytes(addr, &val.to_le_bytes())
+    }
+}
 
-        self.mem[a..a + 4].copy_from_slice(&val.to_le_bytes())
+// Blocks that were never written are equal to ones that were written with zeros.
+impl PartialEq for MainMemory {
+    fn eq(&self, other: &Self) -> bool {
+        let same_blocks = |a: &Self, b: &Self| {
+            a.blocks.iter().all(|(&index, block)| {
+                b.blocks
+                    .get(&index)
+                    .is_some_and(|other| Arc::ptr_eq(block, other))
+                    || **block == *b.block(index)
+            })
+        };
+        self.regions == other.regions && same_blocks(self, other) && same_blocks(other, self)
     }
 }
 
+impl Eq for MainMemory {}
+
 type CacheLevel<const BYTES: usize> = AssociativeCache<
     Addr,
     WithLruTimestamp<()>,
@@ -222,5 +859,153 @@ type CacheLevel<const BYTES: usize> = AssociativeCache<
     LruReplacement,
 >;
 type L1Cache = CacheLevel<L1_CAPACITY_BYTES>;
+type L1ICache = CacheLevel<L1I_CAPACITY_BYTES>;
 type L2Cache = CacheLevel<L2_CAPACITY_BYTES>;
 type L3Cache = CacheLevel<L3_CAPACITY_BYTES>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regions() {
+        let mut mem = MainMemory::with_regions([0xffff_f000..ADDRESS_SPACE_BYTES, 0..4096]);
+        mem.writew(Addr(0xffff_fffc), 7).unwrap();
+        assert_eq!(mem.readw(Addr(0xffff_fffc)), Ok(7));
+        assert_eq!(mem.readw(Addr(0x100)), Ok(0));
+
+        assert_eq!(mem.readw(Addr(4096)), Err(MemFault::Unmapped(Addr(4096))));
+        assert_eq!(
+            mem.writeb(Addr(0x8000), 1),
+            Err(MemFault::Unmapped(Addr(0x8000)))
+        );
+        assert_eq!(mem.readh(Addr(4095)), Err(MemFault::Unmapped(Addr(4095))));
+        assert_eq!(mem.readw(Addr(2)), Err(MemFault::Misaligned(Addr(2))));
+        assert_eq!(mem.blocks.len(), 1);
+    }
+
+    #[test]
+    fn test_clones_share_blocks() {
+        let mut a = MainMemory::new();
+        a.writew(Addr(64), 1).unwrap();
+        let mut b = a.clone();
+        assert!(Arc::ptr_eq(&a.blocks, &b.blocks));
+
+        b.writew(Addr(64), 2).unwrap();
+        assert_eq!(a.readw(Addr(64)), Ok(1));
+        assert_eq!(b.readw(Addr(64)), Ok(2));
+        assert_ne!(a, b);
+
+        // Writing zeros leaves memory equal to memory that was never written.
+        b.writew(Addr(64), 0).unwrap();
+        b.writew(Addr(0x10000), 0).unwrap();
+        assert_eq!(b, MainMemory::new());
+    }
+
+    fn with_two_mshrs() -> MemoryHierarchy {
+        let mshrs = MshrConfig {
+            l1: 2,
+            l2: 2,
+            l3: 2,
+        };
+        MemoryHierarchy::new(MainMemory::new(), mshrs)
+    }
+
+    #[test]
+    fn test_secondary_misses_merge() {
+        let mut mem = with_two_mshrs();
+        let mut stats = Stats::default();
+        let access = |mem: &mut MemoryHierarchy, stats: &mut Stats, seq, addr| {
+            mem.access_complete(Tag::new(seq, 0), Addr(addr), 4, stats);
+        };
+
+        access(&mut mem, &mut stats, 0, 0x1000);
+        access(&mut mem, &mut stats, 1, 0x1004);
+        access(&mut mem, &mut stats, 2, 0x2000);
+        assert_eq!(mem.mshrs[0].misses.len(), 2);
+
+        // A third line has to wait for one of the first two to arrive.
+        access(&mut mem, &mut stats, 3, 0x3000);
+        assert!(!mem.pending_fetches.iter().any(|p| p.addr == Addr(0x3000)));
+        for _ in 0..DRAM_LATENCY {
+            mem.tick(&mut stats);
+        }
+        access(&mut mem, &mut stats, 3, 0x3000);
+        assert!(mem.pending_fetches.iter().any(|p| p.addr == Addr(0x3000)));
+
+        assert_eq!(stats.mshr_stalls, 1);
+        assert_eq!(stats.l3_miss, 3);
+        assert_eq!(stats.mshr_occupancy[0].len(), 3);
+    }
+
+    #[test]
+    fn test_lines_wait_for_mshrs() {
+        let mut mem = with_two_mshrs();
+        let mut stats = Stats::default();
+
+        assert_eq!(mem.access_line(Addr(0x1000), &mut stats), DRAM_LATENCY);
+        assert_eq!(mem.access_line(Addr(0x2000), &mut stats), DRAM_LATENCY);
+        assert_eq!(mem.access_line(Addr(0x3000), &mut stats), 2 * DRAM_LATENCY);
+        assert_eq!(mem.access_line(Addr(0x4000), &mut stats), 2 * DRAM_LATENCY);
+        assert_eq!(mem.access_line(Addr(0x5000), &mut stats), 3 * DRAM_LATENCY);
+        assert_eq!(stats.mshr_stalls, 4 * DRAM_LATENCY);
+        assert!(mem.mshrs.iter().all(|m| m.misses.len() <= m.capacity));
+    }
+
+    #[test]
+    fn test_inst_misses_go_through_the_hierarchy() {
+        let mut mem = with_two_mshrs();
+        let mut stats = Stats::default();
+        let wait_for = |mem: &mut MemoryHierarchy, stats: &mut Stats, addr| {
+            let mut cycles = 0;
+            while !mem.inst_line_ready(Addr(addr), stats) {
+                mem.tick(stats);
+                cycles += 1;
+            }
+            cycles
+        };
+
+        // A cold line comes from DRAM, and is only counted as a miss once.
+        assert_eq!(wait_for(&mut mem, &mut stats, 0x100), DRAM_LATENCY);
+        assert_eq!((stats.l1i_misses, stats.l3_miss), (1, 1));
+        assert_eq!(wait_for(&mut mem, &mut stats, 0x104), 0);
+        assert_eq!(stats.l1i_hits, 1);
+
+        // Code doesn't share lines with data at the same address, or their coherence state.
+        mem.access_line(Addr(0x2000), &mut stats);
+        let state = mem.shared.state(Addr(0x2000));
+        assert_eq!(wait_for(&mut mem, &mut stats, 0x2000), DRAM_LATENCY);
+        assert_eq!(mem.shared.state(Addr(0x2000)), state);
+        assert_eq!(mem.line_source(Addr(0x2000)), Source::L1);
+        assert_eq!(stats.l3_miss, 3);
+
+        // It needs an MSHR like any other miss.
+        // Both of L1's MSHRs are waiting on data lines from DRAM.
+        mem.access_line(Addr(0x3000), &mut stats);
+        mem.access_line(Addr(0x4000), &mut stats);
+        assert!(!mem.inst_line_ready(Addr(0x5000), &mut stats));
+        assert_eq!(stats.l1i_misses, 2);
+        assert_eq!(stats.mshr_stalls, 1);
+    }
+
+    #[test]
+    fn test_split_access_with_one_mshr() {
+        let mshrs = MshrConfig {
+            l1: 1,
+            l2: 1,
+            l3: 1,
+        };
+        let main = MainMemory::new().with_misaligned(Misaligned::Hardware);
+        let mut mem = MemoryHierarchy::new(main, mshrs);
+        let mut stats = Stats::default();
+
+        // The second line waits for the first to arrive and free the MSHR.
+        mem.access_complete(Tag::new(0, 0), Addr(0x103e), 4, &mut stats);
+        assert_eq!(
+            mem.pending_fetches[0].end,
+            2 * DRAM_LATENCY + MISALIGNED_LATENCY
+        );
+        assert_eq!(stats.mshr_stalls, DRAM_LATENCY);
+        assert!(mem.levels().iter().all(|m| m.misses.len() == 1));
+    }
+}
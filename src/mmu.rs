@@ -0,0 +1,429 @@
+use hashbrown::HashMap;
+use std::{collections::VecDeque, fmt::Debug, ops::Range};
+
+use crate::{
+    cpu::Stats,
+    inst::{AbsPc, Csr, Inst},
+    mem::{MainMemory, MemFault, MemoryHierarchy},
+    util::Addr,
+    vector,
+};
+
+pub const PAGE_BYTES: u32 = 4096;
+const PTE_BYTES: u32 = 4;
+const VPN_BITS: u32 = 10;
+
+const SATP_SV32: u32 = 1 << 31;
+const SATP_PPN: u32 = (1 << 22) - 1;
+
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AccessType {
+    Fetch,
+    Load,
+    Store, // Atomics count as stores, even LR
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PageFault {
+    pub access: AccessType,
+    pub addr: Addr, // The virtual address that couldn't be translated, which goes in stval
+}
+
+impl PageFault {
+    // The exception code that goes in scause.
+    pub fn cause(self) -> u32 {
+        match self.access {
+            AccessType::Fetch => 12,
+            AccessType::Load => 13,
+            AccessType::Store => 15,
+        }
+    }
+}
+
+// The supervisor CSRs of one hardware thread. There are no privilege modes, so a trap only
+// records where it came from and jumps to stvec.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct SupervisorCsrs {
+    pub satp: u32,
+    pub stvec: u32,
+    pub sepc: u32,
+    pub scause: u32,
+    pub stval: u32,
+}
+
+impl SupervisorCsrs {
+    pub fn read(&self, csr: Csr) -> u32 {
+        match csr {
+            Csr::Satp => self.satp,
+            Csr::Stvec => self.stvec,
+            Csr::Sepc => self.sepc,
+            Csr::Scause => self.scause,
+            Csr::Stval => self.stval,
+        }
+    }
+
+    // Only direct mode is supported for stvec, and sepc is always 2-byte aligned.
+    pub fn write(&mut self, csr: Csr, val: u32) {
+        match csr {
+            Csr::Satp => self.satp = val,
+            Csr::Stvec => self.stvec = val & !3,
+            Csr::Sepc => self.sepc = val & !1,
+            Csr::Scause => self.scause = val,
+            Csr::Stval => self.stval = val,
+        }
+    }
+
+    pub fn is_translating(&self) -> bool {
+        self.satp & SATP_SV32 != 0
+    }
+
+    // Take a page fault on the instruction at `pc`, giving the handler to go to.
+    pub fn trap(&mut self, pc: AbsPc, fault: PageFault) -> AbsPc {
+        self.sepc = pc.0;
+        self.scause = fault.cause();
+        self.stval = fault.addr.0;
+        AbsPc(self.stvec)
+    }
+}
+
+// A leaf PTE, which is what the TLBs hold. Superpages are broken up into the 4 KiB page used.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Leaf {
+    page: u32, // Physical address of the page
+    pte: u32,
+}
+
+impl Leaf {
+    // The A and D bits are taken to be set already, and U isn't checked as everything runs in
+    // the one mode.
+    fn allows(self, access: AccessType) -> bool {
+        let bit = match access {
+            AccessType::Fetch => PTE_X,
+            AccessType::Load => PTE_R,
+            AccessType::Store => PTE_W,
+        };
+        self.pte & bit != 0
+    }
+}
+
+pub fn page_of(addr: Addr) -> u32 {
+    addr.0 & !(PAGE_BYTES - 1)
+}
+
+// Only 32 bits of physical address are modelled, so a PPN beyond them is as invalid as any other
+// address outside of memory.
+fn ppn_to_addr(ppn: u32) -> Option<u32> {
+    u32::try_from(u64::from(ppn) * u64::from(PAGE_BYTES)).ok()
+}
+
+// Walk the two levels of the page table for `addr`, giving the leaf if there is a valid one along
+// with the address of every PTE read on the way.
+fn walk(satp: u32, addr: Addr, mem: &MainMemory) -> (Option<Leaf>, Vec<Addr>) {
+    let vpn = [
+        (addr.0 >> 12) & ((1 << VPN_BITS) - 1),
+        addr.0 >> (12 + VPN_BITS),
+    ];
+    let mut ptes = Vec::new();
+    let mut table = ppn_to_addr(satp & SATP_PPN);
+
+    for level in (0..vpn.len()).rev() {
+        let Some(pte_addr) = table.and_then(|t| t.checked_add(vpn[level] * PTE_BYTES)) else {
+            break;
+        };
+        let Ok(pte) = mem.readw(Addr(pte_addr)) else {
+            break;
+        };
+        ptes.push(Addr(pte_addr));
+
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+            break;
+        }
+
+        let ppn = pte >> 10;
+        if pte & (PTE_R | PTE_X) == 0 {
+            table = ppn_to_addr(ppn);
+            continue;
+        }
+
+        // A superpage has to be aligned to its size.
+        let ppn = if level == 1 {
+            if ppn & ((1 << VPN_BITS) - 1) != 0 {
+                break;
+            }
+            ppn | vpn[0]
+        } else {
+            ppn
+        };
+        let leaf = ppn_to_addr(ppn).map(|page| Leaf { page, pte });
+        return (leaf, ptes);
+    }
+
+    (None, ptes)
+}
+
+// The physical page behind each virtual page an instruction touches. Addresses outside of them
+// are used as they are, which is how they're treated with translation off.
+#[derive(Debug, Clone, Default)]
+pub struct PageMap(HashMap<u32, u32>);
+
+impl PageMap {
+    pub fn apply(&self, addr: Addr) -> Addr {
+        match self.0.get(&page_of(addr)) {
+            Some(page) => Addr(page | (addr.0 & (PAGE_BYTES - 1))),
+            None => addr,
+        }
+    }
+
+    // Where an access of `size` bytes goes. A misaligned one can only be split across two pages
+    // that are next to each other physically, and faults otherwise.
+    pub fn apply_access(&self, addr: Addr, size: u32) -> Result<Addr, MemFault> {
+        let start = self.apply(addr);
+        let last = self.apply(Addr(addr.0.wrapping_add(size - 1)));
+        if last.0 == start.0.wrapping_add(size - 1) {
+            Ok(start)
+        } else {
+            Err(MemFault::Misaligned(start))
+        }
+    }
+
+    fn insert(&mut self, addr: Addr, leaf: Leaf) {
+        self.0.insert(page_of(addr), leaf.page);
+    }
+}
+
+// The virtual pages a memory instruction touches, in the order it touches them, and how.
+pub fn access_pages<D: Debug + Clone>(inst: &Inst<u32, D>) -> Vec<(Addr, AccessType)> {
+    let access =
+        if inst.is_load() || matches!(inst, Inst::VectorLoad(..) | Inst::VectorLoadStrided(..)) {
+            AccessType::Load
+        } else {
+            AccessType::Store
+        };
+
+    let bytes = if inst.is_vector() {
+        vector::element_ranges(inst)
+    } else {
+        vec![inst.access_range()]
+    };
+
+    let mut pages = Vec::new();
+    for range in bytes {
+        add_pages(
+            &mut pages,
+            range.start,
+            range.end.wrapping_sub(range.start),
+            access,
+        );
+    }
+    pages
+}
+
+// The pages an instruction is fetched from, of which there are two if it straddles a boundary.
+pub fn fetch_pages(pc: AbsPc, size: u32) -> Vec<(Addr, AccessType)> {
+    let mut pages = Vec::new();
+    add_pages(&mut pages, pc.0, size, AccessType::Fetch);
+    pages
+}
+
+// Each page is given by the first address used in it, which is what stval gets on a fault.
+fn add_pages(pages: &mut Vec<(Addr, AccessType)>, start: u32, len: u32, access: AccessType) {
+    let last = start.wrapping_add(len.max(1) - 1);
+    for addr in [Addr(start), Addr(page_of(Addr(last)))] {
+        if !pages.iter().any(|(a, _)| page_of(*a) == page_of(addr)) {
+            pages.push((addr, access));
+        }
+    }
+}
+
+// Translation as the emulator does it, walking the page table every time without a TLB.
+pub fn translate(
+    csrs: &SupervisorCsrs,
+    pages: &[(Addr, AccessType)],
+    mem: &MainMemory,
+) -> Result<PageMap, PageFault> {
+    let mut map = PageMap::default();
+    if !csrs.is_translating() {
+        return Ok(map);
+    }
+
+    for &(addr, access) in pages {
+        match walk(csrs.satp, addr, mem).0 {
+            Some(leaf) if leaf.allows(access) => map.insert(addr, leaf),
+            _ => return Err(PageFault { access, addr }),
+        }
+    }
+    Ok(map)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TlbConfig {
+    pub dtlb_entries: usize,
+    pub itlb_entries: usize,
+}
+
+impl Default for TlbConfig {
+    fn default() -> Self {
+        Self {
+            dtlb_entries: 32,
+            itlb_entries: 32,
+        }
+    }
+}
+
+// Fully associative with LRU replacement. Entries are tagged with their hardware thread, which
+// each have their own satp.
+#[derive(Debug, Clone)]
+struct Tlb {
+    capacity: usize,
+    entries: VecDeque<(usize, u32, Leaf)>, // Most recently used at the back
+}
+
+impl Tlb {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    fn lookup(&mut self, thread: usize, page: u32) -> Option<Leaf> {
+        let pos = self
+            .entries
+            .iter()
+            .position(|&(t, p, _)| t == thread && p == page)?;
+        let entry = self.entries.remove(pos).unwrap();
+        self.entries.push_back(entry);
+        Some(entry.2)
+    }
+
+    fn insert(&mut self, thread: usize, page: u32, leaf: Leaf) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((thread, page, leaf));
+    }
+
+    fn flush(&mut self, thread: usize) {
+        self.entries.retain(|&(t, _, _)| t != thread);
+    }
+}
+
+// The L1 TLBs of a core, with a hardware walker that reads the page table through the cache
+// hierarchy on a miss. Invalid PTEs aren't cached, so a page fault walks every time.
+#[derive(Debug, Clone)]
+pub struct Mmu {
+    dtlb: Tlb,
+    itlb: Tlb,
+}
+
+impl Mmu {
+    pub fn new(config: TlbConfig) -> Self {
+        Self {
+            dtlb: Tlb::new(config.dtlb_entries),
+            itlb: Tlb::new(config.itlb_entries),
+        }
+    }
+
+    // Gives the pages' translations along with the cycles spent walking for those that missed.
+    pub fn translate(
+        &mut self,
+        thread: usize,
+        csrs: &SupervisorCsrs,
+        pages: &[(Addr, AccessType)],
+        mem: &mut MemoryHierarchy,
+        stats: &mut Stats,
+    ) -> Result<(PageMap, u64), PageFault> {
+        let mut map = PageMap::default();
+        let mut latency = 0;
+        if !csrs.is_translating() {
+            return Ok((map, latency));
+        }
+
+        for &(addr, access) in pages {
+            let page = page_of(addr);
+            let (tlb, hits, misses) = match access {
+                AccessType::Fetch => (&mut self.itlb, &mut stats.itlb_hits, &mut stats.itlb_misses),
+                _ => (&mut self.dtlb, &mut stats.dtlb_hits, &mut stats.dtlb_misses),
+            };
+
+            let leaf = match tlb.lookup(thread, page) {
+                Some(leaf) => {
+                    *hits += 1;
+                    Some(leaf)
+                }
+                None => {
+                    *misses += 1;
+                    let (leaf, ptes) = walk(csrs.satp, addr, &mem.main);
+                    if let Some(leaf) = leaf {
+                        tlb.insert(thread, page, leaf);
+                    }
+
+                    // Each level depends on the one before, so they're read one after another.
+                    let walk_cycles = ptes
+                        .into_iter()
+                        .map(|pte| mem.access_line(pte.to_cache_line(), stats))
+                        .sum::<u64>();
+                    stats.page_walks += 1;
+                    stats.walk_cycles += walk_cycles;
+                    latency += walk_cycles;
+                    leaf
+                }
+            };
+
+            match leaf {
+                Some(leaf) if leaf.allows(access) => map.insert(addr, leaf),
+                _ => return Err(PageFault { access, addr }),
+            }
+        }
+
+        Ok((map, latency))
+    }
+
+    // For satp writes and sfence.vma.
+    pub fn flush(&mut self, thread: usize) {
+        self.dtlb.flush(thread);
+        self.itlb.flush(thread);
+    }
+}
+
+// Map every region of memory to itself with 4 KiB pages that allow everything, with the page
+// table at the end of the last region. Gives the satp to use it.
+pub fn identity_map(mem: &mut MainMemory) -> u32 {
+    let regions = mem.regions().to_vec();
+    let pages = |r: &Range<u64>| r.start / PAGE_BYTES as u64..r.end.div_ceil(PAGE_BYTES as u64);
+    let page_numbers = || regions.iter().flat_map(pages).map(|page| page as u32);
+
+    // One level-0 table for every 4 MiB that has something in it.
+    let mut superpages = page_numbers()
+        .map(|page| page >> VPN_BITS)
+        .collect::<Vec<_>>();
+    superpages.dedup();
+
+    let last = regions.last().expect("memory has no regions");
+    let top = (last.end / PAGE_BYTES as u64) as u32;
+    let root = top
+        .checked_sub(superpages.len() as u32 + 1)
+        .filter(|&root| u64::from(root) * PAGE_BYTES as u64 >= last.start)
+        .expect("no room for the page table in the last region");
+
+    let pte_addr = |table: u32, vpn: u32| Addr(table * PAGE_BYTES + vpn * PTE_BYTES);
+    for (i, &superpage) in superpages.iter().enumerate() {
+        let table = root + 1 + i as u32;
+        mem.writew(pte_addr(root, superpage), (table << 10) | PTE_V)
+            .expect("the page table is in mapped memory");
+    }
+    for page in page_numbers() {
+        let i = superpages.binary_search(&(page >> VPN_BITS)).unwrap() as u32;
+        let vpn = page & ((1 << VPN_BITS) - 1);
+        let pte = (page << 10) | PTE_X | PTE_W | PTE_R | PTE_V;
+        mem.writew(pte_addr(root + 1 + i, vpn), pte)
+            .expect("the page table is in mapped memory");
+    }
+
+    SATP_SV32 | root
+}
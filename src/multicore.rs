@@ -0,0 +1,89 @@
+use crate::{
+    cpu::{Cpu, CpuState, ExecResult, Stats},
+    mem::{MainMemory, MemFault, MshrConfig, SharedCache},
+    program::Program,
+    regs::RegSet,
+};
+
+// A CPU that can run alongside others, a cycle at a time.
+pub trait Core: Cpu {
+    fn step(&mut self) -> CpuState;
+
+    // Exchange the core's memory for the memory shared between cores. This is done around
+    // every step, so only one core holds it at a time. A core without caches ignores `shared`.
+    fn swap_shared(&mut self, main: &mut MainMemory, shared: &mut SharedCache);
+
+    fn into_result(self) -> ExecResult;
+}
+
+// Several cores, each with its own program, registers and private caches, sharing memory and
+// the L3. The cores are stepped in lockstep.
+pub struct MultiCore<C: Core> {
+    cores: Vec<C>,
+    main: MainMemory,
+    shared: SharedCache,
+}
+
+#[derive(Debug, Clone)]
+pub struct MultiCoreResult {
+    pub mem: MainMemory,
+    pub regs: Vec<RegSet>,             // Per core
+    pub stats: Vec<Stats>,             // Per core
+    pub faults: Vec<Option<MemFault>>, // Per core
+    pub cycles_taken: u64,
+}
+
+impl<C: Core> MultiCore<C> {
+    // Only the L3's MSHRs are taken from `mshrs`, as each core has its own L1 and L2.
+    pub fn new(workloads: Vec<(Program, RegSet)>, mem: MainMemory, mshrs: MshrConfig) -> Self {
+        let cores = workloads
+            .into_iter()
+            .map(|(prog, regs)| C::new(prog, regs, MainMemory::default()))
+            .collect::<Vec<_>>();
+
+        Self {
+            shared: SharedCache::new(cores.len(), mshrs.l3),
+            cores,
+            main: mem,
+        }
+    }
+
+    pub fn exec_all(mut self) -> MultiCoreResult {
+        let mut running = vec![true; self.cores.len()];
+        let mut cycles_taken = 0;
+
+        while running.iter().any(|&r| r) {
+            for (i, core) in self.cores.iter_mut().enumerate() {
+                if !running[i] {
+                    continue;
+                }
+
+                self.shared.core = i;
+                core.swap_shared(&mut self.main, &mut self.shared);
+                running[i] = core.step() == CpuState::Running;
+                core.swap_shared(&mut self.main, &mut self.shared);
+            }
+
+            cycles_taken += 1;
+        }
+
+        let mut regs = Vec::new();
+        let mut stats = Vec::new();
+        let mut faults = Vec::new();
+        for (core, &invalidations) in self.cores.into_iter().zip(&self.shared.invalidations) {
+            let mut res = core.into_result();
+            res.stats.invalidations = invalidations;
+            regs.push(res.regs);
+            stats.push(res.stats);
+            faults.push(res.fault);
+        }
+
+        MultiCoreResult {
+            mem: self.main,
+            regs,
+            stats,
+            faults,
+            cycles_taken,
+        }
+    }
+}
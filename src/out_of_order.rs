@@ -1,16 +1,27 @@
 use hashbrown::HashMap;
+use std::collections::VecDeque;
+use strum::IntoEnumIterator;
 
 use crate::{
-    branch::BranchPredictor,
-    cpu::{Cpu, ExecResult, Stats},
+    branch::{BranchPredictor, BranchPredictorConfig},
+    cpu::{BranchProfile, Cpu, CpuState, ExecResult, Stats},
     execution_unit::{EuType, ExecutionUnit},
-    inst::{AbsPc, ArchReg, ExecutedInst, Imm, Inst, RenamedInst, Tag, Tagged, INST_SIZE},
-    lsq::LoadStoreQueue,
-    mem::{MainMemory, MemoryHierarchy},
+    fusion::{self, FusionConfig},
+    inst::{
+        AbsPc, ArchReg, BothReg, Csr, ExecutedInst, FpCsr, Inst, RenamedInst, RoundingMode, Tag,
+        Tagged, VectorType, MAX_THREADS,
+    },
+    lsq::{LoadStoreQueue, MemDependence},
+    mem::{MainMemory, MemFault, MemoryHierarchy, MshrConfig, SharedCache, L1_LATENCY},
+    mmu::{self, Mmu, PageFault, SupervisorCsrs, TlbConfig},
+    multicore::Core,
     program::Program,
-    regs::{RegFile, RegSet},
-    reservation_station::ReservationStation,
+    regs::{Elimination, RegFile, RegSet},
+    reservation_station::{IssuePolicy, LoadWakeups, ReservationStation, SchedulerTopology},
     rob::ReorderBuffer,
+    util::Addr,
+    value_predict::{ValuePredictor, ValuePredictorKind},
+    vector::{self, VectorConfig, VectorRegs},
 };
 
 mod stages {
@@ -20,7 +31,7 @@ mod stages {
         use super::*;
 
         #[derive(Debug, Clone)]
-        pub struct FetchDecode {
+        pub struct Predict {
             pub inst: Tagged<Inst>,
             pub next_pc: Option<AbsPc>, // None if we should stall
         }
@@ -41,28 +52,32 @@ mod stages {
     pub mod wide {
         use super::*;
 
+        // A run of sequential instructions from the prediction stage, ending at a predicted-taken
+        // branch, a cache line boundary, after fetch_bytes or after PIPE_WIDTH instructions.
         #[derive(Debug, Clone, Default)]
-        pub struct FetchDecode {
+        pub struct FetchBlock {
             pub insts: Vec<Tagged<Inst>>,
-            pub next_pcs: Vec<AbsPc>,
-            pub stalled: bool,
+            pub start: AbsPc,
+            pub end: AbsPc,
         }
 
         #[derive(Debug, Clone, Default)]
         pub struct Rename {
+            #[allow(unused)]
             pub insts: Vec<RenamedInst>,
-            pub next_fetch_decode: Option<FetchDecode>,
         }
 
+        // Redirects are for a single thread, given alongside where it should fetch from next.
         #[derive(Debug, Clone, Default)]
         pub struct Issue {
-            pub next_fetch: Option<AbsPc>,
+            pub redirect: Option<(usize, AbsPc)>,
         }
 
         #[derive(Debug, Clone, Default)]
         pub struct Writeback {
+            #[allow(unused)]
             pub insts: Vec<Tagged<ExecutedInst>>,
-            pub next_fetch: Option<AbsPc>,
+            pub redirects: Vec<(usize, AbsPc)>, // At most one per thread
         }
     }
 
@@ -75,146 +90,336 @@ mod stages {
 #[derive(Debug, Clone, Default)]
 #[allow(unused)]
 pub struct Pipeline {
-    fetch_decode: stages::wide::FetchDecode,
     rename: stages::wide::Rename,
     writeback: stages::wide::Writeback,
     commit: stages::Commit,
 }
 
+// The branch prediction stage runs ahead of fetch, queueing fetch blocks in the fetch target
+// queue. Fetched blocks then spend `fetch_to_rename` cycles in flight before they can be renamed,
+// so everything queued up here is lost on a redirect. Each hardware thread has its own.
+#[derive(Debug, Clone, Default)]
+struct FrontEnd {
+    predict_pc: Option<AbsPc>, // None past a halt, a fault or a serialising instruction too
+    unresolved_target: bool,   // Waiting for an unpredicted target rather than any of those
+    ftq: VecDeque<stages::wide::FetchBlock>,
+    in_flight: VecDeque<(u64, Vec<Tagged<Inst>>)>, // With the cycle they reach rename
+    halted: bool,                                  // Its halt has committed
+    walk_until: u64,                               // Waiting on an ITLB miss
+    // An instruction that couldn't be fetched, which traps once everything before it is done.
+    fetch_fault: Option<(AbsPc, Tag, PageFault)>,
+}
+
+// Which hardware thread gets to predict and fetch each cycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FetchPolicy {
+    #[default]
+    RoundRobin, // Take turns, skipping threads that can't fetch.
+    ICount, // The thread with the fewest instructions waiting to issue (Tullsen et al.).
+}
+
+#[derive(Debug, Clone)]
+pub struct OutOfOrderConfig {
+    pub branch_predictor: BranchPredictorConfig,
+    pub fusion: FusionConfig,
+    pub move_elimination: bool,
+    pub ftq_entries: usize,
+    pub fetch_bytes: u32, // Per fetch block, which also stops at a cache line boundary
+    pub fetch_to_rename: u64,
+    // Each unit's class, whether it's pipelined, and the cycles between starting two instructions
+    // when it is. There has to be at least one unit of every class.
+    pub execution_units: Vec<(EuType, bool, u64)>,
+    pub scheduler: SchedulerTopology,
+    pub issue_policy: IssuePolicy,
+    pub wakeup_latency: u64, // Cycles from an operand being written to its consumer issuing
+    pub speculative_load_wakeup: bool,
+    pub mem_dependence: MemDependence,
+    pub value_predictor: Option<ValuePredictorKind>,
+    pub fetch_policy: FetchPolicy,
+    pub vector: VectorConfig,
+    pub tlb: TlbConfig,
+    pub mshrs: MshrConfig,
+}
+
+impl Default for OutOfOrderConfig {
+    fn default() -> Self {
+        Self {
+            branch_predictor: BranchPredictorConfig::default(),
+            fusion: FusionConfig::default(),
+            move_elimination: true,
+            ftq_entries: 8,
+            fetch_bytes: 32,
+            fetch_to_rename: 3,
+            execution_units: vec![
+                (EuType::Branch, true, 1),
+                (EuType::LoadStore, true, 1),
+                (EuType::Alu, true, 1),
+                (EuType::Alu, true, 1),
+                (EuType::Mul, true, 1),
+                (EuType::Div, false, 1),
+                (EuType::Special, true, 1),
+                (EuType::Fpu, true, 1),
+                (EuType::VectorLoadStore, false, 1),
+                (EuType::Vector, false, 1),
+                (EuType::Vector, false, 1),
+            ],
+            scheduler: SchedulerTopology::default(),
+            issue_policy: IssuePolicy::OldestFirst,
+            wakeup_latency: 0,
+            speculative_load_wakeup: false,
+            mem_dependence: MemDependence::default(),
+            value_predictor: None,
+            fetch_policy: FetchPolicy::default(),
+            vector: VectorConfig::default(),
+            tlb: TlbConfig::default(),
+            mshrs: MshrConfig::default(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct OutOfOrder {
     mem: MemoryHierarchy,
-    prog: Program,
+    progs: Vec<Program>, // One per hardware thread
     execution_units: Vec<ExecutionUnit>,
     pc_map: HashMap<Tag, AbsPc>,
-    reservation_station: ReservationStation,
+    reservation_stations: Vec<ReservationStation>,
+    load_wakeups: LoadWakeups,
+    value_predictor: Option<ValuePredictor>,
+    value_predictions: HashMap<Tag, (AbsPc, Option<u32>)>, // Every load looked up, by tag
     lsq: LoadStoreQueue,
     rob: ReorderBuffer,
-    branch_predictor: BranchPredictor,
+    branch_predictors: Vec<BranchPredictor>,
     reg_file: RegFile,
+    // Branches resolved at writeback, which only count towards the profile once they commit.
+    resolved_branches: HashMap<Tag, (AbsPc, BranchProfile)>,
+    // Per thread, a mispredicted branch and the cycle it redirected fetch, until the correct path
+    // reaches rename.
+    refills: Vec<Option<(Tag, AbsPc, u64)>>,
+    front_ends: Vec<FrontEnd>,
+    fcsr: Vec<u32>,         // Per thread, only accessed once the thread has drained
+    vtype: Vec<VectorType>, // Per thread, set like fcsr
+    vector_regs: VectorRegs,
+    csrs: Vec<SupervisorCsrs>, // Per thread, written like fcsr
+    fault: Option<MemFault>,
+    mmu: Mmu,
+    pipe: Pipeline,
+    config: OutOfOrderConfig,
     stats: Stats,
 }
 
 const PIPE_WIDTH: u64 = 4;
-const MACRO_OP_FUSE: bool = true;
+
+// When a load's value is written back if it hits in the L1, counted from the cycle it issues.
+const LOAD_HIT_WAKEUP: u64 = L1_LATENCY + 1;
 
 impl Cpu for OutOfOrder {
     fn new(prog: Program, regs: RegSet, mem: MainMemory) -> Self {
+        Self::with_config(prog, regs, mem, OutOfOrderConfig::default())
+    }
+
+    fn exec_all(mut self) -> ExecResult {
+        while self.step() == CpuState::Running {}
+        self.into_result()
+    }
+}
+
+impl Core for OutOfOrder {
+    fn step(&mut self) -> CpuState {
+        OutOfOrder::step(self)
+    }
+
+    fn swap_shared(&mut self, main: &mut MainMemory, shared: &mut SharedCache) {
+        std::mem::swap(&mut self.mem.main, main);
+        std::mem::swap(&mut self.mem.shared, shared);
+    }
+
+    fn into_result(self) -> ExecResult {
+        OutOfOrder::into_result(self)
+    }
+}
+
+impl OutOfOrder {
+    pub fn with_config(
+        prog: Program,
+        regs: RegSet,
+        mem: MainMemory,
+        config: OutOfOrderConfig,
+    ) -> Self {
+        Self::with_threads(vec![(prog, regs)], mem, config)
+    }
+
+    // Simultaneous multithreading: each thread runs its own program from its own registers, but
+    // they share memory along with everything past rename.
+    pub fn with_threads(
+        threads: Vec<(Program, RegSet)>,
+        mem: MainMemory,
+        config: OutOfOrderConfig,
+    ) -> Self {
+        assert!(
+            (1..=MAX_THREADS).contains(&threads.len()),
+            "between 1 and {MAX_THREADS} threads are supported"
+        );
+
+        let num_threads = threads.len();
+        let (progs, regs): (Vec<_>, Vec<_>) = threads.into_iter().unzip();
+
+        for eu_type in EuType::iter() {
+            assert!(
+                config.execution_units.iter().any(|&(t, _, _)| t == eu_type),
+                "no {eu_type:?} execution unit"
+            );
+        }
+        let execution_units = config
+            .execution_units
+            .iter()
+            .map(|&(eu_type, pipelined, initiation_interval)| {
+                ExecutionUnit::with_timing(eu_type, pipelined, initiation_interval)
+            })
+            .collect::<Vec<_>>();
+
+        // The FP and vector registers are renamed from the same file, so it is grown to keep as
+        // many free for renaming as there would be for the integer registers alone.
+        let prf_capacity = 200 + 64 * num_threads;
+
         Self {
-            mem: MemoryHierarchy::new(mem),
-            prog,
-            execution_units: vec![
-                ExecutionUnit::new(EuType::Branch),
-                ExecutionUnit::new(EuType::LoadStore),
-                ExecutionUnit::new(EuType::Alu),
-                ExecutionUnit::new(EuType::Alu),
-                ExecutionUnit::new(EuType::Special),
-            ],
+            mem: MemoryHierarchy::new(mem, config.mshrs),
+            progs,
+            reservation_stations: config.scheduler.build(&execution_units),
+            execution_units,
+            load_wakeups: LoadWakeups::new(),
+            value_predictor: config.value_predictor.map(ValuePredictor::new),
+            value_predictions: HashMap::new(),
             rob: ReorderBuffer::new(250),
-            lsq: LoadStoreQueue::new(70, 70),
+            lsq: LoadStoreQueue::new(70, 70, config.mem_dependence),
             pc_map: HashMap::new(),
-            reservation_station: ReservationStation::new(100),
-            reg_file: RegFile::new(regs, 200),
-            branch_predictor: BranchPredictor::new(),
-            stats: Stats::default(),
+            reg_file: RegFile::new(regs, prf_capacity, config.move_elimination),
+            branch_predictors: vec![BranchPredictor::new(config.branch_predictor); num_threads],
+            resolved_branches: HashMap::new(),
+            refills: vec![None; num_threads],
+            front_ends: vec![
+                FrontEnd {
+                    predict_pc: Some(AbsPc(0)),
+                    ..Default::default()
+                };
+                num_threads
+            ],
+            fcsr: vec![0; num_threads],
+            vtype: vec![VectorType::default(); num_threads],
+            vector_regs: VectorRegs::new(config.vector.vlen),
+            csrs: vec![SupervisorCsrs::default(); num_threads],
+            fault: None,
+            mmu: Mmu::new(config.tlb),
+            pipe: Pipeline::default(),
+            config,
+            stats: Stats {
+                thread_insts_retired: if num_threads > 1 {
+                    vec![0; num_threads]
+                } else {
+                    Vec::new()
+                },
+                ..Default::default()
+            },
         }
     }
 
-    fn exec_all(mut self) -> ExecResult {
-        let mut pipe = Pipeline::default();
-        pipe.fetch_decode.next_pcs.push(AbsPc(0));
+    // Start every thread with translation on, as with `mmu::identity_map`.
+    pub fn with_satp(mut self, satp: u32) -> Self {
+        for csrs in &mut self.csrs {
+            csrs.satp = satp;
+        }
+        self
+    }
 
-        loop {
-            self.mem.tick();
+    // Run a single cycle.
+    pub fn step(&mut self) -> CpuState {
+        let pipe = std::mem::take(&mut self.pipe);
 
-            let commit = self.stage_commit(&pipe);
-            let writeback = self.stage_writeback(&pipe);
+        self.mem.tick(&mut self.stats);
 
-            if commit.should_halt {
-                // assert!(self.reg_file.is_prrt_empty());
+        let commit = self.stage_commit(&pipe);
+        let writeback = self.stage_writeback(&pipe);
 
-                return ExecResult {
-                    regs: self.reg_file.get_reg_set(),
-                    mem: self.mem.main,
-                    stats: self.stats.calculate_util(&self.execution_units),
-                };
-            }
+        if commit.should_halt {
+            // assert!(self.reg_file.is_prrt_empty());
+            return CpuState::Stopped;
+        }
 
-            if let Some(next_pc) = writeback.next_fetch {
-                // let issue = self.stage_issue(&pipe);
-                // self.stage_execute(&pipe);
+        if !writeback.redirects.is_empty() {
+            // let issue = self.stage_issue(&pipe);
+            // self.stage_execute(&pipe);
 
-                pipe = Pipeline {
-                    fetch_decode: stages::wide::FetchDecode {
-                        insts: Vec::new(),
-                        next_pcs: vec![next_pc],
-                        stalled: false,
-                    },
+            for &(thread, next_pc) in &writeback.redirects {
+                self.redirect(thread, next_pc);
+            }
+            self.pipe = Pipeline {
+                rename: stages::wide::Rename::default(),
+                writeback,
+                commit,
+            };
+        } else {
+            let issue = self.stage_issue(&pipe);
+
+            if let Some((thread, next_pc)) = issue.redirect {
+                // println!("JUMPING TO {:?}", next_pc);
+                self.redirect(thread, next_pc);
+                self.pipe = Pipeline {
                     rename: stages::wide::Rename::default(),
                     writeback,
                     commit,
                 };
             } else {
-                let issue = self.stage_issue(&pipe);
-
-                if let Some(next_pc) = issue.next_fetch {
-                    // println!("JUMPING TO {:?}", next_pc);
-                    pipe = Pipeline {
-                        fetch_decode: stages::wide::FetchDecode {
-                            insts: Vec::new(),
-                            next_pcs: vec![next_pc],
-                            stalled: false,
-                        },
-                        rename: stages::wide::Rename::default(),
-                        writeback,
-                        commit,
-                    };
-                } else {
-                    self.stage_execute(&pipe);
-                    let rename = self.stage_rename(&pipe);
-
-                    if let Some(nxt) = &rename.next_fetch_decode {
-                        pipe = Pipeline {
-                            fetch_decode: nxt.clone(),
-                            rename,
-                            writeback,
-                            commit,
-                        };
-                    } else {
-                        let fetch_decode = self.stage_fetch_decode(&pipe);
+                self.stage_execute(&pipe);
 
-                        pipe = Pipeline {
-                            fetch_decode,
-                            rename,
-                            writeback,
-                            commit,
-                        };
-                    }
-                }
+                // Back to front, so each stage sees what the one before it did last cycle.
+                let rename = self.stage_rename(&pipe);
+                self.stage_fetch_decode(&pipe);
+                self.stage_predict(&pipe);
+
+                self.pipe = Pipeline {
+                    rename,
+                    writeback,
+                    commit,
+                };
             }
+        }
 
-            self.stats.cycles_taken += 1;
+        self.stats.cycles_taken += 1;
 
-            #[cfg(debug_assertions)]
-            if std::env::var("SINGLE_STEP").is_ok() {
-                self.dump(&pipe);
-                // std::io::stdin().read_line(&mut String::new()).unwrap();
-            }
+        #[cfg(debug_assertions)]
+        if std::env::var("SINGLE_STEP").is_ok() {
+            self.dump(&self.pipe);
+            // std::io::stdin().read_line(&mut String::new()).unwrap();
+        }
 
-            debug_assert!(
-                self.stats.cycles_taken < 1_000_000,
-                "infinite loop detected"
-            );
+        debug_assert!(
+            self.stats.cycles_taken < 1_000_000,
+            "infinite loop detected"
+        );
+
+        CpuState::Running
+    }
+
+    pub fn into_result(self) -> ExecResult {
+        ExecResult {
+            regs: self.reg_file.get_reg_set(0),
+            thread_regs: (0..self.progs.len())
+                .map(|thread| self.reg_file.get_reg_set(thread))
+                .collect(),
+            mem: self.mem.main,
+            stats: self
+                .stats
+                .calculate_util(&self.execution_units)
+                .collect_rs_stalls(&self.reservation_stations),
+            fault: self.fault,
         }
     }
-}
 
-impl OutOfOrder {
     #[allow(dead_code, unused)]
     fn dump(&self, pipe: &Pipeline) {
         // dbg!(&self.lsq);
         // dbg!(&self.reg_file);
-        // dbg!(&self.reservation_station);
+        // dbg!(&self.reservation_stations);
         // dbg!(&self.rob);
         dbg!(&self.execution_units);
         // dbg!(pipe);
@@ -223,7 +428,11 @@ impl OutOfOrder {
         for Tagged {
             inst: next_inst,
             tag,
-        } in pipe.fetch_decode.insts.iter()
+        } in self
+            .front_ends
+            .iter()
+            .flat_map(|fe| &fe.in_flight)
+            .flat_map(|(_, insts)| insts)
         {
             println!("{:?} @ {:?}", next_inst, tag);
             use std::io::Write;
@@ -231,221 +440,432 @@ impl OutOfOrder {
         }
     }
 
-    fn fetch_decode_one(&mut self, pc: AbsPc, tag: Tag) -> stages::narrow::FetchDecode {
-        let inst = self.prog.fetch(pc).cloned().unwrap_or(Inst::Halt);
-        let next_inst = self
-            .prog
-            .fetch(pc + INST_SIZE)
-            .cloned()
-            .unwrap_or(Inst::Halt);
+    // Decode ahead of fetch, only so that the predictor knows which instructions are branches.
+    // Gives the (possibly fused) instruction and the bytes it takes up.
+    fn decode(&self, thread: usize, pc: AbsPc) -> (Inst, u32) {
+        let prog = &self.progs[thread];
+        let size = prog.inst_size(pc);
+        let inst = prog.fetch(pc).cloned().unwrap_or(Inst::Halt);
+        let next_inst = prog.fetch(pc + size).cloned().unwrap_or(Inst::Halt);
+
+        // A pair isn't fused across a page boundary, so a fetch fault is always on the first
+        // instruction of whatever faults.
+        let next_size = prog.inst_size(pc + size);
+        let same_page =
+            mmu::page_of(Addr(pc.0)) == mmu::page_of(Addr((pc + size + next_size - 1).0));
+        match fusion::fuse(self.config.fusion, &inst, &next_inst) {
+            Some((_, fused)) if same_page => (fused, size + next_size),
+            _ => (inst, size),
+        }
+    }
 
-        let fused = if MACRO_OP_FUSE {
-            #[allow(unused)]
-            match (&inst, &next_inst) {
-                (Inst::Add(rd1, rs1, rs2), Inst::LoadByte(rd2, mem_ref))
-                    if rd1 == rd2 && *rd2 == mem_ref.base =>
-                {
-                    todo!()
-                    // Some(Inst::IndexedLoadByte(*rd2, *rs1, *rs2, mem_ref.offset))
-                }
-                (Inst::Add(rd1, rs1, rs2), Inst::LoadByteU(rd2, mem_ref))
-                    if rd1 == rd2 && *rd2 == mem_ref.base =>
-                {
-                    Some(Inst::IndexedLoadByteU(*rd2, *rs1, *rs2, mem_ref.offset))
-                }
-                (Inst::Add(rd1, rs1, rs2), Inst::LoadHalfWord(rd2, mem_ref))
-                    if rd1 == rd2 && *rd2 == mem_ref.base =>
-                {
-                    todo!()
-                    // Some(Inst::IndexedLoadHalfWord(*rd2, *rs1, *rs2, mem_ref.offset))
-                }
-                // (Inst::Add(rd1, rs1, rs2), Inst::LoadHalfWordU(rd2, mem_ref))
-                //     if rd1 == rd2 && *rd2 == mem_ref.base =>
-                // {
-                //     todo!()
-                //     // Some(Inst::IndexedLoadHalfWordU(*rd2, *rs1, *rs2, mem_ref.offset))
-                // }
-                (Inst::Add(rd1, rs1, rs2), Inst::LoadWord(rd2, mem_ref))
-                    if rd1 == rd2 && *rd2 == mem_ref.base =>
-                {
-                    todo!()
-                    // Some(Inst::IndexedLoadWord(*rd2, *rs1, *rs2, mem_ref.offset))
-                }
-                (Inst::ShiftLeftLogicalImm(rd1, rs1, imm), Inst::Add(rd2, rs2, rs3))
-                    if rd1 == rd2 && rd2 == rs2 =>
-                {
-                    Some(Inst::EffectiveAddress(*rd2, *rs1, *rs3, *imm))
-                }
-                (Inst::LoadUpperImm(rd1, imm1), Inst::AddImm(rd2, rs1, imm2))
-                    if rd1 == rd2 && rd2 == rs1 =>
-                {
-                    let x = (imm1.0 << 12).wrapping_add(imm2.0);
-                    Some(Inst::LoadFullImm(*rd2, Imm(x)))
+    fn predict_one(
+        &mut self,
+        pc: AbsPc,
+        tag: Tag,
+        inst: Inst,
+        size: u32,
+    ) -> stages::narrow::Predict {
+        let prog = &self.progs[tag.thread()];
+        let branch_predictor = &mut self.branch_predictors[tag.thread()];
+
+        // Branch prediction
+        let next_pc = match &inst {
+            Inst::BranchIfEqual(_, _, tgt)
+            | Inst::BranchIfNotEqual(_, _, tgt)
+            | Inst::BranchIfLess(_, _, tgt)
+            | Inst::BranchIfLessU(_, _, tgt)
+            | Inst::BranchIfGreaterEqualU(_, _, tgt)
+            | Inst::BranchIfGreaterEqual(_, _, tgt)
+            | Inst::CompareAndBranch(_, _, _, tgt, _)
+            | Inst::CompareImmAndBranch(_, _, _, tgt, _) => {
+                // A fused branch is predicted at its own PC, the second of the pair.
+                let inst_pc = if inst.is_fused() {
+                    pc + prog.inst_size(pc)
+                } else {
+                    pc
+                };
+                let taken_pc = *tgt;
+                let not_taken_pc = pc + size;
+                let prediction = branch_predictor.predict_direct(inst_pc, taken_pc);
+
+                self.reg_file.begin_predict_direct(
+                    tag,
+                    prediction,
+                    inst_pc,
+                    taken_pc,
+                    not_taken_pc,
+                    branch_predictor.branch_checkpoint(prediction.history),
+                );
+                // println!("begin predict {:?} at {:?} ({})", inst, self.stats.insts_retired, predict_taken);
+
+                if prediction.taken {
+                    Some(taken_pc)
+                } else {
+                    Some(not_taken_pc)
                 }
-                _ => None,
             }
-        } else {
-            None
-        };
+            Inst::JumpAndLink(_, tgt) => {
+                // println!("jal {:?} at {:?}", inst, self.stats.insts_retired);
+                let _ = branch_predictor.predict_indirect(&inst, pc, size); // Update RAS and history
 
-        if let Some(fused) = fused {
-            if fused.is_load() {
                 self.pc_map.insert(tag, pc);
+                Some(*tgt)
             }
-
-            stages::narrow::FetchDecode {
-                inst: Tagged { inst: fused, tag },
-                next_pc: Some(pc + 2 * INST_SIZE),
+            Inst::JumpAndLinkRegister(_, _, _) => {
+                // println!("begin predict indirect {:?} at {:?}", inst, self.stats.insts_retired);
+                let prediction = branch_predictor.predict_indirect(&inst, pc, size);
+                self.reg_file.begin_predict_indirect(
+                    tag,
+                    prediction,
+                    pc,
+                    branch_predictor.branch_checkpoint(prediction.history),
+                );
+                prediction.target
             }
-        } else {
-            // Branch prediction
-            let next_pc = match &inst {
-                Inst::BranchIfEqual(_, _, tgt)
-                | Inst::BranchIfNotEqual(_, _, tgt)
-                | Inst::BranchIfLess(_, _, tgt)
-                | Inst::BranchIfLessU(_, _, tgt)
-                | Inst::BranchIfGreaterEqualU(_, _, tgt)
-                | Inst::BranchIfGreaterEqual(_, _, tgt) => {
-                    let taken_pc = *tgt;
-                    let not_taken_pc = pc + INST_SIZE;
-                    let predict_taken = self.branch_predictor.predict_direct(pc, taken_pc);
+            // Nothing past these is fetched until they have renamed, which is where the thread
+            // is pointed at what comes next.
+            x if x.is_csr_serialising() => {
+                self.pc_map.insert(tag, pc);
+                None
+            }
+            _ => {
+                debug_assert!(!inst.is_branch());
 
+                // Any access can fault, in which case it rolls back like a mis-speculated load.
+                if inst.accesses_memory() {
                     self.reg_file
-                        .begin_predict_direct(tag, predict_taken, taken_pc, not_taken_pc);
-                    // println!("begin predict {:?} at {:?} ({})", inst, self.stats.insts_retired, predict_taken);
-
-                    if predict_taken {
-                        Some(taken_pc)
-                    } else {
-                        Some(not_taken_pc)
-                    }
+                        .begin_predict_mem(tag, pc, branch_predictor.checkpoint());
                 }
-                Inst::JumpAndLink(_, tgt) => {
-                    // println!("jal {:?} at {:?}", inst, self.stats.insts_retired);
-                    let _ = self.branch_predictor.predict_indirect(&inst, pc); // Update RAS
-
+                if inst.is_load() || inst.is_store() || inst.is_atomic() || inst.is_vector_store() {
                     self.pc_map.insert(tag, pc);
-                    Some(*tgt)
                 }
-                Inst::JumpAndLinkRegister(_, _, _) => {
-                    // println!("begin predict indirect {:?} at {:?}", inst, self.stats.insts_retired);
-                    let predicted_addr = self.branch_predictor.predict_indirect(&inst, pc);
-                    self.reg_file
-                        .begin_predict_indirect(tag, predicted_addr, pc);
-                    predicted_addr
-                }
-                _ => {
-                    debug_assert!(!inst.is_branch());
 
-                    if inst.is_load() {
-                        self.pc_map.insert(tag, pc);
-                        self.reg_file.begin_predict_mem(tag, pc);
-                    }
+                Some(pc + size)
+            }
+        };
 
-                    Some(pc + INST_SIZE)
-                }
-            };
+        stages::narrow::Predict {
+            inst: Tagged { inst, tag },
+            next_pc,
+        }
+    }
 
-            stages::narrow::FetchDecode {
-                inst: Tagged { inst, tag },
-                next_pc,
-            }
+    // Instructions a thread has in the front end and waiting to issue, for ICOUNT.
+    fn icount(&self, thread: usize) -> usize {
+        let front_end = &self.front_ends[thread];
+        let queued = front_end.ftq.iter().map(|block| block.insts.len());
+        let in_flight = front_end.in_flight.iter().map(|(_, insts)| insts.len());
+        let waiting = self
+            .reservation_stations
+            .iter()
+            .map(|rs| rs.thread_occupancy(thread));
+
+        queued.chain(in_flight).chain(waiting).sum()
+    }
+
+    // Choose a thread to use the front end this cycle, out of those that are able to.
+    fn pick_thread(&self, able: impl Fn(&FrontEnd) -> bool) -> Option<usize> {
+        let n = self.front_ends.len();
+        let mut candidates = (0..n)
+            .map(|i| (self.stats.cycles_taken as usize + i) % n)
+            .filter(|&t| !self.front_ends[t].halted && able(&self.front_ends[t]));
+
+        match self.config.fetch_policy {
+            FetchPolicy::RoundRobin => candidates.next(),
+            FetchPolicy::ICount => candidates.min_by_key(|&t| self.icount(t)),
         }
     }
 
-    fn stage_fetch_decode(&mut self, pipe: &Pipeline) -> stages::wide::FetchDecode {
-        let next_pc = match pipe.fetch_decode.next_pcs.last() {
-            Some(next_pc) => *next_pc,
-            None => {
-                // Stalled.
-                return stages::wide::FetchDecode {
-                    insts: Vec::new(),
-                    next_pcs: Vec::new(),
-                    stalled: true,
-                };
+    fn stage_predict(&mut self, _pipe: &Pipeline) {
+        let ftq_entries = self.config.ftq_entries;
+        let Some(thread) =
+            self.pick_thread(|fe| fe.predict_pc.is_some() && fe.ftq.len() < ftq_entries)
+        else {
+            // Only a stall if fetch has nothing else to do meanwhile.
+            let front_ends = &self.front_ends;
+            if front_ends.iter().all(|fe| fe.ftq.is_empty())
+                && front_ends.iter().any(|fe| fe.unresolved_target)
+            {
+                self.stats.fetch_stalls += 1;
             }
+            return;
         };
 
-        let mut insts = Vec::new();
-        let mut next_pcs = vec![next_pc];
-        let mut stalled = false;
+        let mut pc = self.front_ends[thread].predict_pc.unwrap();
+
+        let mut block = stages::wide::FetchBlock {
+            insts: Vec::new(),
+            start: pc,
+            end: pc,
+        };
+        let line = Addr(pc.0).to_cache_line();
 
         for i in 0..PIPE_WIDTH {
-            if self.rob.last_is_halt() {
+            // The first instruction always fits, however far it sticks out.
+            let (inst, size) = self.decode(thread, pc);
+            if !block.insts.is_empty() && (pc + size).0 - block.start.0 > self.config.fetch_bytes {
                 break;
             }
 
-            let tag = Tag::from(PIPE_WIDTH * self.stats.cycles_taken + i);
+            // Counted from one, so that every tag has one before it to roll back to.
+            let tag = Tag::new(PIPE_WIDTH * (self.stats.cycles_taken + 1) + i, thread);
+            let res = self.predict_one(pc, tag, inst, size);
+            let is_halt = matches!(res.inst.inst, Inst::Halt);
+            let serialising = res.inst.inst.is_csr_serialising();
 
-            let res = self.fetch_decode_one(*next_pcs.last().unwrap(), tag);
+            block.insts.push(res.inst);
+            block.end = pc + size;
 
-            insts.push(res.inst);
+            // Nothing past a halt will be executed, unless it turns out to be on a wrong path.
+            let next_pc = if is_halt { None } else { res.next_pc };
+            let front_end = &mut self.front_ends[thread];
+            front_end.predict_pc = next_pc;
+            front_end.unresolved_target = next_pc.is_none() && !is_halt && !serialising;
 
-            if let Some(next_pc) = res.next_pc {
-                next_pcs.push(next_pc);
-            } else {
+            match next_pc {
+                Some(next_pc)
+                    if next_pc == block.end && Addr(next_pc.0).to_cache_line() == line =>
+                {
+                    pc = next_pc
+                }
+                _ => break,
+            }
+        }
+
+        // Fused pairs count as both of their instructions.
+        self.stats.fetch_blocks += 1;
+        self.stats.fetch_block_bytes += u64::from(block.end.0 - block.start.0);
+        self.stats.fetch_block_insts += block
+            .insts
+            .iter()
+            .map(|inst| if inst.inst.is_fused() { 2 } else { 1 })
+            .sum::<u64>();
+        self.front_ends[thread].ftq.push_back(block);
+    }
+
+    fn stage_fetch_decode(&mut self, _pipe: &Pipeline) {
+        let fetch_to_rename = self.config.fetch_to_rename;
+        let Some(thread) = self
+            .pick_thread(|fe| !fe.ftq.is_empty() && (fe.in_flight.len() as u64) < fetch_to_rename)
+        else {
+            return;
+        };
+
+        if self.front_ends[thread].walk_until > self.stats.cycles_taken {
+            self.stats.fetch_stalls += 1;
+            return;
+        }
+
+        // The lines are looked up by physical address.
+        let block = self.front_ends[thread].ftq.front().unwrap();
+        let (start, end) = (block.start, block.end);
+        let pages = match self.mmu.translate(
+            thread,
+            &self.csrs[thread],
+            &mmu::fetch_pages(start, end.0 - start.0),
+            &mut self.mem,
+            &mut self.stats,
+        ) {
+            Ok((pages, 0)) => pages,
+            Ok((_, walk)) => {
+                self.front_ends[thread].walk_until = self.stats.cycles_taken + walk;
                 self.stats.fetch_stalls += 1;
-                stalled = true;
+                return;
+            }
+            Err(fault) => {
+                self.fetch_fault(thread, fault);
+                return;
+            }
+        };
+
+        // A fused pair can straddle a line boundary, so a block may need the next line as well.
+        let first = pages.apply(Addr(start.0)).to_cache_line();
+        let last = pages.apply(Addr((end - 1).0)).to_cache_line();
+        let first_ready = self.mem.inst_line_ready(first, &mut self.stats);
+        let ready =
+            first_ready && (first == last || self.mem.inst_line_ready(last, &mut self.stats));
+
+        if !ready {
+            self.stats.fetch_stalls += 1;
+            return;
+        }
+
+        let front_end = &mut self.front_ends[thread];
+        let block = front_end.ftq.pop_front().unwrap();
+        let ready = self.stats.cycles_taken + self.config.fetch_to_rename;
+        front_end.in_flight.push_back((ready, block.insts));
+    }
+
+    // Whatever comes before the faulting instruction in its block carries on to rename, and
+    // nothing more is fetched. Everything after it is thrown away when the trap is taken.
+    fn fetch_fault(&mut self, thread: usize, fault: PageFault) {
+        let mut block = self.front_ends[thread].ftq.pop_front().unwrap();
+
+        let mut pc = block.start;
+        let mut fetched = 0;
+        for _ in &block.insts {
+            let (_, size) = self.decode(thread, pc);
+            let pages = mmu::fetch_pages(pc, size);
+            if pages
+                .iter()
+                .any(|(addr, _)| mmu::page_of(*addr) == mmu::page_of(fault.addr))
+            {
                 break;
             }
+            pc += size;
+            fetched += 1;
         }
 
-        stages::wide::FetchDecode {
-            insts,
-            next_pcs,
-            stalled,
+        let tag = block.insts[fetched].tag;
+        block.insts.truncate(fetched);
+
+        let ready = self.stats.cycles_taken + self.config.fetch_to_rename;
+        let front_end = &mut self.front_ends[thread];
+        if !block.insts.is_empty() {
+            front_end.in_flight.push_back((ready, block.insts));
         }
+        front_end.ftq.clear();
+        front_end.predict_pc = None;
+        front_end.unresolved_target = false;
+        front_end.fetch_fault = Some((pc, tag, fault));
     }
 
-    fn stage_rename(&mut self, pipe: &Pipeline) -> stages::wide::Rename {
+    // Everything in the thread's front end is younger than whatever caused the redirect.
+    fn redirect(&mut self, thread: usize, next_pc: AbsPc) {
+        let front_end = &mut self.front_ends[thread];
+        front_end.ftq.clear();
+        front_end.in_flight.clear();
+        front_end.predict_pc = Some(next_pc);
+        front_end.unresolved_target = false;
+        front_end.fetch_fault = None;
+    }
+
+    // Each thread can rename one fetched group a cycle, within the overall width. A stall holds
+    // up every thread, since rename is in order.
+    fn stage_rename(&mut self, _pipe: &Pipeline) -> stages::wide::Rename {
         let mut insts = vec![];
         let mut num_renamed = 0;
-        let mut should_stall = false;
 
-        for inst in &pipe.fetch_decode.insts {
-            let renamed = self.rename_one(inst);
+        let n = self.front_ends.len();
+        for i in 0..n {
+            let thread = (self.stats.cycles_taken as usize + i) % n;
+
+            let (ready, mut group) = match self.front_ends[thread].in_flight.pop_front() {
+                Some((ready, group)) if ready <= self.stats.cycles_taken => (ready, group),
+                Some(not_ready) => {
+                    self.front_ends[thread].in_flight.push_front(not_ready);
+                    continue;
+                }
+                None => continue,
+            };
+
+            let mut stalled = false;
+            let mut group_renamed = 0;
+            for inst in &group {
+                if num_renamed == PIPE_WIDTH {
+                    break;
+                }
+
+                let renamed = self.rename_one(inst);
 
-            if renamed.should_stall {
-                should_stall = true;
+                if renamed.should_stall {
+                    stalled = true;
+                    break;
+                }
+
+                num_renamed += 1;
+                group_renamed += 1;
+                if let Some(inst) = renamed.inst {
+                    insts.push(inst);
+                }
+            }
+
+            group.drain(..group_renamed);
+            if !group.is_empty() {
+                self.front_ends[thread].in_flight.push_front((ready, group));
+            }
+
+            if stalled {
                 break;
             }
+        }
 
-            if let Some(inst) = renamed.inst {
-                num_renamed += 1;
-                insts.push(inst);
+        stages::wide::Rename { insts }
+    }
+
+    // Pick the least occupied queue that can issue the instruction, charging a stall to every
+    // candidate if they're all full.
+    fn steer(&mut self, inst: &Inst) -> Option<usize> {
+        let eu_type = inst.eu_type();
+        let candidates = (0..self.reservation_stations.len())
+            .filter(|&q| self.reservation_stations[q].accepts(eu_type, &self.execution_units))
+            .collect::<Vec<_>>();
+
+        let chosen = candidates
+            .iter()
+            .copied()
+            .filter(|&q| !self.reservation_stations[q].is_full())
+            .min_by_key(|&q| self.reservation_stations[q].occupancy());
+
+        if chosen.is_none() {
+            for q in candidates {
+                self.reservation_stations[q].stalls += 1;
             }
         }
 
-        let next_fetch_decode = if should_stall {
-            Some(stages::wide::FetchDecode {
-                insts: pipe.fetch_decode.insts[num_renamed..].to_vec(),
-                next_pcs: pipe.fetch_decode.next_pcs[num_renamed..].to_vec(),
-                stalled: false,
-            })
-        } else {
-            None
+        chosen
+    }
+
+    // Make a predicted load result available to dependents straight away. It's checked against
+    // the real value at writeback.
+    fn predict_load_value(&mut self, tag: Tag, dst: BothReg) {
+        let Some(value_predictor) = &mut self.value_predictor else {
+            return;
         };
 
-        stages::wide::Rename {
-            insts,
-            next_fetch_decode,
+        let pc = self.pc_map[&tag];
+        let predicted = value_predictor
+            .predict(pc)
+            .filter(|_| dst.arch != ArchReg::Zero);
+
+        if let Some(val) = predicted {
+            self.reg_file.set_phys_active(dst.phys, val);
         }
+        self.value_predictions.insert(tag, (pc, predicted));
     }
 
     fn rename_one(&mut self, inst: &Tagged<Inst>) -> stages::narrow::Rename {
         let mut stall = false;
 
         let Tagged { inst, tag } = inst.clone();
+        let thread = tag.thread();
+
+        // Writing fcsr waits for everything older to finish, so it can be done here and seen by
+        // everything after. This also makes the rounding mode known at rename.
+        let frm = RoundingMode::from_frm(FpCsr::Frm.read(self.fcsr[thread]));
+        let inst = inst.with_rounding(frm);
+        if matches!(inst, Inst::WriteFcsr(_, _, _)) && !self.rob.is_thread_empty(thread) {
+            stall = true;
+        }
+        // The same goes for vsetvli and the vector type, and for changes to the supervisor CSRs.
+        let inst = inst.with_vector_type(self.vtype[thread]);
+        if (inst.is_vector_config() || inst.is_csr_serialising())
+            && !self.rob.is_thread_empty(thread)
+        {
+            stall = true;
+        }
 
         if self.rob.is_full() {
             self.stats.rob_stalls += 1;
             stall = true;
         }
-        if self.reservation_station.is_full() {
+        let rs = self.steer(&inst);
+        if rs.is_none() {
             self.stats.reservation_station_stalls += 1;
             stall = true;
         }
-        if inst.is_mem_access() && !self.lsq.has_space(&inst) {
+        if (inst.is_mem_access() || inst.is_vector_store()) && !self.lsq.has_space(&inst) {
             self.stats.lsq_stalls += 1;
             stall = true;
         }
@@ -457,12 +877,53 @@ impl OutOfOrder {
             };
         }
 
-        if let Some(renamed_inst) = self.reg_file.perform_rename(tag, inst.clone()) {
+        if let Some((renamed_inst, elimination)) = self.reg_file.perform_rename(tag, inst.clone()) {
             assert_eq!(self.rob.try_push(tag, inst), None);
-            self.reservation_station.insert(tag, renamed_inst.clone());
+            self.end_refill(tag);
+
+            if let Some(elimination) = elimination {
+                // Already complete, it never needs an execution unit.
+                match elimination {
+                    Elimination::Move => self.stats.moves_eliminated += 1,
+                    Elimination::ZeroIdiom => self.stats.zero_idioms_eliminated += 1,
+                }
+                self.rob.mark_complete(tag);
 
-            if renamed_inst.is_mem_access() {
-                self.lsq.insert_access(renamed_inst.clone(), tag);
+                return stages::narrow::Rename {
+                    inst: Some(renamed_inst),
+                    should_stall: false,
+                };
+            }
+
+            if renamed_inst.is_csr_access() {
+                self.access_csr(tag, &renamed_inst);
+                self.rob.mark_complete(tag);
+
+                return stages::narrow::Rename {
+                    inst: Some(renamed_inst),
+                    should_stall: false,
+                };
+            }
+
+            if renamed_inst.is_fcsr_access() || renamed_inst.is_vector_config() {
+                self.access_fcsr(thread, &renamed_inst);
+                self.rob.mark_complete(tag);
+
+                return stages::narrow::Rename {
+                    inst: Some(renamed_inst),
+                    should_stall: false,
+                };
+            }
+
+            self.reservation_stations[rs.unwrap()].insert(tag, renamed_inst.clone());
+
+            if renamed_inst.is_mem_access() || renamed_inst.is_vector_store() {
+                self.lsq
+                    .insert_access(renamed_inst.clone(), tag, self.pc_map[&tag]);
+            }
+
+            if let Some(dst) = renamed_inst.load_dst() {
+                self.predict_load_value(tag, dst.clone());
             }
 
             stages::narrow::Rename {
@@ -478,117 +939,296 @@ impl OutOfOrder {
         }
     }
 
+    // Done at rename, with the old value going straight to the destination. vsetvli gives the
+    // new vl instead.
+    fn access_fcsr(&mut self, thread: usize, inst: &RenamedInst) {
+        let fcsr = self.fcsr[thread];
+        let (dst, old) = match inst.get_ready(&self.reg_file).expect("thread not drained") {
+            Inst::ReadFcsr(dst, csr) => (dst, csr.read(fcsr)),
+            Inst::WriteFcsr(dst, val, csr) => {
+                self.fcsr[thread] = csr.write(fcsr, val);
+                (dst, csr.read(fcsr))
+            }
+            Inst::VectorSetLength(dst, avl, sew) => {
+                let vl = avl.min(self.vector_regs.vlmax(sew));
+                self.vtype[thread] = VectorType { sew, vl };
+                (dst, vl)
+            }
+            Inst::VectorSetMaxLength(dst, sew) => {
+                let vl = self.vector_regs.vlmax(sew);
+                self.vtype[thread] = VectorType { sew, vl };
+                (dst, vl)
+            }
+            _ => unreachable!(),
+        };
+
+        if dst.arch != ArchReg::Zero {
+            self.reg_file.set_phys_active(dst.phys, old);
+        }
+    }
+
+    // Like fcsr, but changing satp or sfence.vma also flushes the thread's TLB entries. Fetch
+    // stopped after anything that changes them, so it's restarted from here.
+    fn access_csr(&mut self, tag: Tag, inst: &RenamedInst) {
+        let thread = tag.thread();
+        let csrs = &mut self.csrs[thread];
+        let (dst, old) = match inst.get_ready(&self.reg_file).expect("thread not drained") {
+            Inst::ReadCsr(dst, csr) => (Some(dst), csrs.read(csr)),
+            Inst::WriteCsr(dst, val, csr) => {
+                let old = csrs.read(csr);
+                csrs.write(csr, val);
+                (Some(dst), old)
+            }
+            Inst::SupervisorReturn | Inst::FenceVma => (None, 0),
+            _ => unreachable!(),
+        };
+
+        if let Some(dst) = dst.filter(|dst| dst.arch != ArchReg::Zero) {
+            self.reg_file.set_phys_active(dst.phys, old);
+        }
+
+        if inst.is_csr_serialising() {
+            if matches!(inst, Inst::WriteCsr(_, _, Csr::Satp) | Inst::FenceVma) {
+                self.mmu.flush(thread);
+            }
+
+            let pc = self.pc_map.remove(&tag).unwrap();
+            self.front_ends[thread].predict_pc = Some(match inst {
+                Inst::SupervisorReturn => AbsPc(self.csrs[thread].sepc),
+                _ => pc + self.progs[thread].inst_size(pc),
+            });
+        }
+    }
+
     fn stage_issue(&mut self, _pipe: &Pipeline) -> stages::wide::Issue {
-        let mut remove_tags = vec![];
         let mut kill_tags = Vec::new();
         let mut reinsert_insts = Vec::new();
 
-        for (tag, ready_inst) in self.reservation_station.get_ready(&self.reg_file) {
-            if ready_inst.is_load()
-                && !self.lsq.can_execute_load(
-                    *tag,
-                    *self
-                        .pc_map
-                        .get(tag)
-                        .unwrap_or_else(|| panic!("no tag {:?}", tag)),
-                    ready_inst.access_range(),
-                    &mut self.reg_file,
-                )
-            {
-                continue;
-            } else if ready_inst.is_store() {
-                let (eu_kills, mispredicts) =
-                    self.lsq
-                        .store_addr_known(*tag, ready_inst.access_range(), &mut self.reg_file);
-
-                for tag in eu_kills {
-                    // Kill it from the EUs (but this isn't a misprediction)
-                    for eu in &mut self.execution_units {
-                        if eu.eu_type == EuType::LoadStore {
-                            self.lsq.kill_inflight(tag);
-                            let killed = eu.kill_specific(tag);
-                            reinsert_insts.push(killed);
-                        }
+        'queues: for q in 0..self.reservation_stations.len() {
+            let units = self.reservation_stations[q].units.clone();
+            let issue_width = self.reservation_stations[q].issue_width;
+            let mut remove_tags = vec![];
+            let mut slots = 0;
+
+            let selected = self.reservation_stations[q].select(
+                &self.reg_file,
+                &self.load_wakeups,
+                self.stats.cycles_taken,
+                self.config.wakeup_latency,
+                self.config.issue_policy,
+            );
+
+            for (tag, ready_inst) in &selected {
+                if slots == issue_width {
+                    break;
+                }
+
+                let Some(ready_inst) = ready_inst else {
+                    // Woken for a load that missed, so the select slot is wasted.
+                    self.reservation_stations[q].replay(*tag);
+                    self.stats.load_replays += 1;
+                    slots += 1;
+                    continue;
+                };
+
+                // Atomics are not speculative, they wait for everything older to commit. Vector
+                // loads and stores go to memory when they issue, so they do the same.
+                if (ready_inst.is_atomic() || ready_inst.eu_type() == EuType::VectorLoadStore)
+                    && !self.rob.is_thread_head(*tag)
+                {
+                    continue;
+                }
+
+                if ready_inst.is_load()
+                    && !self.lsq.can_execute_load(
+                        *tag,
+                        *self
+                            .pc_map
+                            .get(tag)
+                            .unwrap_or_else(|| panic!("no tag {:?}", tag)),
+                        ready_inst.access_range(),
+                        &mut self.reg_file,
+                    )
+                {
+                    continue;
+                } else if ready_inst.is_store()
+                    || ready_inst.is_atomic()
+                    || ready_inst.is_vector_store()
+                {
+                    let (eu_kills, mispredicts) = self.lsq.store_addr_known(
+                        *tag,
+                        ready_inst.access_range(),
+                        &mut self.reg_file,
+                    );
+
+                    self.stats.mem_order_violations += (eu_kills.len() + mispredicts.len()) as u64;
+                    if !mispredicts.is_empty() {
+                        self.stats.mem_order_flushes += 1;
+                    }
+
+                    for tag in eu_kills {
+                        // Kill it from the EU executing it (but this isn't a misprediction)
+                        self.lsq.kill_inflight(tag);
+                        let (i, eu) = self
+                            .execution_units
+                            .iter_mut()
+                            .enumerate()
+                            .find(|(_, eu)| eu.is_executing(tag))
+                            .expect("killed load isn't executing");
+                        reinsert_insts.push((i, eu.kill_specific(tag)));
                     }
+
+                    // dbg!(&mispredicts);
+                    // assert!(mispredicts.len() <= 1);
+                    kill_tags = mispredicts;
                 }
 
-                // dbg!(&mispredicts);
-                // assert!(mispredicts.len() <= 1);
-                kill_tags = mispredicts;
-            }
+                if let Some(eu) = self
+                    .execution_units
+                    .iter_mut()
+                    .enumerate()
+                    .find(|(i, eu)| units.contains(i) && eu.can_execute(ready_inst))
+                    .map(|(_, eu)| eu)
+                {
+                    if ready_inst.is_load() {
+                        self.lsq.begin_execute_load(*tag);
+
+                        if let Some(dst) = ready_inst.load_dst() {
+                            if self.config.speculative_load_wakeup {
+                                let expected = self.stats.cycles_taken + LOAD_HIT_WAKEUP;
+                                self.load_wakeups.insert(dst.phys, (*tag, expected));
+                            }
+                        }
+                    }
 
-            if let Some(eu) = self
-                .execution_units
-                .iter_mut()
-                .find(|eu| eu.can_execute(ready_inst))
-            {
-                if ready_inst.is_load() {
-                    self.lsq.begin_execute_load(*tag);
+                    let translation = ready_inst.accesses_memory().then(|| {
+                        self.mmu.translate(
+                            tag.thread(),
+                            &self.csrs[tag.thread()],
+                            &mmu::access_pages(ready_inst),
+                            &mut self.mem,
+                            &mut self.stats,
+                        )
+                    });
+
+                    match translation {
+                        Some(Err(fault)) => eu.begin_access(ready_inst.clone(), *tag, Err(fault)),
+                        _ if ready_inst.is_vector() => {
+                            let (pages, walk) = translation.map(Result::unwrap).unwrap_or_default();
+                            let dst = ready_inst
+                                .vector_dst()
+                                .map(|dst| usize::from(dst.phys) as u32)
+                                .unwrap_or(0);
+                            let val = vector::execute(
+                                ready_inst,
+                                dst,
+                                &mut self.vector_regs,
+                                &mut self.mem.main,
+                                &pages,
+                            );
+                            let mut timing = self.config.vector.timing(
+                                ready_inst,
+                                &pages,
+                                &mut self.mem,
+                                &mut self.stats,
+                            );
+                            // Nothing is sent out until every page has been translated.
+                            timing.latency += walk;
+                            timing.chain += walk;
+                            eu.begin_vector(ready_inst.clone(), *tag, timing, val);
+                        }
+                        Some(Ok((pages, walk))) => {
+                            if ready_inst.is_store() {
+                                let addr = pages.apply(ready_inst.access_addr());
+                                self.lsq.store_translated(*tag, addr);
+                            }
+                            eu.begin_access(ready_inst.clone(), *tag, Ok((pages, walk)));
+                        }
+                        None => eu.begin_execute(ready_inst.clone(), *tag),
+                    }
+                    remove_tags.push(*tag);
+                    slots += 1;
                 }
 
-                eu.begin_execute(ready_inst.clone(), *tag);
-                remove_tags.push(*tag);
+                if !kill_tags.is_empty() {
+                    break;
+                }
             }
 
-            if !kill_tags.is_empty() {
-                break;
+            for tag in remove_tags {
+                self.reservation_stations[q].pop_ready(tag);
             }
-        }
 
-        for tag in remove_tags {
-            self.reservation_station.pop_ready(tag);
+            if !kill_tags.is_empty() {
+                break 'queues;
+            }
         }
 
-        for tagged in reinsert_insts {
-            self.reservation_station
-                .insert_ready(tagged.tag, tagged.inst);
+        // Killed loads go back to the queue that issued them.
+        for (eu, tagged) in reinsert_insts {
+            let rs = self
+                .reservation_stations
+                .iter_mut()
+                .find(|rs| rs.units.contains(&eu))
+                .unwrap();
+            rs.insert_ready(tagged.tag, tagged.inst);
         }
 
         // we should roll back to the earliest mis-speculation
         // kill_tags.iter().filter_map(|t| self.reg_file.end_predict_mem(*t, false))
         kill_tags.sort();
 
-        let mut next_fetch = None;
+        let mut redirect = None;
         for tag in kill_tags.iter().rev() {
-            next_fetch = self.reg_file.end_predict_mem(*tag, false);
+            let thread = tag.thread();
+            redirect = self
+                .reg_file
+                .end_predict_mem(*tag, false, &mut self.branch_predictors[thread])
+                .map(|next_pc| (thread, next_pc));
         }
 
         for tag in kill_tags {
             // println!("KILLING TAGS AFTER {:?}", tag);
-            self.kill_tags_after(Tag(tag.0 - 1));
+            self.kill_tags_after(tag.prev());
         }
 
-        stages::wide::Issue { next_fetch }
+        stages::wide::Issue { redirect }
     }
 
     // Advance execution of all the execution units.
     fn stage_execute(&mut self, _pipe: &Pipeline) {
         for eu in &mut self.execution_units {
             eu.advance(&mut self.mem, &mut self.stats);
+
+            for (phys, val) in eu.take_chained() {
+                self.reg_file.set_phys_active(phys, val);
+            }
         }
     }
 
     fn stage_writeback(&mut self, pipe: &Pipeline) -> stages::wide::Writeback {
         let mut completed = vec![];
-        let mut next_fetch = None;
+        let mut redirects: Vec<(usize, AbsPc)> = vec![];
 
         for _ in 0..PIPE_WIDTH {
             let mut res = self.writeback_one(pipe);
-            if let Some(tagged) = res.inst.take() {
-                completed.push(tagged);
-            }
 
-            // This will only happen once per cycle because there is only one branch unit
-            // Otherwise we will need to recover to the oldest mis-speculated branch.
+            // Anything younger than a flush was killed by it, so a later flush from the same
+            // thread in the same cycle always comes from an older instruction and takes priority.
             if let Some(next_pc) = res.next_fetch.take() {
-                debug_assert!(next_fetch.is_none());
-                next_fetch = Some(next_pc);
+                let thread = res.inst.as_ref().unwrap().tag.thread();
+                redirects.retain(|&(t, _)| t != thread);
+                redirects.push((thread, next_pc));
+            }
+
+            if let Some(tagged) = res.inst.take() {
+                completed.push(tagged);
             }
         }
 
         stages::wide::Writeback {
             insts: completed,
-            next_fetch,
+            redirects,
         }
     }
 
@@ -596,7 +1236,21 @@ impl OutOfOrder {
     fn writeback_one(&mut self, _pipe: &Pipeline) -> stages::narrow::Writeback {
         for eu in &mut self.execution_units {
             if let Some((Tagged { tag, inst }, result)) = eu.take_complete() {
+                // Nothing is written back, it traps once it's the oldest in its thread.
+                if let Some(fault) = result.page_fault {
+                    if inst.is_load() {
+                        self.lsq.writeback_load(tag);
+                    }
+                    self.rob.mark_excepted(tag, fault);
+
+                    return stages::narrow::Writeback {
+                        inst: Some(Tagged { tag, inst }),
+                        next_fetch: None,
+                    };
+                }
+
                 let mut next_fetch = None;
+                let mut killed = false;
 
                 match &inst {
                     Inst::Add(dst, _, _)
@@ -622,37 +1276,90 @@ impl OutOfOrder {
                     | Inst::ShiftRightArithImm(dst, _, _)
                     | Inst::ShiftRightLogicalImm(dst, _, _)
                     | Inst::EffectiveAddress(dst, _, _, _)
+                    | Inst::ShiftAdd(dst, _, _, _)
+                    | Inst::Bit(_, dst, _, _)
+                    | Inst::BitUnary(_, dst, _)
+                    | Inst::ZeroExtend(dst, _, _)
+                    | Inst::IndexedLoadByte(dst, _, _, _)
                     | Inst::IndexedLoadByteU(dst, _, _, _)
+                    | Inst::IndexedLoadHalfWord(dst, _, _, _)
+                    | Inst::IndexedLoadWord(dst, _, _, _)
                     | Inst::LoadWord(dst, _)
                     | Inst::LoadHalfWord(dst, _)
                     | Inst::LoadByte(dst, _)
-                    | Inst::LoadByteU(dst, _) => {
+                    | Inst::LoadByteU(dst, _)
+                    | Inst::LoadReserved(dst, _, _)
+                    | Inst::StoreConditional(dst, _, _, _)
+                    | Inst::Amo(_, dst, _, _, _)
+                    | Inst::Fp(_, dst, _, _, _)
+                    | Inst::FpSqrt(dst, _, _)
+                    | Inst::FpFma(_, dst, _, _, _, _)
+                    | Inst::FpCompare(_, dst, _, _)
+                    | Inst::FpConvert(_, dst, _, _)
+                    | Inst::FpClass(dst, _)
+                    | Inst::VectorLoad(dst, _, _, _, _)
+                    | Inst::VectorLoadStrided(dst, _, _, _, _, _)
+                    | Inst::VectorArith(_, dst, _, _, _, _)
+                    | Inst::VectorArithScalar(_, dst, _, _, _, _)
+                    | Inst::VectorArithImm(_, dst, _, _, _, _)
+                    | Inst::VectorReduce(_, dst, _, _, _, _)
+                    | Inst::VectorSplat(dst, _, _, _)
+                    | Inst::VectorToScalar(dst, _, _) => {
                         if inst.is_load() {
                             self.lsq.writeback_load(tag);
                             self.pc_map.remove(&tag);
+                            self.load_wakeups.remove(&dst.phys);
                         }
 
                         if dst.arch != ArchReg::Zero {
                             self.reg_file.set_phys_active(dst.phys, result.val);
                         }
+
+                        if let Some(next_pc) = self.verify_load_value(tag, result.val) {
+                            // Refetch the load along with everything after it.
+                            self.kill_tags_after(tag.prev());
+                            next_fetch = Some(next_pc);
+                            killed = true;
+                        }
                     }
                     Inst::BranchIfEqual(_, _, _)
                     | Inst::BranchIfLess(_, _, _)
                     | Inst::BranchIfLessU(_, _, _)
                     | Inst::BranchIfNotEqual(_, _, _)
                     | Inst::BranchIfGreaterEqualU(_, _, _)
-                    | Inst::BranchIfGreaterEqual(_, _, _) => {
-                        let taken = result.val == 1;
-                        let predicted_taken = self.reg_file.was_predicted_taken(tag);
+                    | Inst::BranchIfGreaterEqual(_, _, _)
+                    | Inst::CompareAndBranch(_, _, _, _, _)
+                    | Inst::CompareImmAndBranch(_, _, _, _, _) => {
+                        let taken = match &inst {
+                            Inst::CompareAndBranch(dst, _, _, _, cmp)
+                            | Inst::CompareImmAndBranch(dst, _, _, _, cmp) => {
+                                self.reg_file.set_phys_active(dst.phys, result.val);
+                                (result.val == 1) == cmp.branch_if_set
+                            }
+                            _ => result.val == 1,
+                        };
+                        let (prediction, inst_pc) = self.reg_file.direct_prediction(tag);
 
                         self.stats.direct_predicts += 1;
 
-                        if let Some(next_pc) = self.reg_file.end_predict_direct(
+                        if prediction.from_loop {
+                            self.stats.loop_predicts += 1;
+                            if prediction.taken != prediction.base_taken {
+                                self.stats.loop_overrides += 1;
+                            }
+                            if prediction.taken != taken {
+                                self.stats.loop_mispredicts += 1;
+                            }
+                        }
+
+                        let flush = self.reg_file.end_predict_direct(
                             tag,
                             taken,
-                            predicted_taken,
-                            &mut self.branch_predictor,
-                        ) {
+                            &mut self.branch_predictors[tag.thread()],
+                        );
+                        self.resolve_branch(inst_pc, tag, taken, flush.is_some());
+
+                        if let Some(next_pc) = flush {
                             // Flush
                             // println!("FLUSH DIRECT");
                             self.stats.direct_mispredicts += 1;
@@ -664,20 +1371,36 @@ impl OutOfOrder {
                         let inst_pc = self.pc_map.remove(&tag).unwrap();
 
                         if dst.arch != ArchReg::Zero {
-                            self.reg_file
-                                .set_phys_active(dst.phys, (inst_pc + INST_SIZE).0);
+                            let size = self.progs[tag.thread()].inst_size(inst_pc);
+                            self.reg_file.set_phys_active(dst.phys, (inst_pc + size).0);
                         }
                     }
                     Inst::JumpAndLinkRegister(dst, _, _) => {
-                        let (predicted_pc, inst_pc) = self.reg_file.predicted_addr(tag);
+                        let (prediction, inst_pc) = self.reg_file.predicted_addr(tag);
+                        let predicted_pc = prediction.target;
+                        let is_return = prediction.is_return;
+                        let mispredicted = predicted_pc
+                            .map(|predicted_pc| AbsPc(result.val) != predicted_pc)
+                            .unwrap_or(false);
 
                         if predicted_pc.is_some() {
-                            self.stats.indirect_predicts += 1;
+                            if is_return {
+                                self.stats.return_predicts += 1;
+                            } else {
+                                self.stats.indirect_predicts += 1;
+                            }
+                        }
+
+                        if prediction.from_table {
+                            self.stats.path_table_predicts += 1;
+                            if mispredicted {
+                                self.stats.path_table_mispredicts += 1;
+                            }
                         }
 
                         if dst.arch != ArchReg::Zero {
-                            self.reg_file
-                                .set_phys_active(dst.phys, (inst_pc + INST_SIZE).0);
+                            let size = self.progs[tag.thread()].inst_size(inst_pc);
+                            self.reg_file.set_phys_active(dst.phys, (inst_pc + size).0);
                         }
 
                         let actual_pc = AbsPc(result.val);
@@ -685,14 +1408,17 @@ impl OutOfOrder {
                             tag,
                             actual_pc,
                             predicted_pc,
-                            &mut self.branch_predictor,
+                            &mut self.branch_predictors[tag.thread()],
                         );
-                        if predicted_pc
-                            .map(|predicted_pc| actual_pc != predicted_pc)
-                            .unwrap_or(false)
-                        {
+                        self.resolve_branch(inst_pc, tag, true, mispredicted);
+
+                        if mispredicted {
                             // println!("FLUSH INDIRECT");
-                            self.stats.indirect_mispredicts += 1;
+                            if is_return {
+                                self.stats.return_mispredicts += 1;
+                            } else {
+                                self.stats.indirect_mispredicts += 1;
+                            }
                             self.kill_tags_after(tag);
                             next_fetch = Some(actual_pc);
                         } else if predicted_pc.is_none() {
@@ -702,11 +1428,28 @@ impl OutOfOrder {
                     Inst::StoreWord(_, _)
                     | Inst::StoreHalfWord(_, _)
                     | Inst::StoreByte(_, _)
+                    | Inst::VectorStore(_, _, _, _)
+                    | Inst::VectorStoreStrided(_, _, _, _, _)
                     | Inst::Halt => (),
-                    // _ => unimplemented!("{:?}", inst),
+                    Inst::ReadFcsr(_, _)
+                    | Inst::WriteFcsr(_, _, _)
+                    | Inst::ReadCsr(_, _)
+                    | Inst::WriteCsr(_, _, _)
+                    | Inst::SupervisorReturn
+                    | Inst::FenceVma
+                    | Inst::VectorSetLength(_, _, _)
+                    | Inst::VectorSetMaxLength(_, _) => {
+                        unreachable!("CSR accesses and vsetvli complete at rename")
+                    } // _ => unimplemented!("{:?}", inst),
                 };
 
-                self.rob.mark_complete(tag);
+                if !killed {
+                    if let Some(fault) = result.fault {
+                        self.rob.mark_faulted(tag, fault);
+                    } else {
+                        self.rob.mark_complete(tag);
+                    }
+                }
 
                 return stages::narrow::Writeback {
                     inst: Some(Tagged { tag, inst }),
@@ -721,15 +1464,84 @@ impl OutOfOrder {
         }
     }
 
+    // Check a load's predicted value, if it had one, returning where to refetch from if it was
+    // wrong.
+    fn verify_load_value(&mut self, tag: Tag, val: u32) -> Option<AbsPc> {
+        let (pc, predicted) = self.value_predictions.remove(&tag)?;
+        self.value_predictor.as_mut().unwrap().train(pc, val);
+
+        self.stats.value_lookups += 1;
+        let predicted = predicted?;
+        self.stats.value_predicts += 1;
+
+        if predicted == val {
+            return None;
+        }
+
+        self.stats.value_mispredicts += 1;
+        let next_pc = self
+            .reg_file
+            .end_predict_mem(tag, false, &mut self.branch_predictors[tag.thread()])
+            .expect("load has no speculation point");
+        Some(next_pc)
+    }
+
     // Commit instructions from the ROB to architectural state.
     fn stage_commit(&mut self, _pipe: &Pipeline) -> stages::Commit {
+        // Everything older than a faulting access has committed by the time it's at the head.
+        while let Some((tag, fault)) = self.rob.head_exception() {
+            let pc = self
+                .reg_file
+                .end_predict_mem(tag, false, &mut self.branch_predictors[tag.thread()])
+                .expect("memory access has no speculation point");
+            self.take_trap(tag, pc, fault);
+        }
+
+        // The thread stops as if it had halted before the access, keeping the first fault.
+        while let Some((tag, fault)) = self.rob.head_fault() {
+            self.reg_file
+                .end_predict_mem(tag, false, &mut self.branch_predictors[tag.thread()])
+                .expect("memory access has no speculation point");
+            self.kill_tags_after(tag.prev());
+            self.front_ends[tag.thread()] = FrontEnd {
+                halted: true,
+                ..Default::default()
+            };
+            self.fault.get_or_insert(fault);
+
+            if self.front_ends.iter().all(|fe| fe.halted) {
+                return stages::Commit { should_halt: true };
+            }
+        }
+
+        for thread in 0..self.front_ends.len() {
+            let front_end = &self.front_ends[thread];
+            if let Some((pc, tag, fault)) = front_end.fetch_fault {
+                if front_end.in_flight.is_empty() && self.rob.is_thread_empty(thread) {
+                    self.take_trap(tag, pc, fault);
+                }
+            }
+        }
+
         for _ in 0..PIPE_WIDTH {
             let tagged = self.rob.try_pop();
 
             let Tagged { tag, inst } = match tagged {
                 Some(Tagged {
-                    inst: Inst::Halt, ..
-                }) => return stages::Commit { should_halt: true },
+                    inst: Inst::Halt,
+                    tag,
+                }) => {
+                    // Nothing was fetched past the halt, so the thread has nothing left in flight.
+                    self.front_ends[tag.thread()] = FrontEnd {
+                        halted: true,
+                        ..Default::default()
+                    };
+
+                    if self.front_ends.iter().all(|fe| fe.halted) {
+                        return stages::Commit { should_halt: true };
+                    }
+                    continue;
+                }
                 Some(tagged) => tagged,
                 None => return Default::default(),
             };
@@ -758,53 +1570,194 @@ impl OutOfOrder {
                 | Inst::JumpAndLink(dst, _)
                 | Inst::JumpAndLinkRegister(dst, _, _)
                 | Inst::EffectiveAddress(dst, _, _, _)
+                | Inst::ShiftAdd(dst, _, _, _)
+                | Inst::Bit(_, dst, _, _)
+                | Inst::BitUnary(_, dst, _)
+                | Inst::ZeroExtend(dst, _, _)
+                | Inst::CompareAndBranch(dst, _, _, _, _)
+                | Inst::CompareImmAndBranch(dst, _, _, _, _)
+                | Inst::IndexedLoadByte(dst, _, _, _)
                 | Inst::IndexedLoadByteU(dst, _, _, _)
+                | Inst::IndexedLoadHalfWord(dst, _, _, _)
+                | Inst::IndexedLoadWord(dst, _, _, _)
                 | Inst::LoadFullImm(dst, _)
                 | Inst::LoadUpperImm(dst, _)
                 | Inst::LoadByteU(dst, _)
                 | Inst::LoadByte(dst, _)
                 | Inst::LoadHalfWord(dst, _)
-                | Inst::LoadWord(dst, _) => {
+                | Inst::LoadWord(dst, _)
+                | Inst::Fp(_, dst, _, _, _)
+                | Inst::FpSqrt(dst, _, _)
+                | Inst::FpFma(_, dst, _, _, _, _)
+                | Inst::FpCompare(_, dst, _, _)
+                | Inst::FpConvert(_, dst, _, _)
+                | Inst::FpClass(dst, _)
+                | Inst::ReadFcsr(dst, _)
+                | Inst::WriteFcsr(dst, _, _)
+                | Inst::ReadCsr(dst, _)
+                | Inst::WriteCsr(dst, _, _)
+                | Inst::VectorSetLength(dst, _, _)
+                | Inst::VectorSetMaxLength(dst, _)
+                | Inst::VectorLoad(dst, _, _, _, _)
+                | Inst::VectorLoadStrided(dst, _, _, _, _, _)
+                | Inst::VectorArith(_, dst, _, _, _, _)
+                | Inst::VectorArithScalar(_, dst, _, _, _, _)
+                | Inst::VectorArithImm(_, dst, _, _, _, _)
+                | Inst::VectorReduce(_, dst, _, _, _, _)
+                | Inst::VectorSplat(dst, _, _, _)
+                | Inst::VectorToScalar(dst, _, _) => {
                     if dst != ArchReg::Zero {
                         self.reg_file.release_phys(tag);
                     }
 
-                    if inst.is_mem_access() {
+                    if inst.accesses_memory() {
                         // If we got to this point, the speculation was correct.
-                        self.reg_file.end_predict_mem(tag, true);
+                        self.reg_file.end_predict_mem(
+                            tag,
+                            true,
+                            &mut self.branch_predictors[tag.thread()],
+                        );
                         self.lsq.release_load(tag);
                     }
                 }
-                Inst::StoreByte(_, _) | Inst::StoreWord(_, _) => {
+                Inst::StoreByte(_, _)
+                | Inst::StoreHalfWord(_, _)
+                | Inst::StoreWord(_, _)
+                | Inst::VectorStore(_, _, _, _)
+                | Inst::VectorStoreStrided(_, _, _, _, _) => {
+                    self.pc_map.remove(&tag);
+                    self.reg_file.end_predict_mem(
+                        tag,
+                        true,
+                        &mut self.branch_predictors[tag.thread()],
+                    );
                     self.lsq.commit_store(tag, &self.reg_file, &mut self.mem)
                 }
+                Inst::LoadReserved(dst, _, _)
+                | Inst::StoreConditional(dst, _, _, _)
+                | Inst::Amo(_, dst, _, _, _) => {
+                    // Before the destination's old register, which may be a source, is freed.
+                    self.pc_map.remove(&tag);
+                    self.reg_file.end_predict_mem(
+                        tag,
+                        true,
+                        &mut self.branch_predictors[tag.thread()],
+                    );
+                    self.lsq.commit_store(tag, &self.reg_file, &mut self.mem);
+
+                    if dst != ArchReg::Zero {
+                        self.reg_file.release_phys(tag);
+                    }
+                }
                 Inst::BranchIfEqual(_, _, _)
                 | Inst::BranchIfLess(_, _, _)
                 | Inst::BranchIfLessU(_, _, _)
                 | Inst::BranchIfNotEqual(_, _, _)
                 | Inst::BranchIfGreaterEqual(_, _, _)
-                | Inst::BranchIfGreaterEqualU(_, _, _) => (),
+                | Inst::BranchIfGreaterEqualU(_, _, _)
+                | Inst::SupervisorReturn
+                | Inst::FenceVma => (),
                 _ => unimplemented!("{:?}", inst),
             }
 
+            self.commit_branch(tag);
             self.stats.insts_retired += 1;
+            if let Some(retired) = self.stats.thread_insts_retired.get_mut(tag.thread()) {
+                *retired += 1;
+            }
 
-            if inst.is_fused() {
+            if let Some(rule) = inst.fusion_rule() {
                 self.stats.macro_ops_fused += 1;
+                *self.stats.fusions.entry(rule).or_default() += 1;
             }
         }
 
         stages::Commit { should_halt: false }
     }
 
+    // Throw away the instruction and everything after it, and go to the handler.
+    fn take_trap(&mut self, tag: Tag, pc: AbsPc, fault: PageFault) {
+        let thread = tag.thread();
+        self.kill_tags_after(tag.prev());
+        let handler = self.csrs[thread].trap(pc, fault);
+        self.redirect(thread, handler);
+        self.stats.page_faults += 1;
+    }
+
+    // Held until the branch commits, as one on the wrong path may yet be squashed.
+    fn resolve_branch(&mut self, pc: AbsPc, tag: Tag, taken: bool, mispredicted: bool) {
+        let profile = BranchProfile {
+            executions: 1,
+            taken: u64::from(taken),
+            mispredicts: u64::from(mispredicted),
+            flush_cycles: 0,
+        };
+        self.resolved_branches.insert(tag, (pc, profile));
+
+        if mispredicted {
+            self.refills[tag.thread()] = Some((tag, pc, self.stats.cycles_taken));
+        }
+    }
+
+    // The first instruction on the correct path after a misprediction has reached rename, so the
+    // branch is charged for the cycles since it redirected fetch.
+    fn end_refill(&mut self, tag: Tag) {
+        let thread = tag.thread();
+        let Some((branch, pc, redirected)) = self.refills[thread] else {
+            return;
+        };
+        self.refills[thread] = None;
+
+        let cycles = self.stats.cycles_taken - redirected;
+        match self.resolved_branches.get_mut(&branch) {
+            Some((_, profile)) => profile.flush_cycles += cycles,
+            // It has already committed.
+            None => {
+                let profile = self.stats.branch_profile.entry((thread, pc)).or_default();
+                profile.flush_cycles += cycles;
+            }
+        }
+    }
+
+    fn commit_branch(&mut self, tag: Tag) {
+        if let Some((pc, resolved)) = self.resolved_branches.remove(&tag) {
+            let profile = self
+                .stats
+                .branch_profile
+                .entry((tag.thread(), pc))
+                .or_default();
+            profile.executions += resolved.executions;
+            profile.taken += resolved.taken;
+            profile.mispredicts += resolved.mispredicts;
+            profile.flush_cycles += resolved.flush_cycles;
+        }
+    }
+
     fn kill_tags_after(&mut self, tag: Tag) {
         for eu in &mut self.execution_units {
             eu.kill_tags_after(tag);
         }
 
-        self.pc_map.retain(|t, _| *t <= tag);
+        self.pc_map.retain(|t, _| !t.is_after(tag));
+        self.resolved_branches.retain(|t, _| !t.is_after(tag));
+        let refill = &mut self.refills[tag.thread()];
+        if refill.is_some_and(|(branch, _, _)| branch.is_after(tag)) {
+            *refill = None;
+        }
+
+        for rs in &mut self.reservation_stations {
+            rs.kill_tags_after(tag);
+        }
+        self.load_wakeups.retain(|_, (t, _)| !t.is_after(tag));
 
-        self.reservation_station.kill_tags_after(tag);
+        if let Some(value_predictor) = &mut self.value_predictor {
+            self.value_predictions.retain(|t, (pc, _)| {
+                if t.is_after(tag) {
+                    value_predictor.squash(*pc);
+                }
+                !t.is_after(tag)
+            });
+        }
         self.lsq.kill_tags_after(tag);
         self.rob.kill_tags_after(tag);
         self.reg_file.kill_tags_after(tag);
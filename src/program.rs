@@ -1,11 +1,21 @@
-use crate::inst::{AbsPc, Inst, Label, LabeledInst, INST_SIZE};
+use crate::{
+    compressed,
+    inst::{AbsPc, ArchReg, Inst, Label, LabeledInst, COMPRESSED_INST_SIZE, INST_SIZE},
+};
 use hashbrown::HashMap;
 use std::str::FromStr;
 
+// An instruction whose jump target is the index of another instruction, before the program is
+// laid out.
+type IndexedInst = Inst<ArchReg, ArchReg, usize>;
+
 #[derive(Debug, Clone)]
 pub struct Program {
     pub insts: Vec<Inst>,
     pub labels: HashMap<Label, AbsPc>,
+    sizes: Vec<u32>,
+    // The instruction starting at each halfword, up to and including the end of the program.
+    index: Vec<Option<usize>>,
 }
 
 impl FromStr for Program {
@@ -13,6 +23,7 @@ impl FromStr for Program {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut insts = Vec::default();
+        let mut lines = Vec::default();
         let mut labels = HashMap::new();
 
         for (i, line) in s.lines().enumerate() {
@@ -25,16 +36,18 @@ impl FromStr for Program {
 
             // Line numbers start at 1
             let i = i + 1;
-            let abs_pc = AbsPc(INST_SIZE * u32::try_from(insts.len()).unwrap());
 
             if line.ends_with(':') {
                 match Label::from_str(&line[0..line.len() - 1]) {
-                    Ok(label) => labels.insert(label, abs_pc),
+                    Ok(label) => labels.insert(label, insts.len()),
                     Err(e) => return Err(format!("error parsing label on line {i}: {e}")),
                 };
             } else {
                 match LabeledInst::from_str(line) {
-                    Ok(inst) => insts.push(inst),
+                    Ok(inst) => {
+                        insts.push(inst);
+                        lines.push((i, line));
+                    }
                     Err(e) => {
                         return Err(format!(
                             "error parsing instruction '{line}' on line {i}: {e}"
@@ -54,16 +67,145 @@ impl FromStr for Program {
                         .unwrap_or_else(|| panic!("unknown label {:?}", tgt))
                 })
             })
-            .collect();
+            .collect::<Vec<_>>();
+
+        // RVC instructions are written with a c. prefix.
+        let sizes = lines
+            .iter()
+            .map(|(_, line)| match line.get(..2) {
+                Some(prefix) if prefix.eq_ignore_ascii_case("c.") => COMPRESSED_INST_SIZE,
+                _ => INST_SIZE,
+            })
+            .collect::<Vec<_>>();
 
-        Ok(Program { insts, labels })
+        let starts = starts(&sizes);
+        for (idx, (i, line)) in lines.into_iter().enumerate() {
+            let inst = &insts[idx];
+            if sizes[idx] == COMPRESSED_INST_SIZE
+                && !compressed::is_compressible(inst, jump_offset(inst, idx, &starts))
+            {
+                return Err(format!(
+                    "instruction '{line}' on line {i} has no compressed encoding"
+                ));
+            }
+        }
+
+        Ok(Program::lay_out(insts, labels, sizes))
     }
 }
 
 impl Program {
+    // The closest label at or before the given PC, and the offset from it.
+    pub fn nearest_label(&self, pc: AbsPc) -> Option<(&Label, u32)> {
+        self.labels
+            .iter()
+            .filter(|(_, &label_pc)| label_pc <= pc)
+            .max_by(|(a, a_pc), (b, b_pc)| a_pc.cmp(b_pc).then_with(|| b.0.cmp(&a.0)))
+            .map(|(label, &label_pc)| (label, pc.0 - label_pc.0))
+    }
+
     pub fn fetch(&self, pc: AbsPc) -> Option<&Inst> {
-        let pc = pc.0;
-        debug_assert_eq!(pc % 4, 0);
-        self.insts.get(usize::try_from(pc / 4).unwrap())
+        self.slot(pc).and_then(|idx| self.insts.get(idx))
     }
+
+    // The size of the instruction at the given PC. Past the end, where fetch finds nothing, it
+    // is taken as a full-size instruction.
+    pub fn inst_size(&self, pc: AbsPc) -> u32 {
+        self.slot(pc)
+            .and_then(|idx| self.sizes.get(idx))
+            .copied()
+            .unwrap_or(INST_SIZE)
+    }
+
+    // The size of the program's code in bytes.
+    pub fn code_size(&self) -> u32 {
+        self.sizes.iter().sum()
+    }
+
+    // The same program with every instruction that has an RVC encoding compressed, as an
+    // assembler targeting RVC would. Compressing one instruction can only bring jumps across it
+    // closer to their targets, so this is repeated until nothing else fits.
+    pub fn compressed(&self) -> Program {
+        let to_idx = |pc: AbsPc| {
+            self.slot(pc)
+                .expect("jump into the middle of an instruction")
+        };
+        let insts = self
+            .insts
+            .iter()
+            .map(|inst| inst.clone().map_jumps(to_idx))
+            .collect::<Vec<_>>();
+        let labels = self
+            .labels
+            .iter()
+            .map(|(label, &pc)| (label.clone(), to_idx(pc)))
+            .collect();
+
+        let mut sizes = self.sizes.clone();
+        loop {
+            let starts = starts(&sizes);
+            let mut changed = false;
+            for (idx, inst) in insts.iter().enumerate() {
+                if sizes[idx] != COMPRESSED_INST_SIZE
+                    && compressed::is_compressible(inst, jump_offset(inst, idx, &starts))
+                {
+                    sizes[idx] = COMPRESSED_INST_SIZE;
+                    changed = true;
+                }
+            }
+
+            if !changed {
+                break;
+            }
+        }
+
+        Program::lay_out(insts, labels, sizes)
+    }
+
+    fn slot(&self, pc: AbsPc) -> Option<usize> {
+        debug_assert_eq!(pc.0 % COMPRESSED_INST_SIZE, 0);
+        self.index
+            .get(usize::try_from(pc.0 / COMPRESSED_INST_SIZE).unwrap())
+            .copied()
+            .flatten()
+    }
+
+    // Place each instruction after the last, and resolve jumps to the PCs they end up at.
+    fn lay_out(insts: Vec<IndexedInst>, labels: HashMap<Label, usize>, sizes: Vec<u32>) -> Self {
+        let starts = starts(&sizes);
+
+        let mut index = vec![None; (starts[insts.len()].0 / COMPRESSED_INST_SIZE) as usize + 1];
+        for (idx, pc) in starts.iter().enumerate() {
+            index[(pc.0 / COMPRESSED_INST_SIZE) as usize] = Some(idx);
+        }
+
+        Program {
+            insts: insts
+                .into_iter()
+                .map(|inst| inst.map_jumps(|idx| starts[idx]))
+                .collect(),
+            labels: labels
+                .into_iter()
+                .map(|(label, idx)| (label, starts[idx]))
+                .collect(),
+            sizes,
+            index,
+        }
+    }
+}
+
+// Where each instruction starts, followed by the end of the program.
+fn starts(sizes: &[u32]) -> Vec<AbsPc> {
+    let mut pc = AbsPc(0);
+    let mut starts = vec![pc];
+    for size in sizes {
+        pc += *size;
+        starts.push(pc);
+    }
+    starts
+}
+
+fn jump_offset(inst: &IndexedInst, idx: usize, starts: &[AbsPc]) -> Option<i64> {
+    inst.jump_target()
+        .map(|&tgt| i64::from(starts[tgt].0) - i64::from(starts[idx].0))
 }
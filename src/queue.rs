@@ -26,6 +26,10 @@ impl<T: Clone> Queue<T> {
         self.data.pop_front()
     }
 
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        self.data.remove(index)
+    }
+
     pub fn front(&self) -> Option<&T> {
         self.data.front()
     }
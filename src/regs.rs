@@ -1,8 +1,6 @@
 use crate::{
-    branch::BranchPredictor,
-    inst::{
-        AbsPc, ArchReg, BothReg, Inst, MemRef, PhysReg, RenamedInst, Tag, ValueOrReg, INST_SIZE,
-    },
+    branch::{BranchPredictor, DirectPrediction, IndirectPrediction, PredictorCheckpoint},
+    inst::{AbsPc, ArchReg, BothReg, Imm, Inst, MemRef, PhysReg, RenamedInst, Tag, ValueOrReg},
     mem,
     util::Addr,
 };
@@ -20,12 +18,13 @@ type AliasTable = HashMap<ArchReg, PhysReg>;
 #[derive(Debug, Clone)]
 enum SpecType {
     Direct {
-        taken: bool,
+        prediction: DirectPrediction,
+        inst_pc: AbsPc, // The branch's own PC, the second of a fused pair
         taken_pc: AbsPc,
         not_taken_pc: AbsPc,
     },
     Indirect {
-        predicted_pc: Option<AbsPc>,
+        prediction: IndirectPrediction,
         inst_pc: AbsPc,
     },
     Memory {
@@ -39,20 +38,42 @@ pub struct SpecInfo {
     // Options because we snapshot during rename (so that everything is in-order)
     rat_cp: Option<AliasTable>,
     alloc_list: Option<Vec<PhysReg>>,
+    // Taken at fetch, after the speculating instruction has made its own predictor updates.
+    bp_cp: PredictorCheckpoint,
     info: SpecType,
 }
 
 #[derive(Clone)]
 pub struct PhysFile(Vec<PrfEntry>);
 
-#[derive(Debug, Clone)]
-pub struct RegFile {
+// Rename state private to one hardware thread. Speculation is tracked per thread, so a rollback
+// only ever touches the thread that mispredicted.
+#[derive(Debug, Clone, Default)]
+struct ThreadRename {
     rat: AliasTable,
-    phys_rf: PhysFile,
     prrt: VecDeque<PhysReg>,
     spec_info: HashMap<Tag, SpecInfo>,
 }
 
+// The physical registers are shared between every hardware thread.
+#[derive(Debug, Clone)]
+pub struct RegFile {
+    threads: Vec<ThreadRename>,
+    phys_rf: PhysFile,
+    // Number of RAT and PRRT entries naming each physical register. Eliminated moves let several
+    // architectural registers share one.
+    refs: Vec<u32>,
+    zero: PhysReg, // Always holds zero, for eliminated zero idioms.
+    move_elimination: bool,
+}
+
+// An instruction resolved entirely at rename, which never needs to issue.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Elimination {
+    Move,
+    ZeroIdiom,
+}
+
 // https://ece.uwaterloo.ca/~maagaard/ece720-t4/lec-05.pdf
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub enum PrfEntry {
@@ -62,28 +83,36 @@ pub enum PrfEntry {
 }
 
 impl RegFile {
-    pub fn new(initial_regs: RegSet, prf_capacity: usize) -> Self {
+    // One set of initial registers per hardware thread.
+    pub fn new(initial_regs: Vec<RegSet>, prf_capacity: usize, move_elimination: bool) -> Self {
         assert!(
-            ArchReg::iter().count() <= prf_capacity,
+            ArchReg::iter().count() * initial_regs.len() <= prf_capacity,
             "prf not large enough"
         );
-        assert!(PhysReg::try_from(prf_capacity).is_ok());
+        assert!(i32::try_from(prf_capacity).is_ok());
 
         let mut rf = Self {
-            rat: Default::default(),
+            threads: vec![ThreadRename::default(); initial_regs.len()],
             phys_rf: PhysFile(vec![PrfEntry::Free; prf_capacity]),
-            prrt: Default::default(),
-            spec_info: Default::default(),
+            refs: vec![0; prf_capacity],
+            zero: PhysReg::none(),
+            move_elimination,
         };
 
-        for reg in ArchReg::iter() {
-            if reg == ArchReg::Zero {
-                continue;
-            }
+        // Pinned with an extra reference, so it is never freed.
+        rf.zero = rf.allocate_phys_internal().unwrap();
+        rf.set_phys_active(rf.zero, 0);
 
-            let slot = rf.allocate_phys_internal().unwrap();
-            rf.set_phys_active(slot, initial_regs.get(reg));
-            rf.set_alias(reg, slot);
+        for (thread, initial_regs) in initial_regs.iter().enumerate() {
+            for reg in ArchReg::iter() {
+                if reg == ArchReg::Zero {
+                    continue;
+                }
+
+                let slot = rf.allocate_phys_internal().unwrap();
+                rf.set_phys_active(slot, initial_regs.get(reg));
+                rf.set_alias(thread, reg, slot);
+            }
         }
 
         rf
@@ -91,13 +120,23 @@ impl RegFile {
 
     #[allow(dead_code)]
     pub fn is_prrt_empty(&self) -> bool {
-        self.prrt.is_empty()
+        self.threads.iter().all(|t| t.prrt.is_empty())
+    }
+
+    fn thread(&self, tag: Tag) -> &ThreadRename {
+        &self.threads[tag.thread()]
     }
 
-    pub fn get_reg_set(self) -> RegSet {
-        let map: HashMap<ArchReg, u32> = self
+    fn thread_mut(&mut self, tag: Tag) -> &mut ThreadRename {
+        &mut self.threads[tag.thread()]
+    }
+
+    pub fn get_reg_set(&self, thread: usize) -> RegSet {
+        let map: HashMap<ArchReg, u32> = self.threads[thread]
             .rat
             .iter()
+            // Vector registers hold handles, which differ between models.
+            .filter(|(k, _)| k.vector_index().is_none())
             .map(|(&k, &v)| match self.phys_rf.0[usize::from(v)] {
                 PrfEntry::Active(v) => (k, v),
                 // _ => unreachable!(),
@@ -107,31 +146,38 @@ impl RegFile {
         RegSet::from(map)
     }
 
-    pub fn was_predicted_taken(&self, branch: Tag) -> bool {
+    pub fn direct_prediction(&self, branch: Tag) -> (DirectPrediction, AbsPc) {
         match self
+            .thread(branch)
             .spec_info
             .get(&branch)
             .expect("no branch info for direct branch")
             .info
         {
-            SpecType::Direct { taken, .. } => taken,
+            SpecType::Direct {
+                prediction,
+                inst_pc,
+                ..
+            } => (prediction, inst_pc),
             _ => unreachable!(),
         }
     }
 
     pub fn snapshot_for(&mut self, tag: Tag) {
-        if let Some(bi) = self.spec_info.get_mut(&tag) {
-            bi.rat_cp = Some(self.rat.clone());
+        let thread = self.thread_mut(tag);
+        if let Some(bi) = thread.spec_info.get_mut(&tag) {
+            bi.rat_cp = Some(thread.rat.clone());
             bi.alloc_list = Some(Vec::new());
         }
     }
 
-    pub fn begin_predict_mem(&mut self, load: Tag, pc: AbsPc) {
-        self.spec_info.insert(
+    pub fn begin_predict_mem(&mut self, load: Tag, pc: AbsPc, bp_cp: PredictorCheckpoint) {
+        self.thread_mut(load).spec_info.insert(
             load,
             SpecInfo {
                 rat_cp: None,
                 alloc_list: None,
+                bp_cp,
                 info: SpecType::Memory { next_pc: pc },
             },
         );
@@ -140,17 +186,21 @@ impl RegFile {
     pub fn begin_predict_direct(
         &mut self,
         branch: Tag,
-        taken: bool,
+        prediction: DirectPrediction,
+        inst_pc: AbsPc,
         taken_pc: AbsPc,
         not_taken_pc: AbsPc,
+        bp_cp: PredictorCheckpoint,
     ) {
-        self.spec_info.insert(
+        self.thread_mut(branch).spec_info.insert(
             branch,
             SpecInfo {
                 rat_cp: None,
                 alloc_list: None,
+                bp_cp,
                 info: SpecType::Direct {
-                    taken,
+                    prediction,
+                    inst_pc,
                     taken_pc,
                     not_taken_pc,
                 },
@@ -161,27 +211,34 @@ impl RegFile {
     pub fn begin_predict_indirect(
         &mut self,
         branch: Tag,
-        predicted_pc: Option<AbsPc>,
+        prediction: IndirectPrediction,
         inst_pc: AbsPc,
+        bp_cp: PredictorCheckpoint,
     ) {
-        self.spec_info.insert(
+        self.thread_mut(branch).spec_info.insert(
             branch,
             SpecInfo {
                 rat_cp: None,
                 alloc_list: None,
+                bp_cp,
                 info: SpecType::Indirect {
-                    predicted_pc,
+                    prediction,
                     inst_pc,
                 },
             },
         );
     }
 
-    pub fn end_predict_mem(&mut self, load: Tag, correct: bool) -> Option<AbsPc> {
-        match self.spec_info.remove(&load) {
+    pub fn end_predict_mem(
+        &mut self,
+        load: Tag,
+        correct: bool,
+        branch_predictor: &mut BranchPredictor,
+    ) -> Option<AbsPc> {
+        match self.thread_mut(load).spec_info.remove(&load) {
             Some(spec_info) => {
                 if !correct {
-                    self.mispredict(load, &spec_info);
+                    self.mispredict(load, &spec_info, None, branch_predictor);
 
                     if let SpecType::Memory { next_pc } = spec_info.info {
                         Some(next_pc)
@@ -203,11 +260,15 @@ impl RegFile {
         predicted_pc: Option<AbsPc>,
         branch_predictor: &mut BranchPredictor,
     ) {
-        let branch_info = self.spec_info.remove(&branch).unwrap();
+        let branch_info = self.thread_mut(branch).spec_info.remove(&branch).unwrap();
 
         match branch_info.info {
-            SpecType::Indirect { inst_pc, .. } => {
-                branch_predictor.update_predict_indirect(inst_pc, actual_pc);
+            SpecType::Indirect {
+                inst_pc,
+                prediction,
+                ..
+            } => {
+                branch_predictor.update_predict_indirect(inst_pc, actual_pc, prediction);
             }
             _ => unreachable!(),
         }
@@ -216,7 +277,7 @@ impl RegFile {
             .map(|predicted_pc| actual_pc != predicted_pc)
             .unwrap_or(false)
         {
-            self.mispredict(branch, &branch_info);
+            self.mispredict(branch, &branch_info, Some(actual_pc), branch_predictor);
         }
     }
 
@@ -224,22 +285,34 @@ impl RegFile {
         &mut self,
         branch: Tag,
         taken: bool,
-        predicted: bool,
         branch_predictor: &mut BranchPredictor,
     ) -> Option<AbsPc> {
-        let branch_info = self.spec_info.remove(&branch).unwrap();
+        let branch_info = self.thread_mut(branch).spec_info.remove(&branch).unwrap();
 
-        match branch_info.info {
-            SpecType::Direct { not_taken_pc, .. } => {
-                let inst_pc = not_taken_pc - INST_SIZE;
-                branch_predictor.update_predict_direct(inst_pc, taken);
-            }
+        let (prediction, inst_pc) = match branch_info.info {
+            SpecType::Direct {
+                prediction,
+                inst_pc,
+                ..
+            } => (prediction, inst_pc),
             _ => unreachable!(),
+        };
+
+        let mispredicted = taken != prediction.taken;
+
+        // Roll back the predictor's speculative state before training it on the outcome.
+        if mispredicted {
+            let taken_pc = match branch_info.info {
+                SpecType::Direct { taken_pc, .. } => taken_pc,
+                _ => unreachable!(),
+            };
+            let target = taken.then_some(taken_pc);
+            self.mispredict(branch, &branch_info, target, branch_predictor);
         }
 
-        if taken != predicted {
-            self.mispredict(branch, &branch_info);
+        branch_predictor.update_predict_direct(inst_pc, taken, prediction);
 
+        if mispredicted {
             match branch_info.info {
                 SpecType::Direct {
                     taken_pc,
@@ -259,22 +332,35 @@ impl RegFile {
         }
     }
 
-    fn mispredict(&mut self, tag: Tag, info: &SpecInfo) {
+    // `taken_target` is where a branch actually went, which goes into the path history in place
+    // of its prediction.
+    fn mispredict(
+        &mut self,
+        tag: Tag,
+        info: &SpecInfo,
+        taken_target: Option<AbsPc>,
+        branch_predictor: &mut BranchPredictor,
+    ) {
         let alloc_list = info.alloc_list.as_ref().unwrap();
-        self.rat = info.rat_cp.as_ref().unwrap().clone();
+        self.thread_mut(tag).rat = info.rat_cp.as_ref().unwrap().clone();
+        branch_predictor.restore(info.bp_cp);
+        if let Some(target) = taken_target {
+            branch_predictor.push_path(target);
+        }
 
         let num_removed = alloc_list.len();
 
         for &phys_reg in alloc_list {
-            self.phys_rf.0[usize::from(phys_reg)] = PrfEntry::Free;
-            self.prrt.pop_back().unwrap();
+            self.unref_phys(phys_reg);
+            self.thread_mut(tag).prrt.pop_back().unwrap();
         }
 
+        let thread = self.thread_mut(tag);
         // self.branch_info.iter().for_each(|(&t, _)| debug_assert!(t >= tag));
-        self.spec_info.retain(|&t, _| t <= tag);
+        thread.spec_info.retain(|&t, _| t <= tag);
 
         // Is this needed?
-        for si in self.spec_info.values_mut() {
+        for si in thread.spec_info.values_mut() {
             for _ in 0..num_removed {
                 si.alloc_list.as_mut().map(|al| al.pop());
             }
@@ -286,44 +372,64 @@ impl RegFile {
         // self.spec_info
         //     .iter()
         //     .for_each(|(&t, _)| debug_assert!(t <= tag));
-        self.spec_info.retain(|t, _| t <= &tag);
+        self.thread_mut(tag).spec_info.retain(|t, _| t <= &tag);
     }
 
     fn allocate_phys_internal(&mut self) -> Option<PhysReg> {
         let slot = self.phys_rf.0.iter().position(|&r| r == PrfEntry::Free)?;
         self.phys_rf.0[slot] = PrfEntry::Reserved;
+        self.refs[slot] = 1;
 
         Some(PhysReg::from(slot))
     }
 
-    pub fn allocate_phys(&mut self, _tag: Tag) -> Option<PhysReg> {
-        self.allocate_phys_internal().map(|slot| {
-            for branch in self.spec_info.values_mut() {
-                if let Some(al) = branch.alloc_list.as_mut() {
-                    al.push(slot)
-                }
+    pub fn allocate_phys(&mut self, tag: Tag) -> Option<PhysReg> {
+        let slot = self.allocate_phys_internal()?;
+        self.track_alloc(tag, slot);
+        Some(slot)
+    }
+
+    // Share an existing physical register instead of allocating a new one.
+    fn share_phys(&mut self, tag: Tag, slot: PhysReg) {
+        self.refs[usize::from(slot)] += 1;
+        self.track_alloc(tag, slot);
+    }
+
+    // Every speculation in flight in the thread must give this reference back if it is rolled
+    // back.
+    fn track_alloc(&mut self, tag: Tag, slot: PhysReg) {
+        for branch in self.thread_mut(tag).spec_info.values_mut() {
+            if let Some(al) = branch.alloc_list.as_mut() {
+                al.push(slot)
             }
+        }
+    }
 
-            slot
-        })
+    fn unref_phys(&mut self, slot: PhysReg) {
+        let refs = &mut self.refs[usize::from(slot)];
+        *refs -= 1;
+        if *refs == 0 {
+            self.phys_rf.0[usize::from(slot)] = PrfEntry::Free;
+        }
     }
 
-    pub fn release_phys(&mut self, _tag: Tag) {
+    pub fn release_phys(&mut self, tag: Tag) {
         let slot = self
+            .thread_mut(tag)
             .prrt
             .pop_front()
             .expect("released PRRT entry when none was allocated");
-        let slot = &mut self.phys_rf.0[usize::from(slot)];
-        assert!(*slot != PrfEntry::Free && *slot != PrfEntry::Reserved);
-        *slot = PrfEntry::Free;
+        let entry = self.phys_rf.0[usize::from(slot)];
+        assert!(entry != PrfEntry::Free && entry != PrfEntry::Reserved);
+        self.unref_phys(slot);
     }
 
-    pub fn get_alias(&self, arch_reg: ArchReg) -> PhysReg {
+    pub fn get_alias(&self, thread: usize, arch_reg: ArchReg) -> PhysReg {
         if arch_reg == ArchReg::Zero {
-            return PhysReg::try_from(0).unwrap();
+            return self.zero;
         }
 
-        self.rat.get(&arch_reg).copied().unwrap()
+        self.threads[thread].rat.get(&arch_reg).copied().unwrap()
     }
 
     pub fn get_phys(&self, phys_reg: PhysReg) -> PrfEntry {
@@ -338,24 +444,68 @@ impl RegFile {
         self.phys_rf.0[usize::from(phys_reg)] = PrfEntry::Active(val);
     }
 
-    pub fn set_alias(&mut self, arch_reg: ArchReg, phys_reg: PhysReg) {
+    pub fn set_alias(&mut self, thread: usize, arch_reg: ArchReg, phys_reg: PhysReg) {
         if arch_reg == ArchReg::Zero {
             unreachable!();
         }
 
-        self.rat.insert(arch_reg, phys_reg);
+        self.threads[thread].rat.insert(arch_reg, phys_reg);
     }
 
-    pub fn perform_rename(&mut self, tag: Tag, inst: Inst) -> Option<RenamedInst> {
-        self.snapshot_for(tag);
+    // Moves share their source's physical register, and anything that always produces zero shares
+    // the zero register.
+    fn elimination(&self, thread: usize, inst: &Inst) -> Option<(Elimination, PhysReg)> {
+        if !self.move_elimination {
+            return None;
+        }
+
+        let (elimination, src) = match *inst {
+            Inst::AddImm(ArchReg::Zero, _, _)
+            | Inst::Add(ArchReg::Zero, _, _)
+            | Inst::Xor(ArchReg::Zero, _, _)
+            | Inst::Sub(ArchReg::Zero, _, _) => return None,
+            Inst::AddImm(_, src, Imm(0))
+            | Inst::Add(_, src, ArchReg::Zero)
+            | Inst::Add(_, ArchReg::Zero, src) => (Elimination::Move, src),
+            Inst::Xor(_, src0, src1) | Inst::Sub(_, src0, src1) if src0 == src1 => {
+                (Elimination::ZeroIdiom, ArchReg::Zero)
+            }
+            _ => return None,
+        };
+
+        if src == ArchReg::Zero {
+            Some((Elimination::ZeroIdiom, self.zero))
+        } else {
+            Some((elimination, self.get_alias(thread, src)))
+        }
+    }
+
+    pub fn perform_rename(
+        &mut self,
+        tag: Tag,
+        inst: Inst,
+    ) -> Option<(RenamedInst, Option<Elimination>)> {
+        // A mis-speculated load is executed again, so it rolls back to before its own rename. A
+        // branch keeps its result (the link register, or a fused compare), so it rolls back to
+        // just after.
+        let thread = tag.thread();
+        let is_branch = matches!(
+            self.threads[thread].spec_info.get(&tag).map(|si| &si.info),
+            Some(SpecType::Direct { .. } | SpecType::Indirect { .. })
+        );
+        if !is_branch {
+            self.snapshot_for(tag);
+        }
+
+        let elimination = self.elimination(thread, &inst);
 
         // Have to do this in two separate steps to prevent borrowing issues.
         let renamed_inst = inst.map_src_regs(|src_reg| match src_reg {
             ArchReg::Zero => ValueOrReg::Value(0),
-            src_reg => ValueOrReg::Reg(self.get_alias(src_reg)),
+            src_reg => ValueOrReg::Reg(self.get_alias(thread, src_reg)),
         });
 
-        renamed_inst.try_map(
+        let renamed_inst = renamed_inst.try_map(
             Some,
             |dst_reg| {
                 if dst_reg == ArchReg::Zero {
@@ -363,11 +513,20 @@ impl RegFile {
                         arch: ArchReg::Zero,
                         phys: PhysReg::none(),
                     })
+                } else if let Some((_, slot)) = elimination {
+                    self.share_phys(tag, slot);
+                    let old_phys = self.get_alias(thread, dst_reg);
+                    self.threads[thread].prrt.push_back(old_phys);
+                    self.set_alias(thread, dst_reg, slot);
+                    Some(BothReg {
+                        arch: dst_reg,
+                        phys: slot,
+                    })
                 } else if let Some(slot) = self.allocate_phys(tag) {
                     // Prepare the old PhysReg for reclaim.
-                    let old_phys = self.get_alias(dst_reg);
-                    self.prrt.push_back(old_phys);
-                    self.set_alias(dst_reg, slot);
+                    let old_phys = self.get_alias(thread, dst_reg);
+                    self.threads[thread].prrt.push_back(old_phys);
+                    self.set_alias(thread, dst_reg, slot);
                     Some(BothReg {
                         arch: dst_reg,
                         phys: slot,
@@ -377,15 +536,28 @@ impl RegFile {
                 }
             },
             Some,
-        )
+        );
+
+        if is_branch && renamed_inst.is_some() {
+            self.snapshot_for(tag);
+        }
+
+        renamed_inst.map(|inst| (inst, elimination.map(|(elimination, _)| elimination)))
     }
 
-    pub fn predicted_addr(&self, tag: Tag) -> (Option<AbsPc>, AbsPc) {
-        match self.spec_info.get(&tag).expect("no branch info").info {
+    pub fn predicted_addr(&self, tag: Tag) -> (IndirectPrediction, AbsPc) {
+        match self
+            .thread(tag)
+            .spec_info
+            .get(&tag)
+            .expect("no branch info")
+            .info
+        {
             SpecType::Indirect {
-                predicted_pc,
+                prediction,
                 inst_pc,
-            } => (predicted_pc, inst_pc),
+                ..
+            } => (prediction, inst_pc),
             _ => unreachable!(),
         }
     }
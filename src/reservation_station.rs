@@ -1,74 +1,271 @@
+use hashbrown::HashMap;
+
 use crate::{
-    inst::{ReadyInst, RenamedInst, Tag},
-    regs::RegFile,
+    execution_unit::{EuType, ExecutionUnit},
+    inst::{PhysReg, ReadyInst, RenamedInst, Tag, ValueOrReg},
+    regs::{PrfEntry, RegFile},
 };
 
+// Destinations of issued loads, with the cycle their value is expected assuming an L1 hit.
+pub type LoadWakeups = HashMap<PhysReg, (Tag, u64)>;
+
+// How the scheduler is split into queues. The capacity is per queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerTopology {
+    // A single queue feeding every unit.
+    Unified { capacity: usize },
+    // One queue per class of execution unit.
+    PerClass { capacity: usize },
+    // x86-style issue ports, each issuing at most one instruction a cycle. Every ALU and
+    // load/store unit gets a port, the remaining units share the ALU ports.
+    Ports { capacity: usize },
+}
+
+// The order woken instructions are selected for issue in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssuePolicy {
+    OldestFirst,
+    Random,
+    CriticalPath, // Longest latency first, as a cheap stand-in for the critical path
+    LoadFirst,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    tag: Tag,
+    inst: RenamedInst,
+    selectable_from: Option<u64>, // None until woken up
+    replayed: bool,               // Speculative wakeups are ignored after a replay
+}
+
 #[derive(Debug, Clone)]
 pub struct ReservationStation {
-    waiting: Vec<(Tag, RenamedInst)>,
-    ready: Vec<(Tag, ReadyInst)>,
+    pub name: String,
+    pub units: Vec<usize>, // Indices of the execution units this queue issues to
+    pub issue_width: usize,
+    pub stalls: u64,
+    entries: Vec<Entry>, // Sorted by tag
     capacity: usize,
+    rng: u64,
+}
+
+impl Default for SchedulerTopology {
+    fn default() -> Self {
+        Self::Unified { capacity: 100 }
+    }
+}
+
+impl SchedulerTopology {
+    pub fn build(&self, eus: &[ExecutionUnit]) -> Vec<ReservationStation> {
+        match *self {
+            SchedulerTopology::Unified { capacity } => {
+                let units = (0..eus.len()).collect::<Vec<_>>();
+                vec![ReservationStation::new(
+                    "Unified",
+                    capacity,
+                    units.clone(),
+                    units.len(),
+                )]
+            }
+            SchedulerTopology::PerClass { capacity } => {
+                let mut queues: Vec<ReservationStation> = Vec::new();
+                for (i, eu) in eus.iter().enumerate() {
+                    let name = format!("{:?}", eu.eu_type);
+                    match queues.iter_mut().find(|q| q.name == name) {
+                        Some(q) => {
+                            q.units.push(i);
+                            q.issue_width += 1;
+                        }
+                        None => queues.push(ReservationStation::new(&name, capacity, vec![i], 1)),
+                    }
+                }
+                queues
+            }
+            SchedulerTopology::Ports { capacity } => {
+                let mut ports = eus
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, eu)| matches!(eu.eu_type, EuType::Alu | EuType::LoadStore))
+                    .map(|(i, _)| vec![i])
+                    .collect::<Vec<_>>();
+                let alu_ports = ports
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, units)| eus[units[0]].eu_type == EuType::Alu)
+                    .map(|(p, _)| p)
+                    .collect::<Vec<_>>();
+                assert!(!alu_ports.is_empty(), "issue ports need at least one ALU");
+
+                let shared = eus
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, eu)| !matches!(eu.eu_type, EuType::Alu | EuType::LoadStore));
+                for (n, (i, _)) in shared.enumerate() {
+                    ports[alu_ports[n % alu_ports.len()]].push(i);
+                }
+
+                ports
+                    .into_iter()
+                    .enumerate()
+                    .map(|(p, units)| {
+                        ReservationStation::new(&format!("Port{p}"), capacity, units, 1)
+                    })
+                    .collect()
+            }
+        }
+    }
 }
 
 impl ReservationStation {
-    pub fn new(capacity: usize) -> Self {
+    pub fn new(name: &str, capacity: usize, units: Vec<usize>, issue_width: usize) -> Self {
         Self {
+            name: name.to_owned(),
+            units,
+            issue_width,
+            stalls: 0,
             capacity,
-            waiting: Default::default(),
-            ready: Default::default(),
+            entries: Default::default(),
+            rng: 0x2545_f491_4f6c_dd1d,
         }
     }
 
+    pub fn accepts(&self, eu_type: EuType, eus: &[ExecutionUnit]) -> bool {
+        self.units.iter().any(|&i| eus[i].eu_type == eu_type)
+    }
+
+    pub fn occupancy(&self) -> usize {
+        self.entries.len()
+    }
+
     pub fn is_full(&self) -> bool {
-        self.waiting.len() + self.ready.len() >= self.capacity
+        self.occupancy() >= self.capacity
     }
 
     pub fn insert(&mut self, tag: Tag, inst: RenamedInst) {
         debug_assert!(!self.is_full());
         debug_assert!(
-            !self.waiting.iter().any(|(t, _)| *t == tag),
+            !self.entries.iter().any(|e| e.tag == tag),
             "inst {:?} already in RS",
             tag
         );
-        // debug_assert!(!self.ready.iter().any(|(t, _)| *t == tag));
-        self.waiting.push((tag, inst));
+        self.insert_entry(Entry {
+            tag,
+            inst,
+            selectable_from: None,
+            replayed: false,
+        });
     }
 
+    // Put back an instruction that was killed after issuing, it can be selected straight away.
     pub fn insert_ready(&mut self, tag: Tag, inst: ReadyInst) {
+        let inst = inst.map_src_regs(ValueOrReg::Value);
+        self.insert_entry(Entry {
+            tag,
+            inst,
+            selectable_from: Some(0),
+            replayed: false,
+        });
+    }
+
+    fn insert_entry(&mut self, entry: Entry) {
         let pos = self
-            .ready
-            .binary_search_by_key(&tag, |(t, _)| *t)
+            .entries
+            .binary_search_by_key(&entry.tag, |e| e.tag)
             .unwrap_err();
-        self.ready.insert(pos, (tag, inst));
+        self.entries.insert(pos, entry);
     }
 
-    // TODO: This interface (get_ready, pop_ready) is a bit janky
-    pub fn get_ready(&mut self, reg_file: &RegFile) -> impl Iterator<Item = &(Tag, ReadyInst)> {
-        self.waiting.retain(
-            |(tag, renamed_inst)| match renamed_inst.get_ready(reg_file) {
-                Some(ready_inst) => {
-                    // TODO: use linear search?
-                    let pos = self
-                        .ready
-                        .binary_search_by_key(&tag, |(t, _)| t)
-                        .unwrap_err();
-                    self.ready.insert(pos, (*tag, ready_inst));
-                    false
+    // Wake up instructions whose operands are available, then return the ones that can be
+    // selected this cycle in policy order. None means a speculatively woken instruction whose
+    // load missed, which has to be replayed.
+    pub fn select(
+        &mut self,
+        reg_file: &RegFile,
+        load_wakeups: &LoadWakeups,
+        now: u64,
+        wakeup_latency: u64,
+        policy: IssuePolicy,
+    ) -> Vec<(Tag, Option<ReadyInst>)> {
+        for entry in &mut self.entries {
+            if entry.selectable_from.is_some() {
+                continue;
+            }
+
+            // The wakeup broadcast for a load goes out early enough for a dependent to be
+            // selected as the data arrives.
+            let replayed = entry.replayed;
+            let mut selectable_from = now + wakeup_latency;
+            let woken = entry.inst.clone().try_map(
+                |r| match r {
+                    ValueOrReg::Value(_) => Some(()),
+                    ValueOrReg::Reg(phys) => {
+                        if matches!(reg_file.get_phys(phys), PrfEntry::Active(_)) {
+                            Some(())
+                        } else {
+                            let (_, cycle) = load_wakeups.get(&phys).filter(|_| !replayed)?;
+                            selectable_from = selectable_from.max(*cycle);
+                            Some(())
+                        }
+                    }
+                },
+                Some,
+                Some,
+            );
+
+            if woken.is_some() {
+                entry.selectable_from = Some(selectable_from);
+            }
+        }
+
+        let mut candidates = self
+            .entries
+            .iter()
+            .filter(|e| e.selectable_from.is_some_and(|c| c <= now))
+            .collect::<Vec<_>>();
+
+        match policy {
+            IssuePolicy::OldestFirst => {}
+            IssuePolicy::Random => {
+                for i in (1..candidates.len()).rev() {
+                    // xorshift64
+                    self.rng ^= self.rng << 13;
+                    self.rng ^= self.rng >> 7;
+                    self.rng ^= self.rng << 17;
+                    candidates.swap(i, (self.rng % (i as u64 + 1)) as usize);
                 }
-                None => true,
-            },
-        );
+            }
+            IssuePolicy::CriticalPath => {
+                candidates.sort_by_key(|e| std::cmp::Reverse(e.inst.latency()));
+            }
+            IssuePolicy::LoadFirst => candidates.sort_by_key(|e| !e.inst.is_load()),
+        }
 
-        self.ready.iter()
+        candidates
+            .into_iter()
+            .map(|e| (e.tag, e.inst.get_ready(reg_file)))
+            .collect()
+    }
+
+    // Send a speculatively woken instruction back to wait for its operands.
+    pub fn replay(&mut self, tag: Tag) {
+        let entry = self.entries.iter_mut().find(|e| e.tag == tag).unwrap();
+        entry.selectable_from = None;
+        entry.replayed = true;
     }
 
     pub fn pop_ready(&mut self, tag: Tag) {
-        let pos = self.ready.iter().position(|&(t, _)| t == tag).unwrap();
-        self.ready.remove(pos);
+        let pos = self.entries.iter().position(|e| e.tag == tag).unwrap();
+        self.entries.remove(pos);
+    }
+
+    pub fn thread_occupancy(&self, thread: usize) -> usize {
+        self.entries
+            .iter()
+            .filter(|e| e.tag.thread() == thread)
+            .count()
     }
 
     pub fn kill_tags_after(&mut self, tag: Tag) {
-        self.waiting.retain(|&(t, _)| t <= tag);
-        self.ready.retain(|&(t, _)| t <= tag);
+        self.entries.retain(|e| !e.tag.is_after(tag));
     }
 }
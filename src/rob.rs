@@ -1,5 +1,7 @@
 use crate::{
-    inst::{Inst, Tag, Tagged},
+    inst::{Inst, Tag, Tagged, MAX_THREADS},
+    mem::MemFault,
+    mmu::PageFault,
     queue::Queue,
 };
 
@@ -7,6 +9,8 @@ use crate::{
 pub enum RobStatus {
     Executing,
     Executed,
+    Faulted(MemFault),   // Stops the program instead of committing
+    Excepted(PageFault), // Traps instead of committing
 }
 
 #[derive(Debug, Clone)]
@@ -19,12 +23,14 @@ pub struct RobEntry {
 #[derive(Debug, Clone)]
 pub struct ReorderBuffer {
     rob: Queue<RobEntry>,
+    excepted: Vec<Tag>,
 }
 
 impl ReorderBuffer {
     pub fn new(capacity: usize) -> Self {
         Self {
             rob: Queue::new(capacity),
+            excepted: Vec::new(),
         }
     }
 
@@ -50,24 +56,76 @@ impl ReorderBuffer {
             .map(|ent| ent.inst)
     }
 
+    // Threads retire independently, so this takes the oldest entry that is at the head of its own
+    // thread and has executed.
     pub fn try_pop(&mut self) -> Option<Tagged<Inst>> {
-        if self
-            .rob
-            .front()
-            .map(|ent| ent.status == RobStatus::Executed)
-            .unwrap_or(false)
-        {
-            self.rob.try_pop().map(|ent| Tagged {
-                tag: ent.tag,
-                inst: ent.inst,
-            })
-        } else {
-            None
+        let mut seen = [false; MAX_THREADS];
+        let mut pos = None;
+
+        for (i, ent) in self.rob.iter().enumerate() {
+            if std::mem::replace(&mut seen[ent.tag.thread()], true) {
+                continue;
+            }
+
+            match ent.status {
+                RobStatus::Executed => {
+                    pos = Some(i);
+                    break;
+                }
+                RobStatus::Executing | RobStatus::Faulted(_) | RobStatus::Excepted(_) => (),
+            }
+
+            if seen.iter().all(|&s| s) {
+                break;
+            }
         }
+
+        self.rob.remove(pos?).map(|ent| Tagged {
+            tag: ent.tag,
+            inst: ent.inst,
+        })
+    }
+
+    pub fn is_thread_empty(&self, thread: usize) -> bool {
+        !self.rob.iter().any(|ent| ent.tag.thread() == thread)
+    }
+
+    // Everything older from the same thread has committed.
+    pub fn is_thread_head(&self, tag: Tag) -> bool {
+        self.rob
+            .iter()
+            .find(|ent| ent.tag.thread() == tag.thread())
+            .is_some_and(|ent| ent.tag == tag)
+    }
+
+    // An instruction at the head of its thread that has to trap, along with why.
+    pub fn head_exception(&self) -> Option<(Tag, PageFault)> {
+        self.excepted
+            .iter()
+            .filter(|&&tag| self.is_thread_head(tag))
+            .find_map(
+                |&tag| match self.rob.iter().find(|ent| ent.tag == tag)?.status {
+                    RobStatus::Excepted(fault) => Some((tag, fault)),
+                    _ => None,
+                },
+            )
+    }
+
+    // An access at the head of its thread that stops the thread, along with why.
+    pub fn head_fault(&self) -> Option<(Tag, MemFault)> {
+        let mut seen = [false; MAX_THREADS];
+        self.rob
+            .iter()
+            .filter(|ent| !std::mem::replace(&mut seen[ent.tag.thread()], true))
+            .find_map(|ent| match ent.status {
+                RobStatus::Faulted(fault) => Some((ent.tag, fault)),
+                _ => None,
+            })
     }
 
     pub fn kill_tags_after(&mut self, tag: Tag) {
-        self.rob.retain(|ent| ent.tag <= tag);
+        self.rob.retain(|ent| !ent.tag.is_after(tag));
+        self.excepted.retain(|t| !t.is_after(tag));
     }
 
     pub fn mark_complete(&mut self, tag: Tag) {
@@ -79,4 +137,25 @@ impl ReorderBuffer {
 
         ent.status = RobStatus::Executed;
     }
+
+    pub fn mark_faulted(&mut self, tag: Tag, fault: MemFault) {
+        let ent = self
+            .rob
+            .iter_mut()
+            .find(|ent| ent.tag == tag)
+            .expect("no entry found in ROB");
+
+        ent.status = RobStatus::Faulted(fault);
+    }
+
+    pub fn mark_excepted(&mut self, tag: Tag, fault: PageFault) {
+        let ent = self
+            .rob
+            .iter_mut()
+            .find(|ent| ent.tag == tag)
+            .expect("no entry found in ROB");
+
+        ent.status = RobStatus::Excepted(fault);
+        self.excepted.push(tag);
+    }
 }
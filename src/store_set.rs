@@ -0,0 +1,110 @@
+use crate::inst::{AbsPc, Tag};
+
+const SSIT_ENTRIES: usize = 1024;
+const LFST_ENTRIES: usize = 128;
+
+type StoreSetId = usize;
+
+// Store set memory dependence predictor (Chrysos & Emer). Loads and stores that have been seen
+// to conflict are put in the same store set, and a load then waits for the most recent store
+// fetched from its set, rather than for every older store.
+#[derive(Debug, Clone)]
+pub struct StoreSets {
+    ssit: Vec<Option<StoreSetId>>,   // Store set ID table, indexed by PC.
+    lfst: Vec<Option<(AbsPc, Tag)>>, // Last fetched store table, indexed by store set ID.
+    next_id: StoreSetId,
+}
+
+impl StoreSets {
+    pub fn new() -> Self {
+        Self {
+            ssit: vec![None; SSIT_ENTRIES],
+            lfst: vec![None; LFST_ENTRIES],
+            next_id: 0,
+        }
+    }
+
+    fn index(pc: AbsPc) -> usize {
+        pc.table_index() as usize % SSIT_ENTRIES
+    }
+
+    // The store a newly renamed load should wait for, if any.
+    pub fn load_renamed(&self, pc: AbsPc) -> Option<Tag> {
+        let id = self.ssit[Self::index(pc)]?;
+        self.lfst[id].map(|(_, tag)| tag)
+    }
+
+    pub fn store_renamed(&mut self, pc: AbsPc, tag: Tag) {
+        if let Some(id) = self.ssit[Self::index(pc)] {
+            self.lfst[id] = Some((pc, tag));
+        }
+    }
+
+    // Once a store's address is known, loads in its set no longer need to wait for it.
+    pub fn store_issued(&mut self, pc: AbsPc, tag: Tag) {
+        if let Some(id) = self.ssit[Self::index(pc)] {
+            if self.lfst[id] == Some((pc, tag)) {
+                self.lfst[id] = None;
+            }
+        }
+    }
+
+    // A load executed before an older store to the same address, so put them in the same set.
+    pub fn violation(&mut self, load_pc: AbsPc, store_pc: AbsPc) {
+        let (load, store) = (Self::index(load_pc), Self::index(store_pc));
+
+        let id = match (self.ssit[load], self.ssit[store]) {
+            (None, None) => {
+                let id = self.next_id;
+                self.next_id = (self.next_id + 1) % LFST_ENTRIES;
+                id
+            }
+            (Some(id), None) | (None, Some(id)) => id,
+            // Merge towards the smaller ID, so both sides agree on one set.
+            (Some(a), Some(b)) => a.min(b),
+        };
+
+        self.ssit[load] = Some(id);
+        self.ssit[store] = Some(id);
+    }
+}
+
+impl Default for StoreSets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_waits_after_violation() {
+        let mut ss = StoreSets::new();
+        let (load_pc, store_pc) = (AbsPc(0x40), AbsPc(0x20));
+
+        ss.store_renamed(store_pc, Tag(1));
+        assert_eq!(ss.load_renamed(load_pc), None);
+
+        ss.violation(load_pc, store_pc);
+        ss.store_renamed(store_pc, Tag(5));
+        assert_eq!(ss.load_renamed(load_pc), Some(Tag(5)));
+
+        ss.store_issued(store_pc, Tag(5));
+        assert_eq!(ss.load_renamed(load_pc), None);
+    }
+
+    #[test]
+    fn test_sets_merge() {
+        let mut ss = StoreSets::new();
+        ss.violation(AbsPc(0x40), AbsPc(0x20));
+        ss.violation(AbsPc(0x80), AbsPc(0x60));
+
+        // The second load also conflicts with the first store.
+        ss.violation(AbsPc(0x80), AbsPc(0x20));
+        ss.store_renamed(AbsPc(0x20), Tag(9));
+        assert_eq!(ss.load_renamed(AbsPc(0x80)), Some(Tag(9)));
+        assert_eq!(ss.load_renamed(AbsPc(0x40)), Some(Tag(9)));
+    }
+}
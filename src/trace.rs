@@ -0,0 +1,243 @@
+use std::io::{self, Read, Write};
+
+use crate::{
+    branch::BranchPredictor,
+    inst::{AbsPc, ArchReg, Imm, Inst, COMPRESSED_INST_SIZE, INST_SIZE},
+};
+
+const MAGIC: &[u8; 4] = b"BTRC";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BranchKind {
+    Conditional,
+    Jump,
+    Call,
+    Return,
+    Indirect,
+    IndirectCall,
+}
+
+// One resolved branch. For conditional branches `target` is the taken target, whether or not
+// the branch was taken.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BranchRecord {
+    pub kind: BranchKind,
+    pub pc: AbsPc,
+    pub target: AbsPc,
+    pub taken: bool,
+    pub compressed: bool, // Calls return two bytes on rather than four
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BranchTrace {
+    pub insts: u64,
+    pub records: Vec<BranchRecord>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReplayStats {
+    pub insts: u64,
+    pub direct_predicts: u64,
+    pub direct_mispredicts: u64,
+    pub indirect_predicts: u64,
+    pub indirect_mispredicts: u64,
+}
+
+// Anything that can be driven by a branch trace. Returns whether the branch was predicted
+// correctly, and must train itself on the outcome before returning.
+pub trait TracePredictor {
+    fn replay(&mut self, record: &BranchRecord) -> bool;
+}
+
+impl BranchKind {
+    fn from_u8(x: u8) -> Option<Self> {
+        Some(match x {
+            0 => BranchKind::Conditional,
+            1 => BranchKind::Jump,
+            2 => BranchKind::Call,
+            3 => BranchKind::Return,
+            4 => BranchKind::Indirect,
+            5 => BranchKind::IndirectCall,
+            _ => return None,
+        })
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            BranchKind::Conditional => 0,
+            BranchKind::Jump => 1,
+            BranchKind::Call => 2,
+            BranchKind::Return => 3,
+            BranchKind::Indirect => 4,
+            BranchKind::IndirectCall => 5,
+        }
+    }
+
+    pub fn is_indirect(self) -> bool {
+        matches!(
+            self,
+            BranchKind::Return | BranchKind::Indirect | BranchKind::IndirectCall
+        )
+    }
+
+    // A representative instruction, for predictors which decode the branch.
+    fn to_inst(self, target: AbsPc) -> Inst {
+        match self {
+            BranchKind::Conditional => Inst::BranchIfNotEqual(ArchReg::T0, ArchReg::T1, target),
+            BranchKind::Jump => Inst::JumpAndLink(ArchReg::Zero, target),
+            BranchKind::Call => Inst::JumpAndLink(ArchReg::RA, target),
+            BranchKind::Return => Inst::JumpAndLinkRegister(ArchReg::Zero, ArchReg::RA, Imm(0)),
+            BranchKind::Indirect => Inst::JumpAndLinkRegister(ArchReg::Zero, ArchReg::T0, Imm(0)),
+            BranchKind::IndirectCall => Inst::JumpAndLinkRegister(ArchReg::RA, ArchReg::T0, Imm(0)),
+        }
+    }
+}
+
+impl BranchTrace {
+    const RECORD_SIZE: usize = 10;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Called for every executed instruction, with its size and the PC it went on to.
+    pub fn record(&mut self, inst: &Inst, pc: AbsPc, size: u32, next_pc: AbsPc) {
+        self.insts += 1;
+
+        let (kind, target, taken) = match inst {
+            Inst::BranchIfEqual(_, _, tgt)
+            | Inst::BranchIfNotEqual(_, _, tgt)
+            | Inst::BranchIfLess(_, _, tgt)
+            | Inst::BranchIfLessU(_, _, tgt)
+            | Inst::BranchIfGreaterEqual(_, _, tgt)
+            | Inst::BranchIfGreaterEqualU(_, _, tgt) => {
+                (BranchKind::Conditional, *tgt, next_pc != pc + size)
+            }
+            Inst::JumpAndLink(_, tgt) if inst.is_call() => (BranchKind::Call, *tgt, true),
+            Inst::JumpAndLink(_, tgt) => (BranchKind::Jump, *tgt, true),
+            _ if inst.is_return() => (BranchKind::Return, next_pc, true),
+            Inst::JumpAndLinkRegister(_, _, _) if inst.is_call() => {
+                (BranchKind::IndirectCall, next_pc, true)
+            }
+            Inst::JumpAndLinkRegister(_, _, _) => (BranchKind::Indirect, next_pc, true),
+            _ => return,
+        };
+
+        self.records.push(BranchRecord {
+            kind,
+            pc,
+            target,
+            taken,
+            compressed: size == COMPRESSED_INST_SIZE,
+        });
+    }
+
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&self.insts.to_le_bytes())?;
+        w.write_all(&(self.records.len() as u64).to_le_bytes())?;
+
+        for rec in &self.records {
+            let mut buf = [0; Self::RECORD_SIZE];
+            buf[0] = rec.kind.to_u8();
+            buf[1..5].copy_from_slice(&rec.pc.0.to_le_bytes());
+            buf[5..9].copy_from_slice(&rec.target.0.to_le_bytes());
+            buf[9] = u8::from(rec.taken) | (u8::from(rec.compressed) << 1);
+            w.write_all(&buf)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(mut r: R) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a branch trace"));
+        }
+
+        let mut word = [0; 8];
+        r.read_exact(&mut word)?;
+        let insts = u64::from_le_bytes(word);
+        r.read_exact(&mut word)?;
+        let len = u64::from_le_bytes(word);
+
+        let mut records = Vec::new();
+        for _ in 0..len {
+            let mut buf = [0; Self::RECORD_SIZE];
+            r.read_exact(&mut buf)?;
+            records.push(BranchRecord {
+                kind: BranchKind::from_u8(buf[0]).ok_or_else(|| invalid("bad branch kind"))?,
+                pc: AbsPc(u32::from_le_bytes(buf[1..5].try_into().unwrap())),
+                target: AbsPc(u32::from_le_bytes(buf[5..9].try_into().unwrap())),
+                taken: buf[9] & 1 != 0,
+                compressed: buf[9] & 2 != 0,
+            });
+        }
+
+        Ok(Self { insts, records })
+    }
+
+    pub fn replay<P: TracePredictor>(&self, predictor: &mut P) -> ReplayStats {
+        let mut stats = ReplayStats {
+            insts: self.insts,
+            ..Default::default()
+        };
+
+        for rec in &self.records {
+            let correct = predictor.replay(rec);
+
+            match rec.kind {
+                BranchKind::Conditional => {
+                    stats.direct_predicts += 1;
+                    stats.direct_mispredicts += u64::from(!correct);
+                }
+                kind if kind.is_indirect() => {
+                    stats.indirect_predicts += 1;
+                    stats.indirect_mispredicts += u64::from(!correct);
+                }
+                _ => (),
+            }
+        }
+
+        stats
+    }
+}
+
+impl ReplayStats {
+    pub fn mispredicts(&self) -> u64 {
+        self.direct_mispredicts + self.indirect_mispredicts
+    }
+
+    // Mispredictions per thousand instructions.
+    pub fn mpki(&self) -> f64 {
+        1000.0 * self.mispredicts() as f64 / self.insts.max(1) as f64
+    }
+}
+
+impl TracePredictor for BranchPredictor {
+    // There is no wrong path when replaying a trace, so the predictor is never rolled back.
+    fn replay(&mut self, record: &BranchRecord) -> bool {
+        if record.kind == BranchKind::Conditional {
+            let prediction = self.predict_direct(record.pc, record.target);
+            self.update_predict_direct(record.pc, record.taken, prediction);
+            prediction.taken == record.taken
+        } else {
+            let inst = record.kind.to_inst(record.target);
+            let size = if record.compressed {
+                COMPRESSED_INST_SIZE
+            } else {
+                INST_SIZE
+            };
+            let prediction = self.predict_indirect(&inst, record.pc, size);
+
+            if record.kind.is_indirect() {
+                self.update_predict_indirect(record.pc, record.target, prediction);
+            }
+
+            prediction.target == Some(record.target)
+        }
+    }
+}
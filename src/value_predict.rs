@@ -0,0 +1,168 @@
+use crate::inst::AbsPc;
+
+const TABLE_ENTRIES: usize = 1024;
+const CONTEXT_ENTRIES: usize = 4096;
+
+// A misprediction costs a full flush, so only predict once a pattern has held for a while.
+const CONFIDENCE_MAX: u8 = 7;
+const CONFIDENCE_THRESHOLD: u8 = 6;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ValuePredictorKind {
+    LastValue, // The value the load returned last time.
+    Stride,    // The last value plus the difference between the last two.
+    Context,   // Whatever followed the same recent history of values (finite context method).
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct Entry {
+    pc: AbsPc,
+    last: u32,
+    stride: u32,
+    history: u32, // The last four values folded to a byte each, for the context predictor.
+    confidence: u8, // For last value and stride.
+    in_flight: u32, // Renamed but not yet trained, so a stride is applied once per instance.
+}
+
+#[derive(Debug, Clone)]
+pub struct ValuePredictor {
+    kind: ValuePredictorKind,
+    table: Vec<Option<Entry>>,
+    context: Vec<(u32, u8)>, // Value and confidence, indexed by history.
+}
+
+impl ValuePredictor {
+    pub fn new(kind: ValuePredictorKind) -> Self {
+        Self {
+            kind,
+            table: vec![None; TABLE_ENTRIES],
+            context: vec![(0, 0); CONTEXT_ENTRIES],
+        }
+    }
+
+    fn index(pc: AbsPc) -> usize {
+        pc.table_index() as usize % TABLE_ENTRIES
+    }
+
+    fn context_index(entry: &Entry) -> usize {
+        let h = entry.history ^ (entry.history >> 12) ^ (entry.history >> 24);
+        (h ^ (entry.pc.table_index())) as usize % CONTEXT_ENTRIES
+    }
+
+    fn entry_mut(&mut self, pc: AbsPc) -> Option<&mut Entry> {
+        self.table[Self::index(pc)].as_mut().filter(|e| e.pc == pc)
+    }
+
+    // Look up a prediction for a load that is being renamed. Every lookup must be followed by
+    // either `train` or `squash` for the same PC.
+    pub fn predict(&mut self, pc: AbsPc) -> Option<u32> {
+        let kind = self.kind;
+        let entry = *self.entry_mut(pc)?;
+        self.entry_mut(pc).unwrap().in_flight += 1;
+
+        match kind {
+            ValuePredictorKind::LastValue => {
+                (entry.confidence >= CONFIDENCE_THRESHOLD).then_some(entry.last)
+            }
+            ValuePredictorKind::Stride => (entry.confidence >= CONFIDENCE_THRESHOLD).then(|| {
+                entry
+                    .last
+                    .wrapping_add(entry.stride.wrapping_mul(entry.in_flight + 1))
+            }),
+            ValuePredictorKind::Context => {
+                let (value, confidence) = self.context[Self::context_index(&entry)];
+                (confidence >= CONFIDENCE_THRESHOLD).then_some(value)
+            }
+        }
+    }
+
+    // Update with the value the load actually returned.
+    pub fn train(&mut self, pc: AbsPc, value: u32) {
+        let idx = Self::index(pc);
+        let Some(mut entry) = self.table[idx].filter(|e| e.pc == pc) else {
+            self.table[idx] = Some(Entry {
+                pc,
+                last: value,
+                ..Default::default()
+            });
+            return;
+        };
+
+        entry.in_flight = entry.in_flight.saturating_sub(1);
+
+        let predicted = match self.kind {
+            ValuePredictorKind::LastValue => entry.last,
+            ValuePredictorKind::Stride => entry.last.wrapping_add(entry.stride),
+            ValuePredictorKind::Context => {
+                let ctx = &mut self.context[Self::context_index(&entry)];
+                if ctx.0 == value {
+                    ctx.1 = (ctx.1 + 1).min(CONFIDENCE_MAX);
+                } else {
+                    *ctx = (value, 0);
+                }
+                value
+            }
+        };
+
+        if predicted == value {
+            entry.confidence = (entry.confidence + 1).min(CONFIDENCE_MAX);
+        } else {
+            entry.confidence = 0;
+        }
+
+        entry.stride = value.wrapping_sub(entry.last);
+        entry.last = value;
+        entry.history = (entry.history << 8) | fold(value);
+        self.table[idx] = Some(entry);
+    }
+
+    // A load that was looked up got squashed before it could train.
+    pub fn squash(&mut self, pc: AbsPc) {
+        if let Some(entry) = self.entry_mut(pc) {
+            entry.in_flight = entry.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+fn fold(value: u32) -> u32 {
+    value.to_le_bytes().iter().fold(0, |acc, b| acc ^ *b as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn confident(vp: &mut ValuePredictor, pc: AbsPc, values: impl Iterator<Item = u32>) {
+        for v in values {
+            let _ = vp.predict(pc);
+            vp.train(pc, v);
+        }
+    }
+
+    #[test]
+    fn test_stride() {
+        let pc = AbsPc(0x40);
+        let mut vp = ValuePredictor::new(ValuePredictorKind::Stride);
+        confident(&mut vp, pc, (0..10).map(|i| 100 + 4 * i));
+        assert_eq!(vp.predict(pc), Some(140));
+
+        // A second instance in flight predicts one stride further on.
+        assert_eq!(vp.predict(pc), Some(144));
+    }
+
+    #[test]
+    fn test_context() {
+        let pc = AbsPc(0x40);
+        let mut vp = ValuePredictor::new(ValuePredictorKind::Context);
+        confident(
+            &mut vp,
+            pc,
+            [3, 1, 4, 1, 5].iter().copied().cycle().take(60),
+        );
+
+        // Last value and stride can't follow this, but the history picks out the next value.
+        assert_eq!(vp.predict(pc), Some(3));
+        vp.train(pc, 3);
+        assert_eq!(vp.predict(pc), Some(1));
+    }
+}
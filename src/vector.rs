@@ -0,0 +1,306 @@
+use hashbrown::HashMap;
+use std::{fmt::Debug, ops::Range};
+
+use crate::{
+    cpu::Stats,
+    execution_unit::EuType,
+    inst::{Imm, Inst, ReadyInst, Sew, VectorOp, VectorType},
+    mem::{MainMemory, MemFault, MemoryHierarchy},
+    mmu::PageMap,
+    util::Addr,
+};
+
+pub const DEFAULT_VLEN: u32 = 256;
+
+// Cycles before the first element group comes out.
+const ALU_STARTUP: u64 = 2;
+const MUL_STARTUP: u64 = 4;
+const MEM_STARTUP: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorConfig {
+    pub vlen: u32,      // Bits in each vector register
+    pub lanes: u32,     // Each lane handles 32 bits of elements a cycle
+    pub chaining: bool, // Dependents can start on the first elements rather than waiting for all
+}
+
+impl Default for VectorConfig {
+    fn default() -> Self {
+        Self {
+            vlen: DEFAULT_VLEN,
+            lanes: 4,
+            chaining: true,
+        }
+    }
+}
+
+// Cycles from a vector instruction issuing to it completing, and to its destination being
+// available to a chained instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorTiming {
+    pub latency: u64,
+    pub chain: u64,
+}
+
+// The elements of the vector registers. The value a vector register holds in the register file is
+// a handle to its elements here: its architectural number in Emulated, its physical register in
+// OutOfOrder. Handles that were never written hold zeroes.
+#[derive(Debug, Clone)]
+pub struct VectorRegs {
+    vlen_bytes: u32,
+    regs: HashMap<u32, Vec<u8>>,
+}
+
+impl VectorRegs {
+    pub fn new(vlen: u32) -> Self {
+        assert!(
+            vlen.is_power_of_two() && vlen >= 32,
+            "VLEN must be a power of two of at least 32 bits"
+        );
+        Self {
+            vlen_bytes: vlen / 8,
+            regs: HashMap::new(),
+        }
+    }
+
+    // The most elements that fit in a register.
+    pub fn vlmax(&self, sew: Sew) -> u32 {
+        self.vlen_bytes / sew.bytes()
+    }
+
+    pub fn get(&self, handle: u32) -> Vec<u8> {
+        self.regs
+            .get(&handle)
+            .cloned()
+            .unwrap_or_else(|| vec![0; self.vlen_bytes as usize])
+    }
+
+    // Write the first vl elements of `dst`, leaving the rest as they were in `old`.
+    fn write(&mut self, dst: u32, old: u32, sew: Sew, vl: u32, f: impl Fn(u32) -> u32) -> u32 {
+        let mut elements = self.get(old);
+        for i in 0..vl.min(self.vlmax(sew)) {
+            set_element(&mut elements, i, sew, f(i));
+        }
+        self.regs.insert(dst, elements);
+        dst
+    }
+}
+
+// Run a vector instruction, whose scalar sources have been read and whose vector sources are
+// handles, putting any vector result in `dst`. Gives the value of the destination register.
+// Memory is accessed through the translations in `pages`. A fault leaves memory and registers
+// as they were.
+pub fn execute<D: Debug + Clone>(
+    inst: &Inst<u32, D>,
+    dst: u32,
+    regs: &mut VectorRegs,
+    mem: &mut MainMemory,
+    pages: &PageMap,
+) -> Result<u32, MemFault> {
+    Ok(match *inst {
+        Inst::VectorLoad(_, old, _, eew, vtype)
+        | Inst::VectorLoadStrided(_, old, _, _, eew, vtype) => {
+            let vals = element_addrs(inst)
+                .into_iter()
+                .map(|addr| read(mem, pages, addr, eew))
+                .collect::<Result<Vec<_>, _>>()?;
+            regs.write(dst, old, eew, vtype.vl, |i| vals[i as usize])
+        }
+        Inst::VectorStore(src, _, eew, _) | Inst::VectorStoreStrided(src, _, _, eew, _) => {
+            let data = regs.get(src);
+            let mut bytes = Vec::new();
+            for (i, &addr) in element_addrs(inst).iter().enumerate() {
+                let val = element(&data, i as u32, eew);
+                for (b, byte) in val
+                    .to_le_bytes()
+                    .into_iter()
+                    .take(eew.bytes() as usize)
+                    .enumerate()
+                {
+                    let addr = pages.apply(Addr(addr.0.wrapping_add(b as u32)));
+                    mem.check(addr, 1)?;
+                    bytes.push((addr, byte));
+                }
+            }
+            for (addr, byte) in bytes {
+                mem.writeb(addr, u32::from(byte))?;
+            }
+            0
+        }
+        Inst::VectorArith(op, _, old, src0, src1, VectorType { sew, vl }) => {
+            let (a, b) = (regs.get(src0), regs.get(src1));
+            regs.write(dst, old, sew, vl, |i| {
+                op.apply(element(&a, i, sew), element(&b, i, sew), sew)
+            })
+        }
+        Inst::VectorArithScalar(op, _, old, src0, val, VectorType { sew, vl })
+        | Inst::VectorArithImm(op, _, old, src0, Imm(val), VectorType { sew, vl }) => {
+            let a = regs.get(src0);
+            regs.write(dst, old, sew, vl, |i| {
+                op.apply(element(&a, i, sew), val, sew)
+            })
+        }
+        Inst::VectorReduce(op, _, old, src0, src1, VectorType { sew, vl }) => {
+            let (a, b) = (regs.get(src0), regs.get(src1));
+            let folded = (0..vl.min(regs.vlmax(sew))).fold(element(&b, 0, sew), |acc, i| {
+                op.apply(acc, element(&a, i, sew), sew)
+            });
+            regs.write(dst, old, sew, vl.min(1), |_| folded)
+        }
+        Inst::VectorSplat(_, old, val, VectorType { sew, vl }) => {
+            regs.write(dst, old, sew, vl, |_| sew.truncate(val))
+        }
+        Inst::VectorToScalar(_, src, VectorType { sew, .. }) => {
+            sew.sign_extend(element(&regs.get(src), 0, sew))
+        }
+        _ => unimplemented!("{:?}", inst),
+    })
+}
+
+// The bytes a vector load or store may touch. An element width other than SEW can give more
+// elements than fit in a register, in which case this is an overestimate.
+pub fn footprint<D: Debug + Clone>(inst: &Inst<u32, D>) -> Range<u32> {
+    let eew = access_width(inst);
+    let addrs = element_addrs(inst);
+    let start = addrs.iter().map(|a| a.0).min().unwrap_or(0);
+    let end = addrs
+        .iter()
+        .map(|a| a.0.wrapping_add(eew.bytes()))
+        .max()
+        .unwrap_or(start);
+    start..end
+}
+
+// The bytes of each element a vector load or store touches.
+pub fn element_ranges<D: Debug + Clone>(inst: &Inst<u32, D>) -> Vec<Range<u32>> {
+    let eew = access_width(inst);
+    element_addrs(inst)
+        .into_iter()
+        .map(|a| a.0..a.0.wrapping_add(eew.bytes()))
+        .collect()
+}
+
+// The physical cache lines a vector load or store touches, in the order it touches them.
+pub fn lines<D: Debug + Clone>(inst: &Inst<u32, D>, pages: &PageMap) -> Vec<Addr> {
+    let mut lines: Vec<Addr> = Vec::new();
+    for addr in element_addrs(inst) {
+        let line = pages.apply(addr).to_cache_line();
+        if !lines.contains(&line) {
+            lines.push(line);
+        }
+    }
+    lines
+}
+
+fn access_width<D: Debug + Clone>(inst: &Inst<u32, D>) -> Sew {
+    match *inst {
+        Inst::VectorLoad(_, _, _, eew, _)
+        | Inst::VectorLoadStrided(_, _, _, _, eew, _)
+        | Inst::VectorStore(_, _, eew, _)
+        | Inst::VectorStoreStrided(_, _, _, eew, _) => eew,
+        _ => unreachable!("not a vector access"),
+    }
+}
+
+fn element_addrs<D: Debug + Clone>(inst: &Inst<u32, D>) -> Vec<Addr> {
+    let (base, stride, vl) = match *inst {
+        Inst::VectorLoad(_, _, addr, eew, vtype) | Inst::VectorStore(_, addr, eew, vtype) => {
+            (addr.compute_addr(), eew.bytes(), vtype.vl)
+        }
+        Inst::VectorLoadStrided(_, _, addr, stride, _, vtype)
+        | Inst::VectorStoreStrided(_, addr, stride, _, vtype) => {
+            (addr.compute_addr(), stride, vtype.vl)
+        }
+        _ => unreachable!("not a vector access"),
+    };
+
+    (0..vl)
+        .map(|i| Addr(base.0.wrapping_add(i.wrapping_mul(stride))))
+        .collect()
+}
+
+// Elements don't have to be aligned, so they're read a byte at a time.
+fn read(mem: &MainMemory, pages: &PageMap, addr: Addr, eew: Sew) -> Result<u32, MemFault> {
+    (0..eew.bytes()).try_fold(0, |val, b| {
+        Ok(val | mem.readbu(pages.apply(Addr(addr.0.wrapping_add(b))))? << (8 * b))
+    })
+}
+
+fn element(reg: &[u8], i: u32, sew: Sew) -> u32 {
+    let size = sew.bytes() as usize;
+    let start = i as usize * size;
+    let mut bytes = [0; 4];
+    bytes[..size].copy_from_slice(&reg[start..start + size]);
+    u32::from_le_bytes(bytes)
+}
+
+fn set_element(reg: &mut [u8], i: u32, sew: Sew, val: u32) {
+    let size = sew.bytes() as usize;
+    let start = i as usize * size;
+    reg[start..start + size].copy_from_slice(&val.to_le_bytes()[..size]);
+}
+
+impl VectorConfig {
+    // Elements go through the lanes a group at a time after a startup latency. Loads and stores
+    // send out one cache line a cycle, and a load's elements can be chained from once its first
+    // line arrives. Reductions and moves to a scalar only produce anything at the end.
+    pub fn timing(
+        &self,
+        inst: &ReadyInst,
+        pages: &PageMap,
+        mem: &mut MemoryHierarchy,
+        stats: &mut Stats,
+    ) -> VectorTiming {
+        let vtype = inst.vector_type().expect("not a vector instruction");
+        let width = match inst {
+            x if x.eu_type() == EuType::VectorLoadStore => access_width(x),
+            _ => vtype.sew,
+        };
+        let groups = u64::from((vtype.vl * width.bytes()).div_ceil(self.lanes * 4)).max(1);
+
+        let (latency, chain) = match inst {
+            Inst::VectorLoad(_, _, _, _, _)
+            | Inst::VectorLoadStrided(_, _, _, _, _, _)
+            | Inst::VectorStore(_, _, _, _)
+            | Inst::VectorStoreStrided(_, _, _, _, _) => {
+                let arrivals = lines(inst, pages)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, line)| {
+                        if inst.is_vector_store() {
+                            mem.commit_write(line, 1);
+                        }
+                        i as u64 + mem.access_line(line, stats)
+                    })
+                    .collect::<Vec<_>>();
+                let first = arrivals.first().copied().unwrap_or(0);
+                let last = arrivals.iter().max().copied().unwrap_or(0);
+                (MEM_STARTUP + last.max(groups), MEM_STARTUP + first + 1)
+            }
+            Inst::VectorReduce(_, _, _, _, _, _) => {
+                let latency = ALU_STARTUP + groups + u64::from(self.lanes.ilog2());
+                (latency, latency)
+            }
+            Inst::VectorToScalar(_, _, _) => (ALU_STARTUP + 1, ALU_STARTUP + 1),
+            Inst::VectorArith(op, _, _, _, _, _)
+            | Inst::VectorArithScalar(op, _, _, _, _, _)
+            | Inst::VectorArithImm(op, _, _, _, _, _) => {
+                let startup = match op {
+                    VectorOp::Mul => MUL_STARTUP,
+                    _ => ALU_STARTUP,
+                };
+                (startup + groups, startup + 1)
+            }
+            _ => (ALU_STARTUP + groups, ALU_STARTUP + 1),
+        };
+
+        VectorTiming {
+            latency,
+            chain: if self.chaining {
+                chain.min(latency)
+            } else {
+                latency
+            },
+        }
+    }
+}
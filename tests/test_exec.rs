@@ -1,6 +1,11 @@
 use aca::{
-    cpu::Cpu, emulated::Emulated, inst::ArchReg, mem::MainMemory, out_of_order::OutOfOrder,
-    parse_and_exec, util::Addr,
+    cpu::Cpu,
+    emulated::Emulated,
+    inst::ArchReg,
+    mem::{MainMemory, MemFault, Misaligned, ADDRESS_SPACE_BYTES, DRAM},
+    out_of_order::OutOfOrder,
+    parse_and_exec,
+    util::Addr,
 };
 
 #[generic_tests::define]
@@ -20,22 +25,98 @@ mod t {
 
         let mut initial_mem = MainMemory::new();
         for i in 0..10 {
-            initial_mem.writew(Addr(40 + i * 4), i);
-            initial_mem.writew(Addr(80 + i * 4), 10 - i);
+            initial_mem.writew(Addr(40 + i * 4), i).unwrap();
+            initial_mem.writew(Addr(80 + i * 4), 10 - i).unwrap();
         }
 
         let res = parse_and_exec::<C>("loop", initial_regs, initial_mem);
 
         for i in 0..10 {
-            assert_eq!(res.mem.readw(Addr(i * 4)), 10);
+            assert_eq!(res.mem.readw(Addr(i * 4)).unwrap(), 10);
         }
     }
 
+    #[test]
+    fn test_sparse_regions<C: Cpu>() {
+        let regions = [DRAM, 0xffff_f000..ADDRESS_SPACE_BYTES];
+        let mut initial_mem = MainMemory::with_regions(regions);
+        for i in 0..64 {
+            initial_mem.writew(Addr(0xffff_f000 + i * 4), i).unwrap();
+            initial_mem
+                .writew(Addr(0xffff_f800 + i * 4), 2 * i)
+                .unwrap();
+        }
+        let initial_regs = RegSet::from([
+            (ArchReg::A0, 0xffff_ff00),
+            (ArchReg::A1, 0xffff_f000),
+            (ArchReg::A2, 0xffff_f800),
+            (ArchReg::A3, 64),
+        ]);
+
+        // The last word written is the last in the address space.
+        let res = parse_and_exec::<C>("loop", initial_regs, initial_mem);
+        assert_eq!(res.fault, None);
+        for i in 0..64 {
+            assert_eq!(res.mem.readw(Addr(0xffff_ff00 + i * 4)).unwrap(), 3 * i);
+        }
+    }
+
+    #[test]
+    fn test_unmapped_access<C: Cpu>() {
+        let initial_regs = RegSet::from([
+            (ArchReg::A0, 0x8000_0000),
+            (ArchReg::A1, 40),
+            (ArchReg::A2, 80),
+            (ArchReg::A3, 10),
+        ]);
+
+        // Stops at the first store, with everything before it done.
+        let res = parse_and_exec::<C>("loop", initial_regs, MainMemory::new());
+        assert_eq!(res.fault, Some(MemFault::Unmapped(Addr(0x8000_0000))));
+        assert_eq!(res.regs.get(ArchReg::A0), 0x8000_0000);
+        assert_eq!(res.regs.get(ArchReg::A1), 44);
+        assert_eq!(res.regs.get(ArchReg::A2), 84);
+    }
+
+    #[test]
+    fn test_misaligned_in_hardware<C: Cpu>() {
+        let mem = MainMemory::new().with_misaligned(Misaligned::Hardware);
+        let regs = RegSet::from([(ArchReg::A0, 0x1000)]);
+        let res = parse_and_exec::<C>("misaligned", regs, mem);
+
+        let expected = [
+            0x1122_3344,
+            0x2233,
+            0x44ff_fe00,
+            0x2233_44ff,
+            0xffff_fffe,
+            0,
+        ];
+        for (i, val) in expected.into_iter().enumerate() {
+            assert_eq!(
+                res.mem.readw(Addr(0x1080 + 4 * i as u32)).unwrap(),
+                val,
+                "word {i}"
+            );
+        }
+        assert_eq!(res.fault, Some(MemFault::Misaligned(Addr(0x1002))));
+    }
+
+    #[test]
+    fn test_misaligned_fault<C: Cpu>() {
+        let regs = RegSet::from([(ArchReg::A0, 0x1000)]);
+        let res = parse_and_exec::<C>("misaligned", regs, MainMemory::new());
+
+        assert_eq!(res.fault, Some(MemFault::Misaligned(Addr(0x1001))));
+        assert_eq!(res.mem, MainMemory::new());
+        assert_eq!(res.regs.get(ArchReg::T0), 0x1122_3344);
+    }
+
     #[test]
     fn test_label<C: Cpu>() {
         let res = parse_and_exec::<C>("label", RegSet::new(), MainMemory::new());
         for i in 0..10 {
-            assert_eq!(res.mem.readw(Addr(i * 4)), 0);
+            assert_eq!(res.mem.readw(Addr(i * 4)).unwrap(), 0);
         }
 
         assert_eq!(res.stats.insts_retired, 7);
@@ -44,31 +125,31 @@ mod t {
     #[test]
     fn test_branch<C: Cpu>() {
         let res = parse_and_exec::<C>("branch", RegSet::new(), MainMemory::new());
-        assert_eq!(res.mem.readw(Addr(0)), 4);
-        assert_eq!(res.mem.readw(Addr(4)), 3);
-        assert_eq!(res.mem.readw(Addr(8)), 2);
+        assert_eq!(res.mem.readw(Addr(0)).unwrap(), 4);
+        assert_eq!(res.mem.readw(Addr(4)).unwrap(), 3);
+        assert_eq!(res.mem.readw(Addr(8)).unwrap(), 2);
     }
 
     #[test]
     fn test_hazard_raw<C: Cpu>() {
         let res = parse_and_exec::<C>("hazard_raw", RegSet::new(), MainMemory::new());
-        assert_eq!(res.mem.readw(Addr(0)), 3);
-        assert_eq!(res.mem.readw(Addr(4)), 1);
-        assert_eq!(res.mem.readw(Addr(8)), 1);
+        assert_eq!(res.mem.readw(Addr(0)).unwrap(), 3);
+        assert_eq!(res.mem.readw(Addr(4)).unwrap(), 1);
+        assert_eq!(res.mem.readw(Addr(8)).unwrap(), 1);
     }
 
     #[test]
     fn test_hazard_war<C: Cpu>() {
         let res = parse_and_exec::<C>("hazard_war", RegSet::new(), MainMemory::new());
-        assert_eq!(res.mem.readw(Addr(0)), 1);
-        assert_eq!(res.mem.readw(Addr(4)), 2);
+        assert_eq!(res.mem.readw(Addr(0)).unwrap(), 1);
+        assert_eq!(res.mem.readw(Addr(4)).unwrap(), 2);
     }
 
     #[test]
     fn test_hazard_waw<C: Cpu>() {
         let res = parse_and_exec::<C>("hazard_waw", RegSet::new(), MainMemory::new());
-        assert_eq!(res.mem.readw(Addr(0)), 2);
-        assert_eq!(res.mem.readw(Addr(4)), 2);
+        assert_eq!(res.mem.readw(Addr(0)).unwrap(), 2);
+        assert_eq!(res.mem.readw(Addr(4)).unwrap(), 2);
     }
 
     #[test]
@@ -102,7 +183,7 @@ mod t {
                 for j in 0..dim {
                     let c_start = 2 * (4 * dim * dim);
                     let val = if i == j { 1 } else { 0 };
-                    assert_eq!(mem.readw(Addr(c_start + 4 * (j * dim + i))), val);
+                    assert_eq!(mem.readw(Addr(c_start + 4 * (j * dim + i))).unwrap(), val);
                 }
             }
         };
@@ -125,7 +206,7 @@ mod t {
             .mem;
 
             for i in 0..len {
-                let val = mem.readw(Addr(4 * i));
+                let val = mem.readw(Addr(4 * i)).unwrap();
                 assert_eq!(val, i + 1, "addr 4*{} = {}", i, val);
             }
         };
@@ -186,6 +267,256 @@ mod t {
         }
     }
 
+    #[test]
+    fn test_atomics<C: Cpu>() {
+        let regs = RegSet::from([(ArchReg::A0, 128)]);
+        let mem = parse_and_exec::<C>("atomics", regs, MainMemory::new()).mem;
+
+        let expected = [42, 5, 8, 8, 8, 0xffff_fffe, 0xfe, 0x1fe, 0x1ee, 1, 1];
+        for (i, val) in expected.into_iter().enumerate() {
+            assert_eq!(
+                mem.readw(Addr(128 + 4 * i as u32)).unwrap(),
+                val,
+                "word {i}"
+            );
+        }
+        assert_eq!(mem.readw(Addr(128 + 64)).unwrap(), 10);
+        assert_eq!(mem.readw(Addr(128 + 68)).unwrap(), 10);
+    }
+
+    #[test]
+    fn test_page_fault<C: Cpu>() {
+        const PAGES: u32 = 8;
+        let data = 0x40000;
+        let regs = RegSet::from([
+            (ArchReg::A0, 0x20000),
+            (ArchReg::A1, 0x21000),
+            (ArchReg::A2, data),
+            (ArchReg::A3, PAGES),
+        ]);
+        let mut mem = MainMemory::new();
+        for i in 0..PAGES {
+            mem.writew(Addr(data + (PAGES + i) * 4096), i).unwrap();
+        }
+        let res = parse_and_exec::<C>("page_fault", regs, mem);
+
+        for i in 0..PAGES {
+            assert_eq!(
+                res.mem.readw(Addr(data + i * 4096 + 4)).unwrap(),
+                PAGES - i,
+                "page {i}"
+            );
+        }
+        // Every data page faults once, the stores with cause 15 and the loads with 13.
+        assert_eq!(res.mem.readw(Addr(data + 8)).unwrap(), 2 * PAGES);
+        assert_eq!(res.mem.readw(Addr(data + 12)).unwrap(), PAGES * (15 + 13));
+        assert_eq!(
+            res.mem.readw(Addr(data + 16)).unwrap(),
+            (0..PAGES).sum::<u32>()
+        );
+        assert_eq!(res.stats.page_faults, 2 * PAGES as u64);
+    }
+
+    #[test]
+    fn test_bitmanip<C: Cpu>() {
+        let regs = RegSet::from([
+            (ArchReg::A0, 0),
+            (ArchReg::A1, 0x8000_9f80),
+            (ArchReg::A2, 36),
+        ]);
+        let mem = parse_and_exec::<C>("bitmanip", regs, MainMemory::new()).mem;
+
+        #[rustfmt::skip]
+        let expected = [
+            0x13f24, 0x27e24, 0x4fc24,                  // sh[123]add
+            0x8000_9f80, 0xffff_ffdb, 0x7fff_605b,      // andn, orn, xnor
+            0x8000_9f80, 0x24, 0x24, 0x8000_9f80,       // min, minu, max, maxu
+            0x9_f808, 0x800_09f8,                       // rol, ror by 36 % 32
+            0, 7, 8,                                    // clz, ctz, cpop
+            0xffff_ff80, 0xffff_9f80, 0x9f80,           // sext.b, sext.h, zext.h
+            0x809f_0080,                                // rev8
+            32, 32,                                     // clz, ctz of zero
+        ];
+        for (i, val) in expected.into_iter().enumerate() {
+            assert_eq!(mem.readw(Addr(4 * i as u32)).unwrap(), val, "word {i}");
+        }
+    }
+
+    #[test]
+    fn test_compressed<C: Cpu>() {
+        let regs = RegSet::from([(ArchReg::A0, 10), (ArchReg::A2, 256)]);
+        let res = parse_and_exec::<C>("compressed", regs, MainMemory::new());
+
+        assert_eq!(res.mem.readw(Addr(256)).unwrap(), 55);
+        assert_eq!(res.regs.get(ArchReg::A3), 14);
+        assert_eq!(res.regs.get(ArchReg::A0), 41);
+        assert_eq!(res.regs.get(ArchReg::A4), 1 << 12);
+        assert_eq!(res.regs.get(ArchReg::A5), res.regs.get(ArchReg::SP) + 8);
+    }
+
+    #[test]
+    fn test_matmul_float<C: Cpu>() {
+        let run = |dim: u32| {
+            let n = (dim * dim) as usize;
+            let a: Vec<f32> = (0..n).map(|i| (i % 7) as f32 * 0.3 - 0.7).collect();
+            let b: Vec<f32> = (0..n).map(|i| 1.1 / (i + 1) as f32).collect();
+
+            let mut initial_mem = MainMemory::new();
+            for (i, (a, b)) in a.iter().zip(&b).enumerate() {
+                initial_mem.writew(Addr(4 * i as u32), a.to_bits()).unwrap();
+                initial_mem
+                    .writew(Addr(4 * (n + i) as u32), b.to_bits())
+                    .unwrap();
+            }
+            let regs = RegSet::from([(ArchReg::A0, 0), (ArchReg::A1, dim)]);
+            let mem = parse_and_exec::<C>("matmul_float", regs, initial_mem).mem;
+
+            let dim = dim as usize;
+            for j in 0..dim {
+                for i in 0..dim {
+                    let expected =
+                        (0..dim).fold(0.0f32, |acc, k| a[j * dim + k].mul_add(b[k * dim + i], acc));
+                    let addr = Addr(4 * (2 * n + j * dim + i) as u32);
+                    assert_eq!(
+                        mem.readw(addr).unwrap(),
+                        expected.to_bits(),
+                        "out[{j}][{i}]"
+                    );
+                }
+            }
+        };
+
+        run(1);
+        run(4);
+        run(9);
+    }
+
+    #[test]
+    fn test_rounding<C: Cpu>() {
+        let regs = RegSet::from([(ArchReg::A0, 0)]);
+        let mem = parse_and_exec::<C>("rounding", regs, MainMemory::new()).mem;
+
+        let third = [0x3eaaaaab, 0x3eaaaaaa, 0x3eaaaaaa, 0x3eaaaaab, 0x3eaaaaab];
+        let positive = [2, 2, 2, 3, 3];
+        let negative = [-2, -2, -3, -2, -3];
+        for mode in 0..5 {
+            let addr = |offset| Addr(16 * mode + offset);
+            assert_eq!(
+                mem.readw(addr(0)).unwrap(),
+                third[mode as usize],
+                "mode {mode}"
+            );
+            assert_eq!(
+                mem.readw(addr(4)).unwrap(),
+                positive[mode as usize],
+                "mode {mode}"
+            );
+            assert_eq!(
+                mem.readw(addr(8)).unwrap() as i32,
+                negative[mode as usize],
+                "mode {mode}"
+            );
+            assert_eq!(mem.readw(addr(12)).unwrap(), mode);
+        }
+
+        // The static rounding mode wins over the last mode written, and fflags sits below it.
+        assert_eq!(mem.readw(Addr(80)).unwrap() as i32, -2);
+        assert_eq!(mem.readw(Addr(84)).unwrap(), (4 << 5) | 0x1f);
+    }
+
+    #[test]
+    fn test_vector_ops<C: Cpu>() {
+        let words: Vec<u32> = (0..16u32).map(|i| i.wrapping_mul(0x1357_9bdf)).collect();
+        let mut initial_mem = MainMemory::new();
+        for (i, &w) in words.iter().enumerate() {
+            initial_mem.writew(Addr(4 * i as u32), w).unwrap();
+        }
+        let regs = RegSet::from([(ArchReg::A0, 0), (ArchReg::A1, 256), (ArchReg::A2, 5)]);
+        let mem = parse_and_exec::<C>("vector_ops", regs, initial_mem).mem;
+
+        let (a, b) = (&words[..5], &words[8..13]);
+        let section = |n: u32| -> Vec<u32> {
+            (0..8)
+                .map(|i| mem.readw(Addr(256 + 32 * n + 4 * i)).unwrap())
+                .collect()
+        };
+        // Results past vl stay as the zeroes they started as.
+        let padded = |elements: Vec<u32>| -> Vec<u32> {
+            let mut words = elements;
+            words.resize(8, 0);
+            words
+        };
+
+        assert_eq!(section(0)[0], 5);
+        let binary: [fn(u32, u32) -> u32; 3] = [u32::wrapping_add, u32::wrapping_sub, |a, b| a | b];
+        for (n, op) in [1, 2, 5].into_iter().zip(binary) {
+            let expected = a.iter().zip(b).map(|(&a, &b)| op(a, b)).collect();
+            assert_eq!(section(n), padded(expected), "section {n}");
+        }
+        let unary: [fn(u32) -> u32; 5] = [
+            |a| a.wrapping_mul(-3i32 as u32),
+            |a| a & 12,
+            |a| a ^ -3i32 as u32,
+            |a| a >> 1,
+            |a| (a as i32 >> 2) as u32,
+        ];
+        for (n, op) in [3, 4, 6, 8, 9].into_iter().zip(unary) {
+            let expected = a.iter().map(|&a| op(a)).collect();
+            assert_eq!(section(n), padded(expected), "section {n}");
+        }
+        assert_eq!(section(7), padded(b.iter().map(|&b| b << 3).collect()));
+
+        let fold = |f: fn(u32, u32) -> u32| a.iter().fold(b[0], |acc, &x| f(acc, x));
+        let reductions = [
+            fold(u32::wrapping_add),
+            fold(|acc, x| acc & x),
+            fold(|acc, x| acc | x),
+            fold(|acc, x| acc ^ x),
+            fold(|acc, x| (acc as i32).max(x as i32) as u32),
+            fold(u32::max),
+            fold(|acc, x| (acc as i32).min(x as i32) as u32),
+            fold(u32::min),
+        ];
+        assert_eq!(section(10), reductions);
+
+        assert_eq!(section(11), padded(vec![-3i32 as u32; 5]));
+        let every_other = (0..5).map(|i| words[2 * i]).collect();
+        assert_eq!(section(12), padded(every_other));
+        let scattered = (0..16)
+            .map(|i| mem.readw(Addr(256 + 32 * 13 + 4 * i)).unwrap())
+            .collect::<Vec<_>>();
+        for i in 0..16 {
+            let expected = if i % 2 == 0 && i < 10 { a[i / 2] } else { 0 };
+            assert_eq!(scattered[i], expected, "strided store word {i}");
+        }
+
+        let mut tail: Vec<u32> = a.iter().zip(b).map(|(&a, &b)| a.wrapping_add(b)).collect();
+        tail.resize(8, -3i32 as u32);
+        assert_eq!(section(15), tail);
+
+        assert_eq!(section(16)[0], 32);
+        for i in 0..32 {
+            let byte = words[i / 4].to_le_bytes()[i % 4].wrapping_sub(1);
+            assert_eq!(
+                mem.readbu(Addr(256 + 32 * 17 + i as u32)).unwrap(),
+                byte.into(),
+                "byte {i}"
+            );
+        }
+        let first = words[0].to_le_bytes()[0].wrapping_sub(1) as i8;
+        assert_eq!(section(18)[0], first as u32);
+
+        for i in 0..8 {
+            let half = (words[i / 2] >> (16 * (i % 2))) as u16;
+            let expected = if i < 5 { half.wrapping_mul(half) } else { 0 };
+            let addr = Addr(256 + 32 * 19 + 2 * i as u32);
+            assert_eq!(
+                mem.readbu(addr).unwrap() | mem.readbu(Addr(addr.0 + 1)).unwrap() << 8,
+                expected.into()
+            );
+        }
+    }
+
     #[instantiate_tests(<Emulated>)]
     mod emulated {}
 
@@ -196,7 +527,7 @@ mod t {
 #[cfg(test)]
 mod cosim {
     use super::*;
-    use aca::regs::RegSet;
+    use aca::{load_program, mem::STACK_TOP, mmu, regs::RegSet};
 
     fn qoi_decode<C: Cpu>(path: &str) -> (MainMemory, MainMemory) {
         let load_addr = 1000;
@@ -204,7 +535,7 @@ mod cosim {
 
         let run = |name| {
             let mut mem = MainMemory::new();
-            mem.copy_from_slice(&data, Addr(load_addr));
+            mem.copy_from_slice(&data, Addr(load_addr)).unwrap();
             parse_and_exec::<C>(
                 name,
                 RegSet::from([(ArchReg::A0, load_addr), (ArchReg::A1, data.len() as u32)]),
@@ -225,4 +556,1024 @@ mod cosim {
         assert!(a_gcc == b_gcc, "qoi (gcc) decode results differ!");
         assert!(a_clang == b_clang, "qoi (clang) decode results differ!");
     }
+
+    #[test]
+    fn test_identity_mapped_quicksort() {
+        let prog = load_program("quicksort");
+        let (regs, mut mem) = aca::initial_state(Some("0".to_owned()), Some("200".to_owned()));
+        let satp = mmu::identity_map(&mut mem);
+
+        let expected = Emulated::new(prog.clone(), regs.clone(), mem.clone()).exec_all();
+        let a = Emulated::new(prog.clone(), regs.clone(), mem.clone())
+            .with_satp(satp)
+            .exec_all();
+        let b = OutOfOrder::new(prog, regs, mem).with_satp(satp).exec_all();
+
+        assert!(
+            a.mem == expected.mem,
+            "emulated results differ under translation"
+        );
+        assert!(
+            b.mem == expected.mem,
+            "out of order results differ under translation"
+        );
+        assert!(b.stats.dtlb_hits > 0 && b.stats.itlb_hits > 0);
+        assert!(b.stats.page_walks > 0);
+        assert_eq!(b.stats.page_faults, 0);
+    }
+
+    #[test]
+    fn test_compressed_qoi_decode() {
+        let load_addr = 1000;
+        let data = std::fs::read("data/test-8x8.qoi").expect("could not open file");
+        let regs = RegSet::from([(ArchReg::A0, load_addr), (ArchReg::A1, data.len() as u32)]);
+        let mut mem = MainMemory::new();
+        mem.copy_from_slice(&data, Addr(load_addr)).unwrap();
+
+        let prog = load_program("qoi_decode_clang");
+        let expected = Emulated::new(prog.clone(), regs.clone(), mem.clone())
+            .exec_all()
+            .mem;
+
+        let prog = prog.compressed();
+        let a = Emulated::new(prog.clone(), regs.clone(), mem.clone())
+            .exec_all()
+            .mem;
+        let b = OutOfOrder::new(prog, regs, mem).exec_all().mem;
+        assert!(a == b, "compressed qoi decode results differ!");
+
+        // Return addresses saved on the stack move, but nothing else should.
+        for addr in (0..STACK_TOP as u32 - 4096).step_by(4) {
+            assert_eq!(
+                a.readw(Addr(addr)).unwrap(),
+                expected.readw(Addr(addr)).unwrap(),
+                "{addr:#x}"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod front_end {
+    use super::*;
+    use aca::{cpu::ExecResult, load_program, out_of_order::OutOfOrderConfig, regs::RegSet};
+
+    #[test]
+    fn test_fetch_to_rename_depth() {
+        let run = |fetch_to_rename| {
+            let config = OutOfOrderConfig {
+                fetch_to_rename,
+                ..Default::default()
+            };
+            OutOfOrder::with_config(
+                load_program("quicksort"),
+                RegSet::from([(ArchReg::A0, 0), (ArchReg::A1, 64)]),
+                MainMemory::new(),
+                config,
+            )
+            .exec_all()
+        };
+
+        let (shallow, deep) = (run(3), run(12));
+        for i in 0..64 {
+            assert_eq!(
+                shallow.mem.readw(Addr(4 * i)).unwrap(),
+                deep.mem.readw(Addr(4 * i)).unwrap()
+            );
+        }
+
+        // Mispredictions cost more the further fetch is from rename. A very shallow front end has
+        // too little buffering to hide instruction misses, so the default depth is compared.
+        let flush_cycles = |res: &ExecResult| {
+            let profiles = res.stats.branch_profile.values();
+            profiles.map(|p| p.flush_cycles).sum::<u64>()
+        };
+        assert_eq!(
+            deep.stats.direct_mispredicts,
+            shallow.stats.direct_mispredicts
+        );
+        assert!(flush_cycles(&deep) > flush_cycles(&shallow));
+        assert!(deep.stats.cycles_taken > shallow.stats.cycles_taken);
+    }
+
+    #[test]
+    fn test_icache_cold_misses() {
+        let res = parse_and_exec::<OutOfOrder>(
+            "quicksort",
+            RegSet::from([(ArchReg::A0, 0), (ArchReg::A1, 64)]),
+            MainMemory::new(),
+        );
+
+        // Each line of the program misses once, and is then reused by every iteration.
+        let lines = load_program("quicksort").insts.len().div_ceil(16) as u64;
+        assert!(res.stats.l1i_misses <= lines);
+        assert!(res.stats.l1i_hits > 10 * res.stats.l1i_misses);
+        assert!(res.stats.fetch_stalls > 0);
+    }
+
+    #[test]
+    fn test_fetch_stalls_are_real_waits() {
+        let regs = RegSet::from([
+            (ArchReg::A0, 0),
+            (ArchReg::A1, 4096),
+            (ArchReg::A2, 8192),
+            (ArchReg::A3, 256),
+        ]);
+        let res = parse_and_exec::<OutOfOrder>("loop", regs, MainMemory::new());
+
+        // The loop fits in a line, so fetch only ever waits for it to come from DRAM, and not for
+        // the halt to commit once it has been fetched.
+        assert_eq!(res.stats.l1i_misses, 1);
+        assert_eq!(res.stats.fetch_stalls, 400);
+    }
+
+    #[test]
+    fn test_compressed_fetch() {
+        let prog = load_program("quicksort");
+        let compressed = prog.compressed();
+        assert!(compressed.code_size() < prog.code_size());
+
+        // A narrow fetch holds more compressed instructions.
+        let run = |prog| {
+            let config = OutOfOrderConfig {
+                fetch_bytes: 8,
+                ..Default::default()
+            };
+            let regs = RegSet::from([(ArchReg::A0, 0), (ArchReg::A1, 200)]);
+            OutOfOrder::with_config(prog, regs, MainMemory::new(), config).exec_all()
+        };
+        let (full, short) = (run(prog), run(compressed));
+
+        for i in 0..200 {
+            assert_eq!(short.mem.readw(Addr(4 * i)).unwrap(), i + 1);
+        }
+        assert_eq!(short.stats.insts_retired, full.stats.insts_retired);
+
+        let per_block = |res: &aca::cpu::ExecResult| {
+            res.stats.fetch_block_insts as f32 / res.stats.fetch_blocks as f32
+        };
+        assert!(full.stats.fetch_block_bytes <= 8 * full.stats.fetch_blocks);
+        assert!(per_block(&short) > per_block(&full));
+        assert!(short.stats.cycles_taken < full.stats.cycles_taken);
+    }
+}
+
+#[cfg(test)]
+mod branch_profile {
+    use super::*;
+    use aca::{
+        cpu::{BranchReport, ExecResult},
+        load_program,
+        out_of_order::OutOfOrderConfig,
+        regs::RegSet,
+    };
+
+    #[test]
+    fn test_only_committed_branches_count() {
+        let run = |fetch_to_rename| {
+            let config = OutOfOrderConfig {
+                fetch_to_rename,
+                ..Default::default()
+            };
+            OutOfOrder::with_config(
+                load_program("quicksort"),
+                RegSet::from([(ArchReg::A0, 0), (ArchReg::A1, 64)]),
+                MainMemory::new(),
+                config,
+            )
+            .exec_all()
+        };
+        let counts = |res: &ExecResult| {
+            let mut counts = res
+                .stats
+                .branch_profile
+                .iter()
+                .map(|((thread, pc), p)| (*thread, pc.0, p.executions, p.taken))
+                .collect::<Vec<_>>();
+            counts.sort();
+            counts
+        };
+
+        // Wrong-path branches are squashed, so how often each branch runs doesn't depend on how
+        // far down the wrong path the core gets.
+        let (shallow, deep) = (run(1), run(8));
+        assert_eq!(counts(&shallow), counts(&deep));
+
+        // Each misprediction costs at least the trip from fetch to rename.
+        for (res, fetch_to_rename) in [(&shallow, 1), (&deep, 8)] {
+            let profiles = res.stats.branch_profile.values();
+            assert!(profiles.clone().any(|p| p.mispredicts > 0));
+            for p in profiles {
+                assert!(p.flush_cycles >= p.mispredicts * fetch_to_rename);
+            }
+        }
+    }
+
+    #[test]
+    fn test_threads_are_profiled_apart() {
+        let sort = RegSet::from([(ArchReg::A0, 0), (ArchReg::A1, 16)]);
+        let prime = RegSet::from([(ArchReg::A0, 97)]);
+        let alone = |name, regs: &RegSet| {
+            let res = parse_and_exec::<OutOfOrder>(name, regs.clone(), MainMemory::new());
+            let mut executions = res
+                .stats
+                .branch_profile
+                .iter()
+                .map(|(&(_, pc), p)| (pc, p.executions))
+                .collect::<Vec<_>>();
+            executions.sort();
+            executions
+        };
+
+        let progs = [load_program("quicksort"), load_program("prime")];
+        let threads = vec![
+            (progs[0].clone(), sort.clone()),
+            (progs[1].clone(), prime.clone()),
+        ];
+        let res =
+            OutOfOrder::with_threads(threads, MainMemory::new(), Default::default()).exec_all();
+
+        // Each thread's branches are counted as if it ran alone.
+        for (thread, expected) in [alone("quicksort", &sort), alone("prime", &prime)]
+            .into_iter()
+            .enumerate()
+        {
+            let mut executions = res
+                .stats
+                .branch_profile
+                .iter()
+                .filter(|((t, _), _)| *t == thread)
+                .map(|(&(_, pc), p)| (pc, p.executions))
+                .collect::<Vec<_>>();
+            executions.sort();
+            assert_eq!(executions, expected);
+        }
+
+        // And are located in their own program.
+        let report = BranchReport {
+            stats: &res.stats,
+            progs: &progs,
+        }
+        .to_string();
+        assert!(report.contains("0:generate"));
+        assert!(report.contains("1:is_prime"));
+    }
+}
+
+#[cfg(test)]
+mod fusion {
+    use super::*;
+    use aca::{
+        fusion::{FusionConfig, FusionRule},
+        load_program,
+        out_of_order::OutOfOrderConfig,
+        regs::RegSet,
+    };
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn test_every_rule_matches_emulated() {
+        let run = |n: u32| {
+            let mut mem = MainMemory::new();
+            for i in 0..n {
+                // Mix of signs and magnitudes, so both sides of each compare are taken.
+                let val = i.wrapping_mul(0x9e37_79b9) ^ (i << 29);
+                mem.writew(Addr(4 * i), val).unwrap();
+            }
+            (mem, RegSet::from([(ArchReg::A0, 0), (ArchReg::A1, n)]))
+        };
+
+        let (mem, regs) = run(32);
+        let expected = parse_and_exec::<Emulated>("fusion", regs.clone(), mem.clone());
+        let res = parse_and_exec::<OutOfOrder>("fusion", regs, mem);
+
+        for reg in ArchReg::iter() {
+            assert_eq!(res.regs.get(reg), expected.regs.get(reg), "{reg:?}");
+        }
+
+        for rule in FusionRule::iter() {
+            assert!(
+                res.stats.fusions.get(&rule).is_some(),
+                "{rule:?} never fused"
+            );
+        }
+    }
+
+    #[test]
+    fn test_shift_add_instruction() {
+        let n = 50;
+        let run = |name, fusion| {
+            let mut mem = MainMemory::new();
+            for i in 0..n {
+                mem.writew(Addr(4 * i), i * i).unwrap();
+                mem.writew(Addr(1000 + 4 * i), (7 * i) % n).unwrap();
+            }
+            let regs = RegSet::from([(ArchReg::A0, 0), (ArchReg::A1, 1000), (ArchReg::A2, n)]);
+            let config = OutOfOrderConfig {
+                fusion,
+                ..Default::default()
+            };
+            OutOfOrder::with_config(load_program(name), regs, mem, config).exec_all()
+        };
+
+        let fused = run("gather", FusionConfig::all());
+        let unfused = run("gather", FusionConfig::none());
+        let zba = run("gather_zba", FusionConfig::all());
+
+        let expected: u32 = (0..n).map(|i| ((7 * i) % n).pow(2)).sum();
+        for res in [&fused, &unfused, &zba] {
+            assert_eq!(res.regs.get(ArchReg::A0), expected);
+        }
+
+        // sh2add does in hardware what fusion does to each slli+add pair.
+        assert_eq!(
+            fused.stats.fusions.get(&FusionRule::ShiftAdd),
+            Some(&(2 * n as u64))
+        );
+        assert_eq!(zba.stats.fusions.get(&FusionRule::ShiftAdd), None);
+        assert_eq!(zba.stats.insts_retired, fused.stats.insts_retired);
+        assert_eq!(
+            unfused.stats.insts_retired,
+            zba.stats.insts_retired + 2 * n as u64
+        );
+    }
+}
+
+#[cfg(test)]
+mod rename {
+    use super::*;
+    use aca::{load_program, out_of_order::OutOfOrderConfig, regs::RegSet};
+
+    #[test]
+    fn test_move_elimination() {
+        let run = |move_elimination| {
+            let config = OutOfOrderConfig {
+                move_elimination,
+                ..Default::default()
+            };
+            OutOfOrder::with_config(
+                load_program("fibonnaci"),
+                RegSet::from([(ArchReg::A0, 10)]),
+                MainMemory::new(),
+                config,
+            )
+            .exec_all()
+        };
+
+        let (without, with) = (run(false), run(true));
+        assert_eq!(with.regs.get(ArchReg::A0), without.regs.get(ArchReg::A0));
+        assert_eq!(with.stats.insts_retired, without.stats.insts_retired);
+
+        assert_eq!(without.stats.moves_eliminated, 0);
+        assert!(with.stats.moves_eliminated > 0);
+    }
+}
+
+#[cfg(test)]
+mod execution_units {
+    use super::*;
+    use aca::{execution_unit::EuType, load_program, out_of_order::OutOfOrderConfig, regs::RegSet};
+
+    #[test]
+    fn test_divider_is_not_pipelined() {
+        let regs = RegSet::from([(ArchReg::A0, 1_000_003)]);
+        let expected = parse_and_exec::<Emulated>("divide", regs.clone(), MainMemory::new());
+        let res = parse_and_exec::<OutOfOrder>("divide", regs, MainMemory::new());
+
+        for reg in [
+            ArchReg::A2,
+            ArchReg::A6,
+            ArchReg::T0,
+            ArchReg::T2,
+            ArchReg::T3,
+        ] {
+            assert_eq!(res.regs.get(reg), expected.regs.get(reg), "{reg:?}");
+        }
+
+        // Each divide has to wait for the previous one to leave the unit.
+        assert!(res.stats.cycles_taken >= 8 * 20);
+
+        let util = |eu_type| {
+            res.stats
+                .eu_util
+                .iter()
+                .find(|(t, _)| *t == eu_type)
+                .map(|(_, util)| *util)
+                .unwrap()
+        };
+        assert!(util(EuType::Div) > util(EuType::Mul));
+    }
+
+    #[test]
+    fn test_unit_timing_is_configurable() {
+        let run = |div_units: Vec<(EuType, bool, u64)>| {
+            let mut config = OutOfOrderConfig::default();
+            config.execution_units.retain(|&(t, _, _)| t != EuType::Div);
+            config.execution_units.extend(div_units);
+            OutOfOrder::with_config(
+                load_program("divide"),
+                RegSet::from([(ArchReg::A0, 1_000_003)]),
+                MainMemory::new(),
+                config,
+            )
+            .exec_all()
+        };
+
+        let blocking = run(vec![(EuType::Div, false, 1)]);
+        let pipelined = run(vec![(EuType::Div, true, 1)]);
+        let interval = run(vec![(EuType::Div, true, 4)]);
+        let two = run(vec![(EuType::Div, false, 1), (EuType::Div, false, 1)]);
+
+        for res in [&pipelined, &interval, &two] {
+            assert_eq!(res.regs.get(ArchReg::A2), blocking.regs.get(ArchReg::A2));
+            assert!(res.stats.cycles_taken < blocking.stats.cycles_taken);
+        }
+        assert!(pipelined.stats.cycles_taken < interval.stats.cycles_taken);
+    }
+}
+
+#[cfg(test)]
+mod scheduler {
+    use super::*;
+    use aca::{
+        load_program,
+        out_of_order::OutOfOrderConfig,
+        regs::RegSet,
+        reservation_station::{IssuePolicy, SchedulerTopology},
+    };
+
+    fn run(scheduler: SchedulerTopology) -> aca::cpu::ExecResult {
+        run_with(OutOfOrderConfig {
+            scheduler,
+            ..Default::default()
+        })
+    }
+
+    fn run_with(config: OutOfOrderConfig) -> aca::cpu::ExecResult {
+        OutOfOrder::with_config(
+            load_program("quicksort"),
+            RegSet::from([(ArchReg::A0, 0), (ArchReg::A1, 64)]),
+            MainMemory::new(),
+            config,
+        )
+        .exec_all()
+    }
+
+    #[test]
+    fn test_topologies_match() {
+        let unified = run(SchedulerTopology::Unified { capacity: 100 });
+        for topology in [
+            SchedulerTopology::PerClass { capacity: 16 },
+            SchedulerTopology::Ports { capacity: 16 },
+        ] {
+            let res = run(topology);
+            for i in 0..64 {
+                assert_eq!(
+                    res.mem.readw(Addr(4 * i)).unwrap(),
+                    unified.mem.readw(Addr(4 * i)).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_per_queue_stalls() {
+        let res = run(SchedulerTopology::PerClass { capacity: 2 });
+        let stalls = |name: &str| {
+            res.stats
+                .rs_stalls
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, stalls)| *stalls)
+                .unwrap()
+        };
+
+        assert!(stalls("LoadStore") > 0);
+        // Nothing in quicksort needs the divider, so its queue never fills.
+        assert_eq!(stalls("Div"), 0);
+        assert!(res.stats.reservation_station_stalls >= stalls("LoadStore"));
+    }
+
+    #[test]
+    fn test_issue_policies_match() {
+        let expected = run(SchedulerTopology::default());
+        for issue_policy in [
+            IssuePolicy::Random,
+            IssuePolicy::CriticalPath,
+            IssuePolicy::LoadFirst,
+        ] {
+            let res = run_with(OutOfOrderConfig {
+                issue_policy,
+                ..Default::default()
+            });
+            for i in 0..64 {
+                assert_eq!(
+                    res.mem.readw(Addr(4 * i)).unwrap(),
+                    expected.mem.readw(Addr(4 * i)).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_wakeup_latency() {
+        let instant = run(SchedulerTopology::default());
+        let slow = run_with(OutOfOrderConfig {
+            wakeup_latency: 2,
+            ..Default::default()
+        });
+        let speculative = run_with(OutOfOrderConfig {
+            wakeup_latency: 2,
+            speculative_load_wakeup: true,
+            ..Default::default()
+        });
+
+        for i in 0..64 {
+            assert_eq!(
+                slow.mem.readw(Addr(4 * i)).unwrap(),
+                instant.mem.readw(Addr(4 * i)).unwrap()
+            );
+            assert_eq!(
+                speculative.mem.readw(Addr(4 * i)).unwrap(),
+                instant.mem.readw(Addr(4 * i)).unwrap()
+            );
+        }
+
+        assert!(slow.stats.cycles_taken > instant.stats.cycles_taken);
+        assert_eq!(slow.stats.load_replays, 0);
+
+        // Waking load dependents early hides some of the latency, at the cost of replaying
+        // them when the load takes longer than an L1 hit.
+        assert!(speculative.stats.cycles_taken < slow.stats.cycles_taken);
+        assert!(speculative.stats.load_replays > 0);
+    }
+}
+
+#[cfg(test)]
+mod memory_dependence {
+    use super::*;
+    use aca::{
+        execution_unit::EuType, load_program, lsq::MemDependence, out_of_order::OutOfOrderConfig,
+        regs::RegSet,
+    };
+
+    #[test]
+    fn test_store_sets_reduce_flushes() {
+        let run = |mem_dependence| {
+            let config = OutOfOrderConfig {
+                mem_dependence,
+                ..Default::default()
+            };
+            OutOfOrder::with_config(
+                load_program("fibonnaci"),
+                RegSet::from([(ArchReg::A0, 12)]),
+                MainMemory::new(),
+                config,
+            )
+            .exec_all()
+        };
+
+        let blind = run(MemDependence::Blind);
+        let conservative = run(MemDependence::Conservative);
+        let store_sets = run(MemDependence::StoreSets);
+
+        for res in [&conservative, &store_sets] {
+            assert_eq!(res.regs.get(ArchReg::A0), blind.regs.get(ArchReg::A0));
+        }
+
+        assert_eq!(conservative.stats.mem_order_violations, 0);
+        assert!(blind.stats.mem_order_flushes > 0);
+
+        // The first few violations train the predictor, after that the loads wait.
+        assert!(store_sets.stats.mem_order_flushes * 10 < blind.stats.mem_order_flushes);
+        assert!(store_sets.stats.cycles_taken < blind.stats.cycles_taken);
+    }
+
+    #[test]
+    fn test_violations_with_two_load_store_units() {
+        let mut config = OutOfOrderConfig {
+            mem_dependence: MemDependence::Blind,
+            ..Default::default()
+        };
+        config.execution_units.push((EuType::LoadStore, true, 1));
+
+        let regs = RegSet::from([(ArchReg::A0, 12)]);
+        let expected = parse_and_exec::<Emulated>("fibonnaci", regs.clone(), MainMemory::new());
+        let res =
+            OutOfOrder::with_config(load_program("fibonnaci"), regs, MainMemory::new(), config)
+                .exec_all();
+
+        // A load caught executing is only killed from the unit it's on.
+        assert_eq!(res.regs.get(ArchReg::A0), expected.regs.get(ArchReg::A0));
+        assert!(res.stats.mem_order_violations > 0);
+    }
+}
+
+#[cfg(test)]
+mod misaligned {
+    use super::*;
+    use aca::regs::RegSet;
+
+    #[test]
+    fn test_misaligned_accesses_are_slower() {
+        let run = |name, misaligned| {
+            let mem = MainMemory::new().with_misaligned(misaligned);
+            let regs = RegSet::from([(ArchReg::A0, 0x1000)]);
+            parse_and_exec::<OutOfOrder>(name, regs, mem).stats
+        };
+
+        let stats = run("misaligned", Misaligned::Hardware);
+        assert_eq!(stats.misaligned_accesses, 7);
+        assert_eq!(stats.split_accesses, 2);
+
+        // Aligned accesses cost the same whatever the policy.
+        let aligned = |misaligned| run("hazard_raw", misaligned).cycles_taken;
+        assert_eq!(aligned(Misaligned::Hardware), aligned(Misaligned::Fault));
+
+        // The same loop over arrays starting a byte past a word boundary takes longer.
+        let copy = |offset: u32| {
+            let regs = RegSet::from([
+                (ArchReg::A0, 0x1000 + offset),
+                (ArchReg::A1, 0x2000 + offset),
+                (ArchReg::A2, 0x3000 + offset),
+                (ArchReg::A3, 64),
+            ]);
+            let mem = MainMemory::new().with_misaligned(Misaligned::Hardware);
+            parse_and_exec::<OutOfOrder>("loop", regs, mem).stats
+        };
+        let (aligned, misaligned) = (copy(0), copy(1));
+        assert_eq!(aligned.insts_retired, misaligned.insts_retired);
+        assert_eq!(aligned.misaligned_accesses, 0);
+        assert_eq!(misaligned.misaligned_accesses, 3 * 64);
+        assert!(misaligned.split_accesses > 0);
+        assert!(misaligned.cycles_taken > aligned.cycles_taken);
+    }
+}
+
+#[cfg(test)]
+mod mshrs {
+    use super::*;
+    use aca::{
+        cpu::ExecResult, load_program, mem::MshrConfig, out_of_order::OutOfOrderConfig,
+        regs::RegSet,
+    };
+
+    fn run(name: &str, regs: RegSet, mem: MainMemory, mshrs: usize) -> ExecResult {
+        let config = OutOfOrderConfig {
+            mshrs: MshrConfig {
+                l1: mshrs,
+                l2: mshrs,
+                l3: mshrs,
+            },
+            ..Default::default()
+        };
+        OutOfOrder::with_config(load_program(name), regs, mem, config).exec_all()
+    }
+
+    #[test]
+    fn test_fewer_mshrs_are_slower() {
+        let regs = RegSet::from([(ArchReg::A0, 0), (ArchReg::A1, 16)]);
+        let [one, few, some, plenty] =
+            [1, 2, 8, 128].map(|mshrs| run("matmul", regs.clone(), MainMemory::new(), mshrs));
+
+        assert_eq!(one.mem, plenty.mem);
+        assert_eq!(few.mem, plenty.mem);
+        assert_eq!(some.mem, plenty.mem);
+        assert!(one.stats.cycles_taken > few.stats.cycles_taken);
+        assert!(few.stats.cycles_taken > some.stats.cycles_taken);
+        assert!(some.stats.cycles_taken > plenty.stats.cycles_taken);
+        assert!(few.stats.mshr_stalls > some.stats.mshr_stalls);
+        assert_eq!(plenty.stats.mshr_stalls, 0);
+
+        // No level ever has more in use than there are.
+        for (res, mshrs) in [(&one, 1), (&few, 2), (&some, 8)] {
+            for histogram in &res.stats.mshr_occupancy {
+                assert_eq!(histogram.len(), mshrs + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_accesses_with_one_mshr() {
+        // Accesses that cross a line take their two misses one after the other.
+        let run = |mshrs| {
+            let mem = MainMemory::new().with_misaligned(Misaligned::Hardware);
+            run(
+                "misaligned",
+                RegSet::from([(ArchReg::A0, 0x1000)]),
+                mem,
+                mshrs,
+            )
+        };
+        let (one, plenty) = (run(1), run(128));
+
+        assert_eq!(one.mem, plenty.mem);
+        assert_eq!(one.fault, plenty.fault);
+        assert_eq!(one.stats.split_accesses, 2);
+        assert!(one.stats.cycles_taken > plenty.stats.cycles_taken);
+    }
+}
+
+#[cfg(test)]
+mod value_prediction {
+    use super::*;
+    use aca::{
+        load_program, out_of_order::OutOfOrderConfig, regs::RegSet,
+        value_predict::ValuePredictorKind,
+    };
+
+    #[test]
+    fn test_predictors_match_emulated() {
+        let regs = RegSet::from([(ArchReg::A0, 0), (ArchReg::A1, 64)]);
+        let expected = parse_and_exec::<Emulated>("quicksort", regs.clone(), MainMemory::new());
+
+        for kind in [
+            ValuePredictorKind::LastValue,
+            ValuePredictorKind::Stride,
+            ValuePredictorKind::Context,
+        ] {
+            let config = OutOfOrderConfig {
+                value_predictor: Some(kind),
+                ..Default::default()
+            };
+            let res = OutOfOrder::with_config(
+                load_program("quicksort"),
+                regs.clone(),
+                MainMemory::new(),
+                config,
+            )
+            .exec_all();
+
+            for i in 0..64 {
+                assert_eq!(
+                    res.mem.readw(Addr(4 * i)).unwrap(),
+                    expected.mem.readw(Addr(4 * i)).unwrap()
+                );
+            }
+
+            // Every load is looked up, only some are confident enough to predict.
+            assert!(res.stats.value_lookups > 0);
+            assert!(res.stats.value_predicts > 0, "{kind:?} never predicted");
+            assert!(res.stats.value_predicts <= res.stats.value_lookups);
+            assert!(res.stats.value_mispredicts < res.stats.value_predicts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod smt {
+    use super::*;
+    use aca::{
+        load_program,
+        mem::STACK_TOP,
+        out_of_order::{FetchPolicy, OutOfOrderConfig},
+        regs::RegSet,
+    };
+
+    #[test]
+    fn test_threads_match_emulated() {
+        let sorts = [0, 4096].map(|base| RegSet::from([(ArchReg::A0, base), (ArchReg::A1, 64)]));
+        let expected = sorts
+            .clone()
+            .map(|regs| parse_and_exec::<Emulated>("quicksort", regs, MainMemory::new()));
+
+        // Each thread needs a stack of its own.
+        let mut second = sorts[1].clone();
+        second.set(ArchReg::SP, (STACK_TOP - 64_000) as u32);
+
+        for fetch_policy in [FetchPolicy::RoundRobin, FetchPolicy::ICount] {
+            let threads = vec![
+                (load_program("quicksort"), sorts[0].clone()),
+                (load_program("quicksort"), second.clone()),
+            ];
+            let config = OutOfOrderConfig {
+                fetch_policy,
+                ..Default::default()
+            };
+            let res = OutOfOrder::with_threads(threads, MainMemory::new(), config).exec_all();
+
+            for (base, expected) in [(0, &expected[0]), (4096, &expected[1])] {
+                for i in 0..64 {
+                    let addr = Addr(base + 4 * i);
+                    assert_eq!(
+                        res.mem.readw(addr).unwrap(),
+                        expected.mem.readw(addr).unwrap()
+                    );
+                }
+            }
+
+            assert_eq!(res.thread_regs.len(), 2);
+            assert_eq!(res.thread_regs[1].get(ArchReg::A0), 4096);
+
+            let retired = &res.stats.thread_insts_retired;
+            assert_eq!(retired[0], retired[1]);
+            assert_eq!(retired.iter().sum::<u64>(), res.stats.insts_retired);
+        }
+    }
+}
+
+#[cfg(test)]
+mod multicore {
+    use super::*;
+    use aca::{
+        load_program,
+        mem::MshrConfig,
+        multicore::{Core, MultiCore},
+        regs::RegSet,
+    };
+
+    const DIM: u32 = 12;
+
+    fn matmul_inputs() -> MainMemory {
+        let mut mem = MainMemory::new();
+        for j in 0..DIM {
+            for k in 0..DIM {
+                mem.writew(Addr(4 * (j * DIM + k)), j + 2 * k).unwrap();
+                mem.writew(Addr(4 * (DIM * DIM + j * DIM + k)), j * k % 5)
+                    .unwrap();
+            }
+        }
+        mem
+    }
+
+    fn parallel<C: Core>(
+        name: &str,
+        a0: u32,
+        a1: u32,
+        mem: MainMemory,
+        cores: u32,
+    ) -> MultiCore<C> {
+        let workloads = (0..cores)
+            .map(|core| {
+                let regs = RegSet::from([
+                    (ArchReg::A0, a0),
+                    (ArchReg::A1, a1),
+                    (ArchReg::A2, core),
+                    (ArchReg::A3, cores),
+                ]);
+                (load_program(name), regs)
+            })
+            .collect();
+        MultiCore::new(workloads, mem, MshrConfig::default())
+    }
+
+    #[test]
+    fn test_parallel_matmul() {
+        let inputs = matmul_inputs();
+        let out = |j, i| Addr(4 * (2 * DIM * DIM + j * DIM + i));
+
+        for cores in [1, 2, 4] {
+            let res =
+                parallel::<OutOfOrder>("matmul_parallel", 0, DIM, inputs.clone(), cores).exec_all();
+            let emulated =
+                parallel::<Emulated>("matmul_parallel", 0, DIM, inputs.clone(), cores).exec_all();
+
+            for j in 0..DIM {
+                for i in 0..DIM {
+                    let expected = (0..DIM)
+                        .map(|k| {
+                            inputs.readw(Addr(4 * (j * DIM + k))).unwrap()
+                                * inputs.readw(Addr(4 * (DIM * DIM + k * DIM + i))).unwrap()
+                        })
+                        .sum::<u32>();
+                    assert_eq!(res.mem.readw(out(j, i)).unwrap(), expected);
+                    assert_eq!(emulated.mem.readw(out(j, i)).unwrap(), expected);
+                }
+            }
+
+            // Rows are interleaved between cores and share cache lines, so each core's writes
+            // invalidate the others' copies.
+            let invalidations = res.stats.iter().map(|s| s.invalidations).sum::<u64>();
+            let coherence_misses = res.stats.iter().map(|s| s.coherence_misses).sum::<u64>();
+            if cores == 1 {
+                assert_eq!(invalidations + coherence_misses, 0);
+            } else {
+                assert!(invalidations > 0);
+                assert!(coherence_misses > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_box_blur() {
+        let dims = 10;
+        let mut mem = MainMemory::new();
+        for i in 0..dims * dims {
+            mem.writew(Addr(1000 + 4 * i), i * 7 % 13).unwrap();
+        }
+
+        let regs = RegSet::from([(ArchReg::A0, 1000), (ArchReg::A1, dims)]);
+        let expected = parse_and_exec::<Emulated>("box_blur", regs, mem.clone());
+        let res = parallel::<OutOfOrder>("box_blur_parallel", 1000, dims, mem, 3).exec_all();
+
+        for i in 0..dims * dims {
+            let addr = Addr(500_000 + 4 * i);
+            assert_eq!(
+                res.mem.readw(addr).unwrap(),
+                expected.mem.readw(addr).unwrap()
+            );
+        }
+        assert_eq!(res.stats.len(), 3);
+    }
+
+    #[test]
+    fn test_spinlock() {
+        fn run<C: Core>(cores: u32) -> MainMemory {
+            parallel::<C>("spinlock", 256, 20, MainMemory::new(), cores)
+                .exec_all()
+                .mem
+        }
+
+        for cores in [1, 2, 4] {
+            for mem in [run::<OutOfOrder>(cores), run::<Emulated>(cores)] {
+                assert_eq!(mem.readw(Addr(256)).unwrap(), 0, "lock left held");
+                assert_eq!(mem.readw(Addr(260)).unwrap(), 20 * cores);
+                assert_eq!(mem.readw(Addr(256 + 64)).unwrap(), 20 * cores);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod vector {
+    use super::*;
+    use aca::{
+        cpu::ExecResult, load_program, out_of_order::OutOfOrderConfig, regs::RegSet,
+        vector::VectorConfig,
+    };
+
+    const PIXELS: u32 = 1000;
+
+    fn images() -> (RegSet, MainMemory) {
+        let mut mem = MainMemory::new();
+        for i in 0..PIXELS {
+            mem.writeb(Addr(1000 + i), i * 7 % 256).unwrap();
+            mem.writeb(Addr(2000 + i), i * 13 % 256).unwrap();
+        }
+        let regs = RegSet::from([
+            (ArchReg::A0, 1000),
+            (ArchReg::A1, 2000),
+            (ArchReg::A2, 3000),
+            (ArchReg::A3, PIXELS),
+        ]);
+        (regs, mem)
+    }
+
+    fn run(vector: VectorConfig) -> ExecResult {
+        let (regs, mem) = images();
+        let config = OutOfOrderConfig {
+            vector,
+            ..Default::default()
+        };
+        OutOfOrder::with_config(load_program("blend"), regs, mem, config).exec_all()
+    }
+
+    fn with_vlen(vlen: u32) -> VectorConfig {
+        VectorConfig {
+            vlen,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_vlens_match_emulated() {
+        for vlen in [128, 256, 512] {
+            let (regs, mem) = images();
+            let expected = Emulated::with_vlen(load_program("blend"), regs, mem, vlen).exec_all();
+            let res = run(with_vlen(vlen));
+
+            assert_eq!(res.mem, expected.mem, "VLEN {vlen}");
+            for reg in [ArchReg::A0, ArchReg::A2, ArchReg::T0] {
+                assert_eq!(res.regs.get(reg), expected.regs.get(reg), "VLEN {vlen}");
+            }
+            for i in 0..PIXELS {
+                let blended = (i * 7 % 256) / 2 + (i * 13 % 256) / 2;
+                assert_eq!(
+                    res.mem.readbu(Addr(3000 + i)).unwrap(),
+                    blended,
+                    "pixel {i}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_longer_vectors_are_faster() {
+        let cycles = [128, 256, 512].map(|vlen| run(with_vlen(vlen)).stats.cycles_taken);
+        assert!(cycles[0] > cycles[1] && cycles[1] > cycles[2], "{cycles:?}");
+    }
+
+    #[test]
+    fn test_chaining() {
+        let chained = run(VectorConfig::default());
+        let unchained = run(VectorConfig {
+            chaining: false,
+            ..Default::default()
+        });
+
+        assert_eq!(chained.mem, unchained.mem);
+        assert!(chained.stats.vector_chains > 0);
+        assert_eq!(unchained.stats.vector_chains, 0);
+        assert!(chained.stats.cycles_taken < unchained.stats.cycles_taken);
+    }
 }
@@ -14,7 +14,7 @@ fn parse_all() {
         println!("parsing {prog_name}...");
         contents
             .parse::<Program>()
-            .expect(&format!("failed to parse program {}", prog_name));
+            .unwrap_or_else(|_| panic!("failed to parse program {}", prog_name));
     }
 }
 
@@ -31,3 +31,50 @@ fn check_labels() {
     test.insert(Label("baz5".to_owned()), AbsPc::from(16));
     assert_eq!(prog.labels, test);
 }
+
+#[test]
+fn check_compressed_layout() {
+    let prog = "start:\n c.li a0, 1\n addi a0, a0, 1\nmid:\n c.addi a0, 1\nend:\n"
+        .parse::<Program>()
+        .expect("failed to parse compressed program");
+
+    assert_eq!(prog.labels[&Label("mid".to_owned())], AbsPc::from(6));
+    assert_eq!(prog.labels[&Label("end".to_owned())], AbsPc::from(8));
+    assert_eq!(prog.code_size(), 8);
+    assert_eq!(prog.inst_size(AbsPc::from(2)), 4);
+
+    // Compressing everything that fits leaves the same instructions two bytes each.
+    let compressed = prog.compressed();
+    assert_eq!(compressed.insts, prog.insts);
+    assert_eq!(compressed.code_size(), 6);
+
+    // T0 isn't one of the registers a compressed load can name.
+    assert!("c.lw t0, 0(a0)".parse::<Program>().is_err());
+    assert!("c.lw a1, 0(a0)".parse::<Program>().is_ok());
+}
+
+#[test]
+fn check_vector_operands() {
+    assert!("vsetvli t0, a0, e16, m1, ta, ma".parse::<Program>().is_ok());
+    assert!("vsetvli t0, a0, e64".parse::<Program>().is_err());
+    assert!("vsetvli t0, a0, e8, m2".parse::<Program>().is_err());
+
+    assert!("vle32.v v1, (a0)".parse::<Program>().is_ok());
+    assert!("vle32.v v1, 4(a0)".parse::<Program>().is_err());
+    assert!("vle32.v a1, (a0)".parse::<Program>().is_err());
+
+    assert!("vadd.vi v1, v2, -16".parse::<Program>().is_ok());
+    assert!("vadd.vi v1, v2, 16".parse::<Program>().is_err());
+    assert!("vsll.vi v1, v2, 31".parse::<Program>().is_ok());
+    assert!("vsll.vi v1, v2, -1".parse::<Program>().is_err());
+}
+
+#[test]
+fn check_csr_operands() {
+    assert!("csrw satp, t0".parse::<Program>().is_ok());
+    assert!("csrrw t1, stvec, t0".parse::<Program>().is_ok());
+    assert!("csrr t0, scause".parse::<Program>().is_ok());
+    assert!("csrr t0, frm".parse::<Program>().is_ok());
+    assert!("csrr t0, mstatus".parse::<Program>().is_err());
+    assert!("sret\nsfence.vma".parse::<Program>().is_ok());
+}
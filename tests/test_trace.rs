@@ -0,0 +1,70 @@
+use aca::{
+    branch::{BranchPredictor, BranchPredictorConfig},
+    cpu::Cpu,
+    emulated::Emulated,
+    inst::ArchReg,
+    load_program,
+    mem::MainMemory,
+    regs::RegSet,
+    trace::{BranchKind, BranchTrace},
+};
+
+fn record(name: &str, regs: RegSet) -> BranchTrace {
+    let (res, trace) = Emulated::new(load_program(name), regs, MainMemory::new()).exec_traced();
+    assert_eq!(trace.insts, res.stats.insts_retired);
+    trace
+}
+
+#[test]
+fn trace_round_trip() {
+    let trace = record("fibonnaci", RegSet::from([(ArchReg::A0, 8)]));
+    assert!(trace.records.iter().any(|r| r.kind == BranchKind::Call));
+    assert!(trace.records.iter().any(|r| r.kind == BranchKind::Return));
+
+    let mut buf = Vec::new();
+    trace.write_to(&mut buf).unwrap();
+    assert_eq!(BranchTrace::read_from(buf.as_slice()).unwrap(), trace);
+    assert!(BranchTrace::read_from(&buf[1..]).is_err());
+}
+
+#[test]
+fn trace_compressed_returns() {
+    let regs = RegSet::from([(ArchReg::A0, 10), (ArchReg::A2, 256)]);
+    let trace = record("compressed", regs);
+    assert!(trace.records.iter().all(|r| r.compressed));
+
+    let mut buf = Vec::new();
+    trace.write_to(&mut buf).unwrap();
+    assert_eq!(BranchTrace::read_from(buf.as_slice()).unwrap(), trace);
+
+    // Calls push the address two bytes on, so every return is predicted by the RAS.
+    let stats = trace.replay(&mut BranchPredictor::new(BranchPredictorConfig::default()));
+    assert_eq!(stats.indirect_predicts, 11);
+    assert_eq!(stats.indirect_mispredicts, 0);
+}
+
+#[test]
+fn trace_replay() {
+    let trace = record("matmul", RegSet::from([(ArchReg::A0, 0), (ArchReg::A1, 8)]));
+
+    let replay = |loop_entries| {
+        let config = BranchPredictorConfig {
+            loop_entries,
+            ..Default::default()
+        };
+        trace.replay(&mut BranchPredictor::new(config))
+    };
+
+    let without_loops = replay(0);
+    let with_loops = replay(64);
+
+    assert_eq!(
+        with_loops.direct_predicts,
+        trace
+            .records
+            .iter()
+            .filter(|r| r.kind == BranchKind::Conditional)
+            .count() as u64
+    );
+    assert!(with_loops.mpki() < without_loops.mpki());
+}